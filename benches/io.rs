@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use daafs::{backend::MemoryBackend, config::Config, DiscordDrivePlugin};
+use nbdkit::Server;
+use serenity::model::prelude::ChannelId;
+
+const CHANNEL: ChannelId = ChannelId(1);
+const PAGE_SIZE: usize = 1024 * 1024;
+/// Pages written before reading, four times the cache.
+const PAGES: u64 = 16;
+const BLOCK: usize = 4096;
+
+/// Drive of `PAGES` pages of 1MB on the in-memory backend, all of them written and flushed.
+fn drive() -> (Arc<MemoryBackend>, DiscordDrivePlugin) {
+    let backend = Arc::new(MemoryBackend::new());
+    let config = Config { page_size: PAGE_SIZE, ..Config::default() };
+    let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+    for page in 0..PAGES {
+        plugin.write(page * PAGE_SIZE as u64, &[1; BLOCK]).unwrap();
+    }
+    plugin.flush().unwrap();
+
+    (backend, plugin)
+}
+
+/// Offsets of 4KB blocks spread over the whole drive, the same on every run.
+fn random_offsets(count: usize) -> Vec<u64> {
+    let blocks = PAGES * (PAGE_SIZE / BLOCK) as u64;
+    let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+
+    (0..count).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) % blocks * BLOCK as u64
+    }).collect()
+}
+
+fn sequential_reads(c: &mut Criterion) {
+    let (_backend, plugin) = drive();
+    let offsets: Vec<u64> = (0..PAGES * (PAGE_SIZE / BLOCK) as u64).map(|i| i * BLOCK as u64).collect();
+
+    let mut group = c.benchmark_group("reads");
+    group.throughput(Throughput::Bytes((offsets.len() * BLOCK) as u64));
+    group.sample_size(10);
+    group.bench_function("sequential 4KB", |b| {
+        b.iter(|| offsets.iter().map(|offset| plugin.read(*offset).unwrap().len()).sum::<usize>())
+    });
+    group.finish();
+}
+
+fn random_reads(c: &mut Criterion) {
+    let (_backend, plugin) = drive();
+    let offsets = random_offsets(256);
+
+    let mut group = c.benchmark_group("reads");
+    group.throughput(Throughput::Bytes((offsets.len() * BLOCK) as u64));
+    group.sample_size(10);
+    group.bench_function("random 4KB", |b| {
+        b.iter(|| offsets.iter().map(|offset| plugin.read(*offset).unwrap().len()).sum::<usize>())
+    });
+    group.finish();
+}
+
+fn sequential_writes(c: &mut Criterion) {
+    let offsets: Vec<u64> = (0..PAGES * (PAGE_SIZE / BLOCK) as u64).map(|i| i * BLOCK as u64).collect();
+
+    let mut group = c.benchmark_group("writes");
+    group.throughput(Throughput::Bytes((offsets.len() * BLOCK) as u64));
+    group.sample_size(10);
+    group.bench_function("sequential 4KB and flush", |b| {
+        b.iter_batched(drive, |(_backend, plugin)| {
+            for offset in offsets.iter() {
+                plugin.write(*offset, &[2; BLOCK]).unwrap();
+            }
+            plugin.flush().unwrap();
+        }, BatchSize::PerIteration)
+    });
+    group.finish();
+}
+
+/// Not timed, prints how many random reads are served without downloading the page.
+/// Compare it between runs like the timings, a drop means the cache got worse.
+fn cache_hit_ratio(_: &mut Criterion) {
+    let (backend, plugin) = drive();
+    let offsets = random_offsets(4096);
+
+    let before = backend.calls("download");
+    for offset in offsets.iter() {
+        plugin.read(*offset).unwrap();
+    }
+    let misses = backend.calls("download") - before;
+
+    println!(
+        "cache hit ratio: {:.1}% ({} of {} random reads downloaded a page)",
+        100.0 * (offsets.len() - misses) as f64 / offsets.len() as f64, misses, offsets.len()
+    );
+}
+
+criterion_group!(benches, sequential_reads, random_reads, sequential_writes, cache_hit_ratio);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use daafs::metadata::{MetadataBlock, Page};
+
+/// Serializes a drive with 500 pages, spread over metadata blocks of 5 pages.
+fn serialize_metadata(c: &mut Criterion) {
+    let blocks: Vec<MetadataBlock> = (0..100u64)
+        .map(|b| {
+            let mut block = MetadataBlock::empty(Some(1_100_000_000_000_000_000 + b));
+            for p in 0..5 {
+                let mut page = Page::new(b * 5 + p);
+                page.message_id = Some(1_100_000_000_000_001_000 + b * 5 + p);
+                block.pages.push(page);
+            }
+            block
+        })
+        .collect();
+
+    c.bench_function("serialize 500 pages", |b| {
+        b.iter(|| blocks.iter().map(|block| block.as_text().len()).sum::<usize>())
+    });
+}
+
+criterion_group!(benches, serialize_metadata);
+criterion_main!(benches);
@@ -0,0 +1,1856 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, future::Future, ops::Range, sync::{Arc, Mutex, RwLock, atomic::{AtomicUsize, Ordering}}, time::{Duration, Instant}};
+
+use async_trait::async_trait;
+use serenity::{http::{Http, HttpError}, model::prelude::{Channel, ChannelId, Message}};
+
+use crate::error::{Error, Result};
+use crate::latency::{Histogram, Latencies};
+use crate::utils::LockOrRecover;
+
+/// Most characters discord accepts in the content of a message.
+pub const MESSAGE_LIMIT: usize = 2000;
+
+/// Message as seen by the drive.
+#[derive(Clone, Debug)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub content: String,
+    /// Urls of the attachments of this message.
+    pub attachments: Vec<String>,
+}
+
+impl From<Message> for StoredMessage {
+    fn from(message: Message) -> Self {
+        Self {
+            id: message.id.0,
+            content: message.content,
+            attachments: message.attachments.into_iter().map(|a| a.url).collect(),
+        }
+    }
+}
+
+/// Attachment data returned by `download_range`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Download {
+    /// Just the requested bytes, and the size of the whole attachment.
+    Range { data: Vec<u8>, total: usize },
+    /// The whole attachment, the range was not honored.
+    Full(Vec<u8>),
+}
+
+/// Errors returned by the storage backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendError {
+    /// Requested message or attachment doesn't exist.
+    NotFound,
+    /// Connection to discord was lost or the token was rejected.
+    /// Reconnecting may fix it.
+    Disconnected(String),
+    /// The bot is not allowed to do the operation (it lacks permissions in the channel).
+    Forbidden(String),
+    /// Operation kept failing until it ran out of attempts.
+    RetriesExhausted { attempts: u32, last: String },
+    /// Any other failure.
+    Other(String),
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::NotFound => write!(f, "Not found"),
+            BackendError::Disconnected(reason) => write!(f, "Disconnected: {}", reason),
+            BackendError::Forbidden(reason) => write!(f, "Forbidden: {}", reason),
+            BackendError::RetriesExhausted { attempts, last } => write!(f, "Gave up after {} attempts: {}", attempts, last),
+            BackendError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl BackendError {
+    /// Returns true if discord couldn't be reached, rather than refusing the operation.
+    pub fn is_connection_error(&self) -> bool {
+        matches!(self, BackendError::Disconnected(_) | BackendError::RetriesExhausted { .. })
+    }
+}
+
+pub type BackendResult<T> = std::result::Result<T, BackendError>;
+
+impl From<serenity::Error> for BackendError {
+    fn from(error: serenity::Error) -> Self {
+        match &error {
+            serenity::Error::Http(http) => match http.as_ref() {
+                HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 404 => BackendError::NotFound,
+                HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 401 => BackendError::Disconnected(error.to_string()),
+                HttpError::UnsuccessfulRequest(response) if response.status_code.as_u16() == 403 => BackendError::Forbidden(error.to_string()),
+                HttpError::Request(_) => BackendError::Disconnected(error.to_string()),
+                _ => BackendError::Other(error.to_string()),
+            },
+            _ => BackendError::Other(error.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for BackendError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.status().map(|s| s.as_u16()) == Some(404) {
+            BackendError::NotFound
+        } else if error.status().map(|s| s.as_u16()) == Some(403) {
+            BackendError::Forbidden(error.to_string())
+        } else if error.is_connect() || error.is_timeout() || error.is_request() {
+            BackendError::Disconnected(error.to_string())
+        } else {
+            BackendError::Other(error.to_string())
+        }
+    }
+}
+
+/// Everything the drive needs from discord.
+/// Abstracted so that the drive can be tested without a real connection.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage>;
+
+    /// Returns up to `limit` messages older than `before` (or the newest ones), newest first.
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>>;
+
+    /// Returns up to `limit` of the oldest messages newer than `after`, newest first.
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>>;
+
+    /// Sends a text message and returns its id.
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64>;
+
+    /// Sends a message with a single attachment and returns its id.
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64>;
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()>;
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()>;
+
+    /// Pins a message in the channel.
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()>;
+
+    /// Returns all pinned messages of the channel.
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>>;
+
+    /// Makes sure the thread (or forum post) accepts messages, unarchiving it if needed.
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()>;
+
+    /// Downloads an attachment.
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>>;
+
+    /// Downloads the bytes of an attachment in the range, if the server supports range requests.
+    /// Otherwise the whole attachment is returned.
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        let _ = range;
+        Ok(Download::Full(self.download(url).await?))
+    }
+
+    /// Re-establishes the connection after it was lost.
+    async fn reconnect(&self) -> BackendResult<()>;
+}
+
+// ========< DISCORD >========
+/// Backend talking to the actual discord api.
+pub struct DiscordBackend {
+    token: String,
+    http: RwLock<Arc<Http>>,
+    /// Client attachments are downloaded with.
+    downloads: reqwest::Client,
+}
+
+impl DiscordBackend {
+    pub fn new(token: &str) -> Self {
+        Self::with_download_client(token, reqwest::Client::new())
+    }
+
+    pub fn with_download_client(token: &str, downloads: reqwest::Client) -> Self {
+        Self {
+            token: token.to_string(),
+            http: RwLock::new(Arc::new(Http::new(token))),
+            downloads,
+        }
+    }
+
+    /// Returns the currently used http client.
+    pub fn http(&self) -> Arc<Http> {
+        self.http.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+#[async_trait]
+impl Backend for DiscordBackend {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        Ok(channel.message(self.http(), message_id).await?.into())
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        let messages = channel.messages(self.http(), |retriever| {
+            retriever.limit(limit);
+            if let Some(before) = before {
+                retriever.before(before);
+            }
+            retriever
+        }).await?;
+
+        Ok(messages.into_iter().map(StoredMessage::from).collect())
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        let messages = channel.messages(self.http(), |retriever| retriever.limit(limit).after(after)).await?;
+
+        Ok(messages.into_iter().map(StoredMessage::from).collect())
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        let message = channel.send_message(self.http(), |m| {
+            m.content(content)
+        }).await?;
+
+        Ok(message.id.0)
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        let files = vec![(data, filename)];
+        let message = channel.send_files(self.http(), files, |m| {
+            m.content(content)
+        }).await?;
+
+        Ok(message.id.0)
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        channel.edit_message(self.http(), message_id, |m| {
+            m.content(content)
+        }).await?;
+
+        Ok(())
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        channel.delete_message(self.http(), message_id).await?;
+
+        Ok(())
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        channel.pin(self.http(), message_id).await?;
+
+        Ok(())
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        let messages = channel.pins(self.http()).await?;
+
+        Ok(messages.into_iter().map(StoredMessage::from).collect())
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        let channel = self.http().get_channel(thread.0).await?;
+
+        let archived = match channel {
+            Channel::Guild(channel) => channel.thread_metadata.is_some_and(|metadata| metadata.archived),
+            _ => false,
+        };
+
+        if archived {
+            thread.edit_thread(self.http(), |t| t.archived(false)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        let response = self.downloads.get(url).send().await?.error_for_status()?;
+
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        let response = self.downloads.get(url)
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", range.start, range.end.saturating_sub(1)))
+            .send().await?
+            .error_for_status()?;
+
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Ok(Download::Full(response.bytes().await?.to_vec()));
+        }
+
+        // Content-Range: bytes <start>-<end>/<total>
+        let total = response.headers().get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit_once('/')?.1.parse().ok())
+            .ok_or_else(|| BackendError::Other("Partial download without the attachment size".to_string()))?;
+
+        Ok(Download::Range { data: response.bytes().await?.to_vec(), total })
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        let http = Arc::new(Http::new(&self.token));
+
+        // Make sure the new client actually works before using it.
+        http.get_current_user().await?;
+
+        *self.http.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = http;
+        Ok(())
+    }
+}
+
+/// Builds the client attachments are downloaded with.
+/// Proxies from `HTTP_PROXY`/`HTTPS_PROXY` are used, unless `proxy` is set.
+/// `root_certificate` (PEM) is trusted in addition to the system certificates.
+pub fn download_client(proxy: Option<&str>, root_certificate: Option<&[u8]>) -> BackendResult<reqwest::Client> {
+    let invalid = |what: &str, e: reqwest::Error| BackendError::Other(format!("Invalid {}: {}", what, e));
+    let mut builder = reqwest::Client::builder();
+
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(|e| invalid("download proxy", e))?);
+    }
+
+    if let Some(pem) = root_certificate {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem).map_err(|e| invalid("root certificate", e))?);
+    }
+
+    builder.build().map_err(|e| invalid("download client", e))
+}
+
+/// Does everything the drive does in the channel once, with a message that is deleted right away:
+/// reads the history, sends a file, edits and deletes it.
+/// Returns the first operation the bot is not allowed to do.
+pub async fn check_permissions(backend: &dyn Backend, channel: ChannelId) -> BackendResult<Option<&'static str>> {
+    let refused = |operation| move |e| match e {
+        BackendError::Forbidden(_) => Ok(Some(operation)),
+        e => Err(e),
+    };
+
+    if let Err(e) = backend.get_messages(channel, None, 1).await {
+        return refused("read the message history")(e);
+    }
+    let message_id = match backend.send_file(channel, "daafs permission check", "check.bin", &[0]).await {
+        Ok(message_id) => message_id,
+        Err(e) => return refused("send files")(e),
+    };
+    if let Err(e) = backend.edit_message(channel, message_id, "daafs permission check (edited)").await {
+        backend.delete_message(channel, message_id).await.ok();
+        return refused("edit messages")(e);
+    }
+    if let Err(e) = backend.delete_message(channel, message_id).await {
+        return refused("delete messages")(e);
+    }
+
+    Ok(None)
+}
+
+/// Checks that the drive can work in the channel: sends a small test file, downloads and compares it, and deletes it again.
+/// The error names the step that failed.
+pub async fn self_test(backend: &dyn Backend, channel: ChannelId) -> Result<()> {
+    let failed = |step| move |error| Error::SelfTestFailed { step, error };
+
+    backend.get_messages(channel, None, 1).await.map_err(failed("read the message history"))?;
+    let data = crate::crypto::random_name("").into_bytes();
+    let message_id = backend.send_file(channel, "daafs self test", "self-test.bin", &data).await.map_err(failed("send a file"))?;
+
+    let checked = async {
+        let message = backend.get_message(channel, message_id).await.map_err(failed("fetch the test message"))?;
+        let url = message.attachments.first().ok_or(BackendError::NotFound).map_err(failed("fetch the test message"))?;
+        let downloaded = backend.download(url).await.map_err(failed("download the test file"))?;
+        if downloaded != data {
+            return Err(failed("verify the test file")(BackendError::Other("it downloaded differently than it was uploaded".to_string())));
+        }
+        Ok(())
+    }.await;
+
+    if let Err(e) = checked {
+        backend.delete_message(channel, message_id).await.ok();
+        return Err(e);
+    }
+    backend.delete_message(channel, message_id).await.map_err(failed("delete the test message"))
+}
+
+// ========< RECONNECTING >========
+/// What `ReconnectingBackend` does about a failed operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Retry after the exponential backoff of the backend.
+    Retry,
+    /// Retry once the duration passed.
+    RetryAfter(Duration),
+    /// Return the error without retrying.
+    Fatal,
+}
+
+/// Decides which errors of discord operations are retried, and when.
+/// Deployments worried about bans may retry less than `DefaultRetryPolicy`, home setups more.
+pub trait RetryPolicy: Send + Sync + std::fmt::Debug {
+    fn classify(&self, error: &BackendError) -> RetryDecision;
+}
+
+/// Retries lost connections (and timeouts), every other error is returned.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultRetryPolicy;
+
+impl RetryPolicy for DefaultRetryPolicy {
+    fn classify(&self, error: &BackendError) -> RetryDecision {
+        match error {
+            BackendError::Disconnected(_) => RetryDecision::Retry,
+            _ => RetryDecision::Fatal,
+        }
+    }
+}
+
+/// Wraps a backend, retrying failed operations (with exponential backoff) as its `RetryPolicy` decides.
+/// The backend is reconnected before an operation is retried after a lost connection.
+pub struct ReconnectingBackend<B: Backend> {
+    inner: B,
+    attempts: u32,
+    backoff: Duration,
+    policy: Arc<dyn RetryPolicy>,
+}
+
+impl<B: Backend> ReconnectingBackend<B> {
+    pub fn new(inner: B, attempts: u32, backoff: Duration) -> Self {
+        Self {
+            inner,
+            attempts,
+            backoff,
+            policy: Arc::new(DefaultRetryPolicy),
+        }
+    }
+
+    /// Retries the errors `policy` classifies as retryable instead of just lost connections.
+    pub fn with_policy(mut self, policy: Arc<dyn RetryPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    async fn retry<T, F, Fut>(&self, mut operation: F) -> BackendResult<T>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = BackendResult<T>> + Send,
+        T: Send,
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+
+        loop {
+            let error = match operation().await {
+                Err(error) => error,
+                result => return result,
+            };
+            let wait = match self.policy.classify(&error) {
+                RetryDecision::Retry => delay,
+                RetryDecision::RetryAfter(wait) => wait,
+                RetryDecision::Fatal => return Err(error),
+            };
+
+            attempt += 1;
+            let last = match &error {
+                BackendError::Disconnected(reason) => reason.clone(),
+                error => error.to_string(),
+            };
+            if attempt > self.attempts {
+                return Err(BackendError::RetriesExhausted { attempts: attempt, last });
+            }
+
+            if !error.is_connection_error() {
+                log::warn!("Discord operation failed ({}), retrying in {:?} (attempt {}/{}).", last, wait, attempt, self.attempts);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+
+            log::warn!("Lost connection to discord ({}), reconnecting in {:?} (attempt {}/{}).", last, wait, attempt, self.attempts);
+            tokio::time::sleep(wait).await;
+            delay *= 2;
+
+            if let Err(e) = self.inner.reconnect().await {
+                log::warn!("Failed to reconnect: {}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Backend> Backend for ReconnectingBackend<B> {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        self.retry(|| self.inner.get_message(channel, message_id)).await
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.retry(|| self.inner.get_messages(channel, before, limit)).await
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.retry(|| self.inner.get_messages_after(channel, after, limit)).await
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        self.retry(|| self.inner.send_message(channel, content)).await
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        self.retry(|| self.inner.send_file(channel, content, filename, data)).await
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        self.retry(|| self.inner.edit_message(channel, message_id, content)).await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.retry(|| self.inner.delete_message(channel, message_id)).await
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.retry(|| self.inner.pin_message(channel, message_id)).await
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        self.retry(|| self.inner.get_pins(channel)).await
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        self.retry(|| self.inner.unarchive_thread(thread)).await
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        self.retry(|| self.inner.download(url)).await
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        self.retry(|| self.inner.download_range(url, range.clone())).await
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        self.inner.reconnect().await
+    }
+}
+
+// ========< TIMEOUT >========
+/// Wraps a backend, failing operations that don't finish in time.
+/// Timed out operations are reported as a lost connection, so a `ReconnectingBackend`
+/// around this one reconnects and retries them instead of hanging forever.
+pub struct TimeoutBackend<B: Backend> {
+    inner: B,
+    timeout: Duration,
+}
+
+impl<B: Backend> TimeoutBackend<B> {
+    pub fn new(inner: B, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    async fn limit<T>(&self, operation: &str, future: impl Future<Output = BackendResult<T>>) -> BackendResult<T> {
+        tokio::time::timeout(self.timeout, future).await
+            .unwrap_or_else(|_| Err(BackendError::Disconnected(format!("{} timed out after {:?}", operation, self.timeout))))
+    }
+}
+
+#[async_trait]
+impl<B: Backend> Backend for TimeoutBackend<B> {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        self.limit("get_message", self.inner.get_message(channel, message_id)).await
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.limit("get_messages", self.inner.get_messages(channel, before, limit)).await
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.limit("get_messages_after", self.inner.get_messages_after(channel, after, limit)).await
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        self.limit("send_message", self.inner.send_message(channel, content)).await
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        self.limit("send_file", self.inner.send_file(channel, content, filename, data)).await
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        self.limit("edit_message", self.inner.edit_message(channel, message_id, content)).await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.limit("delete_message", self.inner.delete_message(channel, message_id)).await
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.limit("pin_message", self.inner.pin_message(channel, message_id)).await
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        self.limit("get_pins", self.inner.get_pins(channel)).await
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        self.limit("unarchive_thread", self.inner.unarchive_thread(thread)).await
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        self.limit("download", self.inner.download(url)).await
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        self.limit("download_range", self.inner.download_range(url, range)).await
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        self.limit("reconnect", self.inner.reconnect()).await
+    }
+}
+
+// ========< RATE LIMIT >========
+/// Requests per second discord allows a bot across all routes.
+pub const DISCORD_GLOBAL_RATE_LIMIT: u32 = 50;
+
+/// Wraps a backend, pacing requests so a burst of them stays under the global rate limit of discord,
+/// instead of running into it and retrying. Serenity only keeps to the limits of single routes.
+/// Up to `rate` requests are sent at once, after that one every `1 / rate` seconds.
+/// Attachment downloads don't go through the api and are not limited.
+pub struct RateLimitedBackend<B: Backend> {
+    inner: B,
+    rate: f64,
+    /// Available requests (negative if requests are already waiting) and when it was last refilled.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl<B: Backend> RateLimitedBackend<B> {
+    pub fn new(inner: B, rate: u32) -> Self {
+        assert!(rate > 0, "RateLimitedBackend needs a rate of at least one request per second");
+
+        Self {
+            inner,
+            rate: rate as f64,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Waits until the request may be sent.
+    async fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock_or_recover();
+            let (tokens, refilled) = &mut *bucket;
+
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * self.rate).min(self.rate);
+            *refilled = now;
+
+            // Take the token right away, so concurrent requests queue up behind each other.
+            *tokens -= 1.0;
+            Duration::from_secs_f64((-*tokens).max(0.0) / self.rate)
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Backend> Backend for RateLimitedBackend<B> {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        self.acquire().await;
+        self.inner.get_message(channel, message_id).await
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.acquire().await;
+        self.inner.get_messages(channel, before, limit).await
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.acquire().await;
+        self.inner.get_messages_after(channel, after, limit).await
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        self.acquire().await;
+        self.inner.send_message(channel, content).await
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        self.acquire().await;
+        self.inner.send_file(channel, content, filename, data).await
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        self.acquire().await;
+        self.inner.edit_message(channel, message_id, content).await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.acquire().await;
+        self.inner.delete_message(channel, message_id).await
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.acquire().await;
+        self.inner.pin_message(channel, message_id).await
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        self.acquire().await;
+        self.inner.get_pins(channel).await
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        self.acquire().await;
+        self.inner.unarchive_thread(thread).await
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        self.inner.download(url).await
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        self.inner.download_range(url, range).await
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        self.inner.reconnect().await
+    }
+}
+
+// ========< ROTATING >========
+/// Spreads uploads and downloads across several backends (one per bot token),
+/// multiplying the rate limit. Everything else goes through the first one,
+/// as only the author of a message can edit it.
+pub struct RotatingBackend {
+    backends: Vec<Arc<dyn Backend>>,
+    next: AtomicUsize,
+}
+
+impl RotatingBackend {
+    pub fn new(backends: Vec<Arc<dyn Backend>>) -> Self {
+        assert!(!backends.is_empty(), "RotatingBackend needs at least one backend");
+
+        Self {
+            backends,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn primary(&self) -> &dyn Backend {
+        self.backends[0].as_ref()
+    }
+
+    /// Returns the next backend in round-robin order.
+    fn rotate(&self) -> &dyn Backend {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.backends.len();
+        self.backends[index].as_ref()
+    }
+}
+
+#[async_trait]
+impl Backend for RotatingBackend {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        self.primary().get_message(channel, message_id).await
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.primary().get_messages(channel, before, limit).await
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.primary().get_messages_after(channel, after, limit).await
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        self.primary().send_message(channel, content).await
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        self.rotate().send_file(channel, content, filename, data).await
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        self.primary().edit_message(channel, message_id, content).await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.primary().delete_message(channel, message_id).await
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.primary().pin_message(channel, message_id).await
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        self.primary().get_pins(channel).await
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        self.primary().unarchive_thread(thread).await
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        self.rotate().download(url).await
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        self.rotate().download_range(url, range).await
+    }
+
+    /// Reconnects every backend, even if one of them fails, and returns the first failure.
+    async fn reconnect(&self) -> BackendResult<()> {
+        let mut result = Ok(());
+        for backend in &self.backends {
+            if let Err(e) = backend.reconnect().await {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+// ========< TRACING >========
+/// Wraps a backend, logging every operation with what it was called on,
+/// how long it took and how it ended at debug level (`TRACE_DISCORD`).
+pub struct TracingBackend {
+    inner: Arc<dyn Backend>,
+}
+
+impl TracingBackend {
+    pub fn new(inner: Arc<dyn Backend>) -> Self {
+        Self { inner }
+    }
+
+    async fn trace<T: Traced>(&self, operation: &str, target: String, future: impl Future<Output = BackendResult<T>>) -> BackendResult<T> {
+        let start = Instant::now();
+        let result = future.await;
+        match &result {
+            Ok(value) => match value.describe() {
+                Some(outcome) => log::debug!("{} {} ok after {:?}: {}", operation, target, start.elapsed(), outcome),
+                None => log::debug!("{} {} ok after {:?}", operation, target, start.elapsed()),
+            },
+            Err(e) => log::debug!("{} {} failed after {:?}: {}", operation, target, start.elapsed(), e),
+        }
+        result
+    }
+}
+
+/// What a traced operation returned, as it is logged.
+trait Traced {
+    fn describe(&self) -> Option<String>;
+}
+
+impl Traced for () {
+    fn describe(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Id of a sent message.
+impl Traced for u64 {
+    fn describe(&self) -> Option<String> {
+        Some(format!("message {}", self))
+    }
+}
+
+impl Traced for StoredMessage {
+    fn describe(&self) -> Option<String> {
+        Some(format!("message {} with {} attachments", self.id, self.attachments.len()))
+    }
+}
+
+impl Traced for Vec<StoredMessage> {
+    fn describe(&self) -> Option<String> {
+        let ids: Vec<u64> = self.iter().map(|message| message.id).collect();
+        match (ids.iter().min(), ids.iter().max()) {
+            (Some(oldest), Some(newest)) => Some(format!("{} messages from {} to {}", ids.len(), oldest, newest)),
+            _ => Some("no messages".to_string()),
+        }
+    }
+}
+
+impl Traced for Vec<u8> {
+    fn describe(&self) -> Option<String> {
+        Some(format!("{} bytes", self.len()))
+    }
+}
+
+impl Traced for Download {
+    fn describe(&self) -> Option<String> {
+        match self {
+            Download::Range { data, total } => Some(format!("{} of {} bytes", data.len(), total)),
+            Download::Full(data) => Some(format!("whole file of {} bytes", data.len())),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for TracingBackend {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        self.trace("get_message", format!("{} in channel {}", message_id, channel.0), self.inner.get_message(channel, message_id)).await
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        let target = format!("of channel {} before {:?} (limit {})", channel.0, before, limit);
+        self.trace("get_messages", target, self.inner.get_messages(channel, before, limit)).await
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        let target = format!("of channel {} after {} (limit {})", channel.0, after, limit);
+        self.trace("get_messages_after", target, self.inner.get_messages_after(channel, after, limit)).await
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        self.trace("send_message", format!("to channel {}", channel.0), self.inner.send_message(channel, content)).await
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        let target = format!("{} ({} bytes) to channel {}", filename, data.len(), channel.0);
+        self.trace("send_file", target, self.inner.send_file(channel, content, filename, data)).await
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        self.trace("edit_message", format!("{} in channel {}", message_id, channel.0), self.inner.edit_message(channel, message_id, content)).await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.trace("delete_message", format!("{} in channel {}", message_id, channel.0), self.inner.delete_message(channel, message_id)).await
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.trace("pin_message", format!("{} in channel {}", message_id, channel.0), self.inner.pin_message(channel, message_id)).await
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        self.trace("get_pins", format!("of channel {}", channel.0), self.inner.get_pins(channel)).await
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        self.trace("unarchive_thread", thread.0.to_string(), self.inner.unarchive_thread(thread)).await
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        self.trace("download", url.to_string(), self.inner.download(url)).await
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        self.trace("download_range", format!("{} {:?}", url, range), self.inner.download_range(url, range.clone())).await
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        self.trace("reconnect", String::new(), self.inner.reconnect()).await
+    }
+}
+
+// ========< TIMING >========
+/// Wraps a backend, recording how long uploads, downloads, fetches and edits take (see `status`).
+pub struct TimedBackend {
+    inner: Arc<dyn Backend>,
+    latencies: Arc<Latencies>,
+}
+
+impl TimedBackend {
+    pub fn new(inner: Arc<dyn Backend>, latencies: Arc<Latencies>) -> Self {
+        Self { inner, latencies }
+    }
+}
+
+/// Runs the operation, recording how long it took (failed or not) in the histogram.
+async fn timed<T>(histogram: &Histogram, future: impl Future<Output = BackendResult<T>>) -> BackendResult<T> {
+    let start = Instant::now();
+    let result = future.await;
+    histogram.record(start.elapsed());
+    result
+}
+
+#[async_trait]
+impl Backend for TimedBackend {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        timed(&self.latencies.fetch, self.inner.get_message(channel, message_id)).await
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        timed(&self.latencies.fetch, self.inner.get_messages(channel, before, limit)).await
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        timed(&self.latencies.fetch, self.inner.get_messages_after(channel, after, limit)).await
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        timed(&self.latencies.edit, self.inner.send_message(channel, content)).await
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        timed(&self.latencies.upload, self.inner.send_file(channel, content, filename, data)).await
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        timed(&self.latencies.edit, self.inner.edit_message(channel, message_id, content)).await
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.inner.delete_message(channel, message_id).await
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        self.inner.pin_message(channel, message_id).await
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        timed(&self.latencies.fetch, self.inner.get_pins(channel)).await
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        self.inner.unarchive_thread(thread).await
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        timed(&self.latencies.download, self.inner.download(url)).await
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        timed(&self.latencies.download, self.inner.download_range(url, range)).await
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        self.inner.reconnect().await
+    }
+}
+
+// ========< DRY RUN >========
+/// Wraps a backend, logging operations that would change the channel instead of running them.
+/// Sent messages are kept in memory (with ids counting down from the largest id),
+/// so the drive keeps working as if they were sent.
+pub struct DryRunBackend {
+    inner: Arc<dyn Backend>,
+    state: Mutex<DryRunState>,
+}
+
+#[derive(Default)]
+struct DryRunState {
+    next_id: u64,
+    /// Messages that would have been sent, by id.
+    sent: HashMap<u64, StoredMessage>,
+    /// Attachment data by url.
+    files: HashMap<String, Vec<u8>>,
+    /// Operations that were skipped.
+    operations: Vec<String>,
+}
+
+impl DryRunBackend {
+    pub fn new(inner: Arc<dyn Backend>) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(DryRunState { next_id: u64::MAX, ..DryRunState::default() }),
+        }
+    }
+
+    /// Returns the skipped operations, oldest first.
+    pub fn operations(&self) -> Vec<String> {
+        self.state.lock_or_recover().operations.clone()
+    }
+
+    fn skip(state: &mut DryRunState, operation: String) {
+        log::info!("Dry run, skipping {}", operation);
+        state.operations.push(operation);
+    }
+
+    fn send(&self, channel: ChannelId, content: &str, file: Option<(&str, &[u8])>) -> u64 {
+        let mut state = self.state.lock_or_recover();
+        let id = state.next_id;
+        state.next_id -= 1;
+
+        let attachments = match file {
+            Some((filename, data)) => {
+                let url = format!("dry-run://{}/{}/{}", channel.0, id, filename);
+                state.files.insert(url.clone(), data.to_vec());
+                Self::skip(&mut state, format!("send_file {} ({} bytes) to channel {}", filename, data.len(), channel.0));
+                vec![url]
+            }
+            None => {
+                Self::skip(&mut state, format!("send_message {:?} to channel {}", content, channel.0));
+                Vec::new()
+            }
+        };
+
+        state.sent.insert(id, StoredMessage { id, content: content.to_string(), attachments });
+        id
+    }
+}
+
+#[async_trait]
+impl Backend for DryRunBackend {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        let sent = self.state.lock_or_recover().sent.get(&message_id).cloned();
+        match sent {
+            Some(message) => Ok(message),
+            None => self.inner.get_message(channel, message_id).await,
+        }
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.inner.get_messages(channel, before, limit).await
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        self.inner.get_messages_after(channel, after, limit).await
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        Ok(self.send(channel, content, None))
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        Ok(self.send(channel, content, Some((filename, data))))
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        let mut state = self.state.lock_or_recover();
+        if let Some(message) = state.sent.get_mut(&message_id) {
+            message.content = content.to_string();
+        }
+        Self::skip(&mut state, format!("edit_message {} in channel {} to {:?}", message_id, channel.0, content));
+        Ok(())
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        let mut state = self.state.lock_or_recover();
+        state.sent.remove(&message_id);
+        Self::skip(&mut state, format!("delete_message {} in channel {}", message_id, channel.0));
+        Ok(())
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        Self::skip(&mut self.state.lock_or_recover(), format!("pin_message {} in channel {}", message_id, channel.0));
+        Ok(())
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        self.inner.get_pins(channel).await
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        Self::skip(&mut self.state.lock_or_recover(), format!("unarchive_thread {}", thread.0));
+        Ok(())
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        let file = self.state.lock_or_recover().files.get(url).cloned();
+        match file {
+            Some(data) => Ok(data),
+            None => self.inner.download(url).await,
+        }
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        // Pages that were not really uploaded are small enough to be returned whole.
+        let file = self.state.lock_or_recover().files.get(url).cloned();
+        match file {
+            Some(data) => Ok(Download::Full(data)),
+            None => self.inner.download_range(url, range).await,
+        }
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        self.inner.reconnect().await
+    }
+}
+
+// ========< MEMORY >========
+/// Backend keeping all messages in memory. Used for testing.
+#[derive(Default)]
+pub struct MemoryBackend {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    last_id: u64,
+    /// Messages of each channel, by id.
+    channels: HashMap<u64, BTreeMap<u64, StoredMessage>>,
+    /// Attachment data by url.
+    files: HashMap<String, Vec<u8>>,
+    /// Pinned message ids of each channel.
+    pins: HashMap<u64, Vec<u64>>,
+    /// Threads that don't accept messages until unarchived.
+    archived: HashSet<u64>,
+    /// Number of calls of each operation.
+    calls: HashMap<&'static str, usize>,
+    disconnected: bool,
+    reconnect_fails: bool,
+    reconnects: usize,
+    /// Biggest accepted upload (unlimited if None).
+    upload_limit: Option<usize>,
+    /// Whether message history is returned oldest first.
+    oldest_first: bool,
+    /// Number of following downloads that fail.
+    failing_downloads: usize,
+    /// Number of following downloads (after the failing ones) that return corrupted data.
+    corrupt_downloads: usize,
+    /// How long every download takes.
+    download_latency: Duration,
+    /// Downloads that are running, and the most that ran at once.
+    downloads: usize,
+    max_downloads: usize,
+    /// How long every fetch of a single message takes.
+    fetch_latency: Duration,
+    /// Message fetches that are running, and the most that ran at once.
+    fetches: usize,
+    max_fetches: usize,
+    /// How long every edit of a message takes.
+    edit_latency: Duration,
+    /// Message edits that are running, and the most that ran at once.
+    edits: usize,
+    max_edits: usize,
+    /// How long every upload takes.
+    upload_latency: Duration,
+    /// Uploads that are running, and the most that ran at once.
+    uploads: usize,
+    max_uploads: usize,
+    /// Whether range requests return the whole attachment.
+    ignore_ranges: bool,
+    /// Operations that fail as if the bot lacked permissions.
+    forbidden: HashSet<&'static str>,
+    /// Operations whose next call panics.
+    panicking: HashSet<&'static str>,
+    /// Operations that fail once, after the given number of successful calls.
+    failing_after: HashMap<&'static str, usize>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Simulates a lost connection. Every operation fails until `reconnect` is called.
+    pub fn disconnect(&self) {
+        self.state.lock_or_recover().disconnected = true;
+    }
+
+    /// Simulates an archived thread, which rejects new and edited messages.
+    pub fn archive_thread(&self, thread: ChannelId) {
+        self.state.lock_or_recover().archived.insert(thread.0);
+    }
+
+    /// Makes every following reconnect attempt fail (or succeed again).
+    pub fn set_reconnect_fails(&self, fails: bool) {
+        self.state.lock_or_recover().reconnect_fails = fails;
+    }
+
+    /// Returns batches of message history oldest first, unlike discord (the newest messages are still returned).
+    pub fn set_oldest_first(&self, oldest_first: bool) {
+        self.state.lock_or_recover().oldest_first = oldest_first;
+    }
+
+    /// Makes uploads bigger than the limit fail, like discord does.
+    pub fn set_upload_limit(&self, limit: usize) {
+        self.state.lock_or_recover().upload_limit = Some(limit);
+    }
+
+    /// Makes the next `count` downloads fail, and the `corrupt` downloads after them
+    /// return data with a flipped byte, like a flaky CDN.
+    pub fn break_downloads(&self, count: usize, corrupt: usize) {
+        let mut state = self.state.lock_or_recover();
+        state.failing_downloads = count;
+        state.corrupt_downloads = corrupt;
+    }
+
+    /// Makes every download take `latency`, like a real network.
+    pub fn set_download_latency(&self, latency: Duration) {
+        self.state.lock_or_recover().download_latency = latency;
+    }
+
+    /// Makes the operation (named like the trait method) fail as if the bot lacked permissions.
+    pub fn forbid(&self, operation: &'static str) {
+        self.state.lock_or_recover().forbidden.insert(operation);
+    }
+
+    /// Makes the operation (named like the trait method) fail once after `calls` more successful calls,
+    /// as if the bot lacked permissions.
+    pub fn fail_after(&self, operation: &'static str, calls: usize) {
+        self.state.lock_or_recover().failing_after.insert(operation, calls);
+    }
+
+    /// Makes the next call of the operation (named like the trait method) panic, like a bug would.
+    pub fn panic_once(&self, operation: &'static str) {
+        self.state.lock_or_recover().panicking.insert(operation);
+    }
+
+    /// Makes range requests return the whole attachment, like servers without range support.
+    pub fn set_ignore_ranges(&self, ignore: bool) {
+        self.state.lock_or_recover().ignore_ranges = ignore;
+    }
+
+    /// Returns the most downloads that were running at once.
+    pub fn max_concurrent_downloads(&self) -> usize {
+        self.state.lock_or_recover().max_downloads
+    }
+
+    /// Makes every fetch of a single message take `latency`, like a real network.
+    pub fn set_fetch_latency(&self, latency: Duration) {
+        self.state.lock_or_recover().fetch_latency = latency;
+    }
+
+    /// Returns the most message fetches that were running at once.
+    pub fn max_concurrent_fetches(&self) -> usize {
+        self.state.lock_or_recover().max_fetches
+    }
+
+    /// Makes every edit of a message take `latency`, like a real network.
+    pub fn set_edit_latency(&self, latency: Duration) {
+        self.state.lock_or_recover().edit_latency = latency;
+    }
+
+    /// Returns the most message edits that were running at once.
+    pub fn max_concurrent_edits(&self) -> usize {
+        self.state.lock_or_recover().max_edits
+    }
+
+    /// Makes every upload take `latency`, like a real network.
+    pub fn set_upload_latency(&self, latency: Duration) {
+        self.state.lock_or_recover().upload_latency = latency;
+    }
+
+    /// Returns the most uploads that were running at once.
+    pub fn max_concurrent_uploads(&self) -> usize {
+        self.state.lock_or_recover().max_uploads
+    }
+
+    /// Returns how many times the operation (named like the trait method) was called.
+    pub fn calls(&self, operation: &str) -> usize {
+        self.state.lock_or_recover().calls.get(operation).copied().unwrap_or(0)
+    }
+
+    /// Removes an attachment while its message stays, like a file the CDN lost.
+    pub fn remove_attachment(&self, url: &str) {
+        self.state.lock_or_recover().files.remove(url);
+    }
+
+    /// Replaces the data of an attachment, simulating a broken download.
+    pub fn replace_attachment(&self, url: &str, data: Vec<u8>) {
+        self.state.lock_or_recover().files.insert(url.to_string(), data);
+    }
+
+    /// Returns how many times the backend was reconnected.
+    pub fn reconnects(&self) -> usize {
+        self.state.lock_or_recover().reconnects
+    }
+
+    /// Returns all messages of the channel, oldest first.
+    pub fn messages(&self, channel: ChannelId) -> Vec<StoredMessage> {
+        self.state.lock_or_recover().channels
+            .get(&channel.0)
+            .map(|messages| messages.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Records a call of the operation and locks the state if the backend is connected.
+    fn connected(&self, operation: &'static str) -> BackendResult<std::sync::MutexGuard<'_, MemoryState>> {
+        let mut state = self.state.lock_or_recover();
+        *state.calls.entry(operation).or_default() += 1;
+        if state.panicking.remove(operation) {
+            drop(state);
+            panic!("Simulated panic in {}", operation);
+        }
+        if state.disconnected {
+            return Err(BackendError::Disconnected("Simulated disconnect".to_string()));
+        }
+        if state.forbidden.contains(operation) {
+            return Err(BackendError::Forbidden("Missing Permissions".to_string()));
+        }
+        match state.failing_after.get_mut(operation) {
+            Some(0) => {
+                state.failing_after.remove(operation);
+                return Err(BackendError::Forbidden("Missing Permissions".to_string()));
+            }
+            Some(calls) => *calls -= 1,
+            None => {}
+        }
+        Ok(state)
+    }
+
+    /// Rejects content longer than discord accepts.
+    fn check_content(content: &str) -> BackendResult<()> {
+        if content.chars().count() > MESSAGE_LIMIT {
+            return Err(BackendError::Other(format!("Invalid Form Body: content must be {} or fewer in length", MESSAGE_LIMIT)));
+        }
+        Ok(())
+    }
+
+    fn check_not_archived(state: &MemoryState, channel: ChannelId) -> BackendResult<()> {
+        if state.archived.contains(&channel.0) {
+            return Err(BackendError::Other("Thread is archived".to_string()));
+        }
+        Ok(())
+    }
+
+    fn insert(state: &mut MemoryState, channel: ChannelId, content: &str, attachments: Vec<String>) -> u64 {
+        state.last_id += 1;
+        let id = state.last_id;
+
+        state.channels.entry(channel.0).or_default().insert(id, StoredMessage {
+            id,
+            content: content.to_string(),
+            attachments,
+        });
+
+        id
+    }
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn get_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<StoredMessage> {
+        let latency = {
+            let mut state = self.connected("get_message")?;
+            state.fetches += 1;
+            state.max_fetches = state.max_fetches.max(state.fetches);
+            state.fetch_latency
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let mut state = self.state.lock_or_recover();
+        state.fetches -= 1;
+        state.channels
+            .get(&channel.0)
+            .and_then(|messages| messages.get(&message_id))
+            .cloned()
+            .ok_or(BackendError::NotFound)
+    }
+
+    async fn get_messages(&self, channel: ChannelId, before: Option<u64>, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        let state = self.connected("get_messages")?;
+
+        let Some(messages) = state.channels.get(&channel.0) else {
+            return Ok(Vec::new());
+        };
+
+        let mut batch: Vec<StoredMessage> = messages
+            .range(..before.unwrap_or(u64::MAX))
+            .rev()
+            .take(limit as usize)
+            .map(|(_, message)| message.clone())
+            .collect();
+        if state.oldest_first {
+            batch.reverse();
+        }
+
+        Ok(batch)
+    }
+
+    async fn get_messages_after(&self, channel: ChannelId, after: u64, limit: u64) -> BackendResult<Vec<StoredMessage>> {
+        let state = self.connected("get_messages_after")?;
+
+        let Some(messages) = state.channels.get(&channel.0) else {
+            return Ok(Vec::new());
+        };
+
+        // The oldest messages after the cursor, but like all history newest first.
+        let mut batch: Vec<StoredMessage> = messages
+            .range(after.saturating_add(1)..)
+            .take(limit as usize)
+            .map(|(_, message)| message.clone())
+            .collect();
+        if !state.oldest_first {
+            batch.reverse();
+        }
+
+        Ok(batch)
+    }
+
+    async fn send_message(&self, channel: ChannelId, content: &str) -> BackendResult<u64> {
+        let mut state = self.connected("send_message")?;
+        Self::check_not_archived(&state, channel)?;
+        Self::check_content(content)?;
+
+        Ok(Self::insert(&mut state, channel, content, Vec::new()))
+    }
+
+    async fn send_file(&self, channel: ChannelId, content: &str, filename: &str, data: &[u8]) -> BackendResult<u64> {
+        let latency = {
+            let mut state = self.connected("send_file")?;
+            state.uploads += 1;
+            state.max_uploads = state.max_uploads.max(state.uploads);
+            state.upload_latency
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let mut state = self.state.lock_or_recover();
+        state.uploads -= 1;
+        Self::check_not_archived(&state, channel)?;
+        Self::check_content(content)?;
+        if state.upload_limit.is_some_and(|limit| data.len() > limit) {
+            return Err(BackendError::Other("Request entity too large".to_string()));
+        }
+
+        let url = format!("memory://{}/{}/{}", channel.0, state.last_id + 1, filename);
+        state.files.insert(url.clone(), data.to_vec());
+
+        Ok(Self::insert(&mut state, channel, content, vec![url]))
+    }
+
+    async fn edit_message(&self, channel: ChannelId, message_id: u64, content: &str) -> BackendResult<()> {
+        let latency = {
+            let mut state = self.connected("edit_message")?;
+            state.edits += 1;
+            state.max_edits = state.max_edits.max(state.edits);
+            state.edit_latency
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let mut state = self.state.lock_or_recover();
+        state.edits -= 1;
+        Self::check_not_archived(&state, channel)?;
+        Self::check_content(content)?;
+
+        let message = state.channels
+            .get_mut(&channel.0)
+            .and_then(|messages| messages.get_mut(&message_id))
+            .ok_or(BackendError::NotFound)?;
+
+        message.content = content.to_string();
+        Ok(())
+    }
+
+    async fn delete_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        let mut state = self.connected("delete_message")?;
+
+        let message = state.channels
+            .get_mut(&channel.0)
+            .and_then(|messages| messages.remove(&message_id))
+            .ok_or(BackendError::NotFound)?;
+
+        for url in message.attachments {
+            state.files.remove(&url);
+        }
+
+        if let Some(pins) = state.pins.get_mut(&channel.0) {
+            pins.retain(|id| *id != message_id);
+        }
+
+        Ok(())
+    }
+
+    async fn pin_message(&self, channel: ChannelId, message_id: u64) -> BackendResult<()> {
+        let mut state = self.connected("pin_message")?;
+
+        let exists = state.channels
+            .get(&channel.0)
+            .is_some_and(|messages| messages.contains_key(&message_id));
+        if !exists {
+            return Err(BackendError::NotFound);
+        }
+
+        state.pins.entry(channel.0).or_default().push(message_id);
+        Ok(())
+    }
+
+    async fn get_pins(&self, channel: ChannelId) -> BackendResult<Vec<StoredMessage>> {
+        let state = self.connected("get_pins")?;
+
+        let (Some(pins), Some(messages)) = (state.pins.get(&channel.0), state.channels.get(&channel.0)) else {
+            return Ok(Vec::new());
+        };
+
+        Ok(pins.iter().rev().filter_map(|id| messages.get(id).cloned()).collect())
+    }
+
+    async fn unarchive_thread(&self, thread: ChannelId) -> BackendResult<()> {
+        let mut state = self.connected("unarchive_thread")?;
+
+        state.archived.remove(&thread.0);
+        Ok(())
+    }
+
+    async fn download(&self, url: &str) -> BackendResult<Vec<u8>> {
+        let latency = {
+            let mut state = self.connected("download")?;
+            state.downloads += 1;
+            state.max_downloads = state.max_downloads.max(state.downloads);
+            state.download_latency
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let mut state = self.state.lock_or_recover();
+        state.downloads -= 1;
+
+        if state.failing_downloads > 0 {
+            state.failing_downloads -= 1;
+            return Err(BackendError::Other("Download failed".to_string()));
+        }
+
+        let mut data = state.files.get(url).cloned().ok_or(BackendError::NotFound)?;
+        if state.corrupt_downloads > 0 {
+            state.corrupt_downloads -= 1;
+            if let Some(byte) = data.last_mut() {
+                *byte ^= 0xFF;
+            }
+        }
+
+        Ok(data)
+    }
+
+    async fn download_range(&self, url: &str, range: Range<usize>) -> BackendResult<Download> {
+        let state = self.connected("download_range")?;
+        let data = state.files.get(url).ok_or(BackendError::NotFound)?;
+        if state.ignore_ranges {
+            return Ok(Download::Full(data.clone()));
+        }
+
+        let range = range.start.min(data.len())..range.end.min(data.len());
+        Ok(Download::Range { data: data[range].to_vec(), total: data.len() })
+    }
+
+    async fn reconnect(&self) -> BackendResult<()> {
+        let mut state = self.state.lock_or_recover();
+        if state.reconnect_fails {
+            return Err(BackendError::Disconnected("Simulated failed reconnect".to_string()));
+        }
+        state.disconnected = false;
+        state.reconnects += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CHANNEL: ChannelId = ChannelId(1);
+
+    #[test]
+    fn self_test_reports_failed_step() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let backend = MemoryBackend::new();
+            self_test(&backend, CHANNEL).await.unwrap();
+            assert!(backend.messages(CHANNEL).is_empty());
+
+            let backend = MemoryBackend::new();
+            backend.forbid("send_file");
+            let error = self_test(&backend, CHANNEL).await.unwrap_err();
+            assert!(matches!(error, Error::SelfTestFailed { step: "send a file", error: BackendError::Forbidden(_) }));
+
+            let backend = MemoryBackend::new();
+            backend.break_downloads(0, 1);
+            let error = self_test(&backend, CHANNEL).await.unwrap_err();
+            assert!(matches!(error, Error::SelfTestFailed { step: "verify the test file", .. }));
+            assert!(backend.messages(CHANNEL).is_empty());
+
+            let backend = MemoryBackend::new();
+            backend.disconnect();
+            let error = self_test(&backend, CHANNEL).await.unwrap_err();
+            assert!(matches!(error, Error::SelfTestFailed { error: BackendError::Disconnected(_), .. }));
+            assert!(error.to_string().contains("token"));
+        });
+    }
+
+    #[test]
+    fn reconnects_after_failure() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = ReconnectingBackend::new(MemoryBackend::new(), 3, Duration::from_millis(1));
+
+        rt.block_on(async {
+            let id = backend.send_message(CHANNEL, "hello").await.unwrap();
+
+            backend.inner().disconnect();
+            assert!(matches!(backend.inner().get_message(CHANNEL, id).await, Err(BackendError::Disconnected(_))));
+
+            let message = backend.get_message(CHANNEL, id).await.unwrap();
+            assert_eq!(message.content, "hello");
+            assert_eq!(backend.inner().reconnects(), 1);
+        });
+    }
+
+    #[test]
+    fn gives_up_after_attempts() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = ReconnectingBackend::new(MemoryBackend::new(), 2, Duration::from_millis(1));
+
+        backend.inner().disconnect();
+        backend.inner().set_reconnect_fails(true);
+
+        let result = rt.block_on(backend.get_message(CHANNEL, 1));
+        assert!(matches!(result, Err(BackendError::RetriesExhausted { attempts: 3, .. })));
+        assert_eq!(backend.inner().calls("get_message"), 3);
+    }
+
+    /// Never retries a lost connection, and retries refused operations after a moment.
+    #[derive(Debug)]
+    struct CautiousPolicy;
+
+    impl RetryPolicy for CautiousPolicy {
+        fn classify(&self, error: &BackendError) -> RetryDecision {
+            match error {
+                BackendError::Forbidden(_) => RetryDecision::RetryAfter(Duration::from_millis(1)),
+                _ => RetryDecision::Fatal,
+            }
+        }
+    }
+
+    #[test]
+    fn consults_retry_policy() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = ReconnectingBackend::new(MemoryBackend::new(), 2, Duration::from_millis(1))
+            .with_policy(Arc::new(CautiousPolicy));
+        let id = rt.block_on(backend.send_message(CHANNEL, "hello")).unwrap();
+
+        backend.inner().disconnect();
+        let result = rt.block_on(backend.get_message(CHANNEL, id));
+        assert!(matches!(result, Err(BackendError::Disconnected(_))));
+        assert_eq!(backend.inner().calls("get_message"), 1);
+        assert_eq!(backend.inner().reconnects(), 0);
+
+        rt.block_on(backend.inner().reconnect()).unwrap();
+        backend.inner().forbid("delete_message");
+        let result = rt.block_on(backend.delete_message(CHANNEL, id));
+        assert!(matches!(result, Err(BackendError::RetriesExhausted { attempts: 3, .. })));
+        assert_eq!(backend.inner().calls("delete_message"), 3);
+        assert_eq!(backend.inner().reconnects(), 1);
+    }
+
+    /// Backend that never answers.
+    struct StuckBackend;
+
+    #[async_trait]
+    impl Backend for StuckBackend {
+        async fn get_message(&self, _: ChannelId, _: u64) -> BackendResult<StoredMessage> { std::future::pending().await }
+        async fn get_messages(&self, _: ChannelId, _: Option<u64>, _: u64) -> BackendResult<Vec<StoredMessage>> { std::future::pending().await }
+        async fn get_messages_after(&self, _: ChannelId, _: u64, _: u64) -> BackendResult<Vec<StoredMessage>> { std::future::pending().await }
+        async fn send_message(&self, _: ChannelId, _: &str) -> BackendResult<u64> { std::future::pending().await }
+        async fn send_file(&self, _: ChannelId, _: &str, _: &str, _: &[u8]) -> BackendResult<u64> { std::future::pending().await }
+        async fn edit_message(&self, _: ChannelId, _: u64, _: &str) -> BackendResult<()> { std::future::pending().await }
+        async fn delete_message(&self, _: ChannelId, _: u64) -> BackendResult<()> { std::future::pending().await }
+        async fn pin_message(&self, _: ChannelId, _: u64) -> BackendResult<()> { std::future::pending().await }
+        async fn get_pins(&self, _: ChannelId) -> BackendResult<Vec<StoredMessage>> { std::future::pending().await }
+        async fn unarchive_thread(&self, _: ChannelId) -> BackendResult<()> { std::future::pending().await }
+        async fn download(&self, _: &str) -> BackendResult<Vec<u8>> { std::future::pending().await }
+        async fn reconnect(&self) -> BackendResult<()> { Ok(()) }
+    }
+
+    #[test]
+    fn stuck_operations_time_out() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = ReconnectingBackend::new(
+            TimeoutBackend::new(StuckBackend, Duration::from_millis(50)),
+            2,
+            Duration::from_millis(1),
+        );
+
+        let started = std::time::Instant::now();
+        let result = rt.block_on(backend.send_file(CHANNEL, "", "page.bin", &[1, 2, 3]));
+
+        assert!(matches!(result, Err(BackendError::RetriesExhausted { last, .. }) if last.contains("timed out")));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn paces_bursts() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = Arc::new(RateLimitedBackend::new(MemoryBackend::new(), 100));
+
+        let started = Instant::now();
+        rt.block_on(async {
+            let mut tasks = tokio::task::JoinSet::new();
+            for i in 0..150 {
+                let backend = backend.clone();
+                tasks.spawn(async move { backend.send_message(CHANNEL, &i.to_string()).await.unwrap() });
+            }
+            while let Some(result) = tasks.join_next().await {
+                result.unwrap();
+            }
+        });
+
+        // The first 100 requests go at once, the other 50 take half a second.
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "150 requests took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5));
+        assert_eq!(backend.inner().calls("send_message"), 150);
+    }
+
+    #[test]
+    fn downloads_through_proxy() {
+        use std::io::{BufRead, BufReader, Write};
+
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = proxy.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+
+            // Skip the headers.
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc").unwrap();
+            request
+        });
+
+        let client = download_client(Some(&format!("http://{}", address)), None).unwrap();
+        let backend = DiscordBackend::with_download_client("token", client);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let data = rt.block_on(backend.download("http://cdn.daafs.invalid/page.bin")).unwrap();
+
+        assert_eq!(data, b"abc");
+        assert!(server.join().unwrap().starts_with("GET http://cdn.daafs.invalid/page.bin "));
+        assert!(download_client(Some("not a proxy"), None).is_err());
+        assert!(download_client(None, Some(b"not a certificate")).is_err());
+    }
+
+    #[test]
+    fn rotates_uploads() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let a = Arc::new(MemoryBackend::new());
+        let b = Arc::new(MemoryBackend::new());
+        let backend = RotatingBackend::new(vec![a.clone(), b.clone()]);
+
+        rt.block_on(async {
+            for _ in 0..4 {
+                backend.send_file(CHANNEL, "DATA PAGE", "page.bin", &[1, 2, 3]).await.unwrap();
+            }
+            backend.send_message(CHANNEL, "METABLOCK").await.unwrap();
+        });
+
+        assert_eq!(a.calls("send_file"), 2);
+        assert_eq!(b.calls("send_file"), 2);
+        assert_eq!(a.calls("send_message"), 1);
+        assert_eq!(b.calls("send_message"), 0);
+    }
+
+    #[test]
+    fn reconnects_every_rotated_backend() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let a = Arc::new(MemoryBackend::new());
+        let b = Arc::new(MemoryBackend::new());
+        let backend = RotatingBackend::new(vec![a.clone(), b.clone()]);
+
+        a.set_reconnect_fails(true);
+        assert!(rt.block_on(backend.reconnect()).is_err());
+        assert_eq!((a.reconnects(), b.reconnects()), (0, 1));
+    }
+}
@@ -1,44 +1,112 @@
-use std::sync::Mutex;
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Mutex}, time::{Duration, Instant}};
 
-use crate::utils::BitMask;
+use zeroize::Zeroizing;
+
+use crate::error::{Error, Result};
+use crate::metadata::write_block;
+use crate::utils::{BitMask, LockOrRecover};
+
+/// Called with every block evicted by `Cache::push`, before it is returned.
+pub type EvictionHook = Box<dyn Fn(&CacheBlock) + Send + Sync>;
+
+/// Most bytes buffered by a `Combiner` before they are written to the cache.
+const MAX_COMBINED: usize = 1024 * 1024;
 
 pub struct Cache<const S: usize> {
     pub data: Mutex<Vec<CacheBlock>>,
+    /// Bytes tracked by one bit of the zero masks
+    zero_block_size: usize,
+    /// Bytes of data in every cached block, blocks may have any size if None
+    page_size: Option<usize>,
+    on_evict: Option<EvictionHook>,
+    /// Number of writes applied to cached blocks
+    writes: AtomicUsize,
+    /// Most blocks kept, `S` unless set with `with_capacity`
+    capacity: usize,
 }
 
+#[derive(Clone)]
 pub struct CacheBlock {
     pub offset: u64,
-    pub message_id: u64,
-    pub data: Vec<u8>,
+    /// Message the page was downloaded from or uploaded to, None if it was never uploaded
+    pub message_id: Option<u64>,
+    /// Plaintext page data, zeroed when dropped.
+    pub data: Zeroizing<Vec<u8>>,
     pub mask: BitMask<256>,
+    /// Whether the page was written since it was downloaded or uploaded.
+    pub dirty: bool,
+    /// When the page was downloaded or last uploaded
+    pub loaded: Instant,
 }
 
 impl CacheBlock {
-    pub fn new(offset: u64, message_id: u64, data: Vec<u8>, mask: BitMask<256>) -> Self {
+    pub fn new(offset: u64, message_id: Option<u64>, data: impl Into<Zeroizing<Vec<u8>>>, mask: BitMask<256>) -> Self {
         Self {
             offset,
             message_id,
-            data,
+            data: data.into(),
             mask,
+            dirty: false,
+            loaded: Instant::now(),
         }
     }
 }
 
+impl<const S: usize> Default for Cache<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const S: usize> Cache<S> {
+    /// Cache of pages whose zero masks track every 4KB block.
     pub fn new() -> Self {
+        Self::with_zero_block_size(4096)
+    }
+
+    pub fn with_zero_block_size(zero_block_size: usize) -> Self {
         Self {
             data: Mutex::new(Vec::with_capacity(S)),
+            zero_block_size,
+            page_size: None,
+            on_evict: None,
+            writes: AtomicUsize::new(0),
+            capacity: S,
         }
     }
 
+    /// Keeps up to `capacity` blocks instead of `S`.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Only accepts blocks of `page_size` bytes.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    /// Bytes of the drive covered by the block.
+    fn span(&self, block: &CacheBlock) -> u64 {
+        self.page_size.unwrap_or(block.data.len()) as u64
+    }
+
+    /// Calls `hook` whenever a block is evicted, so evictions can be observed
+    /// no matter what the caller of `push` does with the block.
+    pub fn with_eviction_hook(mut self, hook: impl Fn(&CacheBlock) + Send + Sync + 'static) -> Self {
+        self.on_evict = Some(Box::new(hook));
+        self
+    }
+
     pub fn read(&self, offset: u64) -> Option<Vec<u8>> {
-        let data = self.data.lock().unwrap();
+        let data = self.data.lock_or_recover();
         for block in data.iter() {
-            let bo = block.offset * 1024 * 1024 * 8;
-            if offset >= bo && offset + 4096 <= bo + block.data.len() as u64 {
+            let bo = block.offset * self.span(block);
+            if offset >= bo && offset + 4096 <= bo + self.span(block) {
                 let offset = (offset - bo) as usize;
                 // Use mask
-                if block.mask.get(offset / 4096) {
+                if block.mask.get(offset / self.zero_block_size) {
                     return Some(vec![0; 4096]);
                 }
                 // Return data
@@ -49,21 +117,19 @@ impl<const S: usize> Cache<S> {
         None
     }
 
-    /// Returns true if the write was successful.
+    /// Writes data of any length within one cached block. Returns true if the write was successful.
     pub fn write(&self, offset: u64, data: &[u8]) -> bool {
-        let mut sdata = self.data.lock().unwrap();
+        let mut sdata = self.data.lock_or_recover();
         for block in sdata.iter_mut() {
-            let bo = block.offset * 1024 * 1024 * 8;
-            if offset >= bo && offset + 4096 <= bo + block.data.len() as u64 {
+            let span = self.span(block);
+            let bo = block.offset * span;
+            if offset >= bo && offset + data.len() as u64 <= bo + span {
                 let offset = (offset - bo) as usize;
-                block.data[offset..offset + 4096].copy_from_slice(data);
 
-                // Flip mask if needed
-                if data.iter().all(|byte| *byte == 0) {
-                    block.mask.set(offset / 4096, true);
-                } else {
-                    block.mask.set(offset / 4096, false);
-                }
+                // Flip mask if needed, once for every zero block the data touches
+                write_block(&mut block.data, &mut block.mask, self.zero_block_size, offset, data);
+                block.dirty = true;
+                self.writes.fetch_add(1, Ordering::Relaxed);
 
                 return true;
             }
@@ -72,18 +138,170 @@ impl<const S: usize> Cache<S> {
         false
     }
 
+    /// Stores the `len` bytes at offset as data even if they are zeros, by clearing the mask of the zero blocks they touch.
+    /// Must follow a write of the bytes, which leaves the whole data of the touched blocks in the cached block.
+    pub fn unmask(&self, offset: u64, len: usize) {
+        if len == 0 {
+            return;
+        }
+
+        let mut data = self.data.lock_or_recover();
+        for block in data.iter_mut() {
+            let span = self.span(block);
+            let bo = block.offset * span;
+            if offset >= bo && offset + len as u64 <= bo + span {
+                let start = (offset - bo) as usize;
+                for index in start / self.zero_block_size..=(start + len - 1) / self.zero_block_size {
+                    block.mask.set(index, false);
+                }
+                block.dirty = true;
+                return;
+            }
+        }
+    }
+
+    /// Returns the number of writes applied to cached blocks so far.
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the page at given offset (stored as a multiple of the page size) is cached.
+    pub fn contains(&self, offset: u64) -> bool {
+        self.data.lock_or_recover().iter().any(|block| block.offset == offset)
+    }
+
+    /// Returns a copy of the block holding the page at given offset (stored as a multiple of the page size).
+    pub fn get(&self, offset: u64) -> Option<CacheBlock> {
+        let data = self.data.lock_or_recover();
+        data.iter().find(|block| block.offset == offset).cloned()
+    }
+
+    /// Updates the message the cached page is associated with, after it was uploaded.
+    /// The page is clean until it is written again.
+    pub fn mark_synced(&self, offset: u64, message_id: Option<u64>) {
+        let mut data = self.data.lock_or_recover();
+        if let Some(block) = data.iter_mut().find(|block| block.offset == offset) {
+            block.message_id = message_id;
+            block.dirty = false;
+            block.loaded = Instant::now();
+        }
+    }
+
+    /// Drops the cached page at given offset (stored as a multiple of the page size) if it wasn't written
+    /// and is stale: the page is stored in another message now, or it was loaded more than `ttl` ago.
+    /// Returns true if the page was dropped.
+    pub fn drop_stale(&self, offset: u64, message_id: Option<u64>, ttl: Option<Duration>) -> bool {
+        self.take_stale(offset, message_id, ttl).is_some()
+    }
+
+    /// Like `drop_stale`, but returns the dropped block.
+    pub fn take_stale(&self, offset: u64, message_id: Option<u64>, ttl: Option<Duration>) -> Option<CacheBlock> {
+        let mut data = self.data.lock_or_recover();
+        let stale = data.iter().position(|block| {
+            block.offset == offset && !block.dirty
+                && (block.message_id != message_id || ttl.is_some_and(|ttl| block.loaded.elapsed() >= ttl))
+        });
+
+        stale.map(|i| data.remove(i))
+    }
+
+    /// Returns the number of cached blocks that were written since they were loaded.
+    pub fn dirty_len(&self) -> usize {
+        self.data.lock_or_recover().iter().filter(|block| block.dirty).count()
+    }
+
+    /// Removes and returns the oldest dirty block.
+    pub fn take_oldest_dirty(&self) -> Option<CacheBlock> {
+        let mut data = self.data.lock_or_recover();
+        let i = data.iter().position(|block| block.dirty)?;
+        Some(data.remove(i))
+    }
+
+    /// Returns the number of cached blocks.
+    pub fn len(&self) -> usize {
+        self.data.lock_or_recover().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of cached blocks.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns true if pushing another block evicts the oldest one.
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
     /// Pushes a new block to the cache. If the cache is full, the oldest block is removed and returned.
-    pub fn push(&self, block: CacheBlock) -> Option<CacheBlock> {
-        let mut data = self.data.lock().unwrap();
-        if data.len() >= S {
-            let removed = Some(data.remove(0));
-            data.push(block);
-            return removed;
+    /// The eviction hook is called without holding the cache lock.
+    /// Blocks that don't have the configured page size are rejected.
+    pub fn push(&self, block: CacheBlock) -> Result<Option<CacheBlock>> {
+        if let Some(page_size) = self.page_size.filter(|page_size| block.data.len() != *page_size) {
+            return Err(Error::InvalidCacheBlock { offset: block.offset, expected: page_size, actual: block.data.len() });
         }
 
+        let mut data = self.data.lock_or_recover();
+        let removed = if data.len() >= self.capacity { Some(data.remove(0)) } else { None };
         data.push(block);
+        drop(data);
 
-        None
+        if let (Some(hook), Some(removed)) = (&self.on_evict, &removed) {
+            hook(removed);
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Buffers writes that continue the previous one within its page, so sequential writes
+/// are applied to the cached page (and its zero mask) together instead of one by one.
+/// The page must stay cached while anything is buffered, taking the buffer writes it back.
+#[derive(Debug, Default)]
+pub struct Combiner {
+    /// Page (as a multiple of the page size) and offset the next buffered write has to start at
+    next: Option<(u64, u64)>,
+    data: Vec<u8>,
+}
+
+impl Combiner {
+    /// Buffers the write if it continues the last one and stays in its page. Returns true if it was buffered.
+    pub fn append(&mut self, offset: u64, data: &[u8], page_size: usize) -> bool {
+        let Some((page, next)) = self.next else {
+            return false;
+        };
+        let end = offset + data.len() as u64;
+        if offset != next || (end - 1) / page_size as u64 != page || self.data.len() + data.len() > MAX_COMBINED {
+            return false;
+        }
+
+        self.data.extend_from_slice(data);
+        self.next = Some((page, end));
+        true
+    }
+
+    /// Lets writes continuing the one at offset be buffered, it was written to the cached page.
+    pub fn follow(&mut self, page: u64, offset: u64, len: usize) {
+        self.next = Some((page, offset + len as u64));
+    }
+
+    /// Returns true if any of the bytes at offset are buffered.
+    pub fn overlaps(&self, offset: u64, len: usize) -> bool {
+        match self.next {
+            Some((_, next)) if !self.data.is_empty() => offset < next && next - (self.data.len() as u64) < offset + len as u64,
+            _ => false,
+        }
+    }
+
+    /// Removes the buffered data with the offset it was written at.
+    /// Nothing is buffered afterwards until `follow` is called again.
+    pub fn take(&mut self) -> Option<(u64, Vec<u8>)> {
+        let (_, next) = self.next.take()?;
+        let data = std::mem::take(&mut self.data);
+        (!data.is_empty()).then(|| (next - data.len() as u64, data))
     }
 }
 
@@ -99,17 +317,22 @@ mod test {
 
         cache.push(CacheBlock {
             offset: 0,
-            data: vec![0; 8*MB],
-            message_id: 0,
+            data: vec![0; 8*MB].into(),
+            message_id: None,
             mask: BitMask::new(),
-        });
+            dirty: false,
+            loaded: Instant::now(),
+        }).unwrap();
 
+        // Block offsets count pages of 8MB, not bytes.
         cache.push(CacheBlock {
-            offset: 8*MB as u64,
-            data: vec![1; 8*MB],
-            message_id: 0,
+            offset: 1,
+            data: vec![1; 8*MB].into(),
+            message_id: None,
             mask: BitMask::new(),
-        });
+            dirty: false,
+            loaded: Instant::now(),
+        }).unwrap();
 
         assert_eq!(cache.read(0).unwrap(), vec![0; 4096].as_slice());
 
@@ -122,12 +345,201 @@ mod test {
         assert_eq!(cache.read(4096).unwrap(), vec![0; 4096].as_slice());
 
         cache.push(CacheBlock {
-            offset: 16*MB as u64,
-            data: vec![2; 8*MB],
-            message_id: 0,
+            offset: 2,
+            data: vec![2; 8*MB].into(),
+            message_id: None,
             mask: BitMask::new(),
-        });
+            dirty: false,
+            loaded: Instant::now(),
+        }).unwrap();
 
         assert_eq!(cache.read(16*MB as u64+4096).unwrap(), vec![2; 4096].as_slice());
     }
+
+    #[test]
+    fn rejects_blocks_of_other_sizes() {
+        let cache = Cache::<2>::new().with_page_size(MB);
+
+        let pushed = cache.push(CacheBlock::new(0, None, vec![1; 8*MB], BitMask::new()));
+        assert!(matches!(pushed, Err(Error::InvalidCacheBlock { offset: 0, expected: MB, actual }) if actual == 8*MB));
+        assert!(cache.is_empty());
+
+        // Blocks are found by the page size.
+        cache.push(CacheBlock::new(3, None, vec![1; MB], BitMask::new())).unwrap();
+        assert_eq!(cache.read(3 * MB as u64 + 4096).unwrap(), vec![1; 4096]);
+        assert!(cache.read(MB as u64).is_none());
+    }
+
+    #[test]
+    fn test_cache_coarse_zero_blocks() {
+        let cache = Cache::<2>::with_zero_block_size(64 * 1024);
+        cache.push(CacheBlock::new(0, None, vec![1; 8*MB], BitMask::new())).unwrap();
+
+        for offset in (64 * 1024..128 * 1024).step_by(4096) {
+            cache.write(offset, &[0; 4096]);
+        }
+        let block = cache.get(0).unwrap();
+        assert!(block.mask.get(1));
+        assert_eq!(block.mask.count_ones(), 1);
+
+        // The buffer is cleared before the zeroed block is written again.
+        cache.write(64 * 1024, &[2; 4096]);
+        assert_eq!(cache.read(64 * 1024).unwrap(), vec![2; 4096].as_slice());
+        assert_eq!(cache.read(68 * 1024).unwrap(), vec![0; 4096].as_slice());
+        assert!(!cache.get(0).unwrap().mask.get(1));
+    }
+
+    #[test]
+    fn test_cache_spans() {
+        let cache = Cache::<1>::with_zero_block_size(64 * 1024);
+        cache.push(CacheBlock::new(0, None, vec![0; MB], BitMask::from_bytes(&[0xFF; 2]))).unwrap();
+
+        let mut data = vec![1; 100 * 1024];
+        data[64 * 1024..].fill(0);
+        assert!(cache.write(32 * 1024, &data));
+        assert!(!cache.write(MB as u64 - 4096, &data));
+        assert_eq!(cache.writes(), 1);
+
+        let mask = cache.get(0).unwrap().mask;
+        assert_eq!((0..3).map(|i| mask.get(i)).collect::<Vec<_>>(), vec![false, false, true]);
+        assert_eq!(cache.read(60 * 1024).unwrap(), vec![1; 4096]);
+        assert_eq!(cache.read(96 * 1024).unwrap(), vec![0; 4096]);
+    }
+
+    #[test]
+    fn test_combiner() {
+        let mut combiner = Combiner::default();
+        assert!(!combiner.append(0, &[1; 4096], MB));
+
+        combiner.follow(0, 0, 4096);
+        assert!(combiner.append(4096, &[2; 4096], MB));
+        assert!(combiner.append(8192, &[3; 4096], MB));
+        assert!(!combiner.append(4096, &[4; 4096], MB));
+        assert!(combiner.overlaps(8192, 4096) && !combiner.overlaps(0, 4096));
+        assert_eq!(combiner.take(), Some((4096, [[2; 4096], [3; 4096]].concat())));
+        assert!(!combiner.append(12288, &[4; 4096], MB));
+
+        // Writes don't continue into the next page.
+        combiner.follow(0, MB as u64 - 8192, 4096);
+        assert!(!combiner.append(MB as u64 - 4096, &[5; 8192], MB));
+        assert!(combiner.append(MB as u64 - 4096, &[5; 4096], MB));
+        assert_eq!(combiner.take(), Some((MB as u64 - 4096, vec![5; 4096])));
+        assert_eq!(combiner.take(), None);
+    }
+
+    #[test]
+    fn test_cache_occupancy() {
+        let cache = Cache::<2>::new();
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 2);
+
+        cache.push(CacheBlock::new(0, None, vec![0; 4096], BitMask::new())).unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(!cache.is_full());
+
+        cache.push(CacheBlock::new(1, None, vec![0; 4096], BitMask::new())).unwrap();
+        assert!(cache.is_full());
+
+        assert!(cache.push(CacheBlock::new(2, None, vec![0; 4096], BitMask::new())).unwrap().is_some());
+        assert_eq!(cache.len(), 2);
+        assert!(cache.is_full());
+    }
+
+    #[test]
+    fn test_cache_dirty() {
+        let cache = Cache::<4>::new();
+        for offset in 0..3 {
+            cache.push(CacheBlock::new(offset, None, vec![0; 8*MB], BitMask::new())).unwrap();
+        }
+        assert_eq!(cache.dirty_len(), 0);
+
+        cache.write(16*MB as u64, &[1; 4096]);
+        cache.write(8*MB as u64, &[1; 4096]);
+        assert_eq!(cache.dirty_len(), 2);
+
+        assert_eq!(cache.take_oldest_dirty().unwrap().offset, 1);
+        assert_eq!(cache.len(), 2);
+
+        cache.mark_synced(2, Some(7));
+        assert_eq!(cache.dirty_len(), 0);
+        assert_eq!(cache.get(2).unwrap().message_id, Some(7));
+    }
+
+    #[test]
+    fn test_cache_drop_stale() {
+        let cache = Cache::<4>::new();
+        cache.push(CacheBlock::new(0, Some(5), vec![0; 8*MB], BitMask::new())).unwrap();
+        cache.push(CacheBlock::new(1, Some(6), vec![0; 8*MB], BitMask::new())).unwrap();
+        cache.write(8*MB as u64, &[1; 4096]);
+
+        assert!(!cache.drop_stale(0, Some(5), Some(Duration::from_secs(60))));
+        assert!(cache.drop_stale(0, Some(5), Some(Duration::ZERO)));
+        assert!(!cache.contains(0));
+
+        // Written pages are kept until they are uploaded.
+        assert!(!cache.drop_stale(1, Some(7), None));
+        cache.mark_synced(1, Some(7));
+        assert!(!cache.drop_stale(1, Some(7), None));
+        assert!(cache.drop_stale(1, Some(8), None));
+    }
+
+    #[test]
+    fn test_cache_block_zeroized_on_drop() {
+        let page = |byte| {
+            let mut data = crate::utils::WipeCheck::buffer();
+            data.resize(4096, byte);
+            data
+        };
+
+        // A plain buffer is freed with its data.
+        let (wiped, not_wiped) = crate::utils::WipeCheck::freed();
+        drop(page(1));
+        assert_eq!(crate::utils::WipeCheck::freed(), (wiped, not_wiped + 1));
+
+        // An evicted block is wiped once the caller drops it.
+        let cache = Cache::<1>::new();
+        cache.push(CacheBlock::new(0, None, page(1), BitMask::new())).unwrap();
+        let evicted = cache.push(CacheBlock::new(1, None, page(2), BitMask::new())).unwrap().unwrap();
+        let (wiped, not_wiped) = crate::utils::WipeCheck::freed();
+        drop(evicted);
+        assert_eq!(crate::utils::WipeCheck::freed(), (wiped + 1, not_wiped));
+    }
+
+    #[test]
+    fn test_cache_eviction_hook() {
+        let evicted = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let e = evicted.clone();
+        let cache = Cache::<2>::new().with_eviction_hook(move |block| e.lock_or_recover().push((block.offset, block.dirty)));
+
+        cache.push(CacheBlock::new(0, None, vec![0; 4096], BitMask::new())).unwrap();
+        cache.push(CacheBlock::new(1, None, vec![1; 4096], BitMask::new())).unwrap();
+        cache.write(0, &[1; 4096]);
+        assert!(evicted.lock_or_recover().is_empty());
+
+        // The hook sees the same block the caller gets back.
+        let removed = cache.push(CacheBlock::new(2, None, vec![2; 4096], BitMask::new())).unwrap().unwrap();
+        assert_eq!(removed.offset, 0);
+        assert_eq!(*evicted.lock_or_recover(), vec![(0, true)]);
+
+        cache.push(CacheBlock::new(3, None, vec![3; 4096], BitMask::new())).unwrap();
+        assert_eq!(*evicted.lock_or_recover(), vec![(0, true), (1, false)]);
+    }
+
+    #[test]
+    fn test_cache_poisoned() {
+        let cache = std::sync::Arc::new(Cache::<2>::new());
+
+        cache.push(CacheBlock::new(0, None, vec![1; 8*MB], BitMask::new())).unwrap();
+
+        let c = cache.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = c.data.lock().unwrap();
+            panic!("Poisoning the cache lock");
+        }).join();
+        assert!(result.is_err());
+
+        assert_eq!(cache.read(0).unwrap(), vec![1; 4096].as_slice());
+        assert!(cache.write(0, &[2; 4096]));
+        assert_eq!(cache.read(0).unwrap(), vec![2; 4096].as_slice());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,878 @@
+use std::{path::Path, str::FromStr, sync::Arc, time::Duration};
+
+use tokio::sync::Semaphore;
+use zeroize::Zeroizing;
+
+use crate::backend::{DefaultRetryPolicy, RetryPolicy, DISCORD_GLOBAL_RATE_LIMIT};
+use crate::crypto::Keyring;
+use crate::error::{Error, Result};
+use crate::metadata::{self, DEFAULT_PAGE_SIZE, MASK_BITS, PAGES_PER_BLOCK, EditFallback, MetadataFormat, PageOptions, Scan, ScanOrder};
+use crate::deletions::Deletions;
+use crate::pool::{BufferPool, PageMemory};
+use crate::queue::{self, PanicPolicy, QUEUE_PAGES};
+use crate::superblock::{Sealing, SuperblockSecurity};
+use crate::urls::UrlCache;
+use crate::utils::is_volume_name;
+use crate::wal::WalFormat;
+
+/// Fewest and most pages `CACHE_MEMORY` sizes the cache to.
+const MIN_CACHE_PAGES: usize = 4;
+const MAX_CACHE_PAGES: usize = 4096;
+/// Fewest pages `MEMORY_PAGES` allows: a cached and a queued one, the one moved between them,
+/// a download and two buffers of an upload.
+const MIN_MEMORY_PAGES: usize = 6;
+
+/// When written data reaches discord.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Writes stay in the cache until the page is evicted or flushed. Fast, but a crash loses them.
+    WriteBack,
+    /// Every write is uploaded before it returns. Slow, but durable.
+    WriteThrough,
+}
+
+impl FromStr for CacheMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "write-back" => Ok(CacheMode::WriteBack),
+            "write-through" => Ok(CacheMode::WriteThrough),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What a read does with a page whose download is broken (`READ_REPAIR`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadRepair {
+    /// The read fails and the page is left as it is
+    #[default]
+    Off,
+    /// The page is uploaded again from a copy that is still cached, reads fail if there is none
+    Copy,
+    /// Like `Copy`, but pages without a copy are replaced with zeros, losing their data
+    Zeros,
+}
+
+impl FromStr for ReadRepair {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Self::Off),
+            "copy" => Ok(Self::Copy),
+            "zeros" => Ok(Self::Zeros),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What is checked before the drive serves requests (`STARTUP_SCAN`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StartupScan {
+    /// Nothing, problems only show up once the pages are used
+    #[default]
+    Off,
+    /// Every metadata block is fetched and checked to parse and to point at plausible messages
+    Metadata,
+    /// Like `Metadata`, and the message of every page is fetched to check that it exists
+    Messages,
+}
+
+impl FromStr for StartupScan {
+    type Err = ();
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Self::Off),
+            "metadata" => Ok(Self::Metadata),
+            "messages" => Ok(Self::Messages),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Configuration of the drive.
+/// Like the rest of the settings, it is read from `.env` at compile time.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Size of the drive in bytes (`DEVICE_SIZE`, required).
+    pub device_size: u64,
+    /// Use the size stored in the superblock of an existing drive instead of `DEVICE_SIZE` (`ADOPT_DEVICE_SIZE`).
+    /// Otherwise a different stored size is only warned about, and `DEVICE_SIZE` is stored instead.
+    pub adopt_device_size: bool,
+    /// Bytes of data stored in one message (`PAGE_SIZE`, a multiple of 4096).
+    /// Bigger pages need fewer messages, but every page has to fit in the upload limit.
+    /// Pages of an existing drive can't be resized.
+    pub page_size: usize,
+    /// Biggest file discord accepts in the drive channel (`UPLOAD_LIMIT`, in bytes).
+    /// 10MB by default, Nitro and boosted servers allow bigger uploads.
+    pub upload_limit: usize,
+    /// Bytes tracked by one bit of the zero mask of a page (`ZERO_BLOCK_SIZE`, a multiple of 4096).
+    /// Zeroed blocks are not stored, so smaller blocks save more space on sparse drives,
+    /// while bigger blocks make the metadata smaller. A page may have at most 2048 blocks.
+    /// By default 4KB blocks for pages up to 8MB. Can't be changed for an existing drive.
+    pub zero_block_size: Option<usize>,
+    /// How long a flush may wait for the sync queue to drain (`FLUSH_TIMEOUT`, in seconds).
+    pub flush_timeout: Duration,
+    /// How many times to reconnect before giving up on an operation (`RECONNECT_ATTEMPTS`).
+    pub reconnect_attempts: u32,
+    /// Delay before the first reconnect, doubled after each attempt (`RECONNECT_BACKOFF`, in milliseconds).
+    pub reconnect_backoff: Duration,
+    /// Which failed discord operations are retried, lost connections by default.
+    /// Not read from the env, set it to plug in another policy.
+    pub retry_policy: Arc<dyn RetryPolicy>,
+    /// How long a single discord request may take before it is retried (`REQUEST_TIMEOUT`, in seconds).
+    pub request_timeout: Duration,
+    /// Discord requests sent per second and bot token at most (`GLOBAL_RATE_LIMIT`, 0 to not limit them).
+    pub global_rate_limit: u32,
+    /// Proxy attachments are downloaded through (`DOWNLOAD_PROXY`, like `http://127.0.0.1:8118`).
+    /// By default `HTTP_PROXY` and `HTTPS_PROXY` are used.
+    pub download_proxy: Option<String>,
+    /// PEM file with a certificate trusted for downloads, next to the system ones (`ROOT_CERTIFICATE`).
+    pub root_certificate: Option<String>,
+    /// Load all metadata blocks at startup instead of on first access (`EAGER_METADATA`).
+    /// Only worth it for small drives.
+    pub eager_metadata: bool,
+    /// How many of the newest messages are scanned for metadata blocks
+    /// when the drive has no superblock yet (`METADATA_SCAN_LIMIT`).
+    pub scan_limit: usize,
+    /// Where the scan for metadata blocks starts (`METADATA_SCAN_ORDER`, `newest` or `oldest`).
+    pub scan_order: ScanOrder,
+    /// Count only metadata blocks against `METADATA_SCAN_LIMIT` (`METADATA_SCAN_ONLY_METADATA`),
+    /// so data and other messages between the blocks don't use it up.
+    pub scan_only_metadata: bool,
+    /// Most messages scanned with `METADATA_SCAN_ONLY_METADATA` (`METADATA_SCAN_CAP`).
+    pub scan_cap: usize,
+    /// Move metadata blocks that changed to the bottom of the channel on flush (`MOVE_METADATA`).
+    /// The superblock finds blocks wherever they are, this only keeps them easy to find by hand.
+    /// Every move deletes and sends the message again.
+    pub move_metadata: bool,
+    /// Shortest time between two flushes that move metadata blocks (`MOVE_METADATA_INTERVAL`, in seconds).
+    /// Blocks that change meanwhile are moved together by the next flush after it.
+    pub move_interval: Duration,
+    /// Thread (or forum post) to store the drive in instead of the channel itself (`FS_THREAD_ID`).
+    pub thread_id: Option<u64>,
+    /// Channel (or thread) to keep the metadata blocks, the superblock and snapshots in (`METADATA_CHANNEL_ID`),
+    /// separate from the data pages. The channel of the drive by default.
+    pub metadata_channel: Option<u64>,
+    /// Token of the bot (`BOT_TOKEN`), used if it isn't read from `BOT_TOKEN_FILE` or `BOT_TOKEN_KEYRING`.
+    pub bot_token: Option<String>,
+    /// File the bot token is read from at startup (`BOT_TOKEN_FILE`), so it isn't compiled into the binary.
+    pub token_file: Option<String>,
+    /// Service the bot token is stored under in the system keyring (`BOT_TOKEN_KEYRING`),
+    /// looked up with `secret-tool` (or `security` on macOS) if there is no `BOT_TOKEN_FILE`.
+    pub token_keyring: Option<String>,
+    /// Refuse a `BOT_TOKEN_FILE` that other users may read (`STRICT_TOKEN_FILE`), otherwise it is only warned about.
+    pub strict_token_file: bool,
+    /// Tokens of additional bots that share uploads and downloads with the main one
+    /// (`EXTRA_BOT_TOKENS`, comma separated). All bots need the Manage Messages permission.
+    pub extra_tokens: Vec<String>,
+    /// When writes are uploaded (`CACHE_MODE`, `write-back` or `write-through`).
+    pub cache_mode: CacheMode,
+    /// How often the health of the sync queue is checked (`HEALTH_INTERVAL`, in seconds).
+    pub health_interval: Duration,
+    /// How long blocks may wait in the queue before syncing is considered stalled (`STALL_TIMEOUT`, in seconds).
+    pub stall_timeout: Duration,
+    /// Number of queued pages at which a warning is logged that syncing falls behind (`QUEUE_HIGH_WATER`).
+    /// By default when the queue is full and writes start waiting for it.
+    pub queue_high_water: Option<usize>,
+    /// Failed uploads of a page after which it is given up on and the next flush or write fails (`SYNC_ATTEMPTS`).
+    /// By default pages are retried until they sync.
+    pub sync_attempts: Option<u32>,
+    /// Most pages uploaded at once (`MAX_UPLOADS`).
+    pub max_uploads: usize,
+    /// Most metadata blocks the sync thread stores at once (`MAX_METADATA_UPDATES`), one at a time by default.
+    /// They are stored from copies, so reads and writes don't wait for the updates.
+    pub max_metadata_updates: usize,
+    /// Most pages downloaded at once (`MAX_DOWNLOADS`), further reads that miss the cache wait for a download to finish.
+    /// Every download holds a whole page in memory. Unlimited by default.
+    pub max_downloads: Option<usize>,
+    /// What the sync thread does when syncing a page panics (`SYNC_PANIC`, `recover` or `abort`).
+    pub sync_panic: PanicPolicy,
+    /// Most page buffers kept for reuse once their page is evicted or synced (`BUFFER_POOL`), 0 disables pooling.
+    /// Every pooled buffer holds a page worth of memory, even while the drive is idle.
+    pub buffer_pool: usize,
+    /// Print the page map of the loaded metadata at startup (`DUMP_LAYOUT`).
+    pub dump_layout: bool,
+    /// Log the messages that would be sent, edited or deleted instead of changing the channel (`DRY_RUN`).
+    /// Nothing written is kept once the drive is closed.
+    pub dry_run: bool,
+    /// Log every discord operation with its duration and result at debug level (`TRACE_DISCORD`).
+    pub trace_discord: bool,
+    /// Check at startup that the bot may do everything the drive does in the channel (`CHECK_PERMISSIONS`).
+    /// Off by default, as it sends and deletes a message on every start.
+    pub check_permissions: bool,
+    /// Send, download and delete a test file at startup, failing with the step that didn't work (`SELF_TEST`).
+    pub self_test: bool,
+    /// Read every page at startup and print the ones that are damaged (`VERIFY_PAGES`).
+    /// Takes as long as downloading the whole drive.
+    pub verify_pages: bool,
+    /// Check the stored metadata at startup and print the problems (`STARTUP_SCAN`, `off`, `metadata` or `messages`).
+    /// Off by default, it fetches every metadata block (and with `messages` every data message) before the drive opens.
+    pub startup_scan: StartupScan,
+    /// Download every page at startup and fix zero masks that don't match the data (`REPAIR_MASKS`).
+    pub repair_masks: bool,
+    /// Download every uploaded page and compare it with the upload before the metadata points at it (`VERIFY_UPLOADS`).
+    /// Pages that read back differently are uploaded again. Doubles the traffic of writes.
+    pub verify_uploads: bool,
+    /// Restore the newest snapshot with this name at startup (`RESTORE_SNAPSHOT`).
+    pub restore_snapshot: Option<String>,
+    /// Take a snapshot with this name at startup (`TAKE_SNAPSHOT`), after restoring one.
+    /// Needs `KEEP_HISTORY`, otherwise the messages it points at are deleted.
+    pub take_snapshot: Option<String>,
+    /// How often a flush saves a backup of the whole metadata (`METADATA_BACKUP_INTERVAL`, in seconds, off by default).
+    /// Backups are snapshots, they only point at data messages, so pages rewritten since a backup lose their message without `KEEP_HISTORY`.
+    pub backup_interval: Option<Duration>,
+    /// Channel the metadata backups are sent to (`METADATA_BACKUP_CHANNEL`, the metadata channel by default).
+    pub backup_channel: Option<u64>,
+    /// Replace the metadata with the newest backup at startup (`RESTORE_METADATA_BACKUP`).
+    pub restore_backup: bool,
+    /// Directory of the local write-ahead log of pages queued for upload (`WAL_DIR`, none by default).
+    /// Pages that were queued when daafs died are uploaded on the next start.
+    pub wal_dir: Option<String>,
+    /// How pages are stored in the log (`WAL_FORMAT`, `raw`, `trimmed` or `gzip`).
+    pub wal_format: WalFormat,
+    /// Directory pages evicted from the cache wait in until they are uploaded (`LOCAL_TIER_DIR`, none by default).
+    /// Pages only go to the (blocking) sync queue once `LOCAL_TIER_PAGES` of them wait there.
+    pub local_tier_dir: Option<String>,
+    pub local_tier_pages: usize,
+    /// Directory of the local journal of metadata updates of uploaded pages (`MASK_JOURNAL_DIR`, none by default).
+    /// Pages uploaded when daafs died before their metadata was updated get it on the next start.
+    pub mask_journal_dir: Option<String>,
+    /// Keys pages are encrypted with (`ENCRYPTION_KEYS`, comma separated `<id>:<64 hex digits>`).
+    /// New pages use `ENCRYPTION_KEY_ID`, or the last listed key. Pages are not encrypted without keys.
+    pub keyring: Keyring,
+    /// How many pages encrypted with an old key are migrated on every flush (`REKEY_BATCH`).
+    pub rekey_batch: usize,
+    /// Sign or encrypt the superblock with the current encryption key (`SUPERBLOCK_SECURITY`, `none`, `sign` or `encrypt`).
+    /// Superblocks that are not protected are then ignored, and ones that were tampered with fail to open the drive.
+    pub superblock_security: SuperblockSecurity,
+    /// Text of the messages holding page data (`DATA_MESSAGE_CONTENT`, empty by default).
+    /// `{page}`, `{offset}` and `{volume}` are replaced with the page number, its byte offset and the volume.
+    pub data_content: String,
+    /// Name of the files holding page data (`DATA_FILE_NAME`, like `page_{offset}.bin`), with the same placeholders.
+    /// Names are random by default, so only the metadata tells which page a file holds.
+    pub data_file_name: String,
+    /// Extension of the random names of files holding page data (`DATA_FILE_EXTENSION`, `bin` by default).
+    /// Discord picks the content type of a file by its extension.
+    pub data_file_extension: String,
+    /// Mark files holding page data as spoilers (`SPOILER_ATTACHMENTS`), so the channel doesn't preview them.
+    pub spoiler_attachments: bool,
+    /// Keep the old data messages of rewritten pages as a version history (`KEEP_HISTORY`).
+    /// Every data message records the message it replaced, the channel grows with every rewrite.
+    pub keep_history: bool,
+    /// How long old data messages of rewritten or dropped pages are kept before they are deleted (`DELETION_GRACE`, in seconds).
+    /// Reads that started before the rewrite can still download them meanwhile. They are deleted right away by default.
+    pub deletion_grace: Duration,
+    /// Download just the read 4KB blocks of pages that are not cached (`RANGED_READS`).
+    /// Saves bandwidth for random reads, but reads don't fill the cache. Needs unencrypted pages without checksums.
+    pub ranged_reads: bool,
+    /// Track blocks that hold only zeros in the zero masks of pages (`ZERO_MASK`). With false every written block
+    /// is stored and read as data, for drives whose blocks are rarely zero (like encrypted filesystems or disk images).
+    pub zero_mask: bool,
+    /// Name of the drive when several drives share the channel (`VOLUME`, letters, digits, `-` and `_`).
+    /// Every drive only sees its own metadata, an empty name is the default drive.
+    pub volume: String,
+    /// How metadata blocks are written (`METADATA_FORMAT`, `text` or `json`).
+    /// JSON blocks are stored in attachments, blocks of the other format are converted when they are next written.
+    pub metadata_format: MetadataFormat,
+    /// Text metadata blocks longer than this many bytes are stored gzip-compressed in an attachment (`METADATA_COMPRESS_ABOVE`).
+    /// Blocks that shrink below it are written inline again.
+    pub metadata_compress_above: Option<usize>,
+    /// What is done when discord refuses to edit a metadata block message (`METADATA_EDIT_FALLBACK`,
+    /// `fail`, `retry`, `recreate` or `defer`). By default the update fails and the page is synced again later.
+    pub metadata_edit_fallback: EditFallback,
+    /// Store a checksum with every uploaded page and verify it on download (`PAGE_CHECKSUMS`).
+    /// Encrypted pages are always verified.
+    pub checksums: bool,
+    /// How many times a broken or failed page download is attempted (`DOWNLOAD_ATTEMPTS`).
+    pub download_attempts: u32,
+    /// Rewrite pages that are missing or fail their length or checksum check once all download attempts are used up
+    /// (`READ_REPAIR`, `off`, `copy` or `zeros`). Reads then write to discord, so it is off by default.
+    pub read_repair: ReadRepair,
+    /// Upload a page again from its (expired) cached copy when a read finds the attachment of its message gone
+    /// (`REUPLOAD_LOST_ATTACHMENTS`), even with `READ_REPAIR` off. Pages without a copy are marked as lost.
+    pub reupload_lost_attachments: bool,
+    /// Most metadata blocks the drive may have (`MAX_METADATA_BLOCKS`).
+    /// By default as many as it takes to describe every page of the drive.
+    pub max_metadata_blocks: Option<usize>,
+    /// Most pages the drive may store (`MAX_DATA_MESSAGES`), each of them in one data message.
+    /// Writes to new pages fail once there are that many, so a shared server isn't filled up.
+    pub max_data_messages: Option<usize>,
+    /// Pages a data channel may hold before pages that were never uploaded go to the next of `ROLLOVER_CHANNEL_IDS`
+    /// (`CHANNEL_MESSAGE_LIMIT`, unlimited by default). Pages already stored stay where they are.
+    pub channel_message_limit: Option<usize>,
+    /// Channels the drive rolls over to, in order, once the channel of the drive is full (`ROLLOVER_CHANNEL_IDS`, comma separated).
+    pub rollover_channels: Vec<u64>,
+    /// Most written pages kept in the cache before the oldest ones are queued for upload (`DIRTY_LIMIT`).
+    /// By default pages are only uploaded once the cache is full or on flush.
+    pub dirty_limit: Option<usize>,
+    /// How long pages that were only read are served from the cache (`CACHE_TTL`, in seconds).
+    /// 0 downloads every read that isn't of a written page again, by default pages are kept until they are evicted.
+    pub cache_ttl: Option<Duration>,
+    /// Fraction of the memory available at startup used for the cache (`CACHE_MEMORY`, like 0.25).
+    /// Converted to pages of `page_size`, 4 to 4096 of them. By default 4 pages are cached.
+    pub cache_memory: Option<f64>,
+    /// Most page buffers held at once by the cache, the sync queue and downloads and uploads (`MEMORY_PAGES`).
+    /// Pages are encrypted and decrypted in place so every transfer holds a single copy. Unbounded by default.
+    pub memory_pages: Option<usize>,
+}
+
+/// How the pages of `MEMORY_PAGES` are shared out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySplit {
+    /// Pages the cache holds.
+    pub cached: usize,
+    /// Pages waiting in the sync queue or being uploaded.
+    pub queued: usize,
+    /// Pages downloaded at once.
+    pub downloads: usize,
+    /// Buffers of pages being uploaded, an upload read back with `VERIFY_UPLOADS` holds two.
+    pub uploads: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            device_size: 1024 * 1024 * 128,
+            adopt_device_size: false,
+            page_size: DEFAULT_PAGE_SIZE,
+            upload_limit: 1024 * 1024 * 10,
+            zero_block_size: None,
+            flush_timeout: Duration::from_secs(120),
+            reconnect_attempts: 5,
+            reconnect_backoff: Duration::from_millis(500),
+            retry_policy: Arc::new(DefaultRetryPolicy),
+            request_timeout: Duration::from_secs(30),
+            global_rate_limit: DISCORD_GLOBAL_RATE_LIMIT,
+            download_proxy: None,
+            root_certificate: None,
+            eager_metadata: false,
+            scan_limit: 500,
+            scan_order: ScanOrder::NewestFirst,
+            scan_only_metadata: false,
+            scan_cap: 100_000,
+            move_metadata: false,
+            move_interval: Duration::from_secs(600),
+            thread_id: None,
+            metadata_channel: None,
+            bot_token: None,
+            token_file: None,
+            token_keyring: None,
+            strict_token_file: false,
+            extra_tokens: Vec::new(),
+            cache_mode: CacheMode::WriteBack,
+            health_interval: Duration::from_secs(30),
+            stall_timeout: Duration::from_secs(300),
+            queue_high_water: None,
+            sync_attempts: None,
+            max_uploads: queue::DEFAULT_MAX_UPLOADS,
+            max_metadata_updates: 1,
+            max_downloads: None,
+            sync_panic: PanicPolicy::Recover,
+            buffer_pool: 4,
+            dump_layout: false,
+            dry_run: false,
+            trace_discord: false,
+            check_permissions: false,
+            self_test: false,
+            verify_pages: false,
+            startup_scan: StartupScan::Off,
+            repair_masks: false,
+            verify_uploads: false,
+            restore_snapshot: None,
+            take_snapshot: None,
+            backup_interval: None,
+            backup_channel: None,
+            restore_backup: false,
+            wal_dir: None,
+            wal_format: WalFormat::Raw,
+            local_tier_dir: None,
+            local_tier_pages: 256,
+            mask_journal_dir: None,
+            keyring: Keyring::none(),
+            rekey_batch: 4,
+            superblock_security: SuperblockSecurity::None,
+            data_content: String::new(),
+            data_file_name: String::new(),
+            data_file_extension: "bin".to_string(),
+            spoiler_attachments: false,
+            keep_history: false,
+            deletion_grace: Duration::ZERO,
+            ranged_reads: false,
+            zero_mask: true,
+            volume: String::new(),
+            metadata_format: MetadataFormat::Text,
+            metadata_compress_above: None,
+            metadata_edit_fallback: EditFallback::Fail,
+            checksums: false,
+            download_attempts: 2,
+            read_repair: ReadRepair::Off,
+            reupload_lost_attachments: false,
+            max_metadata_blocks: None,
+            max_data_messages: None,
+            channel_message_limit: None,
+            rollover_channels: Vec::new(),
+            dirty_limit: None,
+            cache_ttl: None,
+            cache_memory: None,
+            memory_pages: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration, using defaults for everything that is not set in `.env`.
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+
+        Ok(Self {
+            device_size: parse("DEVICE_SIZE", Some(env!("DEVICE_SIZE")), default.device_size)?,
+            adopt_device_size: parse("ADOPT_DEVICE_SIZE", option_env!("ADOPT_DEVICE_SIZE"), default.adopt_device_size)?,
+            page_size: parse("PAGE_SIZE", option_env!("PAGE_SIZE"), default.page_size)?,
+            upload_limit: parse("UPLOAD_LIMIT", option_env!("UPLOAD_LIMIT"), default.upload_limit)?,
+            zero_block_size: option_env!("ZERO_BLOCK_SIZE").map(|value| parse("ZERO_BLOCK_SIZE", Some(value), 0)).transpose()?,
+            flush_timeout: Duration::from_secs(
+                parse("FLUSH_TIMEOUT", option_env!("FLUSH_TIMEOUT"), default.flush_timeout.as_secs())?
+            ),
+            reconnect_attempts: parse("RECONNECT_ATTEMPTS", option_env!("RECONNECT_ATTEMPTS"), default.reconnect_attempts)?,
+            reconnect_backoff: Duration::from_millis(
+                parse("RECONNECT_BACKOFF", option_env!("RECONNECT_BACKOFF"), default.reconnect_backoff.as_millis() as u64)?
+            ),
+            retry_policy: default.retry_policy.clone(),
+            request_timeout: Duration::from_secs(
+                parse("REQUEST_TIMEOUT", option_env!("REQUEST_TIMEOUT"), default.request_timeout.as_secs())?
+            ),
+            global_rate_limit: parse("GLOBAL_RATE_LIMIT", option_env!("GLOBAL_RATE_LIMIT"), default.global_rate_limit)?,
+            download_proxy: option_env!("DOWNLOAD_PROXY").map(str::to_string),
+            root_certificate: option_env!("ROOT_CERTIFICATE").map(str::to_string),
+            eager_metadata: parse("EAGER_METADATA", option_env!("EAGER_METADATA"), default.eager_metadata)?,
+            scan_limit: parse("METADATA_SCAN_LIMIT", option_env!("METADATA_SCAN_LIMIT"), default.scan_limit)?,
+            scan_order: parse("METADATA_SCAN_ORDER", option_env!("METADATA_SCAN_ORDER"), default.scan_order)?,
+            scan_only_metadata: parse("METADATA_SCAN_ONLY_METADATA", option_env!("METADATA_SCAN_ONLY_METADATA"), default.scan_only_metadata)?,
+            scan_cap: parse("METADATA_SCAN_CAP", option_env!("METADATA_SCAN_CAP"), default.scan_cap)?,
+            move_metadata: parse("MOVE_METADATA", option_env!("MOVE_METADATA"), default.move_metadata)?,
+            move_interval: Duration::from_secs(
+                parse("MOVE_METADATA_INTERVAL", option_env!("MOVE_METADATA_INTERVAL"), default.move_interval.as_secs())?
+            ),
+            thread_id: option_env!("FS_THREAD_ID").map(|value| parse("FS_THREAD_ID", Some(value), 0)).transpose()?,
+            metadata_channel: option_env!("METADATA_CHANNEL_ID").map(|value| parse("METADATA_CHANNEL_ID", Some(value), 0)).transpose()?,
+            bot_token: option_env!("BOT_TOKEN").map(|token| token.trim().to_string()),
+            token_file: option_env!("BOT_TOKEN_FILE").map(str::to_string),
+            token_keyring: option_env!("BOT_TOKEN_KEYRING").map(str::to_string),
+            strict_token_file: parse("STRICT_TOKEN_FILE", option_env!("STRICT_TOKEN_FILE"), default.strict_token_file)?,
+            extra_tokens: option_env!("EXTRA_BOT_TOKENS")
+                .map(|tokens| tokens.split(',').map(|token| token.trim().to_string()).filter(|token| !token.is_empty()).collect())
+                .unwrap_or_default(),
+            cache_mode: parse("CACHE_MODE", option_env!("CACHE_MODE"), default.cache_mode)?,
+            health_interval: Duration::from_secs(
+                parse("HEALTH_INTERVAL", option_env!("HEALTH_INTERVAL"), default.health_interval.as_secs())?
+            ),
+            stall_timeout: Duration::from_secs(
+                parse("STALL_TIMEOUT", option_env!("STALL_TIMEOUT"), default.stall_timeout.as_secs())?
+            ),
+            queue_high_water: option_env!("QUEUE_HIGH_WATER").map(|value| parse("QUEUE_HIGH_WATER", Some(value), 0)).transpose()?,
+            max_uploads: parse("MAX_UPLOADS", option_env!("MAX_UPLOADS"), default.max_uploads)?,
+            max_metadata_updates: parse("MAX_METADATA_UPDATES", option_env!("MAX_METADATA_UPDATES"), default.max_metadata_updates)?,
+            max_downloads: option_env!("MAX_DOWNLOADS").map(|value| parse("MAX_DOWNLOADS", Some(value), 0)).transpose()?,
+            sync_panic: parse("SYNC_PANIC", option_env!("SYNC_PANIC"), default.sync_panic)?,
+            buffer_pool: parse("BUFFER_POOL", option_env!("BUFFER_POOL"), default.buffer_pool)?,
+            sync_attempts: option_env!("SYNC_ATTEMPTS").map(|value| parse("SYNC_ATTEMPTS", Some(value), 0)).transpose()?,
+            dump_layout: parse("DUMP_LAYOUT", option_env!("DUMP_LAYOUT"), default.dump_layout)?,
+            dry_run: parse("DRY_RUN", option_env!("DRY_RUN"), default.dry_run)?,
+            trace_discord: parse("TRACE_DISCORD", option_env!("TRACE_DISCORD"), default.trace_discord)?,
+            check_permissions: parse("CHECK_PERMISSIONS", option_env!("CHECK_PERMISSIONS"), default.check_permissions)?,
+            self_test: parse("SELF_TEST", option_env!("SELF_TEST"), default.self_test)?,
+            verify_pages: parse("VERIFY_PAGES", option_env!("VERIFY_PAGES"), default.verify_pages)?,
+            startup_scan: parse("STARTUP_SCAN", option_env!("STARTUP_SCAN"), default.startup_scan)?,
+            repair_masks: parse("REPAIR_MASKS", option_env!("REPAIR_MASKS"), default.repair_masks)?,
+            verify_uploads: parse("VERIFY_UPLOADS", option_env!("VERIFY_UPLOADS"), default.verify_uploads)?,
+            restore_snapshot: option_env!("RESTORE_SNAPSHOT").map(str::to_string),
+            take_snapshot: option_env!("TAKE_SNAPSHOT").map(str::to_string),
+            backup_interval: option_env!("METADATA_BACKUP_INTERVAL").map(|value| parse("METADATA_BACKUP_INTERVAL", Some(value), 0).map(Duration::from_secs)).transpose()?,
+            backup_channel: option_env!("METADATA_BACKUP_CHANNEL").map(|value| parse("METADATA_BACKUP_CHANNEL", Some(value), 0)).transpose()?,
+            restore_backup: parse("RESTORE_METADATA_BACKUP", option_env!("RESTORE_METADATA_BACKUP"), default.restore_backup)?,
+            wal_dir: option_env!("WAL_DIR").map(str::to_string),
+            wal_format: parse("WAL_FORMAT", option_env!("WAL_FORMAT"), default.wal_format)?,
+            local_tier_dir: option_env!("LOCAL_TIER_DIR").map(str::to_string),
+            local_tier_pages: parse("LOCAL_TIER_PAGES", option_env!("LOCAL_TIER_PAGES"), default.local_tier_pages)?,
+            mask_journal_dir: option_env!("MASK_JOURNAL_DIR").map(str::to_string),
+            keyring: keyring()?,
+            rekey_batch: parse("REKEY_BATCH", option_env!("REKEY_BATCH"), default.rekey_batch)?,
+            superblock_security: parse("SUPERBLOCK_SECURITY", option_env!("SUPERBLOCK_SECURITY"), default.superblock_security)?,
+            data_content: option_env!("DATA_MESSAGE_CONTENT").map(str::to_string).unwrap_or(default.data_content),
+            data_file_name: option_env!("DATA_FILE_NAME").map(str::to_string).unwrap_or(default.data_file_name),
+            data_file_extension: option_env!("DATA_FILE_EXTENSION").map(|value| value.trim().to_string()).unwrap_or(default.data_file_extension),
+            spoiler_attachments: parse("SPOILER_ATTACHMENTS", option_env!("SPOILER_ATTACHMENTS"), default.spoiler_attachments)?,
+            keep_history: parse("KEEP_HISTORY", option_env!("KEEP_HISTORY"), default.keep_history)?,
+            deletion_grace: Duration::from_secs(
+                parse("DELETION_GRACE", option_env!("DELETION_GRACE"), default.deletion_grace.as_secs())?
+            ),
+            ranged_reads: parse("RANGED_READS", option_env!("RANGED_READS"), default.ranged_reads)?,
+            zero_mask: parse("ZERO_MASK", option_env!("ZERO_MASK"), default.zero_mask)?,
+            volume: option_env!("VOLUME").map(str::to_string).unwrap_or(default.volume),
+            metadata_format: parse("METADATA_FORMAT", option_env!("METADATA_FORMAT"), default.metadata_format)?,
+            metadata_compress_above: option_env!("METADATA_COMPRESS_ABOVE").map(|value| parse("METADATA_COMPRESS_ABOVE", Some(value), 0)).transpose()?,
+            metadata_edit_fallback: parse("METADATA_EDIT_FALLBACK", option_env!("METADATA_EDIT_FALLBACK"), default.metadata_edit_fallback)?,
+            checksums: parse("PAGE_CHECKSUMS", option_env!("PAGE_CHECKSUMS"), default.checksums)?,
+            download_attempts: parse("DOWNLOAD_ATTEMPTS", option_env!("DOWNLOAD_ATTEMPTS"), default.download_attempts)?,
+            read_repair: parse("READ_REPAIR", option_env!("READ_REPAIR"), default.read_repair)?,
+            reupload_lost_attachments: parse("REUPLOAD_LOST_ATTACHMENTS", option_env!("REUPLOAD_LOST_ATTACHMENTS"), default.reupload_lost_attachments)?,
+            max_metadata_blocks: option_env!("MAX_METADATA_BLOCKS").map(|value| parse("MAX_METADATA_BLOCKS", Some(value), 0)).transpose()?,
+            max_data_messages: option_env!("MAX_DATA_MESSAGES").map(|value| parse("MAX_DATA_MESSAGES", Some(value), 0)).transpose()?,
+            channel_message_limit: option_env!("CHANNEL_MESSAGE_LIMIT").map(|value| parse("CHANNEL_MESSAGE_LIMIT", Some(value), 0)).transpose()?,
+            rollover_channels: option_env!("ROLLOVER_CHANNEL_IDS")
+                .map(|ids| ids.split(',').filter(|id| !id.trim().is_empty()).map(|id| parse("ROLLOVER_CHANNEL_IDS", Some(id), 0)).collect())
+                .transpose()?
+                .unwrap_or_default(),
+            dirty_limit: option_env!("DIRTY_LIMIT").map(|value| parse("DIRTY_LIMIT", Some(value), 0)).transpose()?,
+            cache_ttl: option_env!("CACHE_TTL").map(|value| parse("CACHE_TTL", Some(value), 0).map(Duration::from_secs)).transpose()?,
+            cache_memory: option_env!("CACHE_MEMORY").map(|value| parse("CACHE_MEMORY", Some(value), 0.0)).transpose()?,
+            memory_pages: option_env!("MEMORY_PAGES").map(|value| parse("MEMORY_PAGES", Some(value), 0)).transpose()?,
+        })
+    }
+
+    /// Checks that pages can be stored with these settings.
+    pub fn validate(&self) -> Result<()> {
+        if !self.volume.is_empty() && !is_volume_name(&self.volume) {
+            return Err(Error::InvalidVolume { volume: self.volume.clone() });
+        }
+
+        if self.page_size == 0 || !self.page_size.is_multiple_of(4096) {
+            return Err(Error::InvalidPageSize { page_size: self.page_size });
+        }
+
+        let zero_block_size = self.zero_block_size();
+        if zero_block_size == 0 || !zero_block_size.is_multiple_of(4096)
+            || metadata::zero_blocks(self.page_size, zero_block_size) > MASK_BITS
+        {
+            return Err(Error::InvalidZeroBlockSize { zero_block_size, page_size: self.page_size });
+        }
+
+        let upload_size = metadata::upload_len(self.keyring.current(), self.page_size, self.checksums);
+        if upload_size > self.upload_limit {
+            return Err(Error::PageTooLarge { page_size: self.page_size, upload_size, limit: self.upload_limit });
+        }
+
+        if self.max_downloads == Some(0) {
+            return Err(Error::InvalidConfig { name: "MAX_DOWNLOADS", reason: "at least one page has to be downloaded at once".to_string() });
+        }
+
+        if !self.data_file_extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::InvalidConfig { name: "DATA_FILE_EXTENSION", reason: format!("{:?} is not a file extension", self.data_file_extension) });
+        }
+
+        if let Some(fraction) = self.cache_memory.filter(|fraction| !(*fraction > 0.0 && *fraction <= 1.0)) {
+            return Err(Error::InvalidConfig { name: "CACHE_MEMORY", reason: format!("{} is not between 0 and 1", fraction) });
+        }
+
+        if let Some(pages) = self.memory_pages.filter(|pages| *pages < MIN_MEMORY_PAGES) {
+            return Err(Error::InvalidConfig { name: "MEMORY_PAGES", reason: format!("{} pages are too few, at least {} are needed", pages, MIN_MEMORY_PAGES) });
+        }
+
+        if self.channel_message_limit.is_some() && self.rollover_channels.is_empty() {
+            return Err(Error::InvalidConfig { name: "CHANNEL_MESSAGE_LIMIT", reason: "there are no ROLLOVER_CHANNEL_IDS to roll over to".to_string() });
+        }
+
+        if self.superblock_security != SuperblockSecurity::None && self.keyring.current() == 0 {
+            return Err(Error::InvalidConfig { name: "SUPERBLOCK_SECURITY", reason: "the superblock can't be protected without ENCRYPTION_KEYS".to_string() });
+        }
+
+        Ok(())
+    }
+
+    /// Text of the messages holding page data, recording the volume they belong to.
+    fn message_content(&self) -> String {
+        let content = self.data_content.replace("{volume}", &self.volume);
+        match (self.volume.as_str(), content.as_str()) {
+            ("", content) => content.to_string(),
+            (volume, "") => format!("volume {}", volume),
+            (volume, content) => format!("volume {}\n{}", volume, content),
+        }
+    }
+
+    /// Bytes tracked by one bit of the zero mask of a page.
+    pub fn zero_block_size(&self) -> usize {
+        self.zero_block_size.unwrap_or_else(|| metadata::default_zero_block_size(self.page_size))
+    }
+
+    /// Most metadata blocks the drive may have.
+    pub fn metadata_block_limit(&self) -> usize {
+        self.max_metadata_blocks.unwrap_or_else(|| {
+            let pages = self.device_size.div_ceil(self.page_size as u64) as usize;
+            pages.div_ceil(PAGES_PER_BLOCK)
+        })
+    }
+
+    /// Pages the cache holds with `CACHE_MEMORY` set, None if it isn't or the available memory is unknown.
+    pub fn cache_capacity(&self) -> Option<usize> {
+        let fraction = self.cache_memory?;
+        match available_memory() {
+            Some(available) => Some(cache_pages(available, fraction, self.page_size)),
+            None => {
+                log::warn!("Available memory is unknown, CACHE_MEMORY is ignored");
+                None
+            }
+        }
+    }
+
+    /// How `MEMORY_PAGES` is shared out, None if memory isn't bounded.
+    /// An eighth goes to downloads and to uploads each and a quarter to the queue, one page is moved
+    /// between the cache and the queue and the cache gets the rest.
+    pub fn memory_split(&self) -> Option<MemorySplit> {
+        let pages = self.memory_pages?.max(MIN_MEMORY_PAGES);
+        let downloads = (pages / 8).max(1);
+        let uploads = (pages / 8).max(2);
+        let queued = (pages / 4).clamp(1, QUEUE_PAGES);
+        Some(MemorySplit { cached: pages - downloads - uploads - queued - 1, queued, downloads, uploads })
+    }
+
+    /// Token of the bot, from `BOT_TOKEN_FILE`, `BOT_TOKEN_KEYRING` or `BOT_TOKEN`, in that order.
+    /// The token is checked to look like a bot token before it is used.
+    pub fn bot_token(&self) -> Result<Zeroizing<String>> {
+        let (name, token) = if let Some(path) = &self.token_file {
+            ("BOT_TOKEN_FILE", read_token_file(Path::new(path), self.strict_token_file)?)
+        } else if let Some(service) = &self.token_keyring {
+            ("BOT_TOKEN_KEYRING", keyring_token(service)?)
+        } else {
+            let token = self.bot_token.clone().ok_or(Error::InvalidConfig {
+                name: "BOT_TOKEN",
+                reason: "no token is set, nor BOT_TOKEN_FILE or BOT_TOKEN_KEYRING".to_string(),
+            })?;
+            ("BOT_TOKEN", Zeroizing::new(token))
+        };
+
+        // The token itself is left out of the error.
+        if !is_bot_token(&token) {
+            return Err(Error::InvalidConfig { name, reason: "the token doesn't look like a discord bot token".to_string() });
+        }
+        Ok(token)
+    }
+
+    /// How the superblock is protected, with the keys to do it.
+    pub fn sealing(&self) -> Sealing {
+        Sealing { security: self.superblock_security, keyring: self.keyring.clone() }
+    }
+
+    /// How the channel is scanned for metadata blocks when there is no superblock.
+    pub fn scan(&self) -> Scan {
+        Scan {
+            order: self.scan_order,
+            limit: self.scan_limit,
+            only_metadata: self.scan_only_metadata,
+            cap: if self.scan_only_metadata { self.scan_cap } else { self.scan_limit },
+        }
+    }
+
+    /// Settings of how pages are stored.
+    pub fn page_options(&self) -> PageOptions {
+        PageOptions {
+            size: self.page_size,
+            zero_block_size: self.zero_block_size(),
+            keyring: self.keyring.clone(),
+            content: self.message_content(),
+            file_name: self.data_file_name.replace("{volume}", &self.volume),
+            file_extension: self.data_file_extension.clone(),
+            spoiler: self.spoiler_attachments,
+            checksums: self.checksums,
+            download_attempts: self.download_attempts,
+            urls: UrlCache::new(),
+            keep_history: self.keep_history,
+            verify_uploads: self.verify_uploads,
+            // Pooled buffers would be held beyond MEMORY_PAGES.
+            buffers: BufferPool::new(if self.memory_pages.is_some() { 0 } else { self.buffer_pool }),
+            deletions: Deletions::new(self.deletion_grace),
+            downloads: self.max_downloads.map(|limit| Arc::new(Semaphore::new(limit))),
+            new_pages: Arc::default(),
+            memory: self.memory_split().map_or_else(Arc::default, |split| Arc::new(PageMemory::bounded(split.downloads, split.uploads))),
+        }
+    }
+}
+
+/// Parses an optional env value, falling back to `default` when it is not set.
+pub fn parse<T: FromStr>(name: &'static str, value: Option<&str>, default: T) -> Result<T> {
+    match value {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| Error::InvalidConfig { name, reason: format!("{:?} can't be parsed", value) }),
+        None => Ok(default),
+    }
+}
+
+/// Pages of `page_size` bytes fitting in `fraction` of `available` bytes of memory.
+fn cache_pages(available: u64, fraction: f64, page_size: usize) -> usize {
+    let pages = (available as f64 * fraction / page_size as f64) as usize;
+    pages.clamp(MIN_CACHE_PAGES, MAX_CACHE_PAGES)
+}
+
+/// Bytes of memory available to new allocations, from `MemAvailable` in `/proc/meminfo`.
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line.trim_start_matches("MemAvailable:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Reads the bot token from the file. A file that other users may read is refused with `strict`, and warned about otherwise.
+fn read_token_file(path: &Path, strict: bool) -> Result<Zeroizing<String>> {
+    let error = |reason: String| Error::InvalidConfig { name: "BOT_TOKEN_FILE", reason };
+    let metadata = std::fs::metadata(path).map_err(|e| error(format!("{} can't be read: {}", path.display(), e)))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            if strict {
+                return Err(error(format!("{} may be read by other users, allow only its owner (chmod 600)", path.display())));
+            }
+            log::warn!("Bot token file {} may be read by other users (STRICT_TOKEN_FILE refuses it)", path.display());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (metadata, strict);
+
+    let token = Zeroizing::new(std::fs::read_to_string(path).map_err(|e| error(format!("{} can't be read: {}", path.display(), e)))?);
+    Ok(Zeroizing::new(token.trim().to_string()))
+}
+
+/// Looks up the bot token stored under `service` in the system keyring.
+fn keyring_token(service: &str) -> Result<Zeroizing<String>> {
+    #[cfg(target_os = "macos")]
+    let (program, args) = ("security", ["find-generic-password", "-s", service, "-w"]);
+    #[cfg(not(target_os = "macos"))]
+    let (program, args) = ("secret-tool", ["lookup", "service", service]);
+
+    let error = |reason: String| Error::InvalidConfig { name: "BOT_TOKEN_KEYRING", reason };
+    let output = std::process::Command::new(program).args(args).output()
+        .map_err(|e| error(format!("{} can't be run: {}", program, e)))?;
+    let token = Zeroizing::new(output.stdout);
+    if !output.status.success() || token.is_empty() {
+        return Err(error(format!("there is no token stored under service {:?}", service)));
+    }
+
+    let token = std::str::from_utf8(&token).map_err(|_| error("the stored token is not text".to_string()))?;
+    Ok(Zeroizing::new(token.trim().to_string()))
+}
+
+/// Returns whether the text looks like a discord bot token: three base64url parts separated by dots.
+fn is_bot_token(token: &str) -> bool {
+    let parts: Vec<&str> = token.split('.').collect();
+    parts.len() == 3 && parts.iter().all(|part| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+/// Loads the encryption keys, selecting the configured current key.
+fn keyring() -> Result<Keyring> {
+    // The keys themselves are left out of the error.
+    let mut keyring = match option_env!("ENCRYPTION_KEYS") {
+        Some(keys) => keys.trim().parse().map_err(|_| Error::InvalidConfig {
+            name: "ENCRYPTION_KEYS",
+            reason: "the keys can't be parsed".to_string(),
+        })?,
+        None => Keyring::none(),
+    };
+
+    if let Some(id) = option_env!("ENCRYPTION_KEY_ID") {
+        if !keyring.set_current(parse("ENCRYPTION_KEY_ID", Some(id), 0)?) {
+            return Err(Error::InvalidConfig { name: "ENCRYPTION_KEY_ID", reason: format!("key {} is not in ENCRYPTION_KEYS", id) });
+        }
+    }
+
+    Ok(keyring)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert_eq!(parse("PAGE_SIZE", Some(" 4096 "), 0usize).unwrap(), 4096);
+        assert_eq!(parse("PAGE_SIZE", None, 42usize).unwrap(), 42);
+        assert!(matches!(
+            parse("PAGE_SIZE", Some("4KB"), 0usize),
+            Err(Error::InvalidConfig { name: "PAGE_SIZE", .. })
+        ));
+
+        let config = Config { cache_memory: Some(1.5), ..Config::default() };
+        assert!(matches!(config.validate(), Err(Error::InvalidConfig { name: "CACHE_MEMORY", .. })));
+
+        let config = Config { memory_pages: Some(5), ..Config::default() };
+        assert!(matches!(config.validate(), Err(Error::InvalidConfig { name: "MEMORY_PAGES", .. })));
+    }
+
+    #[test]
+    fn splits_memory_pages() {
+        assert_eq!(Config::default().memory_split(), None);
+        let split = |pages| Config { memory_pages: Some(pages), ..Config::default() }.memory_split().unwrap();
+        assert_eq!(split(6), MemorySplit { cached: 1, queued: 1, downloads: 1, uploads: 2 });
+        assert_eq!(split(16), MemorySplit { cached: 7, queued: 4, downloads: 2, uploads: 2 });
+        assert_eq!(split(64), MemorySplit { cached: 43, queued: QUEUE_PAGES, downloads: 8, uploads: 8 });
+    }
+
+    /// A token file with the permissions, in a directory that doesn't exist yet.
+    #[cfg(unix)]
+    fn token_file(token: &str, mode: u32) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("daafs-token-{}", crate::crypto::random_name("")));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token");
+        std::fs::write(&path, token).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reads_token_from_file() {
+        let token = "MTA4NzY1NDMyMTA5ODc2NTQzMg.GaBcDe.abcdefghijklmnopqrstuvwxyz-_0123456789";
+        let path = token_file(&format!("{}\n", token), 0o600);
+        let config = Config {
+            bot_token: Some("ignored.compiled.token".to_string()),
+            token_file: Some(path.to_string_lossy().to_string()),
+            ..Config::default()
+        };
+        assert_eq!(config.bot_token().unwrap().as_str(), token);
+
+        std::fs::write(&path, "not a token").unwrap();
+        assert!(matches!(config.bot_token(), Err(Error::InvalidConfig { name: "BOT_TOKEN_FILE", .. })));
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        let config = Config { bot_token: Some("abc.def.ghi".to_string()), ..Config::default() };
+        assert_eq!(config.bot_token().unwrap().as_str(), "abc.def.ghi");
+        assert!(matches!(Config::default().bot_token(), Err(Error::InvalidConfig { name: "BOT_TOKEN", .. })));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn strict_mode_rejects_readable_token_file() {
+        let path = token_file("abc.def.ghi", 0o644);
+        let config = Config { token_file: Some(path.to_string_lossy().to_string()), strict_token_file: true, ..Config::default() };
+        let error = config.bot_token().unwrap_err();
+        assert!(matches!(error, Error::InvalidConfig { name: "BOT_TOKEN_FILE", .. }));
+        assert!(!error.to_string().contains("abc.def.ghi"));
+
+        // Without strict mode it is only warned about.
+        let config = Config { strict_token_file: false, ..config };
+        assert_eq!(config.bot_token().unwrap().as_str(), "abc.def.ghi");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn sizes_cache_by_memory() {
+        const GB: u64 = 1024 * 1024 * 1024;
+        let page_size = DEFAULT_PAGE_SIZE;
+
+        // A quarter of 64GB in 8MB pages, and of 2GB.
+        assert_eq!(cache_pages(64 * GB, 0.25, page_size), 2048);
+        assert_eq!(cache_pages(2 * GB, 0.25, page_size), 64);
+        // Clamped to the fewest and most pages.
+        assert_eq!(cache_pages(GB / 8, 0.1, page_size), MIN_CACHE_PAGES);
+        assert_eq!(cache_pages(1024 * GB, 1.0, page_size), MAX_CACHE_PAGES);
+        assert_eq!(cache_pages(64 * GB, 0.25, 4096), MAX_CACHE_PAGES);
+    }
+}
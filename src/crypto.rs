@@ -0,0 +1,249 @@
+use std::{fmt, str::FromStr};
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, AeadInPlace, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+
+use crate::error::{Error, Result};
+
+/// Bytes added to every encrypted page (nonce and authentication tag).
+pub const OVERHEAD: usize = 12 + 16;
+
+/// Keys used to encrypt pages. Every page records the id of the key it was encrypted with,
+/// so old keys are kept around until all pages are migrated to the current one.
+/// Key id 0 means the page is not encrypted.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    keys: Vec<(u8, [u8; 32])>,
+    current: u8,
+}
+
+impl Keyring {
+    /// Keyring without any keys, pages are stored unencrypted.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key and makes it the current one.
+    pub fn add(&mut self, id: u8, key: [u8; 32]) {
+        assert!(id != 0, "Key id 0 is reserved for unencrypted pages");
+        self.keys.retain(|(i, _)| *i != id);
+        self.keys.push((id, key));
+        self.current = id;
+    }
+
+    /// Selects the key new pages are encrypted with. Returns false if there is no such key.
+    pub fn set_current(&mut self, id: u8) -> bool {
+        if id != 0 && !self.keys.iter().any(|(i, _)| *i == id) {
+            return false;
+        }
+        self.current = id;
+        true
+    }
+
+    /// Id of the key new pages are encrypted with.
+    pub fn current(&self) -> u8 {
+        self.current
+    }
+
+    fn cipher(&self, id: u8) -> Result<ChaCha20Poly1305> {
+        let (_, key) = self.keys.iter().find(|(i, _)| *i == id).ok_or(Error::UnknownKey { key_id: id })?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    /// Size of a stored page holding `len` bytes of data encrypted with given key.
+    pub fn stored_len(key_id: u8, len: usize) -> usize {
+        if key_id == 0 { len } else { len + OVERHEAD }
+    }
+
+    /// Encrypts data with the current key. Returns the key id and the data to store.
+    pub fn encrypt(&self, data: &[u8]) -> Result<(u8, Vec<u8>)> {
+        if self.current == 0 {
+            return Ok((0, data.to_vec()));
+        }
+
+        self.seal(data, &[])
+    }
+
+    /// Encrypts data with the current key, authenticating `associated` data stored next to it in the clear.
+    /// Fails without a current key. Returns the key id and the data to store.
+    pub fn seal(&self, data: &[u8], associated: &[u8]) -> Result<(u8, Vec<u8>)> {
+        let cipher = self.cipher(self.current)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = cipher.encrypt(&nonce, Payload { msg: data, aad: associated }).map_err(|_| Error::Encryption)?;
+
+        let mut stored = nonce.to_vec();
+        stored.extend(encrypted);
+        Ok((self.current, stored))
+    }
+
+    /// Like `encrypt`, but appends the data to store to `out`, encrypting it there
+    /// instead of in a copy of the page.
+    pub fn encrypt_into(&self, data: &[u8], out: &mut Vec<u8>) -> Result<u8> {
+        if self.current == 0 {
+            out.extend_from_slice(data);
+            return Ok(0);
+        }
+
+        let cipher = self.cipher(self.current)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        out.extend_from_slice(&nonce);
+        let start = out.len();
+        out.extend_from_slice(data);
+        let tag = cipher.encrypt_in_place_detached(&nonce, &[], &mut out[start..]).map_err(|_| Error::Encryption)?;
+        out.extend_from_slice(&tag);
+        Ok(self.current)
+    }
+
+    /// Like `decrypt`, but decrypts the data stored from `start` of the buffer where it is,
+    /// leaving just the decrypted data in the buffer.
+    pub fn decrypt_in_place(&self, key_id: u8, buffer: &mut Vec<u8>, start: usize) -> Result<()> {
+        if key_id == 0 {
+            buffer.drain(..start);
+            return Ok(());
+        }
+        if buffer.len() < start + OVERHEAD {
+            return Err(Error::Encryption);
+        }
+
+        let cipher = self.cipher(key_id)?;
+        let nonce = *Nonce::from_slice(&buffer[start..start + 12]);
+        let tag = *Tag::from_slice(&buffer[buffer.len() - 16..]);
+        let end = buffer.len() - 16;
+        cipher.decrypt_in_place_detached(&nonce, &[], &mut buffer[start + 12..end], &tag).map_err(|_| Error::Encryption)?;
+        buffer.truncate(end);
+        buffer.drain(..start + 12);
+        Ok(())
+    }
+
+    /// Decrypts stored data with the key it was encrypted with.
+    pub fn decrypt(&self, key_id: u8, stored: &[u8]) -> Result<Vec<u8>> {
+        if key_id == 0 {
+            return Ok(stored.to_vec());
+        }
+
+        self.open(key_id, stored, &[])
+    }
+
+    /// Decrypts data stored by `seal`, failing if it or the `associated` data was changed.
+    pub fn open(&self, key_id: u8, stored: &[u8], associated: &[u8]) -> Result<Vec<u8>> {
+        if stored.len() < OVERHEAD {
+            return Err(Error::Encryption);
+        }
+
+        let cipher = self.cipher(key_id)?;
+        let (nonce, encrypted) = stored.split_at(12);
+        cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: encrypted, aad: associated }).map_err(|_| Error::Encryption)
+    }
+}
+
+/// Random file name with the extension that doesn't tell anything about the stored data.
+pub fn random_name(extension: &str) -> String {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+
+    let name: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    match extension {
+        "" => name,
+        extension => format!("{}.{}", name, extension),
+    }
+}
+
+/// Keys are never printed.
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<u8> = self.keys.iter().map(|(id, _)| *id).collect();
+        f.debug_struct("Keyring").field("keys", &ids).field("current", &self.current).finish()
+    }
+}
+
+/// Parses `<id>:<64 hex digits>` entries separated by commas.
+/// The last listed key becomes the current one.
+impl FromStr for Keyring {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut keyring = Self::none();
+
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (id, hex) = entry.split_once(':').ok_or(())?;
+            let id: u8 = id.parse().map_err(|_| ())?;
+            if id == 0 || hex.len() != 64 {
+                return Err(());
+            }
+
+            let mut key = [0; 32];
+            for (i, byte) in key.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or(())?, 16).map_err(|_| ())?;
+            }
+
+            keyring.add(id, key);
+        }
+
+        Ok(keyring)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut keyring = Keyring::none();
+        keyring.add(1, [7; 32]);
+
+        let (key_id, stored) = keyring.encrypt(&[1, 2, 3]).unwrap();
+
+        assert_eq!(key_id, 1);
+        assert_eq!(stored.len(), Keyring::stored_len(1, 3));
+        assert_eq!(keyring.decrypt(key_id, &stored).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn in_place_round_trip() {
+        let mut keyring = Keyring::none();
+        keyring.add(1, [7; 32]);
+
+        // Stored the same way as by `encrypt`, after a header.
+        let mut buffer = b"head".to_vec();
+        assert_eq!(keyring.encrypt_into(&[1, 2, 3], &mut buffer).unwrap(), 1);
+        assert_eq!(keyring.decrypt(1, &buffer[4..]).unwrap(), vec![1, 2, 3]);
+
+        keyring.decrypt_in_place(1, &mut buffer, 4).unwrap();
+        assert_eq!(buffer, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_wrong_and_unknown_keys() {
+        let mut keyring = Keyring::none();
+        keyring.add(1, [7; 32]);
+        let (_, stored) = keyring.encrypt(&[1, 2, 3]).unwrap();
+
+        let mut other = Keyring::none();
+        other.add(1, [8; 32]);
+
+        assert!(matches!(other.decrypt(1, &stored), Err(Error::Encryption)));
+        assert!(matches!(keyring.decrypt(2, &stored), Err(Error::UnknownKey { key_id: 2 })));
+    }
+
+    #[test]
+    fn random_names_differ() {
+        let name = random_name("bin");
+
+        assert_eq!(name.len(), 36);
+        assert_ne!(name, random_name("bin"));
+        assert_eq!(random_name("").len(), 32);
+    }
+
+    #[test]
+    fn parse_keyring() {
+        let keyring: Keyring = format!("1:{}, 2:{}", "00".repeat(32), "ff".repeat(32)).parse().unwrap();
+
+        assert_eq!(keyring.current(), 2);
+        assert!(!format!("{:?}", keyring).contains("ff"));
+        assert!("0:00".parse::<Keyring>().is_err());
+        assert!("1:abc".parse::<Keyring>().is_err());
+    }
+}
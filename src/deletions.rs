@@ -0,0 +1,96 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serenity::model::prelude::ChannelId;
+
+use crate::{backend::Backend, utils::LockOrRecover};
+
+/// Old data messages waiting to be deleted (`DELETION_GRACE`).
+/// A read that started before a page was rewritten may still download the old message
+/// (by a cached url), so the message is only deleted once the grace period is over.
+#[derive(Clone, Debug, Default)]
+pub struct Deletions {
+    /// Channel and id of every message to delete, with when it may be deleted
+    pending: Arc<Mutex<Vec<(ChannelId, u64, Instant)>>>,
+    /// How long messages are kept, they are deleted right away if it is zero
+    grace: Duration,
+}
+
+impl Deletions {
+    pub fn new(grace: Duration) -> Self {
+        Self { grace, ..Self::default() }
+    }
+
+    /// Deletes the message once the grace period is over, or right away without one.
+    /// Messages that are already gone are ignored.
+    pub async fn delete(&self, backend: &dyn Backend, channel: ChannelId, message_id: u64) {
+        if self.grace.is_zero() {
+            backend.delete_message(channel, message_id).await.ok();
+            return;
+        }
+
+        self.pending.lock_or_recover().push((channel, message_id, Instant::now() + self.grace));
+    }
+
+    /// Deletes the messages whose grace period is over. Returns how many were deleted.
+    pub async fn run_due(&self, backend: &dyn Backend) -> usize {
+        let now = Instant::now();
+        let due: Vec<(ChannelId, u64, Instant)> = {
+            let mut pending = self.pending.lock_or_recover();
+            let (due, waiting) = pending.drain(..).partition(|(_, _, at)| *at <= now);
+            *pending = waiting;
+            due
+        };
+
+        for (channel, message_id, _) in due.iter() {
+            backend.delete_message(*channel, *message_id).await.ok();
+        }
+        due.len()
+    }
+
+    /// Deletes every pending message, once there can't be any reads left (the drive is closed).
+    pub async fn run_all(&self, backend: &dyn Backend) -> usize {
+        let all: Vec<(ChannelId, u64, Instant)> = self.pending.lock_or_recover().drain(..).collect();
+
+        for (channel, message_id, _) in all.iter() {
+            backend.delete_message(*channel, *message_id).await.ok();
+        }
+        all.len()
+    }
+
+    /// Number of messages waiting to be deleted.
+    pub fn pending(&self) -> usize {
+        self.pending.lock_or_recover().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    const CHANNEL: ChannelId = ChannelId(1);
+
+    #[test]
+    fn deletes_after_grace_period() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            let kept = backend.send_message(CHANNEL, "old").await.unwrap();
+            let deletions = Deletions::new(Duration::from_millis(100));
+            deletions.delete(&backend, CHANNEL, kept).await;
+            assert_eq!(deletions.run_due(&backend).await, 0);
+            assert!(backend.get_message(CHANNEL, kept).await.is_ok());
+
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert_eq!(deletions.run_due(&backend).await, 1);
+            assert!(backend.get_message(CHANNEL, kept).await.is_err());
+            assert_eq!(deletions.pending(), 0);
+
+            // Without a grace period messages go right away.
+            let deleted = backend.send_message(CHANNEL, "old").await.unwrap();
+            Deletions::default().delete(&backend, CHANNEL, deleted).await;
+            assert!(backend.get_message(CHANNEL, deleted).await.is_err());
+        });
+    }
+}
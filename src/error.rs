@@ -0,0 +1,159 @@
+use std::fmt;
+
+use crate::backend::BackendError;
+
+/// Errors that can happen while operating the drive.
+#[derive(Debug)]
+pub enum Error {
+    /// The sync queue didn't drain before the flush timeout elapsed.
+    FlushTimeout { pending: usize },
+    /// The sync thread has exited, so the queue will never drain.
+    SyncThreadDead { pending: usize },
+    /// Syncing is paused, so the queue can't drain until it is resumed.
+    SyncPaused { pending: usize },
+    /// The signed or encrypted superblock was changed (or truncated) since it was saved.
+    TamperedSuperblock { message_id: u64 },
+    /// Syncing a page panicked (`SYNC_PANIC`).
+    SyncPanicked { offset: u64, reason: String },
+    /// The sync queue gave up on pages, their changes are lost.
+    SyncFailed { offsets: Vec<u64>, reason: String },
+    /// Pages were written and synced, but the metadata doesn't point at an uploaded message for them.
+    UnsyncedPages { offsets: Vec<u64> },
+    /// Message holding the page data was deleted.
+    MissingPage { offset: u64, message_id: u64 },
+    /// Message holding the page data exists, but its attachment is gone (a fresh url of it isn't found either).
+    MissingAttachment { offset: u64, message_id: u64 },
+    /// Downloaded page doesn't have the expected size.
+    InvalidPageLength { offset: u64, expected: usize, actual: usize },
+    /// Page pushed to the cache doesn't have the configured page size.
+    InvalidCacheBlock { offset: u64, expected: usize, actual: usize },
+    /// Uploaded page read back differently than it was uploaded.
+    UploadMismatch { offset: u64 },
+    /// Downloaded page doesn't match its checksum.
+    ChecksumMismatch { offset: u64 },
+    /// Metadata message could not be parsed (it may have been edited by hand).
+    InvalidMetadata { message_id: u64, line: String },
+    /// JSON attachment of a metadata block is missing or could not be parsed.
+    InvalidMetadataAttachment { message_id: u64, reason: String },
+    /// Page was encrypted with a key that is not in the keyring.
+    UnknownKey { key_id: u8 },
+    /// Page could not be encrypted or decrypted (wrong key or corrupted data).
+    Encryption,
+    /// Stored data was written in a format version this build doesn't know.
+    UnsupportedVersion { format: &'static str, version: u32 },
+    /// Value set in `.env` could not be used.
+    InvalidConfig { name: &'static str, reason: String },
+    /// Configured page size is not a multiple of 4KB.
+    InvalidPageSize { page_size: usize },
+    /// Configured zero mask blocks are not a multiple of 4KB, or there would be too many of them.
+    InvalidZeroBlockSize { zero_block_size: usize, page_size: usize },
+    /// Uploaded pages would not fit in the upload limit.
+    PageTooLarge { page_size: usize, upload_size: usize, limit: usize },
+    /// Every metadata block is full and no more may be created.
+    DeviceFull { blocks: usize },
+    /// The drive has as many pages as it may store data messages.
+    StorageFull { messages: usize },
+    /// Snapshot name is empty, too long or has more than one line.
+    InvalidSnapshotName { name: String },
+    /// There is no snapshot with the name in the channel.
+    SnapshotNotFound { name: String },
+    /// Volume names are part of message headers.
+    InvalidVolume { volume: String },
+    /// Snapshots only point at data messages, which are deleted on rewrite without history.
+    SnapshotWithoutHistory,
+    /// The bot lacks permissions in the channel of the drive.
+    MissingPermissions { channel: u64, operation: &'static str },
+    /// A step of the self test failed (`SELF_TEST`).
+    SelfTestFailed { step: &'static str, error: BackendError },
+    /// File of the write-ahead log could not be read or written.
+    Wal { path: std::path::PathBuf, error: std::io::Error },
+    /// Page file of the local tier could not be read or written (`LOCAL_TIER_DIR`).
+    LocalTier { path: std::path::PathBuf, error: std::io::Error },
+    /// Entry of the mask journal could not be read or written (`MASK_JOURNAL_DIR`).
+    Journal { path: std::path::PathBuf, error: std::io::Error },
+    /// Request addresses bytes past the end of the drive.
+    OutOfBounds { offset: u64, len: usize, size: u64 },
+    /// There is no page at the offset (it was never written, or was dropped as zeroed).
+    NoPage { offset: u64 },
+    /// Discord operation failed.
+    Backend(BackendError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::FlushTimeout { pending } => write!(f, "Timed out flushing the sync queue ({} blocks pending)", pending),
+            Error::SyncThreadDead { pending } => write!(f, "Sync thread is not running ({} blocks pending)", pending),
+            Error::SyncPaused { pending } => write!(f, "Syncing is paused ({} blocks pending)", pending),
+            Error::TamperedSuperblock { message_id } => write!(f, "Superblock {} doesn't match its authentication tag, it was changed since it was saved", message_id),
+            Error::SyncPanicked { offset, reason } => write!(f, "Syncing page {} panicked: {}", offset, reason),
+            Error::SyncFailed { offsets, reason } => write!(f, "Gave up syncing pages {:?}, their changes are lost: {}", offsets, reason),
+            Error::UnsyncedPages { offsets } => write!(f, "Pages {:?} were synced, but the metadata doesn't record an uploaded message for them", offsets),
+            Error::InvalidCacheBlock { offset, expected, actual } => write!(f, "Page at offset {} has {} bytes instead of the page size {}, it can't be cached", offset, actual, expected),
+            Error::UploadMismatch { offset } => write!(f, "Page at offset {} read back differently than it was uploaded", offset),
+            Error::MissingPage { offset, message_id } => write!(f, "Message {} holding page {} doesn't exist", message_id, offset),
+            Error::MissingAttachment { offset, message_id } => write!(f, "Attachment of message {} holding page {} is gone", message_id, offset),
+            Error::InvalidPageLength { offset, expected, actual } => write!(f, "Page at offset {} has {} bytes instead of {}", offset, actual, expected),
+            Error::ChecksumMismatch { offset } => write!(f, "Page at offset {} doesn't match its checksum", offset),
+            Error::InvalidMetadata { message_id, line } => write!(f, "Metadata block {} has an invalid line: {:?}", message_id, line),
+            Error::InvalidMetadataAttachment { message_id, reason } => write!(f, "Metadata block {} has an invalid attachment: {}", message_id, reason),
+            Error::UnknownKey { key_id } => write!(f, "Key {} is not in the keyring", key_id),
+            Error::Encryption => write!(f, "Failed to encrypt or decrypt a page"),
+            Error::UnsupportedVersion { format, version } => write!(f, "Unsupported {} format version {}, it was written by a newer version of the drive", format, version),
+            Error::InvalidConfig { name, reason } => write!(f, "Invalid {} in .env: {}", name, reason),
+            Error::InvalidPageSize { page_size } => write!(f, "Page size {} is not a multiple of 4096 (PAGE_SIZE)", page_size),
+            Error::InvalidZeroBlockSize { zero_block_size, page_size } => write!(f, "Zero block size {} is not a multiple of 4096 or splits pages of {} bytes into more than 2048 blocks (ZERO_BLOCK_SIZE)", zero_block_size, page_size),
+            Error::PageTooLarge { page_size, upload_size, limit } => write!(f, "Pages of {} bytes are uploaded as {} byte files, which is more than the upload limit of {} bytes (PAGE_SIZE, UPLOAD_LIMIT)", page_size, upload_size, limit),
+            Error::DeviceFull { blocks } => write!(f, "No space left, all {} metadata blocks are full (MAX_METADATA_BLOCKS)", blocks),
+            Error::StorageFull { messages } => write!(f, "No space left, the drive already uses all {} data messages it may (MAX_DATA_MESSAGES)", messages),
+            Error::InvalidSnapshotName { name } => write!(f, "Invalid snapshot name {:?}, it must be a single line of 1 to 100 characters", name),
+            Error::SnapshotNotFound { name } => write!(f, "Snapshot {:?} doesn't exist", name),
+            Error::InvalidVolume { volume } => write!(f, "Invalid volume {:?}, it must be 1 to 32 letters, digits, '-' or '_'", volume),
+            Error::SnapshotWithoutHistory => write!(f, "Snapshots need old data messages to be kept (KEEP_HISTORY)"),
+            Error::Wal { path, error } => write!(f, "Write-ahead log file {} can't be used: {}", path.display(), error),
+            Error::LocalTier { path, error } => write!(f, "Local tier file {} can't be used: {}", path.display(), error),
+            Error::Journal { path, error } => write!(f, "Mask journal file {} can't be used: {}", path.display(), error),
+            Error::OutOfBounds { offset, len, size } => write!(f, "Request of {} bytes at offset {} is past the end of the drive ({} bytes)", len, offset, size),
+            Error::NoPage { offset } => write!(f, "There is no page at offset {}", offset),
+            Error::MissingPermissions { channel, operation } => write!(
+                f,
+                "The bot is not allowed to {} in channel {}, it needs the View Channel, Read Message History, Send Messages, Attach Files and Manage Messages permissions there",
+                operation, channel
+            ),
+            Error::SelfTestFailed { step, error } => {
+                let cause = match error {
+                    BackendError::Disconnected(_) => "the token was rejected or discord can't be reached",
+                    BackendError::Forbidden(_) => "the bot lacks permissions in the channel",
+                    BackendError::NotFound => "the channel or message doesn't exist",
+                    _ => "the request failed",
+                };
+                write!(f, "Self test failed to {}, {}: {}", step, cause, error)
+            }
+            Error::Backend(error) => write!(f, "Discord operation failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<BackendError> for Error {
+    fn from(error: BackendError) -> Self {
+        Error::Backend(error)
+    }
+}
+
+/// Requests past the end of the drive are invalid, a full drive has no space left,
+/// missing permissions (also found by the self test) are not permitted, everything else is reported as an I/O error.
+impl From<Error> for nbdkit::Error {
+    fn from(error: Error) -> Self {
+        let errno = match error {
+            Error::OutOfBounds { .. } => libc::EINVAL,
+            Error::DeviceFull { .. } | Error::StorageFull { .. } => libc::ENOSPC,
+            Error::MissingPermissions { .. } | Error::SelfTestFailed { error: BackendError::Forbidden(_), .. } => libc::EPERM,
+            _ => libc::EIO,
+        };
+        nbdkit::Error::new(errno, error.to_string())
+    }
+}
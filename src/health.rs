@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use crate::latency::LatencyReport;
+
+/// Overall state of syncing with discord.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthStatus {
+    /// Syncing keeps up with writes.
+    Healthy,
+    /// Queue is full, syncs stall or fail, but the sync thread is still running.
+    Degraded,
+    /// Sync thread is dead, nothing will be synced anymore.
+    Failed,
+}
+
+/// Snapshot of the sync queue health.
+#[derive(Clone, Debug)]
+pub struct Health {
+    pub status: HealthStatus,
+    /// Number of blocks waiting to be synced
+    pub queue_depth: usize,
+    pub queue_capacity: usize,
+    /// Time since the last successful sync (or since the queue went from empty to not empty)
+    pub since_last_sync: Duration,
+    /// Failed syncs since the last successful one
+    pub recent_errors: usize,
+    /// Writes that had to wait for room in the queue
+    pub blocked_pushes: usize,
+    /// Time writes spent waiting for room in the queue
+    pub blocked_for: Duration,
+}
+
+/// Snapshot of everything the drive is doing, for monitoring.
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// Health of the sync queue, with its depth
+    pub health: Health,
+    pub cached_pages: usize,
+    pub cache_capacity: usize,
+    /// Cached pages that were written since they were downloaded or uploaded
+    pub dirty_pages: usize,
+    /// Whether pages are being uploaded right now
+    pub syncing: bool,
+    /// Whether syncing is paused (`pause_sync`)
+    pub sync_paused: bool,
+    /// Time since a page was last synced (None if none was synced since the drive was opened)
+    pub since_last_sync: Option<Duration>,
+    /// Pages synced since the drive was opened
+    pub synced_pages: usize,
+    /// Failed syncs since the drive was opened
+    pub sync_errors: usize,
+    /// Bytes read from the drive since it was opened
+    pub bytes_read: u64,
+    /// Bytes written to the drive since it was opened
+    pub bytes_written: u64,
+    /// Damaged pages rewritten by reads (`READ_REPAIR`) since the drive was opened
+    pub repaired_pages: u64,
+    /// Pages whose attachment reads found gone and that couldn't be uploaded again (see `lost_pages`)
+    pub lost_pages: usize,
+    /// Pages waiting in the local tier (`LOCAL_TIER_DIR`) to be uploaded
+    pub tier_pages: usize,
+    /// Page buffers held by the cache, the sync queue and transfers, at most `MEMORY_PAGES`
+    pub resident_pages: usize,
+    /// Pages of the drive in the loaded metadata blocks and the ones listed by the superblock,
+    /// each of them stored in (at most) one data message
+    pub data_messages: usize,
+    /// Most pages the drive may store (`MAX_DATA_MESSAGES`)
+    pub max_data_messages: Option<usize>,
+    /// Share of the drive that is logically zero, from 0 to 1 (see `Usage::sparse_ratio`).
+    /// Pages of metadata blocks that are not loaded yet count as zero.
+    pub sparse_ratio: f64,
+    /// Percentiles of how long uploads, downloads, fetches and edits took since the drive was opened
+    pub latencies: LatencyReport,
+}
+
+impl Health {
+    /// Decides the status from sampled values.
+    /// The queue is considered stalled if blocks wait for longer than `stall_after`.
+    pub fn evaluate(
+        queue_depth: usize,
+        queue_capacity: usize,
+        since_last_sync: Duration,
+        recent_errors: usize,
+        thread_alive: bool,
+        stall_after: Duration,
+    ) -> Self {
+        let status = if !thread_alive {
+            HealthStatus::Failed
+        } else if queue_depth >= queue_capacity
+            || (queue_depth > 0 && since_last_sync >= stall_after)
+            || recent_errors > 0
+        {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Healthy
+        };
+
+        Self {
+            status,
+            queue_depth,
+            queue_capacity,
+            since_last_sync,
+            recent_errors,
+            blocked_pushes: 0,
+            blocked_for: Duration::ZERO,
+        }
+    }
+
+    /// Logs a warning describing the problem, if there is one.
+    pub fn log(&self) {
+        match self.status {
+            HealthStatus::Healthy => {}
+            HealthStatus::Degraded => log::warn!(
+                "Sync is falling behind: {}/{} blocks queued, last sync {:?} ago, {} recent errors, writes waited {:?} for the queue ({} times).",
+                self.queue_depth, self.queue_capacity, self.since_last_sync, self.recent_errors, self.blocked_for, self.blocked_pushes
+            ),
+            HealthStatus::Failed => log::warn!(
+                "Sync thread is dead, {} blocks will never be synced.",
+                self.queue_depth
+            ),
+        }
+    }
+}
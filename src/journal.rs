@@ -0,0 +1,177 @@
+use std::{fs, io::Write, path::{Path, PathBuf}};
+
+use crate::error::{Error, Result};
+use crate::metadata::Page;
+use crate::utils::BitMask;
+
+/// Magic starting every entry, followed by the format version.
+const MAGIC: &[u8; 8] = b"DAAFMASK";
+const VERSION: u8 = 1;
+/// Magic, version, page offset, message id, replaced message id, channel, zero mask, key id and the CRC32 of the rest.
+const ENTRY_LEN: usize = MAGIC.len() + 1 + 8 + 8 + 8 + 8 + 256 + 1 + 4;
+const EXTENSION: &str = "mask";
+
+/// Local journal of metadata changes of uploaded pages (`MASK_JOURNAL_DIR`).
+/// Once the data of a page is uploaded, the sync thread records where it went, its new zero mask and the message
+/// it replaces before the metadata block is updated. The replaced message is deleted and the entry removed once the block
+/// is stored, so a crash in between leaves the metadata pointing at the old message (which still exists) with the old mask.
+/// The next start finishes the update if the uploaded message exists, or drops the entry if it doesn't.
+/// Entries hold no page data, that is what the write-ahead log (`WAL_DIR`) is for.
+pub struct MaskJournal {
+    dir: PathBuf,
+}
+
+impl MaskJournal {
+    /// Opens (or creates) the journal in the directory.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|error| Error::Journal { path: dir.clone(), error })?;
+
+        Ok(Self { dir })
+    }
+
+    fn path(&self, offset: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", offset, EXTENSION))
+    }
+
+    /// Records the uploaded page and the message it replaces, replacing an older entry of the same offset.
+    /// The entry is on disk when this returns.
+    pub fn record(&self, page: &Page, replaced: Option<u64>) -> Result<()> {
+        let mut entry = Vec::with_capacity(ENTRY_LEN);
+        entry.extend_from_slice(MAGIC);
+        entry.push(VERSION);
+        entry.extend_from_slice(&page.offset.to_le_bytes());
+        entry.extend_from_slice(&page.message_id.unwrap_or(0).to_le_bytes());
+        entry.extend_from_slice(&replaced.unwrap_or(0).to_le_bytes());
+        entry.extend_from_slice(&page.channel.unwrap_or(0).to_le_bytes());
+        entry.extend_from_slice(page.zero_mask.as_bytes());
+        entry.push(page.key_id);
+        entry.extend_from_slice(&crc32fast::hash(&entry).to_le_bytes());
+
+        // Written next to the entry and renamed, so a crash never leaves half an entry.
+        let path = self.path(page.offset);
+        let temporary = path.with_extension("tmp");
+        fs::File::create(&temporary)
+            .and_then(|mut file| {
+                file.write_all(&entry)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temporary, &path))
+            .map_err(|error| Error::Journal { path, error })
+    }
+
+    /// Removes the entry of the page at given offset (as a multiple of the page size), its metadata is stored.
+    pub fn remove(&self, offset: u64) -> Result<()> {
+        let path = self.path(offset);
+        match fs::remove_file(&path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(Error::Journal { path, error }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the recorded pages with the messages they replace, by their offset. Damaged entries are skipped and removed.
+    pub fn pending(&self) -> Result<Vec<(Page, Option<u64>)>> {
+        let files = fs::read_dir(&self.dir).map_err(|error| Error::Journal { path: self.dir.clone(), error })?;
+
+        let mut pages = Vec::new();
+        for file in files {
+            let path = file.map_err(|error| Error::Journal { path: self.dir.clone(), error })?.path();
+            if path.extension().is_none_or(|extension| extension != EXTENSION) {
+                continue;
+            }
+
+            let entry = fs::read(&path).map_err(|error| Error::Journal { path: path.clone(), error })?;
+            match Self::parse(&entry) {
+                Ok(entry) => pages.push(entry),
+                Err(reason) => {
+                    log::warn!("Skipping mask journal entry {}: {}", path.display(), reason);
+                    fs::remove_file(&path).map_err(|error| Error::Journal { path: path.clone(), error })?;
+                }
+            }
+        }
+
+        pages.sort_unstable_by_key(|(page, _)| page.offset);
+        Ok(pages)
+    }
+
+    /// Returns the page and replaced message of an entry, or why it can't be used.
+    fn parse(entry: &[u8]) -> std::result::Result<(Page, Option<u64>), String> {
+        if entry.len() != ENTRY_LEN {
+            return Err("it has the wrong length".to_string());
+        }
+        let (magic, header) = entry.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err("it is not an entry".to_string());
+        }
+        if header[0] != VERSION {
+            return Err(Error::UnsupportedVersion { format: "mask journal", version: header[0] as u32 }.to_string());
+        }
+
+        let (rest, checksum) = entry.split_at(ENTRY_LEN - 4);
+        if u32::from_le_bytes(checksum.try_into().unwrap()) != crc32fast::hash(rest) {
+            return Err("its checksum doesn't match".to_string());
+        }
+
+        let offset = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let message_id = u64::from_le_bytes(header[9..17].try_into().unwrap());
+        let replaced = u64::from_le_bytes(header[17..25].try_into().unwrap());
+        let channel = u64::from_le_bytes(header[25..33].try_into().unwrap());
+        let zero_mask = BitMask::from_bytes(&header[33..289]);
+        let key_id = header[289];
+        let page = Page {
+            message_id: (message_id != 0).then_some(message_id),
+            zero_mask,
+            key_id,
+            channel: (channel != 0).then_some(channel),
+            ..Page::new(offset)
+        };
+        Ok((page, (replaced != 0).then_some(replaced)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn keeps_newest_entry_of_every_page() {
+        let dir = TempDir::new("journal");
+        let journal = MaskJournal::open(&dir).unwrap();
+        let mut page = Page { message_id: Some(5), key_id: 2, channel: Some(9), ..Page::new(3) };
+        page.zero_mask.set(1, true);
+        journal.record(&Page { message_id: Some(4), ..Page::new(3) }, Some(2)).unwrap();
+        journal.record(&page, Some(4)).unwrap();
+        journal.record(&Page { message_id: Some(6), ..Page::new(1) }, None).unwrap();
+
+        let journal = MaskJournal::open(&dir).unwrap();
+        let pending = journal.pending().unwrap();
+        assert_eq!(
+            pending.iter().map(|(page, replaced)| (page.offset, page.message_id, *replaced)).collect::<Vec<_>>(),
+            vec![(1, Some(6), None), (3, Some(5), Some(4))],
+        );
+        let (stored, _) = &pending[1];
+        assert_eq!((stored.key_id, stored.channel), (2, Some(9)));
+        assert_eq!(stored.zero_mask.as_bytes(), page.zero_mask.as_bytes());
+
+        journal.remove(3).unwrap();
+        journal.remove(3).unwrap();
+        assert_eq!(journal.pending().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn drops_damaged_entries() {
+        let dir = TempDir::new("journal");
+        let journal = MaskJournal::open(&dir).unwrap();
+        journal.record(&Page { message_id: Some(1), ..Page::new(0) }, None).unwrap();
+        journal.record(&Page { message_id: Some(2), ..Page::new(1) }, None).unwrap();
+
+        let path = journal.path(1);
+        let mut entry = fs::read(&path).unwrap();
+        entry[MAGIC.len() + 10] ^= 1;
+        fs::write(&path, entry).unwrap();
+
+        assert_eq!(journal.pending().unwrap().iter().map(|(page, _)| page.offset).collect::<Vec<_>>(), vec![0]);
+        assert!(!path.exists());
+    }
+}
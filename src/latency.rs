@@ -0,0 +1,145 @@
+use std::{sync::Mutex, time::Duration};
+
+use crate::utils::LockOrRecover;
+
+/// Buckets per doubling of the latency, so percentiles are off by at most a fifth.
+const BUCKETS_PER_DOUBLING: usize = 4;
+/// Buckets from 1µs up to about 70 minutes, longer samples go into the last one.
+const BUCKETS: usize = 32 * BUCKETS_PER_DOUBLING + 1;
+
+/// Distribution of how long an operation took, in buckets growing exponentially.
+/// Recording a sample is a counter increment, nothing is allocated after creation.
+#[derive(Debug)]
+pub struct Histogram {
+    /// Samples per bucket, bucket `i` holds samples of up to `2^(i/4)` microseconds
+    buckets: Mutex<[u64; BUCKETS]>,
+    /// Longest recorded sample
+    max: Mutex<Duration>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: Mutex::new([0; BUCKETS]),
+            max: Mutex::new(Duration::ZERO),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().max(1) as f64;
+        let bucket = ((micros.log2() * BUCKETS_PER_DOUBLING as f64).ceil() as usize).min(BUCKETS - 1);
+
+        self.buckets.lock_or_recover()[bucket] += 1;
+        let mut max = self.max.lock_or_recover();
+        *max = (*max).max(latency);
+    }
+
+    /// Number of recorded samples.
+    pub fn count(&self) -> u64 {
+        self.buckets.lock_or_recover().iter().sum()
+    }
+
+    /// Latency that `quantile` (like 0.95) of the samples didn't exceed, zero without samples.
+    /// It is the upper bound of the bucket, but never more than the longest sample (or it is in the last bucket).
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let buckets = self.buckets.lock_or_recover();
+        let count: u64 = buckets.iter().sum();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((count as f64 * quantile).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (bucket, samples) in buckets.iter().enumerate() {
+            seen += samples;
+            if seen >= rank && bucket < BUCKETS - 1 {
+                let bound = 2f64.powf(bucket as f64 / BUCKETS_PER_DOUBLING as f64);
+                return Duration::from_micros(bound as u64).min(*self.max.lock_or_recover());
+            }
+        }
+        *self.max.lock_or_recover()
+    }
+
+    pub fn summary(&self) -> Percentiles {
+        Percentiles {
+            count: self.count(),
+            p50: self.percentile(0.5),
+            p95: self.percentile(0.95),
+            p99: self.percentile(0.99),
+        }
+    }
+}
+
+/// Percentiles of a histogram, as reported by `status`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// How long discord operations took since the drive was opened, recorded by `TimedBackend`.
+#[derive(Debug, Default)]
+pub struct Latencies {
+    /// Sent files (page data and metadata attachments)
+    pub upload: Histogram,
+    /// Downloaded attachments, whole or ranges of them
+    pub download: Histogram,
+    /// Fetched messages, history and pins
+    pub fetch: Histogram,
+    /// Edited and sent text messages (metadata blocks and the superblock)
+    pub edit: Histogram,
+}
+
+impl Latencies {
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport {
+            upload: self.upload.summary(),
+            download: self.download.summary(),
+            fetch: self.fetch.summary(),
+            edit: self.edit.summary(),
+        }
+    }
+}
+
+/// Percentiles of every kind of operation in `Latencies`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyReport {
+    pub upload: Percentiles,
+    pub download: Percentiles,
+    pub fetch: Percentiles,
+    pub edit: Percentiles,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn computes_percentiles() {
+        let histogram = Histogram::default();
+        assert_eq!(histogram.summary(), Percentiles::default());
+
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 100);
+        // Buckets are a fifth wide, a percentile is at most that much above the sample.
+        for (percentile, expected) in [(summary.p50, 50), (summary.p95, 95), (summary.p99, 99)] {
+            let expected = Duration::from_millis(expected);
+            assert!(percentile >= expected && percentile <= expected.mul_f64(1.2), "{:?} for {:?}", percentile, expected);
+        }
+        assert!(summary.p50 <= summary.p95 && summary.p95 <= summary.p99);
+        assert_eq!(histogram.percentile(1.0), Duration::from_millis(100));
+
+        // Samples longer than the last bucket are clamped, not lost.
+        histogram.record(Duration::from_secs(100_000));
+        assert_eq!(histogram.count(), 101);
+        assert_eq!(histogram.percentile(1.0), Duration::from_secs(100_000));
+    }
+}
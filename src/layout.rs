@@ -0,0 +1,333 @@
+use std::fmt;
+
+use serenity::model::prelude::ChannelId;
+
+use crate::metadata::{MetadataBlock, PageOptions, zero_blocks};
+
+/// One page of the drive as stored on discord.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageLayout {
+    /// Offset of the page (as a multiple of the page size)
+    pub offset: u64,
+    /// Message the page data is stored in (None = never uploaded)
+    pub message_id: Option<u64>,
+    /// Metadata block describing this page (None if the block wasn't sent yet)
+    pub metablock_id: Option<u64>,
+    /// Number of blocks that are zeroed out
+    pub zeroed_blocks: usize,
+    /// Bytes of the page that are not zeroed out
+    pub used_bytes: u64,
+}
+
+/// Map of all loaded pages, used to diagnose the drive.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub channel: ChannelId,
+    pub page_size: usize,
+    /// Bytes tracked by one bit of the zero masks
+    pub zero_block_size: usize,
+    /// Pages sorted by offset
+    pub pages: Vec<PageLayout>,
+}
+
+impl Layout {
+    pub fn of(channel: ChannelId, options: &PageOptions, blocks: &[MetadataBlock]) -> Self {
+        let PageOptions { size: page_size, zero_block_size, .. } = *options;
+        let mut pages: Vec<PageLayout> = blocks.iter()
+            .flat_map(|block| block.pages.iter().map(|page| PageLayout {
+                offset: page.offset,
+                message_id: page.message_id,
+                metablock_id: block.message_id,
+                zeroed_blocks: page.zero_mask.count_ones(),
+                used_bytes: page.used_bytes(page_size, zero_block_size) as u64,
+            }))
+            .collect();
+
+        pages.sort_by_key(|page| page.offset);
+
+        Self { channel, page_size, zero_block_size, pages }
+    }
+
+    /// Bytes of data that are stored on discord (zeroed blocks are not counted).
+    pub fn used_bytes(&self) -> u64 {
+        self.pages.iter()
+            .filter(|page| page.message_id.is_some())
+            .map(|page| page.used_bytes)
+            .sum()
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let id = |id: Option<u64>| id.map_or("-".to_string(), |id| id.to_string());
+        writeln!(f, "Layout of channel {}:", self.channel.0)?;
+        for page in &self.pages {
+            writeln!(
+                f,
+                "  page {:>6} (offset {:>12}): message {:>20}, metablock {:>20}, {:>4}/{} blocks zeroed",
+                page.offset, page.offset * self.page_size as u64, id(page.message_id), id(page.metablock_id), page.zeroed_blocks, zero_blocks(self.page_size, self.zero_block_size)
+            )?;
+        }
+        write!(f, "{} pages, {} bytes used", self.pages.len(), self.used_bytes())
+    }
+}
+
+/// Where the byte at a given offset of the drive is stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageLocation {
+    pub channel: ChannelId,
+    /// Message the page data is stored in (None = not uploaded yet)
+    pub message_id: Option<u64>,
+    /// Attachment of the message holding the page data
+    pub attachment: usize,
+    /// Whether the zero block holding the byte is zeroed out
+    pub zeroed: bool,
+}
+
+/// How much of the drive holds data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes in pages that exist, without zeroed blocks
+    pub used: u64,
+    pub free: u64,
+    /// Size of the drive
+    pub total: u64,
+}
+
+impl Usage {
+    /// Counts every block of an existing page that is not zeroed as used,
+    /// whether or not it was already uploaded.
+    pub fn of(blocks: &[MetadataBlock], options: &PageOptions, total: u64) -> Self {
+        let used: u64 = blocks.iter()
+            .flat_map(|block| block.pages.iter())
+            .map(|page| page.used_bytes(options.size, options.zero_block_size) as u64)
+            .sum();
+        let used = used.min(total);
+
+        Self {
+            used,
+            free: total - used,
+            total,
+        }
+    }
+
+    /// Share of the drive that is logically zero (blocks masked as zeroed and pages that don't exist), from 0 to 1.
+    /// Close to 1 when little of the drive holds data, an empty drive is entirely sparse.
+    pub fn sparse_ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+
+        self.free as f64 / self.total as f64
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes used ({:.1}%), {} bytes free ({:.1}% sparse)",
+            self.used, self.total, self.used as f64 * 100.0 / self.total.max(1) as f64, self.free, self.sparse_ratio() * 100.0
+        )
+    }
+}
+
+/// Why a page could not be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PageDamage {
+    /// Message holding the page data was deleted.
+    Missing,
+    /// Message holding the page data exists, but its attachment is gone.
+    MissingAttachment,
+    /// Page data was downloaded, but it is broken (wrong length, checksum or key).
+    Corrupted(String),
+    /// Page could not be downloaded for another reason.
+    Unreadable(String),
+}
+
+/// Page that could not be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DamagedPage {
+    /// Offset of the page (as a multiple of the page size)
+    pub offset: u64,
+    pub message_id: u64,
+    pub damage: PageDamage,
+}
+
+/// Result of reading every page of the drive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verification {
+    /// Number of pages that were read without problems
+    pub healthy: usize,
+    /// Pages sorted by offset
+    pub damaged: Vec<DamagedPage>,
+}
+
+impl Verification {
+    pub fn is_healthy(&self) -> bool {
+        self.damaged.is_empty()
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for page in &self.damaged {
+            let damage = match &page.damage {
+                PageDamage::Missing => "missing".to_string(),
+                PageDamage::MissingAttachment => "missing its attachment".to_string(),
+                PageDamage::Corrupted(reason) => format!("corrupted ({})", reason),
+                PageDamage::Unreadable(reason) => format!("unreadable ({})", reason),
+            };
+            writeln!(f, "  page {:>6}: message {:>20} is {}", page.offset, page.message_id, damage)?;
+        }
+        write!(f, "{} pages verified, {} healthy, {} damaged", self.healthy + self.damaged.len(), self.healthy, self.damaged.len())
+    }
+}
+
+/// Problem found by `check_integrity` in the stored metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IntegrityProblem {
+    /// Metadata block listed in the superblock was deleted.
+    MissingBlock { message_id: u64 },
+    /// Metadata block listed in the superblock doesn't parse.
+    UnreadableBlock { message_id: u64, reason: String },
+    /// Page is listed by more than one metadata block.
+    DuplicatePage { offset: u64 },
+    /// Page data is stored in the message of a metadata block or of the superblock.
+    ReservedMessage { offset: u64, message_id: u64 },
+    /// Page data is stored in the same message as the data of another page.
+    SharedMessage { offset: u64, other: u64, message_id: u64 },
+    /// Message holding the page data was deleted.
+    MissingMessage { offset: u64, message_id: u64 },
+    /// A read found the attachment of the page message gone, and the page couldn't be uploaded again.
+    LostAttachment { offset: u64, message_id: u64 },
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityProblem::MissingBlock { message_id } => write!(f, "metadata block {} doesn't exist", message_id),
+            IntegrityProblem::UnreadableBlock { message_id, reason } => write!(f, "metadata block {} can't be parsed ({})", message_id, reason),
+            IntegrityProblem::DuplicatePage { offset } => write!(f, "page {} is listed by more than one metadata block", offset),
+            IntegrityProblem::ReservedMessage { offset, message_id } => write!(f, "page {} points at message {}, which holds metadata", offset, message_id),
+            IntegrityProblem::SharedMessage { offset, other, message_id } => write!(f, "page {} points at message {} of page {}", offset, message_id, other),
+            IntegrityProblem::MissingMessage { offset, message_id } => write!(f, "page {} points at message {}, which doesn't exist", offset, message_id),
+            IntegrityProblem::LostAttachment { offset, message_id } => write!(f, "page {} points at message {}, whose attachment is gone", offset, message_id),
+        }
+    }
+}
+
+/// Result of checking the stored metadata (`STARTUP_SCAN`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Integrity {
+    /// Number of metadata blocks that were checked
+    pub blocks: usize,
+    /// Number of pages listed by them
+    pub pages: usize,
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl Integrity {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        write!(f, "{} metadata blocks with {} pages checked, {} problems", self.blocks, self.pages, self.problems.len())
+    }
+}
+
+/// Result of comparing the metadata with the messages in the channel (`reconcile`).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Number of pages whose message was looked up
+    pub pages: usize,
+    /// Offsets of the pages dropped because their message is gone
+    pub dropped: Vec<u64>,
+    /// Offsets of pages dropped earlier whose message is back
+    pub adopted: Vec<u64>,
+    /// Number of metadata block messages that were deleted and sent again
+    pub resent_blocks: usize,
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for offset in &self.dropped {
+            writeln!(f, "  page {:>6}: dropped, its message is gone", offset)?;
+        }
+        for offset in &self.adopted {
+            writeln!(f, "  page {:>6}: adopted again, its message is back", offset)?;
+        }
+        write!(f, "{} pages checked, {} dropped, {} adopted, {} metadata blocks sent again",
+            self.pages, self.dropped.len(), self.adopted.len(), self.resent_blocks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metadata::Page;
+
+    #[test]
+    fn lists_pages_of_all_blocks() {
+        let mut first = MetadataBlock::empty(Some(10));
+        let mut page = Page::new(3);
+        page.message_id = Some(30);
+        first.pages.push(page);
+
+        let mut second = MetadataBlock::empty(Some(20));
+        let mut page = Page::new(1);
+        page.message_id = Some(11);
+        for i in 0..1024 {
+            page.zero_mask.set(i, true);
+        }
+        second.pages.push(page);
+        second.pages.push(Page::new(2));
+
+        let layout = Layout::of(ChannelId(1), &PageOptions::default(), &[first, second]);
+
+        assert_eq!(layout.pages, vec![
+            PageLayout { offset: 1, message_id: Some(11), metablock_id: Some(20), zeroed_blocks: 1024, used_bytes: 1024 * 4096 },
+            PageLayout { offset: 2, message_id: None, metablock_id: Some(20), zeroed_blocks: 0, used_bytes: 2048 * 4096 },
+            PageLayout { offset: 3, message_id: Some(30), metablock_id: Some(10), zeroed_blocks: 0, used_bytes: 2048 * 4096 },
+        ]);
+        assert_eq!(layout.used_bytes(), (1024 + 2048) * 4096);
+
+        let text = layout.to_string();
+        assert!(text.contains("message                   11"));
+        assert!(text.ends_with("3 pages, 12582912 bytes used"));
+    }
+
+    #[test]
+    fn counts_zeroed_blocks_as_free() {
+        let mut block = MetadataBlock::empty(Some(1));
+
+        // Fully used, a quarter zeroed and fully zeroed.
+        block.pages.push(Page::new(0));
+        let mut page = Page::new(1);
+        for i in 0..512 {
+            page.zero_mask.set(i, true);
+        }
+        block.pages.push(page);
+        let mut page = Page::new(2);
+        for i in 0..2048 {
+            page.zero_mask.set(i, true);
+        }
+        block.pages.push(page);
+
+        let total = 1024 * 1024 * 64;
+        let usage = Usage::of(&[block], &PageOptions::default(), total);
+
+        let used = 1024 * 1024 * 8 + 1024 * 1024 * 6;
+        assert_eq!(usage, Usage { used, free: total - used, total });
+        // 14MB of 64MB hold data.
+        assert_eq!(usage.sparse_ratio(), 50.0 / 64.0);
+        assert!(usage.to_string().ends_with("(78.1% sparse)"));
+        assert_eq!(Usage::of(&[], &PageOptions::default(), 0).sparse_ratio(), 1.0);
+    }
+}
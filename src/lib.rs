@@ -1,239 +1,4129 @@
-use std::sync::{Mutex, Arc};
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::{Condvar, Mutex, MutexGuard, PoisonError, Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}, time::Instant};
 
-use cache::Cache;
-use metadata::{MetadataBlock, Page};
+use backend::{Backend, BackendError, DiscordBackend, DryRunBackend, RateLimitedBackend, ReconnectingBackend, RotatingBackend, TimedBackend, TimeoutBackend, TracingBackend};
+use cache::{Cache, Combiner};
+use config::{CacheMode, Config, ReadRepair, StartupScan};
+use error::{Error, Result};
+use health::{Health, Status};
+use latency::Latencies;
+use layout::{DamagedPage, Integrity, IntegrityProblem, Layout, PageDamage, PageLocation, Reconciliation, Usage, Verification};
+use metadata::{EditFallback, Metadata, MetadataBlock, Page, PageOptions, PageRead, PAGES_PER_BLOCK, zero_blocks, zero_mask_of};
+use snapshot::{Snapshot, BACKUP_NAME};
+use journal::MaskJournal;
+use tier::LocalTier;
+use wal::Wal;
 use nbdkit::Server;
-use queue::Queue;
-use serenity::Client;
-use serenity::http::Http;
-use serenity::{model::prelude::ChannelId, prelude::GatewayIntents};
+use queue::{Queue, QUEUE_PAGES};
+use superblock::{Superblock, SuperblockEntry};
+use serenity::model::prelude::ChannelId;
+use zeroize::Zeroizing;
 
 use crate::cache::CacheBlock;
+use crate::pool::MemorySlot;
+use crate::utils::{BitMask, LockOrRecover, join_all};
 
 pub mod utils;
 pub mod metadata;
 pub mod cache;
 pub mod queue;
+pub mod error;
+pub mod config;
+pub mod backend;
+pub mod superblock;
+pub mod health;
+pub mod layout;
+pub mod crypto;
+pub mod urls;
+pub mod snapshot;
+pub mod wal;
+pub mod pool;
+pub mod deletions;
+pub mod latency;
+pub mod tier;
+pub mod journal;
 
 /// Basic struct representing this plugin.
-struct DiscordDrivePlugin {
-    client: Client,
+pub struct DiscordDrivePlugin {
+    backend: Arc<dyn Backend>,
     rt: tokio::runtime::Runtime,
-    meta: Arc<Mutex<Vec<MetadataBlock>>>,
+    meta: Arc<Mutex<Metadata>>,
+    superblock: Mutex<Superblock>,
+    /// Metadata blocks that are not loaded yet.
+    unloaded: Mutex<Vec<SuperblockEntry>>,
+    /// Channel of the data pages.
     channel: ChannelId,
+    /// Channel of the metadata blocks, the superblock and snapshots (`METADATA_CHANNEL_ID`, `channel` by default).
+    meta_channel: ChannelId,
+    config: Config,
+    pages: Arc<PageOptions>,
+    /// Held by every read, write and flush (except while downloading, see `loading`).
+    /// Together with the queue waiting for pages that are being uploaded, every read sees
+    /// the latest write, whether its page is in the cache, in the queue or already on discord.
+    io: Mutex<()>,
+    /// Pages that are being downloaded without holding `io`, so requests for
+    /// other pages don't wait for them. Requests for these pages wait instead,
+    /// and find the page in the cache once it is downloaded.
+    loading: Mutex<HashSet<u64>>,
+    /// Notified whenever a page is removed from `loading`.
+    loaded: Condvar,
+    /// Requests waiting for `loading` to empty (`lock_idle`). New downloads don't start meanwhile,
+    /// so steady reads can't keep a flush waiting forever.
+    idle_waiters: AtomicUsize,
+    /// Sequential writes to a cached page that are not applied to it yet.
+    /// Taken before the page leaves the cache and before reads of the buffered blocks.
+    combined: Mutex<Combiner>,
+    /// Pages queued for upload since the last flush, checked to be in the metadata once they are synced.
+    queued: Mutex<HashSet<u64>>,
+    /// Local log of the queued pages (`WAL_DIR`), cleared once they are synced.
+    wal: Option<Wal>,
+    /// Local journal of the metadata of uploaded pages (`MASK_JOURNAL_DIR`), shared with the sync thread.
+    journal: Option<Arc<MaskJournal>>,
+    /// When metadata blocks were last moved to the bottom of the channel.
+    moved_at: Mutex<Option<Instant>>,
+    /// When the metadata was last backed up (or the drive was opened),
+    /// and the messages of the backup if it was taken since the drive was opened.
+    backed_up: Mutex<(Instant, Vec<u64>)>,
+    /// Bytes read from and written to the drive since it was opened.
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Damaged pages rewritten by reads (`READ_REPAIR`) since the drive was opened.
+    repaired_pages: AtomicU64,
+    /// Pages dropped by `reconcile` because their message was gone, adopted again if it shows up.
+    vanished: Mutex<HashMap<u64, Page>>,
+    /// Pages whose attachment is gone and that couldn't be uploaded again, with their message (see `lost_pages`).
+    lost: Mutex<BTreeMap<u64, u64>>,
+    /// How long discord operations took since the drive was opened.
+    latencies: Arc<Latencies>,
 
     cache: Cache<4>,
-    queue: Queue<4>,
+    queue: Queue<QUEUE_PAGES>,
+}
+
+/// Where `lookup` found the data of a 4KB block.
+enum Lookup {
+    /// The page was cached or queued (and is cached now)
+    Cached(Vec<u8>),
+    /// The page is only on discord and has to be downloaded
+    Stored(Box<Page>),
+    /// The page was never written
+    Missing,
 }
 
 impl DiscordDrivePlugin {
-    pub fn http(&self) -> &Http {
-        &self.client.cache_and_http.http
+    pub fn backend(&self) -> &dyn Backend {
+        self.backend.as_ref()
     }
 
-    pub fn cache(&self, block: CacheBlock) {
-        if let Some(block) = self.cache.push(block) {
-            self.queue.push(Page {
-                offset: block.offset,
-                message_id: block.message_id,
-                zero_mask: block.mask,
-            }, block.data);
+    /// Creates the plugin, loading metadata of the drive stored in `channel`
+    /// (or in the configured thread of it).
+    pub fn new(backend: Arc<dyn Backend>, channel: ChannelId, mut config: Config) -> Result<Self> {
+        config.validate()?;
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let latencies = Arc::new(Latencies::default());
+        let backend: Arc<dyn Backend> = Arc::new(TimedBackend::new(backend, latencies.clone()));
+
+        // Threads and forum posts are channels of their own.
+        let channel = match config.thread_id {
+            Some(thread) => {
+                let thread = ChannelId(thread);
+                rt.block_on(backend.unarchive_thread(thread))?;
+                thread
+            }
+            None => channel,
+        };
+        let meta_channel = config.metadata_channel.map_or(channel, ChannelId);
+
+        // Fail right away instead of on the first write.
+        if config.self_test {
+            rt.block_on(backend::self_test(backend.as_ref(), channel))?;
+        }
+        if config.check_permissions {
+            let channels = if meta_channel == channel { vec![channel] } else { vec![channel, meta_channel] };
+            for channel in channels {
+                if let Some(operation) = rt.block_on(backend::check_permissions(backend.as_ref(), channel))? {
+                    return Err(Error::MissingPermissions { channel: channel.0, operation });
+                }
+            }
         }
-    }
 
-    /// Tries to read from cache ensuring that the data is NOT in the queue.
-    pub fn read_cache(&self, offset: u64) -> Option<Vec<u8>> {
-        // Check if the data is in the queue.
-        if let Some((page, data)) = self.queue.release_offset(offset / (1024*1024*8)) {
-            // Cache the data.
-            self.cache(CacheBlock::new(offset / (1024*1024*8), page.message_id, data, page.zero_mask));
+        let loaded = rt.block_on(async {
+            superblock::load_metadata(backend.as_ref(), meta_channel, &config.scan(), config.eager_metadata, &config.volume, &config.sealing()).await
+        })?;
+
+        let mut blocks = loaded.blocks;
+        for block in blocks.iter_mut() {
+            block.format = config.metadata_format;
+            block.compress_above = config.metadata_compress_above;
+            block.edit_fallback = config.metadata_edit_fallback;
+        }
+
+        // The size the drive was created with, or for older drives the end of the last stored page.
+        let mut superblock = loaded.superblock;
+        match superblock.device_size {
+            Some(size) if size != config.device_size => {
+                log::warn!(
+                    "DEVICE_SIZE is {} bytes, but the drive was created with {} bytes! {}",
+                    config.device_size, size,
+                    if config.adopt_device_size { "Using the stored size." } else { "Set ADOPT_DEVICE_SIZE to use the stored size." },
+                );
+                if config.adopt_device_size {
+                    config.device_size = size;
+                }
+            }
+            Some(_) => {}
+            None => {
+                let end = blocks.iter()
+                    .flat_map(|block| block.pages.iter().map(|page| page.offset))
+                    .chain(loaded.unloaded.iter().flat_map(|entry| entry.offsets.iter().flatten().copied()))
+                    .max()
+                    .map(|offset| (offset + 1) * config.page_size as u64);
+                if let Some(end) = end.filter(|end| *end > config.device_size) {
+                    log::warn!("DEVICE_SIZE is {} bytes, but the drive stores pages up to byte {}!", config.device_size, end);
+                }
+            }
+        }
+        if superblock.set_device_size(config.device_size) {
+            rt.block_on(superblock.save(backend.as_ref(), meta_channel))?;
+        }
+
+        let meta = Arc::new(Mutex::new(Metadata::new(blocks)));
+
+        let mut queue = Queue::new()
+            .with_max_uploads(config.max_uploads)
+            .with_metadata_updates(config.max_metadata_updates)
+            .with_panic_policy(config.sync_panic)
+            .with_metadata_channel(meta_channel);
+        if let Some(depth) = config.queue_high_water {
+            queue = queue.with_high_water(depth);
+        }
+        if let Some(attempts) = config.sync_attempts {
+            queue = queue.with_sync_attempts(attempts);
+        }
+        let memory = config.memory_split();
+        if let Some(split) = memory {
+            queue = queue.with_limit(split.queued);
+        }
+        let pages = Arc::new(config.page_options());
+        if let Some(channel) = superblock.data_channel {
+            pages.new_pages.store(channel, Ordering::Relaxed);
+        }
+        let mut cache = Cache::with_zero_block_size(pages.zero_block_size).with_page_size(pages.size);
+        let capacity = match (config.cache_capacity(), memory) {
+            (Some(capacity), Some(split)) => Some(capacity.min(split.cached)),
+            (capacity, split) => capacity.or(split.map(|split| split.cached)),
+        };
+        if let Some(capacity) = capacity {
+            cache = cache.with_capacity(capacity);
+        }
+        if let Some(dir) = &config.local_tier_dir {
+            let tier = LocalTier::open(dir, config.keyring.clone(), config.local_tier_pages)?;
+            // Left behind by a previous run, see `replay_tier`.
+            if !tier.is_empty() {
+                queue.pause();
+            }
+            queue = queue.with_tier(tier);
+        }
+        let journal = match &config.mask_journal_dir {
+            Some(dir) => Some(Arc::new(MaskJournal::open(dir)?)),
+            None => None,
+        };
+        if let Some(journal) = &journal {
+            queue = queue.with_journal(journal.clone());
+        }
+        let queue = queue.start_sync_thread(backend.clone(), pages.clone(), channel, meta.clone());
+        queue.start_health_monitor(config.health_interval, config.stall_timeout);
+
+        let wal = match &config.wal_dir {
+            Some(dir) => Some(Wal::open(dir, config.keyring.clone())?.with_persistence(Arc::new(config.wal_format))),
+            None => None,
+        };
+
+        let plugin = Self {
+            rt,
+            meta,
+            superblock: Mutex::new(superblock),
+            unloaded: Mutex::new(loaded.unloaded),
+            backend,
+            channel,
+            meta_channel,
+            config,
+            pages,
+            io: Mutex::new(()),
+            loading: Mutex::new(HashSet::new()),
+            loaded: Condvar::new(),
+            idle_waiters: AtomicUsize::new(0),
+            combined: Mutex::new(Combiner::default()),
+            queued: Mutex::new(HashSet::new()),
+            wal,
+            journal,
+            moved_at: Mutex::new(None),
+            backed_up: Mutex::new((Instant::now(), Vec::new())),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            repaired_pages: AtomicU64::new(0),
+            vanished: Mutex::new(HashMap::new()),
+            lost: Mutex::new(BTreeMap::new()),
+            latencies,
+
+            cache,
+            queue,
+        };
+
+        plugin.prefetch_urls(&plugin.meta.lock_or_recover());
+        if plugin.config.restore_backup {
+            plugin.restore_backup()?;
+        } else if plugin.config.backup_interval.is_some() {
+            plugin.offer_backup()?;
+        }
+        // Counting the pages of every channel takes all of the metadata.
+        if plugin.config.channel_message_limit.is_some() {
+            plugin.load_metadata_where(|_| true)?;
+            plugin.roll_over()?;
+        }
+        plugin.replay_journal()?;
+        plugin.replay_tier()?;
+        plugin.replay_wal()?;
+
+        if plugin.config.startup_scan != StartupScan::Off {
+            let integrity = plugin.check_integrity(plugin.config.startup_scan == StartupScan::Messages)?;
+            match integrity.is_clean() {
+                true => log::info!("{}", integrity),
+                false => log::warn!("{}", integrity),
+            }
+        }
+
+        if plugin.config.dump_layout {
+            log::info!("{}", plugin.dump_layout());
+        }
+
+        if plugin.config.verify_pages {
+            let verification = plugin.verify()?;
+            match verification.is_healthy() {
+                true => log::info!("{}", verification),
+                false => log::warn!("{}", verification),
+            }
+        }
+
+        if plugin.config.repair_masks {
+            let repaired = plugin.repair_masks()?;
+            log::info!("Repaired zero masks of {} pages: {:?}", repaired.len(), repaired);
+        }
 
-            // Return the data. Now from the cache.
-            return self.cache.read(offset);
+        if let Some(name) = &plugin.config.restore_snapshot {
+            plugin.restore_snapshot(name)?;
         }
 
-        if let Some(data) = self.cache.read(offset) {
-            return Some(data.to_vec());
+        if let Some(name) = &plugin.config.take_snapshot {
+            plugin.take_snapshot(name)?;
         }
 
-        None
+        Ok(plugin)
     }
 
-    /// Tries to write to cache ensuring that the data is NOT in the queue.
-    pub fn write_cache(&self, offset: u64, dataa: &[u8]) -> bool {
-        // Check if the data is in the queue.
-        if let Some((page, data)) = self.queue.release_offset(offset / (1024*1024*8)) {
-            // Cache the data.
-            self.cache(CacheBlock::new(offset / (1024*1024*8), page.message_id, data, page.zero_mask));
+    /// Returns the map of all loaded pages.
+    /// Blocks that are not loaded yet (see `load_metadata_for`) are not included.
+    pub fn dump_layout(&self) -> Layout {
+        Layout::of(self.channel, &self.pages, &self.meta.lock_or_recover())
+    }
+
+    /// Returns where the byte at given offset of the drive is stored,
+    /// or None if its page was never written.
+    pub fn locate(&self, offset: u64) -> Result<Option<PageLocation>> {
+        let page_offset = self.page_of(offset);
+        self.load_metadata_for(page_offset)?;
+
+        let mut meta = self.meta.lock_or_recover();
+        let Some(page) = meta.find(page_offset) else {
+            return Ok(None);
+        };
+
+        let block = (offset % self.pages.size as u64) as usize / self.pages.zero_block_size;
+        Ok(Some(PageLocation {
+            channel: self.channel,
+            message_id: page.message_id,
+            // Page data is always the first attachment.
+            attachment: 0,
+            zeroed: page.zero_mask.get(block),
+        }))
+    }
+
+    /// Page buffers held right now by the cache, the sync queue (with the pages being uploaded) and transfers.
+    /// Pooled buffers (`BUFFER_POOL`) aren't counted.
+    pub fn resident_pages(&self) -> usize {
+        let in_flight = self.queue.in_flight.lock_or_recover().len();
+        self.cache.len() + self.queue.len() + in_flight + self.pages.memory.held()
+    }
+
+    /// Returns how well syncing with discord keeps up.
+    pub fn health(&self) -> Health {
+        self.queue.health(self.config.stall_timeout)
+    }
+
+    /// Stops uploading queued pages (for maintenance, or to keep quiet on discord for a while)
+    /// until `resume_sync` is called. Written pages wait in the cache and the queue meanwhile,
+    /// writes block once both are full and flushes fail.
+    pub fn pause_sync(&self) {
+        self.queue.pause();
+    }
+
+    pub fn resume_sync(&self) {
+        self.queue.resume();
+    }
 
-            // Return the data. Now from the cache.
-            return self.cache.write(offset, dataa);
+    /// Returns what the drive is doing, without waiting for reads, writes or syncs.
+    pub fn status(&self) -> Status {
+        let stats = &self.queue.stats;
+        let (data_messages, _) = self.page_count(&self.meta.lock_or_recover());
+        let lost_pages = self.lost_pages().len();
+        Status {
+            health: self.health(),
+            cached_pages: self.cache.len(),
+            cache_capacity: self.cache.capacity(),
+            dirty_pages: self.cache.dirty_len(),
+            syncing: self.queue.is_syncing.load(Ordering::SeqCst),
+            sync_paused: self.queue.is_paused(),
+            since_last_sync: stats.since_last_sync(),
+            synced_pages: stats.synced_blocks(),
+            sync_errors: stats.sync_errors(),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            repaired_pages: self.repaired_pages.load(Ordering::Relaxed),
+            lost_pages,
+            tier_pages: self.queue.tier_len(),
+            resident_pages: self.resident_pages(),
+            data_messages,
+            max_data_messages: self.config.max_data_messages,
+            sparse_ratio: self.usage().sparse_ratio(),
+            latencies: self.latencies.report(),
+        }
+    }
+
+    /// Reads every page of the drive and reports the ones that can't be read.
+    /// Nothing is modified, damaged pages are left as they are.
+    pub fn verify(&self) -> Result<Verification> {
+        self.load_metadata_where(|_| true)?;
+
+        let mut pages: Vec<Page> = self.meta.lock_or_recover().iter()
+            .flat_map(|block| block.pages.iter().cloned())
+            .collect();
+        pages.sort_by_key(|page| page.offset);
+
+        let mut verification = Verification::default();
+        for page in pages {
+            // Pages that were never uploaded read as zeros.
+            let Some(message_id) = page.message_id else {
+                verification.healthy += 1;
+                continue;
+            };
+            let _memory = self.rt.block_on(self.pages.memory.hold_download());
+            let damage = match self.rt.block_on(page.read(&self.channel, self.backend(), &self.pages)) {
+                Ok(_) => {
+                    verification.healthy += 1;
+                    continue;
+                }
+                Err(Error::MissingPage { .. }) => PageDamage::Missing,
+                Err(Error::MissingAttachment { .. }) => PageDamage::MissingAttachment,
+                Err(e @ (Error::InvalidPageLength { .. } | Error::ChecksumMismatch { .. } | Error::Encryption | Error::UnsupportedVersion { .. })) => {
+                    PageDamage::Corrupted(e.to_string())
+                }
+                Err(e) => PageDamage::Unreadable(e.to_string()),
+            };
+
+            // The page may have been uploaded again while it was read.
+            let current = self.meta.lock_or_recover().find(page.offset).and_then(|p| p.message_id);
+            if current != Some(message_id) {
+                verification.healthy += 1;
+                continue;
+            }
+
+            log::warn!("Page {} (message {}) is damaged: {:?}", page.offset, message_id, damage);
+            verification.damaged.push(DamagedPage { offset: page.offset, message_id, damage });
         }
 
-        self.cache.write(offset, dataa)
+        Ok(verification)
     }
 
-    pub fn read(&self, offset: u64) -> Vec<u8> {
-        // Try to read from cache first.
-        if let Some(data) = self.read_cache(offset) {
-            return data.to_vec();
+    /// Fetches every metadata block listed in the superblock and checks that it parses and that its pages point at
+    /// messages that can hold their data. With `messages`, the message of every page is fetched to check that it exists.
+    /// Nothing is modified, the problems are only reported.
+    pub fn check_integrity(&self, messages: bool) -> Result<Integrity> {
+        let (entries, superblock_ids) = {
+            let superblock = self.superblock.lock_or_recover();
+            (superblock.blocks.clone(), [superblock.message_id, superblock.attachment_id])
+        };
+        let mut integrity = Integrity { blocks: entries.len(), ..Integrity::default() };
+        let reserved: HashSet<u64> = entries.iter().map(|entry| entry.message_id).chain(superblock_ids).collect();
+
+        let mut offsets = HashSet::new();
+        let mut pages: HashMap<u64, Page> = HashMap::new();
+        for block in self.rt.block_on(superblock::check_blocks(self.backend(), self.meta_channel, &entries))? {
+            let block = match block {
+                Ok(block) => block,
+                Err(problem) => {
+                    integrity.problems.push(problem);
+                    continue;
+                }
+            };
+
+            for page in block.pages.iter() {
+                integrity.pages += 1;
+                if !offsets.insert(page.offset) {
+                    integrity.problems.push(IntegrityProblem::DuplicatePage { offset: page.offset });
+                }
+
+                // Pages without a message were never uploaded.
+                let Some(message_id) = page.message_id else {
+                    continue;
+                };
+                if reserved.contains(&message_id) {
+                    integrity.problems.push(IntegrityProblem::ReservedMessage { offset: page.offset, message_id });
+                } else if let Some(other) = pages.insert(message_id, page.clone()) {
+                    integrity.problems.push(IntegrityProblem::SharedMessage { offset: page.offset, other: other.offset, message_id });
+                }
+            }
         }
 
-        // If cache miss occurs, try to read from metadata blocks.
-        let meta = self.meta.lock().unwrap();
-        for block in meta.iter() {
-            if let Some(data) = self.rt.block_on(async {
-                block.try_read(&self.channel, self.http(), offset).await
-            }) {
-                // Drop the lock to prevent deadlock on the same thread.
-                drop(meta);
+        // Attachments aren't downloaded, but reads may have found them gone.
+        for (offset, message_id) in self.lost.lock_or_recover().iter() {
+            if pages.get(message_id).is_some_and(|page| page.offset == *offset) {
+                integrity.problems.push(IntegrityProblem::LostAttachment { offset: *offset, message_id: *message_id });
+            }
+        }
 
-                // Cache the data.
-                self.cache(CacheBlock::new(offset / (1024*1024*8), data.1.message_id, data.0, data.1.zero_mask));
+        if messages {
+            let mut pages: Vec<Page> = pages.into_values().collect();
+            pages.sort_unstable_by_key(|page| page.offset);
+            for (page, exists) in pages.iter().zip(self.messages_exist(&pages)?) {
+                if !exists {
+                    integrity.problems.push(IntegrityProblem::MissingMessage { offset: page.offset, message_id: page.message_id.unwrap() });
+                }
+            }
+        }
 
-                // Return the data. Now from the cache.
-                return self.cache.read(offset).unwrap();
+        for problem in &integrity.problems {
+            log::warn!("Stored metadata is damaged: {}", problem);
+        }
+        Ok(integrity)
+    }
+
+    /// Returns whether the message of every page exists, fetching 16 at a time. Pages without a message don't exist.
+    fn messages_exist(&self, pages: &[Page]) -> Result<Vec<bool>> {
+        let mut exist = Vec::with_capacity(pages.len());
+        for chunk in pages.chunks(16) {
+            let fetched = self.rt.block_on(join_all(chunk.iter().map(|page| async move {
+                let message_id = page.message_id?;
+                Some(self.backend().get_message(page.channel_or(self.channel), message_id).await)
+            })));
+            for result in fetched {
+                match result {
+                    Some(Ok(_)) => exist.push(true),
+                    None | Some(Err(BackendError::NotFound)) => exist.push(false),
+                    Some(Err(e)) => return Err(e.into()),
+                }
             }
         }
 
-        vec![0; 4096]
+        Ok(exist)
     }
 
-    pub fn write(&self, offset: u64, data: &[u8]) {
-        // Try to write to cache first.
-        if self.write_cache(offset, data) {
-            return;
+    /// Compares the metadata with the channel after messages were deleted by hand, without downloading any page.
+    /// Pages whose message is gone are dropped from their metadata block (and read as zeros), and deleted metadata block
+    /// messages are sent again. Pages dropped by an earlier reconcile are adopted again once their message is back
+    /// (a channel can be hidden from the bot for a while), unless the page was written since.
+    pub fn reconcile(&self) -> Result<Reconciliation> {
+        let _io = self.lock_idle();
+        self.flush_all()?;
+        self.load_metadata_where(|_| true)?;
+
+        let mut meta = self.meta.lock_or_recover();
+        let blocks: Vec<u64> = meta.iter().filter_map(|block| block.message_id).collect();
+        let mut deleted_blocks = HashSet::new();
+        for chunk in blocks.chunks(16) {
+            let fetched = self.rt.block_on(join_all(chunk.iter().map(|message_id| self.backend().get_message(self.meta_channel, *message_id))));
+            for (message_id, result) in chunk.iter().zip(fetched) {
+                match result {
+                    Ok(_) => {}
+                    Err(BackendError::NotFound) => {
+                        deleted_blocks.insert(*message_id);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        let pages: Vec<Page> = meta.iter()
+            .flat_map(|block| block.pages.iter())
+            .filter(|page| page.message_id.is_some())
+            .cloned()
+            .collect();
+        let mut reconciliation = Reconciliation { pages: pages.len(), resent_blocks: deleted_blocks.len(), ..Reconciliation::default() };
+        let mut vanished = std::mem::take(&mut *self.vanished.lock_or_recover());
+        // Pages that were written since they were dropped are stored in a new message.
+        vanished.retain(|offset, _| meta.find(*offset).is_none());
+
+        let mut dropped = HashSet::new();
+        let exist = self.messages_exist(&pages)?;
+        for (page, exists) in pages.into_iter().zip(exist) {
+            if !exists {
+                log::warn!("Message {} of page {} is gone, dropping the page.", page.message_id.unwrap(), page.offset);
+                self.pages.urls.remove(page.message_id.unwrap());
+                dropped.insert(page.offset);
+                reconciliation.dropped.push(page.offset);
+                self.vanished.lock_or_recover().insert(page.offset, page);
+            }
+        }
+
+        let returned: Vec<Page> = vanished.into_values().collect();
+        let mut adopted = Vec::new();
+        let exist = self.messages_exist(&returned)?;
+        for (page, exists) in returned.into_iter().zip(exist) {
+            if exists {
+                log::info!("Message {} of page {} is back, adopting the page again.", page.message_id.unwrap(), page.offset);
+                reconciliation.adopted.push(page.offset);
+                adopted.push(page);
+            } else {
+                self.vanished.lock_or_recover().insert(page.offset, page);
+            }
         }
 
-        let mut meta = self.meta.lock().unwrap();
+        let limit = self.config.metadata_block_limit();
         for block in meta.iter_mut() {
-            if let Some(data) = self.rt.block_on(async {
-                block.try_write(&self.channel, self.http(), offset, data).await
-            }) {
-                // Drop the lock to prevent deadlock on the same thread.
-                drop(meta);
+            let count = block.pages.len();
+            block.pages.retain(|page| !dropped.contains(&page.offset));
+            while block.pages.len() < PAGES_PER_BLOCK && !adopted.is_empty() {
+                block.pages.push(adopted.pop().unwrap());
+            }
+            if block.pages.len() != count || block.message_id.is_some_and(|id| deleted_blocks.contains(&id)) {
+                self.rt.block_on(block.update_message(self.backend(), &self.meta_channel))?;
+            }
+        }
+        for pages in adopted.chunks(PAGES_PER_BLOCK) {
+            if meta.len() >= limit {
+                return Err(Error::DeviceFull { blocks: limit });
+            }
+            let mut block = MetadataBlock { pages: pages.to_vec(), ..self.new_block() };
+            self.rt.block_on(block.update_message(self.backend(), &self.meta_channel))?;
+            meta.push(block);
+        }
+        drop(meta);
+        self.sync_superblock()?;
 
-                // Cache the data.
-                self.cache(CacheBlock::new(offset / (1024*1024*8), data.1.message_id, data.0, data.1.zero_mask));
+        reconciliation.dropped.sort_unstable();
+        reconciliation.adopted.sort_unstable();
+        Ok(reconciliation)
+    }
 
-                // Return.
-                return;
+    /// Downloads every page and replaces its zero mask with the one of its data, if they differ.
+    /// Blocks are only ever stored as zeros while they are masked, so a masked block that holds data
+    /// means the mask is wrong. Pages that can't be read are skipped. Returns the offsets of the repaired pages.
+    pub fn repair_masks(&self) -> Result<Vec<u64>> {
+        let _io = self.lock_idle();
+        self.flush_all()?;
+        self.load_metadata_where(|_| true)?;
+
+        let mut pages: Vec<Page> = self.meta.lock_or_recover().iter()
+            .flat_map(|block| block.pages.iter())
+            .filter(|page| page.message_id.is_some())
+            .cloned()
+            .collect();
+        pages.sort_by_key(|page| page.offset);
+
+        let mut repaired = Vec::new();
+        for page in pages {
+            // Masked blocks are downloaded too, the mask is what is checked.
+            let unmasked = Page { zero_mask: Default::default(), ..page.clone() };
+            let _memory = self.rt.block_on(self.pages.memory.hold_download());
+            let data = match self.rt.block_on(unmasked.read(&self.channel, self.backend(), &self.pages)) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("Skipping the zero mask of page {}, it can't be read: {}", page.offset, e);
+                    continue;
+                }
+            };
+
+            let mask = self.zero_mask_of(&data);
+            if mask.as_bytes() == page.zero_mask.as_bytes() {
+                continue;
+            }
+
+            log::warn!("Zero mask of page {} doesn't match its data, repairing it", page.offset);
+            let mut meta = self.meta.lock_or_recover();
+            if let Some(block) = meta.block_of(page.offset) {
+                self.rt.block_on(block.update_page(self.backend(), &self.meta_channel, Page { zero_mask: mask, ..page.clone() }))?;
+                repaired.push(page.offset);
             }
         }
 
-        let mut block = MetadataBlock::empty(0);
+        Ok(repaired)
+    }
 
-        if let Some(data) = self.rt.block_on(async {
-            block.try_write(&self.channel, self.http(), offset, data).await
-        }) {
-            // Drop the lock to prevent deadlock on the same thread.
-            drop(meta);
+    /// Zero mask of page data, empty with `ZERO_MASK=false` so every block is stored as data.
+    fn zero_mask_of(&self, data: &[u8]) -> BitMask<256> {
+        if self.config.zero_mask {
+            zero_mask_of(data, self.pages.zero_block_size)
+        } else {
+            BitMask::new()
+        }
+    }
 
-            // Cache the data.
-            self.cache(CacheBlock::new(offset / (1024*1024*8), data.1.message_id, data.0, data.1.zero_mask));
+    /// Returns how much of the drive is used.
+    /// Blocks that are not loaded yet (see `load_metadata_for`) are not included.
+    pub fn usage(&self) -> Usage {
+        Usage::of(&self.meta.lock_or_recover(), &self.pages, self.config.device_size)
+    }
+
+    /// Returns the number of pages of the drive, and whether it is exact (the superblock lists the pages of every unloaded block).
+    fn page_count(&self, meta: &Metadata) -> (usize, bool) {
+        let unloaded = self.unloaded.lock_or_recover();
+        let listed: usize = unloaded.iter().filter_map(|entry| entry.offsets.as_ref()).map(Vec::len).sum();
+        let loaded: usize = meta.iter().map(|block| block.pages.len()).sum();
+
+        (loaded + listed, unloaded.iter().all(|entry| entry.offsets.is_some()))
+    }
+
+    /// Fails if adding `new` pages would go past `MAX_DATA_MESSAGES`.
+    /// Unloaded blocks must list their pages in the superblock (or be loaded first).
+    fn check_storage(&self, meta: &Metadata, new: usize) -> Result<()> {
+        match self.config.max_data_messages {
+            Some(limit) if self.page_count(meta).0 + new > limit => Err(Error::StorageFull { messages: limit }),
+            _ => Ok(()),
         }
+    }
 
-        // Acquire the lock again.
-        let mut meta = self.meta.lock().unwrap();
+    /// Returns the offset of the page holding the byte at given offset (as a multiple of the page size).
+    fn page_of(&self, offset: u64) -> u64 {
+        offset / self.config.page_size as u64
+    }
 
-        meta.push(block);
+    /// Loads metadata blocks that may contain the page at given offset
+    /// (stored as a multiple of the page size), if they weren't loaded yet.
+    pub fn load_metadata_for(&self, offset: u64) -> Result<()> {
+        self.load_metadata_where(|entry| entry.may_contain(offset))
+    }
 
-        println!("Created new metadata block at offset {}", offset);
+    /// Loads the listed metadata blocks that match the filter, if they weren't loaded yet.
+    fn load_metadata_where(&self, filter: impl Fn(&SuperblockEntry) -> bool) -> Result<()> {
+        let entries: Vec<SuperblockEntry> = {
+            let mut unloaded = self.unloaded.lock_or_recover();
+            let (entries, rest) = unloaded.drain(..).partition(|entry| filter(entry));
+            *unloaded = rest;
+            entries
+        };
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut blocks = match self.rt.block_on(superblock::load_blocks(self.backend(), self.meta_channel, &entries)) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                // Try again on the next access.
+                self.unloaded.lock_or_recover().extend(entries);
+                return Err(e);
+            }
+        };
+
+        for block in blocks.iter_mut() {
+            block.format = self.config.metadata_format;
+            block.compress_above = self.config.metadata_compress_above;
+            block.edit_fallback = self.config.metadata_edit_fallback;
+        }
+        self.prefetch_urls(&blocks);
+        self.meta.lock_or_recover().extend(blocks);
+        Ok(())
     }
-}
 
-/// Default implementation of the plugin.
-impl Default for DiscordDrivePlugin {
-    fn default() -> Self {
-        let rt = tokio::runtime::Runtime::new().unwrap();
+    /// Fetches download urls of the pages in given blocks in bulk,
+    /// so the first reads of the pages don't have to fetch their messages one by one.
+    fn prefetch_urls(&self, blocks: &[MetadataBlock]) {
+        let message_ids: Vec<u64> = blocks.iter()
+            .flat_map(|block| block.pages.iter())
+            // Relocated pages are not in the history of the channel of the drive.
+            .filter(|page| page.channel.is_none() && !page.is_zeroed(self.pages.size, self.pages.zero_block_size))
+            .filter_map(|page| page.message_id)
+            .collect();
 
-        let client = rt.block_on(async {
-            Client::builder(&env!("BOT_TOKEN"), GatewayIntents::all())
-                .await
-                .expect("Failed to create client")            
-        });
-        
-        let channel = ChannelId(
-            env!("FS_CHANNEL_ID")
-                .parse()
-                .expect("Failed to parse CHANNEL_ID from env")
-        );
-
-        let meta = rt.block_on(async {
-            MetadataBlock::load_all(&client.cache_and_http.http, channel, 500).await
-        });
+        if message_ids.is_empty() {
+            return;
+        }
 
-        let meta = Arc::new(Mutex::new(meta));
+        if let Err(e) = self.rt.block_on(self.pages.urls.prefetch(self.backend(), self.channel, &message_ids)) {
+            // Urls are fetched one by one when the pages are read.
+            log::warn!("Failed to prefetch page urls: {}", e);
+        }
+    }
 
-        let queue = Queue::new();
-        let queue = queue.start_sync_thread(client.cache_and_http.http.clone(), channel.clone(), meta.clone());
+    /// Empty metadata block of the drive, not sent yet.
+    fn new_block(&self) -> MetadataBlock {
+        MetadataBlock {
+            format: self.config.metadata_format,
+            compress_above: self.config.metadata_compress_above,
+            edit_fallback: self.config.metadata_edit_fallback,
+            volume: self.config.volume.clone(),
+            ..MetadataBlock::empty(None)
+        }
+    }
 
-        Self {
-            rt,
-            meta,
-            client,
-            channel,
+    /// Updates the pinned superblock if the metadata blocks changed.
+    pub fn sync_superblock(&self) -> Result<()> {
+        let meta = self.meta.lock_or_recover();
+        let unloaded = self.unloaded.lock_or_recover();
+        let mut superblock = self.superblock.lock_or_recover();
 
-            cache: Cache::new(),
-            queue: queue,
+        let entries = meta.iter()
+            .filter_map(SuperblockEntry::of)
+            .chain(unloaded.iter().cloned())
+            .collect();
+
+        if superblock.set_blocks(entries) {
+            self.rt.block_on(superblock.save(self.backend(), self.meta_channel))?;
         }
+
+        Ok(())
     }
-}
 
-/// Implementation of the plugin.
-impl Server for DiscordDrivePlugin {
-    fn get_size(&self) -> nbdkit::Result<i64> {
-        Ok(
-            env!("DEVICE_SIZE")
-                .parse()
-                .expect("Failed to parse DEVICE_SIZE from env")
-        )
+    /// Caches the block. If the cache is full, the oldest block is evicted
+    /// and uploaded (if it was written since it was downloaded).
+    pub fn cache(&self, block: CacheBlock) {
+        self.apply_combined();
+        match self.cache.push(block) {
+            Ok(Some(block)) if block.dirty => self.enqueue(block),
+            Ok(Some(block)) => self.pages.buffers.put(block.data),
+            Ok(None) => {}
+            Err(e) => log::error!("{}", e),
+        }
     }
 
-    fn name() -> &'static str where Self: Sized {
-        "discorddrive"
+    /// Queues the cached block for upload.
+    fn enqueue(&self, block: CacheBlock) {
+        self.queued.lock_or_recover().insert(block.offset);
+        if let Some(Err(e)) = self.wal.as_ref().map(|wal| wal.append(block.offset, &block.data)) {
+            log::error!("Failed to log page {} before uploading it, it is lost if daafs dies now: {}", block.offset, e);
+        }
+        self.queue.spill(Page {
+            message_id: block.message_id,
+            zero_mask: block.mask,
+            ..Page::new(block.offset)
+        }, block.data);
     }
 
-    fn open(_readonly: bool) -> nbdkit::Result<Box<dyn Server>> where Self: Sized {
-        Ok(Box::new(Self::default()))
+    /// Moves the oldest dirty blocks to the queue while more than `DIRTY_LIMIT` are cached,
+    /// so they start uploading before the cache is full.
+    fn enqueue_dirty(&self) {
+        let Some(limit) = self.config.dirty_limit else {
+            return;
+        };
+        if self.cache.dirty_len() <= limit {
+            return;
+        }
+        self.apply_combined();
+
+        while self.cache.dirty_len() > limit {
+            match self.cache.take_oldest_dirty() {
+                Some(block) => self.enqueue(block),
+                None => break,
+            }
+        }
     }
 
-    fn read_at(&self, buf: &mut [u8], offset: u64) -> nbdkit::Result<()> {
-        let data = self.read(offset);
+    /// Writes the buffered sequential writes to their cached page.
+    fn apply_combined(&self) {
+        if let Some((offset, data)) = self.combined.lock_or_recover().take() {
+            // The page stays cached while writes to it are buffered.
+            let written = self.cache.write(offset, &data);
+            debug_assert!(written, "Combined writes to a page that is not cached");
+            if !self.config.zero_mask {
+                self.cache.unmask(offset, data.len());
+            }
+        }
+    }
+
+    /// Finds the 4KB block at offset, in the queue first, then in the cache, then in the metadata.
+    /// A queued page is moved to the cache. Taking `io` makes the lookup atomic: the page can't move
+    /// between the queue, the cache and discord while it looks, so a written page is always found.
+    fn lookup(&self, io: &MutexGuard<'_, ()>, offset: u64) -> Result<Lookup> {
+        if let Some(data) = self.read_cache(io, offset) {
+            return Ok(Lookup::Cached(data));
+        }
+
+        match self.find_page(self.page_of(offset))? {
+            Some(page) => Ok(Lookup::Stored(Box::new(page))),
+            None => Ok(Lookup::Missing),
+        }
+    }
+
+    /// Moves the page at offset from the queue to the cache if it is queued.
+    /// Waits for the page to be released if it is being uploaded.
+    fn release_queued(&self, _io: &MutexGuard<'_, ()>, offset: u64) {
+        if let Some((page, data)) = self.queue.release_offset(self.page_of(offset)) {
+            self.cache(CacheBlock { dirty: true, ..CacheBlock::new(self.page_of(offset), page.message_id, data, page.zero_mask) });
+        }
+    }
+
+    /// Tries to read from cache ensuring that the data is NOT in the queue.
+    fn read_cache(&self, io: &MutexGuard<'_, ()>, offset: u64) -> Option<Vec<u8>> {
+        self.release_queued(io, offset);
+        self.cache.read(offset).map(|data| data.to_vec())
+    }
+
+    /// Tries to write to cache ensuring that the data is NOT in the queue.
+    fn write_cache(&self, io: &MutexGuard<'_, ()>, offset: u64, data: &[u8]) -> bool {
+        self.release_queued(io, offset);
+        self.cache.write(offset, data)
+    }
+
+    /// Reads the 4KB block at offset. The last block is shorter if the drive
+    /// size is not a multiple of 4KB. Blocks of the drive that were never written
+    /// read as zeros, offsets past the end of the drive are rejected.
+    pub fn read(&self, offset: u64) -> Result<Vec<u8>> {
+        let size = self.config.device_size;
+        if offset >= size {
+            return Err(Error::OutOfBounds { offset, len: 4096, size });
+        }
+
+        let page = self.page_of(offset);
+        let mut fetched = false;
+        let mut copy = None;
+        let mut data = loop {
+            let io = self.lock_page(page);
+            if self.combined.lock_or_recover().overlaps(offset, 4096) {
+                self.apply_combined();
+            }
+
+            // A page downloaded by this read is used even if it is already stale.
+            if !fetched {
+                copy = self.drop_stale(page).or(copy);
+            }
+
+            // If cache miss occurs, download the page and try again.
+            match self.lookup(&io, offset)? {
+                Lookup::Cached(data) => break data,
+                Lookup::Stored(found) if self.config.ranged_reads => {
+                    match self.fetch_range(io, (*found).clone(), offset) {
+                        Ok(Some(data)) => break data,
+                        Ok(None) => {}
+                        Err(e) => self.repair_page(*found, copy.take(), e)?,
+                    }
+                }
+                Lookup::Stored(found) => {
+                    if let Err(e) = self.fetch(io, (*found).clone()) {
+                        self.repair_page(*found, copy.take(), e)?;
+                    }
+                }
+                Lookup::Missing => break vec![0; 4096],
+            }
+            fetched = true;
+        };
+        data.truncate((size - offset).min(4096) as usize);
+        self.bytes_read.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        Ok(data)
+    }
+
+    /// Reads the 4KB block at offset like `read`, logging every step at info level: whether the page
+    /// is queued or cached, which metablock lists it, its message id (0 if it has none), the download url
+    /// and how the read ended. This tells the whole story of a read that fails for one offset,
+    /// without tracing every request of the drive (`TRACE_DISCORD`).
+    pub fn trace_read(&self, offset: u64) -> Result<Vec<u8>> {
+        let page = self.page_of(offset);
+        log::info!("trace_read {}: page {}, byte {} of it", offset, page, offset % self.pages.size as u64);
+
+        let queued = self.queue.state_of(page);
+        log::info!("trace_read {}: queue: {}", offset, queued.unwrap_or("not queued"));
+        let cached = self.cache.get(page);
+        match &cached {
+            Some(block) => log::info!("trace_read {}: cache: hit ({}, message {:?})", offset, if block.dirty { "written" } else { "clean" }, block.message_id),
+            None => log::info!("trace_read {}: cache: miss", offset),
+        }
+
+        let found = self.find_page(page).inspect_err(|e| log::info!("trace_read {}: metadata: failed to load: {}", offset, e))?;
+        let block = self.meta.lock_or_recover().block_of(page).map(|block| block.message_id);
+        match block {
+            Some(block) => log::info!("trace_read {}: metadata: listed in metablock {}", offset, block.map_or("(not sent yet)".to_string(), |id| id.to_string())),
+            None => log::info!("trace_read {}: metadata: no metablock lists the page", offset),
+        }
+
+        let message_id = found.as_ref().and_then(|page| page.message_id).unwrap_or(0);
+        let channel = found.as_ref().map_or(self.channel, |page| page.channel_or(self.channel));
+        log::info!("trace_read {}: message id {} in channel {}", offset, message_id, channel.0);
+
+        if let Some(found) = found.as_ref().filter(|_| cached.is_none() && queued.is_none()) {
+            if found.is_zeroed(self.pages.size, self.pages.zero_block_size) {
+                log::info!("trace_read {}: url: none needed, the zero mask marks the whole page as zeroed", offset);
+            } else {
+                match self.rt.block_on(found.url(&self.channel, self.backend(), &self.pages)) {
+                    Ok(Some((url, true))) => log::info!("trace_read {}: url (cached): {}", offset, url),
+                    Ok(Some((url, false))) => log::info!("trace_read {}: url (fetched): {}", offset, url),
+                    Ok(None) => log::info!("trace_read {}: url: none, the page was never uploaded", offset),
+                    Err(e) => log::info!("trace_read {}: url: failed to fetch: {}", offset, e),
+                }
+            }
+        }
 
-        buf.copy_from_slice(&data);
+        let result = self.read(offset);
+        match &result {
+            Ok(_) if found.is_none() && cached.is_none() && queued.is_none() => {
+                log::info!("trace_read {}: result: zeros, the page was never written", offset)
+            }
+            Ok(data) => log::info!("trace_read {}: result: read {} bytes", offset, data.len()),
+            Err(e) => log::info!("trace_read {}: result: failed: {}", offset, e),
+        }
+
+        result
+    }
+
+    /// Drops the cached page at given offset (as a multiple of the page size) if it wasn't written
+    /// and the metadata points at another message now, or it was cached for longer than `CACHE_TTL`.
+    /// Returns the dropped page if it is still a copy of the stored one, for `READ_REPAIR`.
+    fn drop_stale(&self, page: u64) -> Option<CacheBlock> {
+        let message_id = self.meta.lock_or_recover().find(page).map(|page| page.message_id)?;
+
+        let repair = self.config.read_repair != ReadRepair::Off || self.config.reupload_lost_attachments;
+        self.cache.take_stale(page, message_id, self.config.cache_ttl)
+            .filter(|block| repair && block.message_id == message_id)
+    }
+
+    /// Handles a failed download of the page with `READ_REPAIR`. A page that is missing or fails its length
+    /// or checksum check is cached as written from its copy (or as zeros), so it is uploaded again on the next sync.
+    /// Other errors are returned, a wrong key or a broken connection says nothing about the stored page.
+    /// Pages whose attachment is gone are uploaded again from their copy with `REUPLOAD_LOST_ATTACHMENTS` too,
+    /// and marked as lost if they have none.
+    fn repair_page(&self, page: Page, copy: Option<CacheBlock>, error: Error) -> Result<()> {
+        let lost = matches!(error, Error::MissingAttachment { .. });
+        let damaged = lost || matches!(error, Error::MissingPage { .. } | Error::InvalidPageLength { .. } | Error::ChecksumMismatch { .. });
+        let repair = match self.config.read_repair {
+            ReadRepair::Off => lost && self.config.reupload_lost_attachments,
+            _ => damaged,
+        };
+        if !repair {
+            return Err(self.mark_lost(error));
+        }
+
+        // The page may have been written or uploaded again while it was read, the read is then tried again.
+        let io = self.io.lock_or_recover();
+        self.release_queued(&io, page.offset * self.pages.size as u64);
+        let current = self.meta.lock_or_recover().find(page.offset).map(|p| p.message_id);
+        if self.cache.contains(page.offset) || current != Some(page.message_id) {
+            return Ok(());
+        }
+
+        let (data, source) = match copy {
+            Some(block) => (block.data, "its cached copy"),
+            None if self.config.read_repair == ReadRepair::Zeros => (self.pages.buffers.take(self.pages.size), "zeros"),
+            None => return Err(self.mark_lost(error)),
+        };
+
+        log::warn!("Page {} is damaged ({}), uploading it again from {}", page.offset, error, source);
+        let mask = self.zero_mask_of(&data);
+        self.cache(CacheBlock { dirty: true, ..CacheBlock::new(page.offset, page.message_id, data, mask) });
+        self.repaired_pages.fetch_add(1, Ordering::Relaxed);
+        self.lost.lock_or_recover().remove(&page.offset);
 
         Ok(())
     }
 
-    fn write_at(&self, buf: &[u8], offset: u64, _flags: nbdkit::Flags) -> nbdkit::Result<()> {
-        self.write(offset, buf);
+    /// Records the page as lost if the error says that its attachment is gone, and returns the error.
+    fn mark_lost(&self, error: Error) -> Error {
+        if let Error::MissingAttachment { offset, message_id } = error {
+            if self.lost.lock_or_recover().insert(offset, message_id) != Some(message_id) {
+                log::error!("Attachment of page {} (message {}) is gone and there is no copy of the page, it is lost.", offset, message_id);
+            }
+        }
+        error
+    }
+
+    /// Offsets (as multiples of the page size) and messages of the pages whose attachment reads found gone,
+    /// that couldn't be uploaded again. Pages that were written since are left out.
+    pub fn lost_pages(&self) -> Vec<(u64, u64)> {
+        let lost = self.lost.lock_or_recover().clone();
+        let mut meta = self.meta.lock_or_recover();
+        lost.into_iter()
+            .filter(|(offset, message_id)| meta.find(*offset).is_some_and(|page| page.message_id == Some(*message_id)))
+            .collect()
+    }
+
+    /// Downloads the pages holding `len` bytes at offset into the cache ahead of reads.
+    /// Pages that are cached, queued, zeroed or don't exist are skipped,
+    /// and at most as many pages as the cache holds are downloaded.
+    pub fn prefetch(&self, offset: u64, len: u64) -> Result<()> {
+        let size = self.config.device_size;
+        if len == 0 || offset >= size {
+            return Ok(());
+        }
+
+        let first = self.page_of(offset);
+        let last = self.page_of((offset + len).min(size) - 1);
+        let mut fetched = 0;
+        for page in first..=last {
+            if fetched == self.cache.capacity() {
+                break;
+            }
+
+            let io = self.lock_page(page);
+            self.drop_stale(page);
+            match self.lookup(&io, page * self.config.page_size as u64)? {
+                Lookup::Stored(found) if !found.is_zeroed(self.pages.size, self.pages.zero_block_size) => {
+                    self.fetch(io, *found)?;
+                    fetched += 1;
+                }
+                _ => {}
+            }
+        }
 
         Ok(())
     }
 
-    fn flush(&self) -> nbdkit::Result<()> {
-        for block in self.cache.data.lock().unwrap().drain(..) {
-            self.queue.push(Page {
-                offset: block.offset,
-                message_id: block.message_id,
-                zero_mask: block.mask,
-            }, block.data.clone());
+    /// Locks `io` once the page is not being downloaded by another request.
+    /// Concurrent misses of a page so download it once: the first one downloads,
+    /// the others wait and read it from the cache (or download it again if it failed).
+    fn lock_page(&self, page: u64) -> MutexGuard<'_, ()> {
+        self.lock_when(|loading| !loading.contains(&page) && self.idle_waiters.load(Ordering::SeqCst) == 0)
+    }
+
+    /// Locks `io` once no page is being downloaded. Requests that could start a download wait meanwhile.
+    fn lock_idle(&self) -> MutexGuard<'_, ()> {
+        self.idle_waiters.fetch_add(1, Ordering::SeqCst);
+        let io = self.lock_when(HashSet::is_empty);
+
+        // Decremented holding `loading`, so requests waiting for it can't miss the notification.
+        let _loading = self.loading.lock_or_recover();
+        self.idle_waiters.fetch_sub(1, Ordering::SeqCst);
+        self.loaded.notify_all();
+        io
+    }
+
+    /// Locks `io` once the loading pages are ready.
+    fn lock_when(&self, ready: impl Fn(&HashSet<u64>) -> bool) -> MutexGuard<'_, ()> {
+        loop {
+            let io = self.io.lock_or_recover();
+            let loading = self.loading.lock_or_recover();
+            if ready(&loading) {
+                return io;
+            }
+
+            // Pages only leave `loading` while it is locked, so the notification can't be missed.
+            drop(io);
+            drop(self.loaded.wait(loading).unwrap_or_else(PoisonError::into_inner));
         }
+    }
+
+    /// Marks the page as downloaded and wakes the requests waiting for it.
+    fn finish_loading(&self, _io: &MutexGuard<'_, ()>, page: u64) {
+        self.loading.lock_or_recover().remove(&page);
+        self.loaded.notify_all();
+    }
 
-        self.queue.flush();
+    /// Returns the page at given offset (as a multiple of the page size) if it exists.
+    fn find_page(&self, page: u64) -> Result<Option<Page>> {
+        self.load_metadata_for(page)?;
 
-        // Move all metadata blocks to the bottom of the channel.
-        let mut meta = self.meta.lock().unwrap();
-        for block in meta.iter_mut() {
-            self.rt.block_on(async {
-                block.move_to_bottom(self.http(), self.channel).await;
-            });
+        Ok(self.meta.lock_or_recover().find(page).cloned())
+    }
+
+    /// Takes a download slot (`MEMORY_PAGES`) for the page before it is marked as loading, so waiting for one
+    /// doesn't hold up requests waiting for no pages to load. Without a free slot `io` is released meanwhile and
+    /// the page is looked up again: None if it was cached or changed and has to be looked up by the caller.
+    fn download_slot<'a>(&'a self, io: MutexGuard<'a, ()>, page: Page) -> Result<Option<(MutexGuard<'a, ()>, Page, MemorySlot)>> {
+        if let Some(slot) = self.pages.memory.try_hold_download() {
+            return Ok(Some((io, page, slot)));
+        }
+
+        drop(io);
+        let slot = self.rt.block_on(self.pages.memory.hold_download());
+        let io = self.lock_page(page.offset);
+        match self.lookup(&io, page.offset * self.pages.size as u64)? {
+            Lookup::Stored(found) if found.message_id == page.message_id => Ok(Some((io, *found, slot))),
+            _ => Ok(None),
         }
+    }
+
+    /// Downloads the page into the cache. `io` is released during the download,
+    /// so other requests can run meanwhile.
+    fn fetch(&self, io: MutexGuard<'_, ()>, page: Page) -> Result<()> {
+        // Held until the page is in the cache.
+        let Some((io, page, _memory)) = self.download_slot(io, page)? else {
+            return Ok(());
+        };
+        self.loading.lock_or_recover().insert(page.offset);
+        drop(io);
+
+        let data = self.rt.block_on(page.read(&self.channel, self.backend(), &self.pages));
+
+        let io = self.io.lock_or_recover();
+        self.finish_loading(&io, page.offset);
+        self.cache(CacheBlock::new(page.offset, page.message_id, data?, page.zero_mask));
 
         Ok(())
     }
-}
+
+    /// Downloads just the 4KB block at the offset, without caching it.
+    /// Returns None if the whole page was downloaded (and cached) instead.
+    fn fetch_range(&self, io: MutexGuard<'_, ()>, page: Page, offset: u64) -> Result<Option<Vec<u8>>> {
+        let Some((io, page, _memory)) = self.download_slot(io, page)? else {
+            return Ok(None);
+        };
+        self.loading.lock_or_recover().insert(page.offset);
+        drop(io);
+
+        let start = (offset - page.offset * self.pages.size as u64) as usize;
+        let read = self.rt.block_on(page.read_range(&self.channel, self.backend(), &self.pages, start, 4096));
+
+        let io = self.io.lock_or_recover();
+        self.finish_loading(&io, page.offset);
+        match read? {
+            PageRead::Range(data) => Ok(Some(data)),
+            PageRead::Full(data) => {
+                self.cache(CacheBlock::new(page.offset, page.message_id, data, page.zero_mask));
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        self.write_with(offset, data, self.config.cache_mode, true)
+    }
+
+    /// Writes `len` zeros at offset, a page at a time.
+    /// Without `holes`, the zeros are stored as data instead of being marked as zeroed blocks.
+    pub fn write_zeroes(&self, offset: u64, len: u64, holes: bool) -> Result<()> {
+        let size = self.config.device_size;
+        if offset + len > size {
+            return Err(Error::OutOfBounds { offset, len: len as usize, size });
+        }
+
+        let page_size = self.config.page_size as u64;
+        let mut start = offset;
+        while start < offset + len {
+            let end = ((start / page_size + 1) * page_size).min(offset + len);
+            self.write_with(start, &vec![0; (end - start) as usize], self.config.cache_mode, holes)?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    /// Reserves the pages holding `len` bytes at offset. Pages that don't exist yet are added to the metadata
+    /// entirely zeroed and without a data message, so they read as zeros and nothing is uploaded until they are written.
+    /// Returns the number of added pages.
+    pub fn preallocate(&self, offset: u64, len: u64) -> Result<usize> {
+        let size = self.config.device_size;
+        if offset + len > size {
+            return Err(Error::OutOfBounds { offset, len: len as usize, size });
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let _io = self.lock_idle();
+        // New pages go to the first blocks with room for them, wherever they are.
+        self.load_metadata_where(|_| true)?;
+
+        let mut zero_mask = BitMask::new();
+        for block in 0..zero_blocks(self.pages.size, self.pages.zero_block_size) {
+            zero_mask.set(block, true);
+        }
+
+        let mut meta = self.meta.lock_or_recover();
+        let mut missing: Vec<Page> = (self.page_of(offset)..=self.page_of(offset + len - 1))
+            .filter(|page| meta.find(*page).is_none())
+            .map(|page| Page { zero_mask: zero_mask.clone(), ..Page::new(page) })
+            .collect();
+        let added = missing.len();
+        self.check_storage(&meta, added)?;
+
+        for block in meta.iter_mut() {
+            let room = PAGES_PER_BLOCK.saturating_sub(block.pages.len()).min(missing.len());
+            if room > 0 {
+                block.pages.extend(missing.drain(..room));
+                self.rt.block_on(block.update_message(self.backend(), &self.meta_channel))?;
+            }
+        }
+
+        let limit = self.config.metadata_block_limit();
+        for pages in missing.chunks(PAGES_PER_BLOCK) {
+            if meta.len() >= limit {
+                return Err(Error::DeviceFull { blocks: limit });
+            }
+
+            let mut block = MetadataBlock { pages: pages.to_vec(), ..self.new_block() };
+            self.rt.block_on(block.update_message(self.backend(), &self.meta_channel))?;
+            meta.push(block);
+        }
+        drop(meta);
+
+        self.sync_superblock()?;
+        Ok(added)
+    }
+
+    /// Swaps which data the pages holding the bytes at given offsets point at, without moving any data,
+    /// so each offset reads what the other one held. Pending writes are flushed first.
+    /// Pages of one metadata block are swapped with a single update of its message,
+    /// pages of different blocks update both messages one after another.
+    pub fn swap_pages(&self, offset_a: u64, offset_b: u64) -> Result<()> {
+        let (a, b) = (self.page_of(offset_a), self.page_of(offset_b));
+        let _io = self.lock_idle();
+        self.flush_all()?;
+        self.load_metadata_for(a)?;
+        self.load_metadata_for(b)?;
+
+        let mut meta = self.meta.lock_or_recover();
+        let page_a = meta.find(a).cloned().ok_or(Error::NoPage { offset: a })?;
+        let page_b = meta.find(b).cloned().ok_or(Error::NoPage { offset: b })?;
+
+        let block = meta.block_of(a).unwrap();
+        if self.rt.block_on(block.swap_pages(self.backend(), &self.meta_channel, a, b))? {
+            return Ok(());
+        }
+
+        let pointing_at = |page: &Page, other: &Page| Page {
+            message_id: other.message_id,
+            zero_mask: other.zero_mask.clone(),
+            key_id: other.key_id,
+            channel: other.channel,
+            ..page.clone()
+        };
+        let block = meta.block_of(a).unwrap();
+        self.rt.block_on(block.update_page(self.backend(), &self.meta_channel, pointing_at(&page_a, &page_b)))?;
+        let block = meta.block_of(b).unwrap();
+        if let Err(e) = self.rt.block_on(block.update_page(self.backend(), &self.meta_channel, pointing_at(&page_b, &page_a))) {
+            // Both pages would point at the same message, the first block gets its page back.
+            block.set_page(&page_b);
+            let block = meta.block_of(a).unwrap();
+            if let Err(revert) = self.rt.block_on(block.update_page(self.backend(), &self.meta_channel, page_a)) {
+                // The block stays unsaved and is stored again by the next flush.
+                log::error!("Failed to restore page {} after a failed swap: {}", a, revert);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Moves the data of the page holding the byte at offset to a message in `channel` and deletes the old message
+    /// (after `DELETION_GRACE`, or never with `KEEP_HISTORY`). Requests wait until the metadata points at the new message,
+    /// and reads that started before still find the old message. Later uploads of the page go to `channel` as well.
+    pub fn relocate_page(&self, offset: u64, channel: ChannelId) -> Result<()> {
+        let page = self.page_of(offset);
+        let _io = self.lock_idle();
+        self.flush_all()?;
+        self.load_metadata_for(page)?;
+
+        let mut meta = self.meta.lock_or_recover();
+        let found = meta.find(page).cloned().ok_or(Error::NoPage { offset: page })?;
+        let old_channel = found.channel_or(self.channel);
+        if old_channel == channel {
+            return Ok(());
+        }
+
+        // The page is stored in the channel of the drive again if it is moved back there.
+        let mut moved = Page { message_id: None, channel: (channel != self.channel).then_some(channel.0), ..found.clone() };
+        if found.message_id.is_some() {
+            let _memory = self.rt.block_on(self.pages.memory.hold_download());
+            let data = self.rt.block_on(found.read(&self.channel, self.backend(), &self.pages))?;
+            self.rt.block_on(moved.update_message(self.backend(), &self.channel, &self.pages, &data))?;
+            self.pages.buffers.put(data);
+        }
+
+        let block = meta.block_of(page).unwrap();
+        if let Err(e) = self.rt.block_on(block.update_page(self.backend(), &self.meta_channel, moved.clone())) {
+            // The metadata still points at the old message, the new one is not needed.
+            if let Some(message_id) = moved.message_id {
+                self.rt.block_on(self.backend().delete_message(channel, message_id)).ok();
+            }
+            return Err(e);
+        }
+
+        if let Some(message_id) = found.message_id.filter(|_| !self.config.keep_history) {
+            self.rt.block_on(self.pages.deletions.delete(self.backend(), old_channel, message_id));
+            self.pages.urls.remove(message_id);
+        }
+        log::info!("Relocated page {} to channel {}.", page, channel);
+        Ok(())
+    }
+
+    /// Writes in given cache mode, no matter which one is configured.
+    /// Without `holes`, written blocks are stored as data even if they are all zeros.
+    fn write_with(&self, offset: u64, data: &[u8], mode: CacheMode, holes: bool) -> Result<()> {
+        let size = self.config.device_size;
+        if offset + data.len() as u64 > size {
+            return Err(Error::OutOfBounds { offset, len: data.len(), size });
+        }
+        self.bytes_written.fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        // The last block is shorter if the drive size is not a multiple of 4KB,
+        // the rest of it stays zeroed.
+        let mut padded;
+        let data = if data.len() < 4096 && offset + data.len() as u64 == size {
+            padded = data.to_vec();
+            padded.resize(4096, 0);
+            &padded
+        } else {
+            data
+        };
+
+        let page = self.page_of(offset);
+        let combine = mode == CacheMode::WriteBack && holes;
+        let mut combined = false;
+        let _io = loop {
+            let io = self.lock_page(page);
+
+            // Writes continuing the previous one are applied to the cached page together.
+            if combine && self.combined.lock_or_recover().append(offset, data, self.config.page_size) {
+                combined = true;
+                break io;
+            }
+            self.apply_combined();
+
+            // Try to write to cache first.
+            if self.write_cache(&io, offset, data) {
+                break io;
+            }
+
+            // Download existing pages first, new ones are created right away.
+            match self.find_page(page)? {
+                Some(found) => self.fetch(io, found)?,
+                None => {
+                    self.write_cached(&io, offset, data)?;
+                    break io;
+                }
+            }
+        };
+
+        if combine && !combined && self.cache.contains(page) {
+            self.combined.lock_or_recover().follow(page, offset, data.len());
+        }
+        // Combined writes are unmasked once they are applied.
+        if !holes || (!self.config.zero_mask && !combined) {
+            self.cache.unmask(offset, data.len());
+        }
+
+        if mode == CacheMode::WriteThrough {
+            self.write_through(page)?;
+        } else {
+            self.enqueue_dirty();
+        }
+
+        // An earlier write couldn't be synced and was given up on.
+        if let Some(error) = self.queue.take_failure() {
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the sync queue is empty.
+    fn flush_queue(&self) -> Result<()> {
+        if let Err(e) = self.queue.flush(self.config.flush_timeout) {
+            self.health().log();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Uploads all written pages and tidies up the metadata.
+    /// Must be called while holding `io`, with no page being downloaded.
+    fn flush_all(&self) -> Result<()> {
+        self.apply_combined();
+
+        // Pages that were only read are already on discord.
+        let blocks: Vec<CacheBlock> = self.cache.data.lock_or_recover().drain(..).collect();
+        for block in blocks {
+            match block.dirty {
+                true => self.enqueue(block),
+                false => self.pages.buffers.put(block.data),
+            }
+        }
+
+        match self.flush_queue() {
+            // Pages the queue gave up on are reported by the error, they are not checked again.
+            Err(e @ Error::SyncFailed { .. }) => {
+                self.queued.lock_or_recover().clear();
+                return Err(e);
+            }
+            result => result?,
+        }
+        self.check_synced()?;
+        self.save_metadata()?;
+        self.clear_wal()?;
+        self.rt.block_on(self.pages.deletions.run_due(self.backend()));
+
+        // Zeroed pages don't need to be stored, their blocks may be merged below.
+        let mut meta = self.meta.lock_or_recover();
+        let mut dropped = 0;
+        for block in meta.iter_mut() {
+            dropped += self.rt.block_on(block.drop_zeroed(self.backend(), self.meta_channel, self.channel, &self.pages))?;
+        }
+        drop(meta);
+        if dropped > 0 {
+            log::info!("Dropped {} zeroed pages.", dropped);
+        }
+
+        // Migrate a few pages to the current key while the queue is idle.
+        if self.rekey(self.config.rekey_batch)? > 0 {
+            self.flush_queue()?;
+        }
+
+        // Merge sparse metadata blocks and move the changed ones to the bottom of the channel.
+        let mut meta = self.meta.lock_or_recover();
+        let removed = self.rt.block_on(MetadataBlock::compact(&mut meta, self.backend(), self.meta_channel))?;
+        if removed > 0 {
+            log::info!("Compacted {} metadata blocks.", removed);
+        }
+        if self.should_move_metadata() {
+            // Blocks that didn't change since the last move are still where they were moved to.
+            for block in meta.iter_mut().filter(|block| block.changed) {
+                self.rt.block_on(block.move_to_bottom(self.backend(), self.meta_channel))?;
+            }
+            *self.moved_at.lock_or_recover() = Some(Instant::now());
+        }
+        drop(meta);
+
+        self.roll_over()?;
+        // Moving the blocks changed their message ids.
+        self.sync_superblock()?;
+
+        log::debug!("{}", self.usage());
+
+        Ok(())
+    }
+
+    /// Sends pages that were never uploaded to the next of `ROLLOVER_CHANNEL_IDS` once the channel they go to
+    /// holds `CHANNEL_MESSAGE_LIMIT` pages, and records that channel in the superblock.
+    /// Pages already stored stay in their channel, rewrites replace their message there.
+    fn roll_over(&self) -> Result<()> {
+        let Some(limit) = self.config.channel_message_limit else {
+            return Ok(());
+        };
+
+        let mut stored: HashMap<u64, usize> = HashMap::new();
+        for page in self.meta.lock_or_recover().iter().flat_map(|block| block.pages.iter()) {
+            if page.message_id.is_some() {
+                *stored.entry(page.channel_or(self.channel).0).or_default() += 1;
+            }
+        }
+
+        // Channels are filled in order, the ones before the current channel are full already.
+        let current = self.pages.new_page_channel().unwrap_or(self.channel.0);
+        let next = std::iter::once(self.channel.0)
+            .chain(self.config.rollover_channels.iter().copied())
+            .skip_while(|channel| *channel != current)
+            .find(|channel| stored.get(channel).copied().unwrap_or(0) < limit);
+        let Some(next) = next else {
+            log::warn!("Every channel holds CHANNEL_MESSAGE_LIMIT pages, new pages still go to channel {}.", current);
+            return Ok(());
+        };
+        if next == current {
+            return Ok(());
+        }
+
+        log::warn!("Channel {} holds {} pages (CHANNEL_MESSAGE_LIMIT), new pages go to channel {} from now on.", current, stored.get(&current).copied().unwrap_or(0), next);
+        self.pages.new_pages.store(next, Ordering::Relaxed);
+        let mut superblock = self.superblock.lock_or_recover();
+        if superblock.set_data_channel(next) {
+            self.rt.block_on(superblock.save(self.backend(), self.meta_channel))?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the pages left in the write-ahead log by a previous run (it died before they were synced)
+    /// and flushes them, before anything else can read the drive.
+    fn replay_wal(&self) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let pages = wal.pending()?;
+        if pages.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Replaying {} pages from the write-ahead log.", pages.len());
+        self.rewrite_pages(pages)
+    }
+
+    /// Finishes the metadata updates journaled by a previous run that died after uploading pages (`MASK_JOURNAL_DIR`).
+    /// Pages whose uploaded message exists are pointed at it with the journaled zero mask, so their data and mask agree,
+    /// and the message they replaced is deleted. Entries whose message is gone are rolled back,
+    /// the metadata still points at the replaced message with the old data of their page.
+    fn replay_journal(&self) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let mut entries = journal.pending()?;
+        for (page, _) in &entries {
+            self.load_metadata_for(page.offset)?;
+        }
+
+        // Entries the metadata already has were stored before the crash, only the replaced message may be left.
+        entries.retain(|(page, replaced)| {
+            let stored = self.meta.lock_or_recover().find(page.offset).is_some_and(|stored| stored.message_id == page.message_id);
+            if stored {
+                self.delete_replaced(page, *replaced);
+                journal.remove(page.offset).ok();
+            }
+            !stored
+        });
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Finishing the metadata updates of {} pages from the mask journal.", entries.len());
+        let pages: Vec<Page> = entries.iter().map(|(page, _)| page.clone()).collect();
+        for ((page, replaced), exists) in entries.iter().zip(self.messages_exist(&pages)?) {
+            if !exists {
+                log::warn!("Uploaded message of page {} is gone, keeping its old metadata.", page.offset);
+                journal.remove(page.offset)?;
+                continue;
+            }
+
+            let mut meta = self.meta.lock_or_recover();
+            match meta.block_of(page.offset) {
+                Some(block) => {
+                    self.rt.block_on(block.update_page(self.backend(), &self.meta_channel, page.clone()))?;
+                }
+                None => log::warn!("Page {} is not in the metadata anymore, dropping its journal entry.", page.offset),
+            }
+            drop(meta);
+
+            self.delete_replaced(page, *replaced);
+            journal.remove(page.offset)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the message a journaled upload replaced, now that the metadata points at the upload.
+    fn delete_replaced(&self, page: &Page, replaced: Option<u64>) {
+        if let Some(message_id) = replaced.filter(|_| !self.config.keep_history) {
+            self.rt.block_on(self.pages.deletions.delete(self.backend(), page.channel_or(self.channel), message_id));
+            self.pages.urls.remove(message_id);
+        }
+    }
+
+    /// Writes the pages left in the local tier by a previous run and flushes them, like `replay_wal`.
+    /// Syncing is paused until they are taken, their metadata may not know them yet.
+    fn replay_tier(&self) -> Result<()> {
+        let pages = self.queue.drain_tier();
+        self.queue.resume();
+        let pages = pages?;
+        if pages.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Replaying {} pages from the local tier.", pages.len());
+        self.rewrite_pages(pages.into_iter().map(|(page, data)| (page.offset, data)).collect())
+    }
+
+    /// Writes the data of whole pages (by their offset, as a multiple of the page size) and flushes them.
+    fn rewrite_pages(&self, pages: Vec<(u64, Zeroizing<Vec<u8>>)>) -> Result<()> {
+        let size = self.config.device_size;
+        for (page, data) in pages {
+            let start = page * self.config.page_size as u64;
+            for (i, block) in data.chunks(4096).enumerate() {
+                let offset = start + (i * 4096) as u64;
+                if offset >= size {
+                    break;
+                }
+                // The pages are flushed together below, even with write-through.
+                self.write_with(offset, &block[..block.len().min((size - offset) as usize)], CacheMode::WriteBack, true)?;
+            }
+        }
+
+        let _io = self.lock_idle();
+        self.flush_all()
+    }
+
+    /// Forgets the logged pages, they are synced.
+    fn clear_wal(&self) -> Result<()> {
+        match &self.wal {
+            Some(wal) => wal.clear(),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that every page queued since the last check is recorded in the metadata with an uploaded message.
+    /// A page that is missing means its data never reached discord (or was lost on the way to the metadata).
+    fn check_synced(&self) -> Result<()> {
+        let queued: Vec<u64> = self.queued.lock_or_recover().drain().collect();
+        let mut meta = self.meta.lock_or_recover();
+        let mut missing: Vec<u64> = queued.into_iter()
+            .filter(|offset| meta.find(*offset).is_none_or(|page| page.message_id.is_none()))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        missing.sort_unstable();
+        log::error!("Pages {:?} were synced, but the metadata doesn't record an uploaded message for them", missing);
+        Err(Error::UnsyncedPages { offsets: missing })
+    }
+
+    /// Stores the metadata blocks whose last update failed (or was deferred), so no page synced before the flush
+    /// is missing from discord when it returns. The WAL is only cleared after this.
+    fn save_metadata(&self) -> Result<()> {
+        let mut meta = self.meta.lock_or_recover();
+        for block in meta.iter_mut().filter(|block| block.unsaved) {
+            log::warn!("Metadata block {:?} wasn't stored after its last update, storing it again.", block.message_id);
+            // The flush can't be deferred, a block whose edit fails again is sent as a new message.
+            let fallback = match block.edit_fallback {
+                EditFallback::Defer => EditFallback::Recreate,
+                fallback => fallback,
+            };
+            self.rt.block_on(block.store(self.backend(), &self.meta_channel, fallback))?;
+        }
+        drop(meta);
+
+        self.sync_superblock()
+    }
+
+    /// Returns true if changed metadata blocks should be moved by this flush (`MOVE_METADATA`),
+    /// at most once every `MOVE_METADATA_INTERVAL`.
+    fn should_move_metadata(&self) -> bool {
+        self.config.move_metadata
+            && self.moved_at.lock_or_recover().is_none_or(|at| at.elapsed() >= self.config.move_interval)
+    }
+
+    /// Records which message holds every page under the name, without copying any data.
+    /// Old data messages must be kept (`KEEP_HISTORY`) so the snapshot can be restored later.
+    pub fn take_snapshot(&self, name: &str) -> Result<Snapshot> {
+        if !self.pages.keep_history {
+            return Err(Error::SnapshotWithoutHistory);
+        }
+        let mut snapshot = Snapshot::new(name, Vec::new())?;
+        snapshot.volume = self.config.volume.clone();
+
+        let _io = self.lock_idle();
+        self.flush_all()?;
+        self.load_metadata_where(|_| true)?;
+
+        snapshot.pages = self.meta.lock_or_recover().iter()
+            .flat_map(|block| block.pages.iter().cloned())
+            .collect();
+        snapshot.pages.sort_by_key(|page| page.offset);
+        self.rt.block_on(snapshot.save(self.backend(), self.meta_channel))?;
+
+        log::info!("Took snapshot {:?} of {} pages.", snapshot.name, snapshot.pages.len());
+        Ok(snapshot)
+    }
+
+    /// Points the metadata back at the pages of the newest snapshot with the name.
+    /// Pages written since then are dropped from the metadata (their messages are kept).
+    pub fn restore_snapshot(&self, name: &str) -> Result<()> {
+        let _io = self.lock_idle();
+        self.flush_all()?;
+
+        let snapshot = self.rt.block_on(Snapshot::find(self.backend(), self.meta_channel, &self.config.volume, name))?
+            .ok_or_else(|| Error::SnapshotNotFound { name: name.to_string() })?;
+        let count = snapshot.pages.len();
+        self.restore(snapshot)?;
+
+        log::info!("Restored snapshot {:?} of {} pages.", name, count);
+        Ok(())
+    }
+
+    /// Replaces the metadata with the pages of the snapshot.
+    /// Must be called while holding `io`, after a flush.
+    fn restore(&self, snapshot: Snapshot) -> Result<()> {
+        self.load_metadata_where(|_| true)?;
+
+        // Cached pages may be newer than the snapshot.
+        self.cache.data.lock_or_recover().clear();
+
+        let mut pages = snapshot.pages.into_iter().peekable();
+        let mut meta = self.meta.lock_or_recover();
+        for block in meta.iter_mut() {
+            block.pages = pages.by_ref().take(PAGES_PER_BLOCK).collect();
+            self.rt.block_on(block.update_message(self.backend(), &self.meta_channel))?;
+        }
+        while pages.peek().is_some() {
+            let mut block = MetadataBlock { pages: pages.by_ref().take(PAGES_PER_BLOCK).collect(), ..self.new_block() };
+            self.rt.block_on(block.update_message(self.backend(), &self.meta_channel))?;
+            meta.push(block);
+        }
+
+        // Blocks that were left empty are deleted.
+        self.rt.block_on(MetadataBlock::compact(&mut meta, self.backend(), self.meta_channel))?;
+        drop(meta);
+        self.sync_superblock()
+    }
+
+    /// Channel the metadata backups are sent to (`METADATA_BACKUP_CHANNEL`).
+    fn backup_channel(&self) -> ChannelId {
+        self.config.backup_channel.map_or(self.meta_channel, ChannelId)
+    }
+
+    /// Saves a backup of the whole metadata if `METADATA_BACKUP_INTERVAL` passed since the last one.
+    /// The previous backup is deleted if it was taken since the drive was opened,
+    /// so the state from before the drive was opened stays restorable.
+    /// Must be called while holding `io`, after a flush.
+    fn backup_metadata(&self) -> Result<()> {
+        let Some(interval) = self.config.backup_interval else {
+            return Ok(());
+        };
+        let mut backed_up = self.backed_up.lock_or_recover();
+        if backed_up.0.elapsed() < interval {
+            return Ok(());
+        }
+
+        self.load_metadata_where(|_| true)?;
+        let mut pages: Vec<Page> = self.meta.lock_or_recover().iter()
+            .flat_map(|block| block.pages.iter().cloned())
+            .collect();
+        pages.sort_by_key(|page| page.offset);
+
+        let mut backup = Snapshot::new(BACKUP_NAME, pages)?;
+        backup.volume = self.config.volume.clone();
+        let channel = self.backup_channel();
+        self.rt.block_on(backup.save(self.backend(), channel))?;
+
+        for message_id in std::mem::replace(&mut *backed_up, (Instant::now(), backup.message_ids)).1 {
+            self.rt.block_on(self.backend().delete_message(channel, message_id)).ok();
+        }
+        Ok(())
+    }
+
+    /// Replaces the metadata with the newest backup (`RESTORE_METADATA_BACKUP`).
+    pub fn restore_backup(&self) -> Result<()> {
+        let _io = self.lock_idle();
+        self.flush_all()?;
+
+        let backup = self.rt.block_on(Snapshot::find(self.backend(), self.backup_channel(), &self.config.volume, BACKUP_NAME))?
+            .ok_or_else(|| Error::SnapshotNotFound { name: BACKUP_NAME.to_string() })?;
+        let count = backup.pages.len();
+        self.restore(backup)?;
+
+        log::info!("Restored the metadata backup of {} pages.", count);
+        Ok(())
+    }
+
+    /// Points at the newest backup if the drive has no metadata at all, it may have been lost.
+    fn offer_backup(&self) -> Result<()> {
+        if !self.meta.lock_or_recover().is_empty() || !self.unloaded.lock_or_recover().is_empty() {
+            return Ok(());
+        }
+
+        let backup = self.rt.block_on(Snapshot::find(self.backend(), self.backup_channel(), &self.config.volume, BACKUP_NAME))?;
+        if let Some(backup) = backup.filter(|backup| !backup.pages.is_empty()) {
+            log::info!("No metadata found, but there is a backup of {} pages. Set RESTORE_METADATA_BACKUP=true to restore it.", backup.pages.len());
+        }
+        Ok(())
+    }
+
+    /// Queues up to `limit` pages that are encrypted with an old key, so they get
+    /// uploaded again with the current one. Returns the number of queued pages.
+    /// Must only be called while nothing else is syncing, otherwise a page could
+    /// be overwritten with its older data, so it is left to `flush_all`.
+    fn rekey(&self, limit: usize) -> Result<usize> {
+        let current = self.pages.keyring.current();
+        let stale: Vec<Page> = self.meta.lock_or_recover().iter()
+            .flat_map(|block| block.pages.iter())
+            .filter(|page| page.message_id.is_some() && page.key_id != current)
+            .filter(|page| self.cache.get(page.offset).is_none())
+            .take(limit)
+            .cloned()
+            .collect();
+
+        for page in stale.iter() {
+            let _memory = self.rt.block_on(self.pages.memory.hold_download());
+            let data = self.rt.block_on(page.read(&self.channel, self.backend(), &self.pages))?;
+            self.queue.push(page.clone(), data);
+        }
+
+        Ok(stale.len())
+    }
+
+    /// Uploads the cached page right away and waits until it is synced.
+    fn write_through(&self, offset: u64) -> Result<()> {
+        if let Some(block) = self.cache.get(offset) {
+            self.enqueue(block);
+        }
+
+        self.flush_queue()?;
+        self.clear_wal()?;
+
+        // The page was uploaded as a new message, keep the cached block pointing at it.
+        if let Some(page) = self.meta.lock_or_recover().find(offset) {
+            self.cache.mark_synced(offset, page.message_id);
+        }
+
+        Ok(())
+    }
+
+    fn write_cached(&self, io: &MutexGuard<'_, ()>, offset: u64, data: &[u8]) -> Result<()> {
+        // Try to write to cache first.
+        if self.write_cache(io, offset, data) {
+            return Ok(());
+        }
+
+        self.load_metadata_for(self.page_of(offset))?;
+        let mut meta = self.meta.lock_or_recover();
+
+        // Write the page where it already is, a new page goes to the first block with room for it.
+        let written = match meta.block_of(self.page_of(offset)) {
+            Some(block) => self.rt.block_on(block.try_write(&self.channel, &self.meta_channel, self.backend(), &self.pages, offset, data))?,
+            None => None,
+        };
+        if let Some((data, page)) = written {
+            drop(meta);
+            self.cache(CacheBlock { dirty: true, ..CacheBlock::new(page.offset, page.message_id, data, page.zero_mask) });
+            return Ok(());
+        }
+
+        // The page is new, the pages of every block have to be known to count them.
+        if self.config.max_data_messages.is_some() && !self.page_count(&meta).1 {
+            drop(meta);
+            self.load_metadata_where(|_| true)?;
+            return self.write_cached(io, offset, data);
+        }
+        self.check_storage(&meta, 1)?;
+
+        for block in meta.iter_mut() {
+            if let Some(data) = self.rt.block_on(async {
+                block.try_write(&self.channel, &self.meta_channel, self.backend(), &self.pages, offset, data).await
+            })? {
+                // Drop the lock to prevent deadlock on the same thread.
+                drop(meta);
+
+                // Cache the data.
+                self.cache(CacheBlock { dirty: true, ..CacheBlock::new(self.page_of(offset), data.1.message_id, data.0, data.1.zero_mask) });
+
+                // The page may be new in this block.
+                self.sync_superblock()?;
+
+                // Return.
+                return Ok(());
+            }
+        }
+
+        // Drop the lock to prevent deadlock on the same thread.
+        drop(meta);
+
+        let limit = self.config.metadata_block_limit();
+        if self.meta.lock_or_recover().len() + self.unloaded.lock_or_recover().len() >= limit {
+            // Blocks that are not loaded yet may still have room for the page.
+            if !self.unloaded.lock_or_recover().is_empty() {
+                self.load_metadata_where(|_| true)?;
+                return self.write_cached(io, offset, data);
+            }
+
+            return Err(Error::DeviceFull { blocks: limit });
+        }
+
+        let mut block = self.new_block();
+
+        if let Some(data) = self.rt.block_on(async {
+            block.try_write(&self.channel, &self.meta_channel, self.backend(), &self.pages, offset, data).await
+        })? {
+            // Cache the data.
+            self.cache(CacheBlock { dirty: true, ..CacheBlock::new(self.page_of(offset), data.1.message_id, data.0, data.1.zero_mask) });
+        }
+
+        // Acquire the lock again.
+        let mut meta = self.meta.lock_or_recover();
+
+        meta.push(block);
+        drop(meta);
+
+        self.sync_superblock()?;
+
+        println!("Created new metadata block at offset {}", offset);
+
+        Ok(())
+    }
+}
+
+/// Uploads the queued pages and stops the sync thread, so closed drives don't leak it.
+/// Pages that are only cached are not uploaded, nbdkit flushes before closing.
+impl Drop for DiscordDrivePlugin {
+    fn drop(&mut self) {
+        if let Err(e) = self.queue.shutdown(self.config.flush_timeout) {
+            log::warn!("Closing the drive with unsynced pages: {}", e);
+        }
+
+        // Nothing reads old messages anymore.
+        self.rt.block_on(self.pages.deletions.run_all(self.backend()));
+    }
+}
+
+/// Default implementation of the plugin.
+impl DiscordDrivePlugin {
+    /// Creates the plugin from the configuration in `.env`.
+    pub fn from_env() -> Result<Self> {
+        env_logger::try_init().ok();
+
+        let config = Config::from_env()?;
+
+        let root_certificate = config.root_certificate.as_ref()
+            .map(|path| std::fs::read(path).map_err(|e| BackendError::Other(format!("Failed to read root certificate {}: {}", path, e))))
+            .transpose()?;
+        let downloads = backend::download_client(config.download_proxy.as_deref(), root_certificate.as_deref())?;
+
+        let token = config.bot_token()?;
+        let backends: Vec<Arc<dyn Backend>> = std::iter::once(token.as_str())
+            .chain(config.extra_tokens.iter().map(String::as_str))
+            .map(|token| -> Arc<dyn Backend> {
+                let backend = TimeoutBackend::new(DiscordBackend::with_download_client(token, downloads.clone()), config.request_timeout);
+
+                // The global limit is per token, retries wait for it too.
+                match config.global_rate_limit {
+                    0 => Arc::new(ReconnectingBackend::new(backend, config.reconnect_attempts, config.reconnect_backoff)
+                        .with_policy(config.retry_policy.clone())),
+                    rate => Arc::new(ReconnectingBackend::new(
+                        RateLimitedBackend::new(backend, rate),
+                        config.reconnect_attempts,
+                        config.reconnect_backoff,
+                    ).with_policy(config.retry_policy.clone())),
+                }
+            })
+            .collect();
+
+        let backend: Arc<dyn Backend> = if backends.len() > 1 {
+            Arc::new(RotatingBackend::new(backends))
+        } else {
+            backends[0].clone()
+        };
+
+        let backend: Arc<dyn Backend> = if config.trace_discord {
+            Arc::new(TracingBackend::new(backend))
+        } else {
+            backend
+        };
+
+        let backend: Arc<dyn Backend> = if config.dry_run {
+            Arc::new(DryRunBackend::new(backend))
+        } else {
+            backend
+        };
+
+        let channel = ChannelId(config::parse("FS_CHANNEL_ID", Some(env!("FS_CHANNEL_ID")), 0)?);
+
+        Self::new(backend, channel, config)
+    }
+}
+
+/// Implementation of the plugin.
+impl Server for DiscordDrivePlugin {
+    fn get_size(&self) -> nbdkit::Result<i64> {
+        Ok(self.config.device_size as i64)
+    }
+
+    fn name() -> &'static str where Self: Sized {
+        "discorddrive"
+    }
+
+    fn open(_readonly: bool) -> nbdkit::Result<Box<dyn Server>> where Self: Sized {
+        Ok(Box::new(Self::from_env()?))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> nbdkit::Result<()> {
+        let size = self.config.device_size;
+        if offset + buf.len() as u64 > size {
+            return Err(Error::OutOfBounds { offset, len: buf.len(), size }.into());
+        }
+
+        // The request is read a 4KB block at a time, blocks never reach over the end of a page.
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let block = position - position % 4096;
+            let data = self.read(block)?;
+            let start = (position - block) as usize;
+            let len = (data.len() - start).min(buf.len() - done);
+            buf[done..done + len].copy_from_slice(&data[start..start + len]);
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64, _flags: nbdkit::Flags) -> nbdkit::Result<()> {
+        self.write(offset, buf)?;
+
+        Ok(())
+    }
+
+    fn flush(&self) -> nbdkit::Result<()> {
+        let _io = self.lock_idle();
+        self.flush_all()?;
+        self.backup_metadata()?;
+
+        Ok(())
+    }
+
+    /// Zeros may only become zeroed blocks when the client allows holes (it didn't set NO_HOLE).
+    fn zero(&self, count: u32, offset: u64, flags: nbdkit::Flags) -> nbdkit::Result<()> {
+        self.write_zeroes(offset, count as u64, flags.contains(nbdkit::Flags::MAY_TRIM))?;
+
+        Ok(())
+    }
+
+    fn can_cache(&self) -> nbdkit::Result<nbdkit::CacheFlags> {
+        Ok(nbdkit::CacheFlags::Native)
+    }
+
+    fn cache(&self, count: u32, offset: u64) -> nbdkit::Result<()> {
+        self.prefetch(offset, count as u64)?;
+
+        Ok(())
+    }
+}
 
 // Entry point for the plugin.
-nbdkit::plugin!(DiscordDrivePlugin { write_at, flush });
\ No newline at end of file
+nbdkit::plugin!(DiscordDrivePlugin { write_at, flush, zero, can_cache, cache });
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::crypto::Keyring;
+    use std::time::Duration;
+
+    const CHANNEL: ChannelId = ChannelId(1);
+    const PAGE: u64 = 1024 * 1024 * 8;
+
+    /// Log lines of all tests, captured once a test calls `capture_logs`.
+    static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGS.lock_or_recover().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        log::set_logger(&CaptureLogger).ok();
+        log::set_max_level(log::LevelFilter::Debug);
+    }
+
+    /// Creates a drive with two metadata blocks, holding pages 0 and 1 respectively.
+    fn two_block_drive(backend: &MemoryBackend) {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let mut blocks = Vec::new();
+            for offset in 0..2 {
+                let mut block = MetadataBlock::empty(None);
+                block.pages.push(Page::new(offset));
+                block.update_message(backend, &CHANNEL).await.unwrap();
+                blocks.extend(SuperblockEntry::of(&block));
+            }
+
+            let mut superblock = Superblock::empty();
+            superblock.set_blocks(blocks);
+            superblock.save(backend, CHANNEL).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn lazily_loads_relevant_block() {
+        let backend = Arc::new(MemoryBackend::new());
+        two_block_drive(&backend);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        assert_eq!(plugin.meta.lock_or_recover().len(), 0);
+        assert_eq!(backend.calls("get_message"), 0);
+
+        assert_eq!(plugin.read(PAGE + 4096).unwrap(), vec![0; 4096]);
+
+        let meta = plugin.meta.lock_or_recover();
+        assert_eq!(meta.len(), 1);
+        assert_eq!(meta[0].pages[0].offset, 1);
+        assert_eq!(backend.calls("get_message"), 1);
+    }
+
+    #[test]
+    fn updates_metadata_blocks_concurrently() {
+        for (updates, overlapping) in [(1, 1), (2, 2)] {
+            let backend = Arc::new(MemoryBackend::new());
+            two_block_drive(&backend);
+            let config = Config { max_metadata_updates: updates, ..Config::default() };
+            let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+            // Both pages are uploaded in one batch, their blocks are edited while the other one still is.
+            backend.set_edit_latency(Duration::from_millis(300));
+            plugin.pause_sync();
+            plugin.write(0, &[1; 4096]).unwrap();
+            plugin.write(PAGE, &[2; 4096]).unwrap();
+            plugin.enqueue_dirty();
+            plugin.resume_sync();
+            plugin.flush().unwrap();
+            assert_eq!(backend.max_concurrent_edits(), overlapping);
+            assert!(plugin.meta.lock_or_recover().iter().all(|block| !block.unsaved));
+            drop(plugin);
+
+            let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+            assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+            assert_eq!(plugin.read(PAGE).unwrap(), vec![2; 4096]);
+        }
+    }
+
+    #[test]
+    fn metadata_updates_do_not_hold_the_metadata_lock() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { dirty_limit: Some(0), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        // The metablock of the synced page is edited while the metadata is looked at.
+        backend.set_edit_latency(Duration::from_millis(500));
+        let edits = backend.calls("edit_message");
+        plugin.write(0, &[2; 4096]).unwrap();
+        let started = Instant::now();
+        while backend.calls("edit_message") == edits {
+            assert!(started.elapsed() < Duration::from_secs(10), "the metablock was not edited");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let started = Instant::now();
+        drop(plugin.meta.lock_or_recover());
+        assert!(started.elapsed() < Duration::from_millis(200), "waited {:?} for the metadata lock", started.elapsed());
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![2; 4096]);
+    }
+
+    #[test]
+    fn prefetches_page_urls() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        for page in 0..4 {
+            plugin.write(PAGE * page, &[page as u8 + 1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+        drop(plugin);
+
+        let config = Config { eager_metadata: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        let fetched = backend.calls("get_message");
+
+        for page in 0..4 {
+            assert_eq!(plugin.read(PAGE * page).unwrap(), vec![page as u8 + 1; 4096]);
+        }
+        assert_eq!(backend.calls("get_message"), fetched);
+    }
+
+    #[test]
+    fn uploads_dirty_pages_before_cache_is_full() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { dirty_limit: Some(1), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        for page in 0..3 {
+            plugin.write(PAGE * page, &[page as u8 + 1; 4096]).unwrap();
+        }
+        assert!(!plugin.cache.is_full());
+        assert_eq!(plugin.cache.dirty_len(), 1);
+
+        // The sync thread picks up the queued pages without a flush.
+        let started = std::time::Instant::now();
+        while data_pages(&backend) < 2 {
+            assert!(started.elapsed() < Duration::from_secs(10), "queued pages were not uploaded");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        for page in 0..3 {
+            assert_eq!(plugin.read(PAGE * page).unwrap(), vec![page as u8 + 1; 4096]);
+        }
+    }
+
+    #[test]
+    fn flush_skips_clean_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let uploads = backend.calls("send_file");
+
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        plugin.flush().unwrap();
+        assert_eq!(backend.calls("send_file"), uploads);
+
+        plugin.write(4096, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(backend.calls("send_file"), uploads + 1);
+        assert_eq!(plugin.read(4096).unwrap(), vec![2; 4096]);
+    }
+
+    #[test]
+    fn verify_reports_damaged_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        for page in 0..3 {
+            plugin.write(PAGE * page, &[page as u8 + 1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+
+        let pages: Vec<Page> = plugin.meta.lock_or_recover().iter().flat_map(|block| block.pages.clone()).collect();
+        let message_of = |offset| pages.iter().find(|page| page.offset == offset).unwrap().message_id.unwrap();
+        drop(plugin);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let url = rt.block_on(backend.get_message(CHANNEL, message_of(1))).unwrap().attachments[0].clone();
+        backend.replace_attachment(&url, vec![0; 100]);
+        rt.block_on(backend.delete_message(CHANNEL, message_of(2))).unwrap();
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        let uploads = (backend.calls("send_file"), backend.calls("edit_message"));
+        let verification = plugin.verify().unwrap();
+
+        assert_eq!(verification.healthy, 1);
+        assert_eq!(verification.damaged.len(), 2);
+        assert_eq!(verification.damaged[0].offset, 1);
+        assert!(matches!(verification.damaged[0].damage, PageDamage::Corrupted(_)));
+        assert_eq!(verification.damaged[1], DamagedPage { offset: 2, message_id: message_of(2), damage: PageDamage::Missing });
+        assert_eq!((backend.calls("send_file"), backend.calls("edit_message")), uploads);
+        assert!(verification.to_string().ends_with("3 pages verified, 1 healthy, 2 damaged"));
+    }
+
+    #[test]
+    fn startup_scan_flags_missing_messages() {
+        capture_logs();
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        for page in 0..2 {
+            plugin.write(PAGE * page, &[1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+        let missing = plugin.meta.lock_or_recover().find(1).unwrap().message_id.unwrap();
+        drop(plugin);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(backend.delete_message(CHANNEL, missing)).unwrap();
+
+        // Only the messages scan fetches the data messages.
+        let config = Config { startup_scan: StartupScan::Metadata, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert!(plugin.check_integrity(false).unwrap().is_clean());
+        drop(plugin);
+
+        let config = Config { startup_scan: StartupScan::Messages, ..Config::default() };
+        DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        let warning = format!("Stored metadata is damaged: page 1 points at message {}, which doesn't exist", missing);
+        assert!(LOGS.lock_or_recover().contains(&warning));
+
+        // A page pointing at a metadata message is flagged without fetching anything.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.load_metadata_for(0).unwrap();
+        let mut meta = plugin.meta.lock_or_recover();
+        let block = meta.block_of(0).unwrap();
+        let block_id = block.message_id.unwrap();
+        block.pages.iter_mut().find(|page| page.offset == 0).unwrap().message_id = Some(block_id);
+        rt.block_on(block.update_message(plugin.backend(), &CHANNEL)).unwrap();
+        drop(meta);
+
+        let integrity = plugin.check_integrity(true).unwrap();
+        assert_eq!(integrity.problems, vec![
+            IntegrityProblem::ReservedMessage { offset: 0, message_id: block_id },
+            IntegrityProblem::MissingMessage { offset: 1, message_id: missing },
+        ]);
+        assert!(integrity.to_string().ends_with("1 metadata blocks with 2 pages checked, 2 problems"));
+    }
+
+    #[test]
+    fn reconcile_drops_pages_of_deleted_messages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        for page in 0..3 {
+            plugin.write(PAGE * page, &[page as u8 + 1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+        assert_eq!(plugin.reconcile().unwrap(), Reconciliation { pages: 3, ..Reconciliation::default() });
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let deleted = plugin.meta.lock_or_recover().find(1).cloned().unwrap();
+        rt.block_on(backend.delete_message(CHANNEL, deleted.message_id.unwrap())).unwrap();
+        let block_id = plugin.meta.lock_or_recover()[0].message_id.unwrap();
+        rt.block_on(backend.delete_message(CHANNEL, block_id)).unwrap();
+
+        let reconciliation = plugin.reconcile().unwrap();
+        assert_eq!(reconciliation, Reconciliation { pages: 3, dropped: vec![1], resent_blocks: 1, ..Reconciliation::default() });
+        assert!(plugin.meta.lock_or_recover().find(1).is_none());
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(PAGE * 2).unwrap(), vec![3; 4096]);
+
+        // The resent block is found through the superblock.
+        let reopened = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        reopened.load_metadata_for(0).unwrap();
+        assert_ne!(reopened.meta.lock_or_recover()[0].message_id, Some(block_id));
+        assert_eq!(reopened.dump_layout().pages.iter().map(|page| page.offset).collect::<Vec<_>>(), vec![0, 2]);
+        drop(reopened);
+
+        // A message that shows up again is adopted, as if the channel was only hidden for a while.
+        let mut returned = Page { message_id: None, ..deleted };
+        rt.block_on(returned.update_message(plugin.backend(), &CHANNEL, &plugin.pages, &[2; PAGE as usize])).unwrap();
+        plugin.vanished.lock_or_recover().insert(1, returned);
+        assert_eq!(plugin.reconcile().unwrap().adopted, vec![1]);
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![2; 4096]);
+        assert!(plugin.vanished.lock_or_recover().is_empty());
+    }
+
+    #[test]
+    fn read_repair_rewrites_damaged_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { read_repair: ReadRepair::Copy, cache_ttl: Some(Duration::ZERO), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+
+        let message_of = |plugin: &DiscordDrivePlugin, offset| plugin.meta.lock_or_recover().find(offset).unwrap().message_id.unwrap();
+        let (damaged, missing) = (message_of(&plugin, 0), message_of(&plugin, 1));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let url = rt.block_on(backend.get_message(CHANNEL, damaged)).unwrap().attachments[0].clone();
+        backend.replace_attachment(&url, vec![0; 100]);
+
+        // The expired cached copy is not served, but it is what the page is uploaded from.
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.status().repaired_pages, 1);
+        plugin.flush().unwrap();
+        assert_ne!(message_of(&plugin, 0), damaged);
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert!(plugin.verify().unwrap().is_healthy());
+        drop(plugin);
+
+        // Without a copy the read fails, unless the page may be replaced with zeros.
+        rt.block_on(backend.delete_message(CHANNEL, missing)).unwrap();
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        assert!(matches!(plugin.read(PAGE), Err(Error::MissingPage { .. })));
+        drop(plugin);
+
+        let config = Config { read_repair: ReadRepair::Zeros, ..config };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![0; 4096]);
+        plugin.flush().unwrap();
+        assert!(plugin.verify().unwrap().is_healthy());
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+    }
+
+    #[test]
+    fn lost_attachments_are_uploaded_again() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { reupload_lost_attachments: true, cache_ttl: Some(Duration::ZERO), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+
+        let message_of = |plugin: &DiscordDrivePlugin, offset| plugin.meta.lock_or_recover().find(offset).unwrap().message_id.unwrap();
+        let (resident, gone) = (message_of(&plugin, 0), message_of(&plugin, 1));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        for message_id in [resident, gone] {
+            let url = rt.block_on(backend.get_message(CHANNEL, message_id)).unwrap().attachments[0].clone();
+            backend.remove_attachment(&url);
+        }
+
+        // The page still in the cache is uploaded again from it, its message exists, only the attachment is gone.
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.status().repaired_pages, 1);
+        plugin.flush().unwrap();
+        assert_ne!(message_of(&plugin, 0), resident);
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        drop(plugin);
+
+        // Without a copy the page is lost, which the status and the integrity check report.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        assert!(matches!(plugin.read(PAGE), Err(Error::MissingAttachment { offset: 1, .. })));
+        assert_eq!(plugin.lost_pages(), vec![(1, gone)]);
+        assert_eq!(plugin.status().lost_pages, 1);
+        let integrity = plugin.check_integrity(false).unwrap();
+        assert_eq!(integrity.problems, vec![IntegrityProblem::LostAttachment { offset: 1, message_id: gone }]);
+
+        drop(plugin);
+
+        let config = Config { read_repair: ReadRepair::Zeros, ..config };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![0; 4096]);
+        plugin.flush().unwrap();
+        assert!(plugin.lost_pages().is_empty());
+        assert!(plugin.verify().unwrap().is_healthy());
+    }
+
+    #[test]
+    fn dry_run_doesnt_touch_discord() {
+        let backend = Arc::new(MemoryBackend::new());
+        let dry_run = Arc::new(DryRunBackend::new(backend.clone()));
+        let plugin = DiscordDrivePlugin::new(dry_run.clone(), CHANNEL, Config::default()).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+
+        // Evicting the page from the cache and reading it again works as well.
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+
+        for operation in ["send_message", "send_file", "edit_message", "delete_message", "pin_message"] {
+            assert_eq!(backend.calls(operation), 0, "{} reached the backend", operation);
+        }
+        assert!(backend.messages(CHANNEL).is_empty());
+
+        let operations = dry_run.operations();
+        assert!(operations.iter().any(|operation| operation.starts_with("send_file")));
+        assert!(operations.iter().any(|operation| operation.starts_with("pin_message")));
+    }
+
+    #[test]
+    fn uses_configured_thread() {
+        const THREAD: ChannelId = ChannelId(2);
+
+        let backend = Arc::new(MemoryBackend::new());
+        backend.archive_thread(THREAD);
+
+        let config = Config { thread_id: Some(THREAD.0), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(4096, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        assert_eq!(plugin.read(4096).unwrap(), vec![1; 4096]);
+
+        assert!(backend.messages(CHANNEL).is_empty());
+        let thread = backend.messages(THREAD);
+        assert!(thread.iter().any(|m| m.content.starts_with("METABLOCK")));
+        assert!(thread.iter().any(|m| !m.attachments.is_empty()));
+    }
+
+    fn data_pages(backend: &MemoryBackend) -> usize {
+        backend.messages(CHANNEL).iter().filter(|m| !m.attachments.is_empty()).count()
+    }
+
+    #[test]
+    fn preallocates_pages_without_uploading() {
+        let backend = Arc::new(MemoryBackend::new());
+        let page = 16 * 4096;
+        let config = Config { page_size: page as usize, zero_block_size: Some(4096), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.write(page, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        // Only the pages that don't exist yet are added.
+        assert_eq!(plugin.preallocate(4096, 7 * page).unwrap(), 7);
+        assert_eq!(plugin.preallocate(0, page).unwrap(), 0);
+        assert_eq!(plugin.meta.lock_or_recover().iter().map(|block| block.pages.len()).sum::<usize>(), 8);
+        assert_eq!(plugin.read(3 * page).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(page).unwrap(), vec![1; 4096]);
+
+        // Reserved pages survive flushes and reopening without any data message.
+        plugin.flush().unwrap();
+        drop(plugin);
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.preallocate(0, 8 * page).unwrap(), 0);
+        // Only the written page was ever downloaded.
+        assert_eq!(data_pages(&backend), 1);
+        assert_eq!(backend.calls("download"), 1);
+
+        plugin.write(5 * page + 4096, &[5; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(data_pages(&backend), 2);
+        let written = plugin.meta.lock_or_recover().find(5).cloned().unwrap();
+        assert!(written.message_id.is_some());
+        assert!(!written.zero_mask.get(1));
+        assert!(written.zero_mask.get(0));
+        assert_eq!(plugin.read(5 * page + 4096).unwrap(), vec![5; 4096]);
+        assert_eq!(plugin.read(5 * page).unwrap(), vec![0; 4096]);
+    }
+
+    #[test]
+    fn relocates_pages_between_channels() {
+        const OTHER: ChannelId = ChannelId(3);
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        let page_of = |plugin: &DiscordDrivePlugin| plugin.meta.lock_or_recover().find(0).cloned().unwrap();
+        let old = page_of(&plugin).message_id.unwrap();
+        plugin.relocate_page(0, OTHER).unwrap();
+
+        let moved = page_of(&plugin);
+        assert_eq!(moved.channel, Some(OTHER.0));
+        assert_eq!(backend.messages(OTHER).iter().map(|message| message.id).collect::<Vec<_>>(), vec![moved.message_id.unwrap()]);
+        assert!(!backend.messages(CHANNEL).iter().any(|message| message.id == old));
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert!(matches!(plugin.relocate_page(5 * PAGE, OTHER), Err(Error::NoPage { offset: 5 })));
+        drop(plugin);
+
+        // Reads follow the metadata to the other channel, and rewrites of the page stay there.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![2; 4096]);
+        plugin.write(4096, &[3; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let rewritten = page_of(&plugin);
+        assert_eq!(rewritten.channel, Some(OTHER.0));
+        assert_eq!(backend.messages(OTHER).iter().map(|message| message.id).collect::<Vec<_>>(), vec![rewritten.message_id.unwrap()]);
+        drop(plugin);
+
+        // Moving it back stores it in the channel of the drive like any other page.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        assert_eq!(plugin.read(4096).unwrap(), vec![3; 4096]);
+        plugin.relocate_page(0, CHANNEL).unwrap();
+        assert_eq!(page_of(&plugin).channel, None);
+        assert!(backend.messages(OTHER).is_empty());
+        drop(plugin);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(4096).unwrap(), vec![3; 4096]);
+    }
+
+    #[test]
+    fn rolls_over_to_next_channel_when_full() {
+        const NEXT: ChannelId = ChannelId(3);
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { channel_message_limit: Some(2), rollover_channels: vec![NEXT.0], ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.superblock.lock_or_recover().data_channel, Some(NEXT.0));
+
+        // New pages go to the next channel, the full one keeps its pages.
+        plugin.write(2 * PAGE, &[3; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let page_of = |plugin: &DiscordDrivePlugin, offset| plugin.meta.lock_or_recover().find(offset).cloned().unwrap();
+        assert_eq!(page_of(&plugin, 2).channel, Some(NEXT.0));
+        assert_eq!(backend.messages(NEXT).iter().map(|message| message.id).collect::<Vec<_>>(), vec![page_of(&plugin, 2).message_id.unwrap()]);
+        assert_eq!(page_of(&plugin, 0).channel, None);
+        drop(plugin);
+
+        // The superblock remembers the channel, and pages of both channels stay readable.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.pages.new_page_channel(), Some(NEXT.0));
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.read(2 * PAGE).unwrap(), vec![3; 4096]);
+
+        // Once every channel is full, new pages stay in the last one.
+        plugin.write(3 * PAGE, &[4; 4096]).unwrap();
+        plugin.write(4 * PAGE, &[5; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(backend.messages(NEXT).len(), 3);
+        assert_eq!(plugin.read(4 * PAGE).unwrap(), vec![5; 4096]);
+    }
+
+    #[test]
+    fn swaps_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let page = 16 * 4096;
+        let config = Config { page_size: page as usize, zero_block_size: Some(4096), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(page + 8192, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let edits = backend.calls("edit_message");
+        let uploads = backend.calls("send_file");
+
+        plugin.swap_pages(0, page).unwrap();
+        // The metadata message is updated once and no data is moved.
+        assert_eq!(backend.calls("edit_message"), edits + 1);
+        assert_eq!(backend.calls("send_file"), uploads);
+        assert_eq!(plugin.read(8192).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.read(0).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(page).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(page + 8192).unwrap(), vec![0; 4096]);
+
+        assert!(matches!(plugin.swap_pages(0, 5 * page), Err(Error::NoPage { offset: 5 })));
+    }
+
+    #[test]
+    fn restores_pages_if_swap_fails() {
+        let backend = Arc::new(MemoryBackend::new());
+        let page = 16 * 4096;
+        let config = || Config { page_size: page as usize, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config()).unwrap();
+        // The first and the last page are in different metadata blocks.
+        let other = PAGES_PER_BLOCK as u64 * page;
+        for offset in (0..=other).step_by(page as usize) {
+            plugin.write(offset, &[if offset == other { 2 } else { 1 }; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+
+        // The first block is edited, the edit of the second one fails.
+        backend.fail_after("edit_message", 1);
+        assert!(plugin.swap_pages(0, other).is_err());
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(other).unwrap(), vec![2; 4096]);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config()).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(other).unwrap(), vec![2; 4096]);
+    }
+
+    #[test]
+    fn write_through_persists_immediately() {
+        let backend = Arc::new(MemoryBackend::new());
+
+        let config = Config { cache_mode: CacheMode::WriteThrough, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        assert_eq!(data_pages(&backend), 1);
+
+        // Writing again replaces the uploaded message.
+        plugin.write(4096, &[2; 4096]).unwrap();
+        assert_eq!(data_pages(&backend), 1);
+
+        let meta = plugin.meta.lock_or_recover();
+        let page = &meta[0].pages[0];
+        assert!(page.message_id.is_some());
+        assert_eq!(plugin.cache.get(0).unwrap().message_id, page.message_id);
+    }
+
+    #[test]
+    fn gives_up_after_sync_attempts() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { sync_attempts: Some(2), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        backend.disconnect();
+
+        // Every upload fails, the flush gives up instead of waiting forever.
+        assert!(plugin.flush().is_err());
+        assert_eq!(backend.calls("send_file"), 2);
+        assert!(plugin.queue.is_empty());
+
+        // The failure is only reported once.
+        plugin.rt.block_on(backend.reconnect()).unwrap();
+        plugin.flush().unwrap();
+    }
+
+    #[test]
+    fn flush_waits_for_metadata_of_synced_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { page_size: 16 * 4096, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        for page in 0..8 {
+            plugin.write(page * 16 * 4096, &[page as u8 + 1; 4096]).unwrap();
+        }
+
+        // Uploads are slow and the first metadata update of the sync thread fails.
+        backend.set_upload_latency(Duration::from_millis(50));
+        backend.panic_once("edit_message");
+        plugin.flush().unwrap();
+        assert!(plugin.meta.lock_or_recover().iter().all(|block| !block.unsaved));
+
+        // A drive opened right after the flush sees every synced page.
+        let reopened = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        for page in 0..8 {
+            let stored = reopened.locate(page * 16 * 4096).unwrap().unwrap().message_id;
+            assert!(stored.is_some());
+            assert_eq!(stored, plugin.locate(page * 16 * 4096).unwrap().unwrap().message_id);
+        }
+
+        // A block whose last update failed is stored by the next flush.
+        plugin.meta.lock_or_recover()[0].unsaved = true;
+        let edits = backend.calls("edit_message");
+        plugin.flush().unwrap();
+        assert_eq!(backend.calls("edit_message"), edits + 1);
+        assert!(!plugin.meta.lock_or_recover()[0].unsaved);
+    }
+
+    #[test]
+    fn flush_detects_pages_missing_from_metadata() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { page_size: 16 * 4096, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(16 * 4096, &[2; 4096]).unwrap();
+
+        // The second page is uploaded, but there is no metadata block to record it in.
+        plugin.meta.lock_or_recover()[0].pages.retain(|page| page.offset != 1);
+        match plugin.flush_all() {
+            Err(Error::UnsyncedPages { offsets }) => assert_eq!(offsets, vec![1]),
+            result => panic!("Expected unsynced pages, got {:?}", result),
+        }
+
+        // It is reported once, pages written later are checked again.
+        plugin.flush().unwrap();
+        plugin.write(0, &[3; 4096]).unwrap();
+        plugin.flush().unwrap();
+    }
+
+    #[test]
+    fn flush_moves_only_changed_blocks() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { move_metadata: true, move_interval: Duration::ZERO, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        let created = plugin.meta.lock_or_recover()[0].message_id;
+        plugin.flush().unwrap();
+        assert_ne!(plugin.meta.lock_or_recover()[0].message_id, created);
+
+        let calls = |backend: &MemoryBackend| ["send_message", "edit_message", "delete_message", "pin_message"].map(|op| backend.calls(op));
+        let before = calls(&backend);
+        let message_id = plugin.meta.lock_or_recover()[0].message_id;
+
+        // Nothing changed, so the metadata block stays where it is.
+        plugin.flush().unwrap();
+        assert_eq!(calls(&backend), before);
+        assert_eq!(plugin.meta.lock_or_recover()[0].message_id, message_id);
+    }
+
+    #[test]
+    fn flush_keeps_metadata_in_place() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        let message_id = plugin.meta.lock_or_recover()[0].message_id;
+        let sent = backend.calls("send_message");
+
+        // The block is edited where it is, no message is deleted and sent again.
+        plugin.flush().unwrap();
+        assert_eq!(backend.calls("send_message"), sent);
+        assert_eq!(backend.calls("delete_message"), 0);
+        assert_eq!(plugin.meta.lock_or_recover()[0].message_id, message_id);
+    }
+
+    #[test]
+    fn moves_metadata_once_per_interval() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { page_size: 16 * 4096, move_metadata: true, move_interval: Duration::from_secs(3600), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let moves = backend.calls("delete_message");
+
+        // Later flushes leave the changed block where it is until the interval passes.
+        plugin.write(0, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert!(plugin.meta.lock_or_recover()[0].changed);
+        let deleted = backend.calls("delete_message") - moves;
+        assert_eq!(deleted, 1, "Only the replaced data message is deleted");
+
+        *plugin.moved_at.lock_or_recover() = Some(Instant::now() - Duration::from_secs(3600));
+        plugin.flush().unwrap();
+        assert!(!plugin.meta.lock_or_recover()[0].changed);
+    }
+
+    #[test]
+    fn flush_drops_zeroed_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { page_size: 16 * 4096, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(16 * 4096, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(data_pages(&backend), 2);
+
+        // Zeroing the whole first page removes it, the other page is kept.
+        for block in 0..16 {
+            plugin.write(block * 4096, &[0; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+
+        assert_eq!(data_pages(&backend), 1);
+        let offsets: Vec<u64> = plugin.meta.lock_or_recover().iter().flat_map(|block| block.pages.iter().map(|page| page.offset)).collect();
+        assert_eq!(offsets, vec![1]);
+        assert_eq!(plugin.read(0).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(16 * 4096).unwrap(), vec![2; 4096]);
+    }
+
+    #[test]
+    fn restores_snapshot() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { page_size: 16 * 4096, keep_history: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(16 * 4096, &[2; 4096]).unwrap();
+        assert_eq!(plugin.take_snapshot("before").unwrap().pages.len(), 2);
+
+        plugin.write(0, &[3; 4096]).unwrap();
+        plugin.write(2 * 16 * 4096, &[4; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        plugin.restore_snapshot("before").unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(16 * 4096).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.read(2 * 16 * 4096).unwrap(), vec![0; 4096]);
+
+        assert!(matches!(plugin.restore_snapshot("missing"), Err(Error::SnapshotNotFound { .. })));
+    }
+
+    #[test]
+    fn volumes_share_a_channel() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = |volume: &str| Config { page_size: 16 * 4096, volume: volume.to_string(), ..Config::default() };
+
+        for (volume, byte) in [("a", 1), ("b", 2)] {
+            let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config(volume)).unwrap();
+            plugin.write(0, &[byte; 4096]).unwrap();
+            plugin.flush().unwrap();
+        }
+
+        for (volume, byte) in [("a", 1), ("b", 2), ("", 0)] {
+            let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config(volume)).unwrap();
+            assert_eq!(plugin.read(0).unwrap(), vec![byte; 4096]);
+        }
+
+        let data = backend.messages(CHANNEL).into_iter().filter(|message| !message.attachments.is_empty()).count();
+        assert_eq!(data, 2);
+        assert!(backend.messages(CHANNEL).iter().any(|message| message.content == "volume a"));
+        assert!(matches!(DiscordDrivePlugin::new(backend, CHANNEL, config("a b")), Err(Error::InvalidVolume { .. })));
+    }
+
+    #[test]
+    fn keeps_metadata_in_its_own_channel() {
+        let backend = Arc::new(MemoryBackend::new());
+        let meta_channel = ChannelId(2);
+        let config = Config { page_size: 16 * 4096, metadata_channel: Some(meta_channel.0), ..Config::default() };
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        for page in 0..3 {
+            plugin.write(page * 16 * 4096, &[page as u8 + 1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+        drop(plugin);
+
+        // Data messages stay in the drive channel, everything else is in the metadata channel.
+        let data = backend.messages(CHANNEL);
+        assert_eq!(data.len(), 3);
+        assert!(data.iter().all(|message| !MetadataBlock::is_metablock(&message.content)));
+        let meta = backend.messages(meta_channel);
+        assert!(meta.iter().any(|message| MetadataBlock::is_metablock(&message.content)));
+        assert!(meta.iter().all(|message| message.attachments.is_empty()));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        assert!(rt.block_on(Superblock::find(backend.as_ref(), meta_channel, "", &superblock::Sealing::default())).unwrap().is_some());
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        for page in 0..3 {
+            assert_eq!(plugin.read(page * 16 * 4096).unwrap(), vec![page as u8 + 1; 4096]);
+        }
+        plugin.write(0, &[9; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(backend.messages(CHANNEL).len(), 3);
+        assert_eq!(plugin.read(0).unwrap(), vec![9; 4096]);
+    }
+
+    #[test]
+    fn data_messages_use_templates() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config {
+            page_size: 16 * 4096,
+            volume: "vm".to_string(),
+            data_content: "{volume} page {page}".to_string(),
+            data_file_name: "{volume}_{offset}.bin".to_string(),
+            ..Config::default()
+        };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(2 * 16 * 4096, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        let messages = backend.messages(CHANNEL);
+        let data = messages.iter().find(|message| !message.attachments.is_empty()).unwrap();
+        assert_eq!(data.content, "volume vm\nvm page 2");
+        assert!(data.attachments[0].ends_with("/vm_131072.bin"));
+    }
+
+    #[test]
+    fn snapshots_need_history() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend, CHANNEL, Config::default()).unwrap();
+
+        assert!(matches!(plugin.take_snapshot("before"), Err(Error::SnapshotWithoutHistory)));
+    }
+
+    #[test]
+    fn trace_read_logs_every_step() {
+        capture_logs();
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let trace = |offset: u64| {
+            let prefix = format!("trace_read {}: ", offset);
+            LOGS.lock_or_recover().iter().filter_map(|line| line.strip_prefix(&prefix).map(str::to_string)).collect::<Vec<_>>()
+        };
+
+        // A page that was never written has no message and reads as zeros.
+        assert_eq!(plugin.trace_read(5 * PAGE).unwrap(), vec![0; 4096]);
+        let missing = trace(5 * PAGE);
+        assert!(missing.contains(&"queue: not queued".to_string()));
+        assert!(missing.contains(&"cache: miss".to_string()));
+        assert!(missing.contains(&"metadata: no metablock lists the page".to_string()));
+        assert!(missing.contains(&format!("message id 0 in channel {}", CHANNEL.0)));
+        assert_eq!(missing.last().unwrap(), "result: zeros, the page was never written");
+
+        // A stored page shows its message and where it was downloaded from.
+        let fresh = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        drop(plugin);
+        let message_id = fresh.locate(4096).unwrap().unwrap().message_id.unwrap();
+        assert_eq!(fresh.trace_read(4096).unwrap(), vec![0; 4096]);
+        let stored = trace(4096);
+        assert!(stored.contains(&format!("message id {} in channel {}", message_id, CHANNEL.0)));
+        assert!(stored.iter().any(|line| line.starts_with("url (")));
+        assert_eq!(stored.last().unwrap(), "result: read 4096 bytes");
+    }
+
+    #[test]
+    fn ranged_reads_skip_the_cache() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { page_size: 16 * 4096, zero_block_size: Some(4096), ranged_reads: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(4096, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        assert_eq!(plugin.read(4096).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(2 * 4096).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(4096).unwrap(), vec![1; 4096]);
+        assert_eq!(backend.calls("download"), 0);
+        assert_eq!(backend.calls("download_range"), 3);
+        assert!(plugin.cache.get(0).is_none());
+    }
+
+    #[test]
+    fn drops_cached_pages_of_replaced_messages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+
+        // Another writer uploads the page again, the metadata points at its message once it is reloaded.
+        let other = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        other.write(0, &[2; 4096]).unwrap();
+        other.flush().unwrap();
+        let message_id = other.meta.lock_or_recover().find(0).unwrap().message_id;
+        plugin.meta.lock_or_recover().block_of(0).unwrap().pages[0].message_id = message_id;
+
+        assert_eq!(plugin.read(0).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.cache.get(0).unwrap().message_id, message_id);
+    }
+
+    #[test]
+    fn cache_ttl_expires_read_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { cache_ttl: Some(Duration::ZERO), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(4096).unwrap(), vec![0; 4096]);
+        assert_eq!(backend.calls("download"), 2);
+
+        // Written pages stay cached.
+        plugin.write(0, &[3; 4096]).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![3; 4096]);
+        assert_eq!(backend.calls("download"), 2);
+    }
+
+    #[test]
+    fn prefetch_fills_the_cache() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(1024*1024*8 + 4096, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        // Only the existing page is downloaded, the others read as zeros.
+        plugin.prefetch(0, 1024*1024*24).unwrap();
+        assert_eq!(backend.calls("download"), 1);
+        assert!(plugin.cache.contains(1));
+
+        assert_eq!(plugin.read(1024*1024*8 + 4096).unwrap(), vec![1; 4096]);
+        plugin.prefetch(1024*1024*8, 4096).unwrap();
+        assert_eq!(backend.calls("download"), 1);
+    }
+
+    #[test]
+    fn stores_zeros_as_data_without_zero_mask() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { zero_mask: false, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+
+        // A whole page of zeros, written in combined sequential writes.
+        for offset in (0..PAGE).step_by(1024 * 1024) {
+            plugin.write(offset, &vec![0; 1024 * 1024]).unwrap();
+        }
+        plugin.write_zeroes(PAGE, 4096, true).unwrap();
+        plugin.flush().unwrap();
+
+        assert_eq!(data_pages(&backend), 2);
+        for page in 0..2 {
+            let stored = plugin.meta.lock_or_recover().find(page).cloned().unwrap();
+            assert!(stored.message_id.is_some());
+            assert!(stored.zero_mask.as_bytes().iter().all(|byte| *byte == 0));
+        }
+        drop(plugin);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.read(4096).unwrap(), vec![0; 4096]);
+        assert_eq!(backend.calls("download"), 1);
+    }
+
+    #[test]
+    fn stores_zeros_without_holes() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        plugin.write_zeroes(0, 4096, true).unwrap();
+        plugin.write_zeroes(4096, 2 * 4096, false).unwrap();
+        let mask = plugin.cache.get(0).unwrap().mask;
+        assert_eq!((mask.get(0), mask.get(1), mask.get(2)), (true, false, false));
+
+        plugin.flush().unwrap();
+        assert!(plugin.meta.lock_or_recover().find(0).unwrap().zero_mask.get(0));
+        assert!(!plugin.meta.lock_or_recover().find(0).unwrap().zero_mask.get(1));
+        assert_eq!(plugin.read(4096).unwrap(), vec![0; 4096]);
+    }
+
+    #[test]
+    fn restores_metadata_backup() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { backup_interval: Some(Duration::ZERO), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        plugin.write(PAGE, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        drop(plugin);
+
+        // The newer backup replaced the one taken before it.
+        let backups = |backend: &MemoryBackend| backend.messages(CHANNEL).iter().filter(|message| message.content.contains(BACKUP_NAME)).count();
+        assert_eq!(backups(&backend), 1);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        for message in backend.messages(CHANNEL).iter().filter(|message| MetadataBlock::is_metablock(&message.content)) {
+            rt.block_on(backend.edit_message(CHANNEL, message.id, "METABLOCK v1\nbroken")).unwrap();
+        }
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![0; 4096]);
+        drop(plugin);
+
+        let config = Config { restore_backup: true, ..config };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![2; 4096]);
+    }
+
+    #[test]
+    fn repairs_wrong_zero_masks() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(2 * 4096, &[1; 4096]).unwrap();
+        plugin.write(PAGE + 4096, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        // Blocks that were never written are not masked yet.
+        assert_eq!(plugin.repair_masks().unwrap(), vec![0, 1]);
+
+        // The first block of page 0 holds data, but the stored mask says it is zeroed.
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut meta = plugin.meta.lock_or_recover();
+        let block = meta.block_of(0).unwrap();
+        block.pages.iter_mut().find(|page| page.offset == 0).unwrap().zero_mask.set(0, true);
+        rt.block_on(block.update_message(plugin.backend(), &CHANNEL)).unwrap();
+        drop(meta);
+        assert_eq!(plugin.read(0).unwrap(), vec![0; 4096]);
+
+        assert_eq!(plugin.repair_masks().unwrap(), vec![0]);
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        drop(plugin);
+
+        let config = Config { eager_metadata: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        let page = plugin.meta.lock_or_recover().find(0).cloned().unwrap();
+        assert!(!page.zero_mask.get(0));
+        assert!(page.zero_mask.get(1));
+    }
+
+    #[test]
+    fn reports_missing_permissions() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.forbid("send_file");
+
+        let config = Config { check_permissions: true, ..Config::default() };
+        let error = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).err().unwrap();
+
+        assert!(matches!(error, Error::MissingPermissions { channel: 1, operation: "send files" }));
+        assert!(error.to_string().contains("Attach Files"));
+        assert!(backend.messages(CHANNEL).is_empty());
+
+        // Allowed operations leave nothing behind.
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { check_permissions: true, ..Config::default() };
+        DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert!(backend.messages(CHANNEL).iter().all(|message| !message.content.contains("permission check")));
+    }
+
+    #[test]
+    fn combines_sequential_writes() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        // The first write creates the page, the rest are buffered and applied once.
+        for block in 0..256u64 {
+            plugin.write(block * 4096, &[block as u8; 4096]).unwrap();
+        }
+        assert_eq!(plugin.cache.writes(), 0);
+        assert_eq!(plugin.read(255 * 4096).unwrap(), vec![255; 4096]);
+        assert_eq!(plugin.cache.writes(), 1);
+
+        // Only the write at 301 continues the previous one, it is applied before the write at 100.
+        plugin.write(8 * 4096, &[1; 4096]).unwrap();
+        plugin.write(300 * 4096, &[2; 4096]).unwrap();
+        plugin.write(301 * 4096, &[3; 4096]).unwrap();
+        assert_eq!(plugin.cache.writes(), 3);
+        plugin.write(100 * 4096, &[4; 4096]).unwrap();
+        assert_eq!(plugin.cache.writes(), 5);
+        plugin.flush().unwrap();
+
+        let plugin = DiscordDrivePlugin::new(backend, CHANNEL, Config::default()).unwrap();
+        for (block, byte) in [(0, 0), (8, 1), (100, 4), (200, 200), (300, 2), (301, 3), (302, 0)] {
+            assert_eq!(plugin.read(block * 4096).unwrap(), vec![byte; 4096]);
+        }
+    }
+
+    #[test]
+    fn spills_evicted_pages_to_local_tier() {
+        const PAGE: u64 = 16 * 4096;
+        let backend = Arc::new(MemoryBackend::new());
+        let dir = utils::TempDir::new("tier");
+        let config = Config { page_size: PAGE as usize, local_tier_dir: Some(dir.to_string_lossy().to_string()), ..Config::default() };
+
+        // The cache holds 4 pages, the ones evicted after them wait on the local disk.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.pause_sync();
+        for page in 0..6 {
+            plugin.write(page * PAGE, &[page as u8 + 1; 4096]).unwrap();
+        }
+        assert_eq!(plugin.status().tier_pages, 2);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        assert!(plugin.queue.is_empty());
+        assert_eq!(data_pages(&backend), 0);
+
+        // Reads and writes take the pages back from the tier.
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        plugin.write(PAGE + 4096, &[9; 4096]).unwrap();
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.status().tier_pages, 2);
+
+        plugin.resume_sync();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.status().tier_pages, 0);
+        assert_eq!(data_pages(&backend), 6);
+
+        drop(plugin);
+
+        // Pages left behind by a previous run are uploaded when the drive is opened, even new ones.
+        let tier = LocalTier::open(&dir, Keyring::none(), 256).unwrap();
+        tier.put(&Page::new(0), &[11; PAGE as usize]).unwrap();
+        tier.put(&Page::new(7), &[12; PAGE as usize]).unwrap();
+        drop(tier);
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        assert_eq!(plugin.status().tier_pages, 0);
+        drop(plugin);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config { local_tier_dir: None, ..config }).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![11; 4096]);
+        assert_eq!(plugin.read(PAGE + 4096).unwrap(), vec![9; 4096]);
+        assert_eq!(plugin.read(2 * PAGE).unwrap(), vec![3; 4096]);
+        assert_eq!(plugin.read(7 * PAGE).unwrap(), vec![12; 4096]);
+
+    }
+
+    #[test]
+    fn replays_wal_after_crash() {
+        let backend = Arc::new(MemoryBackend::new());
+        let dir = utils::TempDir::new("wal");
+        let config = Config { page_size: 16 * 4096, wal_dir: Some(dir.to_string_lossy().to_string()), ..Config::default() };
+
+        // Pages were queued, but the process died before they were synced.
+        let wal = Wal::open(&dir, Keyring::none()).unwrap();
+        wal.append(0, &[1; 16 * 4096]).unwrap();
+        wal.append(2, &[[2; 4096], [0; 4096]].concat().repeat(8)).unwrap();
+        drop(wal);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        assert!(plugin.wal.as_ref().unwrap().pending().unwrap().is_empty());
+        assert_eq!(data_pages(&backend), 2);
+        drop(plugin);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config { wal_dir: None, ..config.clone() }).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(2 * 16 * 4096).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.read(2 * 16 * 4096 + 4096).unwrap(), vec![0; 4096]);
+
+        // Queued pages are logged until a flush synced them.
+        let plugin = DiscordDrivePlugin::new(backend, CHANNEL, config).unwrap();
+        for page in 0..5 {
+            plugin.write(page * 16 * 4096, &[3; 4096]).unwrap();
+        }
+        let pending = plugin.wal.as_ref().unwrap().pending().unwrap();
+        assert_eq!(pending.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), vec![0]);
+        plugin.flush().unwrap();
+        assert!(plugin.wal.as_ref().unwrap().pending().unwrap().is_empty());
+
+    }
+
+    #[test]
+    fn finishes_journaled_mask_updates_after_crash() {
+        const PAGE: u64 = 16 * 4096;
+        let backend = Arc::new(MemoryBackend::new());
+        let dir = utils::TempDir::new("journal");
+        let config = Config {
+            page_size: PAGE as usize,
+            mask_journal_dir: Some(dir.to_string_lossy().to_string()),
+            sync_attempts: Some(1),
+            ..Config::default()
+        };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        // Synced pages leave nothing in the journal.
+        assert!(plugin.journal.as_ref().unwrap().pending().unwrap().is_empty());
+        let old: Vec<u64> = (0..2).map(|page| plugin.meta.lock_or_recover().find(page).unwrap().message_id.unwrap()).collect();
+
+        // Both pages are uploaded again, but the process dies before their metadata is stored.
+        plugin.write(0, &[2; 4096]).unwrap();
+        plugin.write(PAGE, &[2; 4096]).unwrap();
+        backend.fail_after("edit_message", 0);
+        assert!(plugin.flush().is_err());
+        let pending = plugin.journal.as_ref().unwrap().pending().unwrap();
+        assert_eq!(pending.iter().map(|(page, replaced)| (page.offset, *replaced)).collect::<Vec<_>>(), vec![(0, Some(old[0])), (1, Some(old[1]))]);
+        drop(plugin);
+
+        // The old messages are kept until the metadata points at the uploads.
+        let messages: Vec<u64> = backend.messages(CHANNEL).iter().map(|message| message.id).collect();
+        assert!(old.iter().all(|id| messages.contains(id)));
+        // The upload of page 1 is lost.
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(backend.delete_message(CHANNEL, pending[1].0.message_id.unwrap())).unwrap();
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert!(plugin.journal.as_ref().unwrap().pending().unwrap().is_empty());
+        let stored = plugin.meta.lock_or_recover().find(0).unwrap().clone();
+        assert_eq!((stored.message_id, stored.zero_mask.as_bytes()), (pending[0].0.message_id, pending[0].0.zero_mask.as_bytes()));
+        assert_eq!(plugin.read(0).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.read(4096).unwrap(), vec![0; 4096]);
+
+        // The entry without its message is rolled back to the old message, which still exists.
+        assert_eq!(plugin.meta.lock_or_recover().find(1).unwrap().message_id, Some(old[1]));
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![1; 4096]);
+        assert_eq!(data_pages(&backend), 2);
+    }
+
+    #[test]
+    fn write_back_defers() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        assert_eq!(data_pages(&backend), 0);
+
+        plugin.flush().unwrap();
+        assert_eq!(data_pages(&backend), 1);
+    }
+
+    #[test]
+    fn reports_health() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        assert_eq!(plugin.health().status, health::HealthStatus::Healthy);
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        let health = plugin.health();
+        assert_eq!(health.status, health::HealthStatus::Healthy);
+        assert_eq!(health.queue_depth, 0);
+    }
+
+    #[test]
+    fn writes_new_pages_without_downloading() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        plugin.write(3 * PAGE + 8192, &[1; 4096]).unwrap();
+        plugin.write(5 * PAGE, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(backend.calls("download") + backend.calls("download_range"), 0);
+
+        // The rest of an existing page has to be downloaded before it is uploaded again.
+        plugin.write(3 * PAGE, &[3; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(backend.calls("download"), 1);
+        assert_eq!(plugin.read(3 * PAGE + 8192).unwrap(), vec![1; 4096]);
+    }
+
+    #[test]
+    fn traces_discord_operations() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        capture_logs();
+        let traced = Arc::new(TracingBackend::new(backend.clone()));
+        let plugin = DiscordDrivePlugin::new(traced, CHANNEL, Config::default()).unwrap();
+        let message_id = plugin.locate(0).unwrap().unwrap().message_id.unwrap();
+        plugin.read(0).unwrap();
+
+        // The page url was fetched with the metadata, so the read only downloads it.
+        let traced = |operation: &str, message_id: u64| -> Vec<String> {
+            let logs = LOGS.lock_or_recover();
+            logs.iter().filter(|line| line.starts_with(operation) && (line.contains(&format!("/{}/", message_id)) || line.contains(&format!(" {} in", message_id)) || line.ends_with(&format!("message {}", message_id)))).cloned().collect()
+        };
+        assert!(traced("download ", message_id).iter().any(|line| line.contains(" ok after ") && line.ends_with(" bytes")));
+
+        // Uploads log the id of the message they sent.
+        plugin.write(0, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let message_id = plugin.locate(0).unwrap().unwrap().message_id.unwrap();
+        assert!(traced("send_file ", message_id).iter().any(|line| line.contains(" ok after ")));
+
+        // The trace tells which message went missing.
+        rt.block_on(backend.delete_message(CHANNEL, message_id)).unwrap();
+        let plugin = DiscordDrivePlugin::new(Arc::new(TracingBackend::new(backend.clone())), CHANNEL, Config::default()).unwrap();
+        assert!(plugin.read(0).is_err());
+        assert!(traced("get_message ", message_id).iter().any(|line| line.contains("failed after") && line.ends_with("Not found")));
+    }
+
+    #[test]
+    fn reuses_page_buffers() {
+        // Rewrites twice as many pages as the cache holds, three times over.
+        let page = 16 * 4096;
+        let writes = 3 * 8;
+        let allocations = |buffer_pool| {
+            let config = Config { page_size: page as usize, zero_block_size: Some(4096), buffer_pool, ..Config::default() };
+            let plugin = DiscordDrivePlugin::new(Arc::new(MemoryBackend::new()), CHANNEL, config).unwrap();
+            for round in 1..=3 {
+                for offset in 0..8 {
+                    plugin.write(offset * page, &[round; 4096]).unwrap();
+                }
+                plugin.flush().unwrap();
+            }
+            assert_eq!(plugin.read(7 * page).unwrap(), vec![3; 4096]);
+            plugin.pages.buffers.allocations()
+        };
+
+        // Without the pool every rewrite needs a new buffer.
+        assert!(allocations(0) >= writes);
+        // Only pages held by the cache, the queue and the running uploads at once need their own buffer.
+        assert!(allocations(4) < writes);
+    }
+
+    #[test]
+    fn reports_status() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        let status = plugin.status();
+        assert_eq!((status.cached_pages, status.synced_pages, status.since_last_sync), (0, 0, None));
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &[2; 4096]).unwrap();
+        plugin.read(0).unwrap();
+        let status = plugin.status();
+        assert_eq!((status.cached_pages, status.dirty_pages, status.cache_capacity), (2, 2, 4));
+        assert_eq!((status.bytes_read, status.bytes_written), (4096, 2 * 4096));
+
+        plugin.flush().unwrap();
+        let status = plugin.status();
+        assert_eq!((status.cached_pages, status.dirty_pages, status.health.queue_depth), (0, 0, 0));
+        assert_eq!((status.synced_pages, status.sync_errors), (2, 0));
+        assert!(status.since_last_sync.is_some());
+        assert!(!status.syncing);
+        // Both pages were uploaded, and their metadata block sent.
+        assert_eq!(status.latencies.upload.count, 2);
+        assert!(status.latencies.edit.count > 0);
+        assert!(status.latencies.upload.p50 <= status.latencies.upload.p99);
+    }
+
+    #[test]
+    fn pauses_and_resumes_sync() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { page_size: 16 * 4096, dirty_limit: Some(0), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.pause_sync();
+        for page in 0..3 {
+            plugin.write(page * 16 * 4096, &[page as u8 + 1; 4096]).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(300));
+
+        // Written pages wait in the queue, and can still be read.
+        assert_eq!(backend.calls("send_file"), 0);
+        assert_eq!(plugin.queue.len(), 3);
+        assert!(plugin.status().sync_paused);
+        assert!(matches!(plugin.flush_all(), Err(Error::SyncPaused { pending: 3 })));
+        assert_eq!(plugin.read(16 * 4096).unwrap(), vec![2; 4096]);
+
+        plugin.resume_sync();
+        plugin.flush().unwrap();
+        assert_eq!(backend.calls("send_file"), 3);
+        assert!(plugin.queue.is_empty());
+        assert!(!plugin.status().sync_paused);
+    }
+
+    #[test]
+    fn dumps_layout() {
+        let backend = Arc::new(MemoryBackend::new());
+        two_block_drive(&backend);
+        let config = Config { eager_metadata: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        let layout = plugin.dump_layout();
+        let offsets: Vec<u64> = layout.pages.iter().map(|page| page.offset).collect();
+
+        assert_eq!(layout.channel, CHANNEL);
+        assert_eq!(offsets, vec![0, 1]);
+    }
+
+    #[test]
+    fn locates_offsets() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        plugin.write(0, &[0; 4096]).unwrap();
+        plugin.write(4096, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        let message_id = backend.messages(CHANNEL).iter().find(|message| !message.attachments.is_empty()).unwrap().id;
+        let location = plugin.locate(4096 + 100).unwrap().unwrap();
+        assert_eq!(location, PageLocation { channel: CHANNEL, message_id: Some(message_id), attachment: 0, zeroed: false });
+
+        // Blocks written with zeroes are zeroed, untouched pages don't exist.
+        assert_eq!(plugin.locate(0).unwrap().map(|location| location.zeroed), Some(true));
+        assert_eq!(plugin.locate(1024*1024*8).unwrap(), None);
+    }
+
+    #[test]
+    fn reports_usage() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        assert_eq!(plugin.usage().used, 0);
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(1024*1024*8, &[0; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        let usage = plugin.usage();
+        assert_eq!(usage.used, 1024*1024*8 + (1024*1024*8 - 4096));
+        assert_eq!(usage.total, 1024*1024*128);
+        // A page and a block of another one hold data, the rest of the drive is zeros.
+        assert_eq!(plugin.status().sparse_ratio, (1024*1024*112 + 4096) as f64 / (1024*1024*128) as f64);
+    }
+
+    #[test]
+    fn clamps_partial_final_page() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { device_size: 1024*1024*12, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        // Last block of the drive is in the middle of the second page.
+        let last = 1024*1024*12 - 4096;
+        plugin.write(last, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(last).unwrap(), vec![1; 4096]);
+
+        assert!(matches!(plugin.read(1024*1024*12), Err(Error::OutOfBounds { .. })));
+        assert!(matches!(plugin.write(1024*1024*12, &[1; 4096]), Err(Error::OutOfBounds { .. })));
+        assert!(matches!(plugin.write(1024*1024*16 - 4096, &[1; 4096]), Err(Error::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn reads_holes_as_zeros_within_the_device() {
+        let backend = Arc::new(MemoryBackend::new());
+        let size = 1024*1024*12;
+        let config = Config { device_size: size, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        plugin.write(4096, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        // Unwritten blocks of a written page and of a page that doesn't exist.
+        assert_eq!(plugin.read(0).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(size - 4096).unwrap(), vec![0; 4096]);
+
+        for offset in [size, size + 4096, u64::MAX - 4095] {
+            assert!(matches!(plugin.read(offset), Err(Error::OutOfBounds { size: 12582912, .. })));
+        }
+    }
+
+    #[test]
+    fn keeps_or_adopts_stored_device_size() {
+        capture_logs();
+        let backend = Arc::new(MemoryBackend::new());
+        let stored = 1024*1024*12;
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config { device_size: stored, ..Config::default() }).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        drop(plugin);
+
+        // A different DEVICE_SIZE is only warned about and replaces the stored size.
+        let configured = 1024*1024*16;
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config { device_size: configured, ..Config::default() }).unwrap();
+        assert_eq!(plugin.read(stored).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.superblock.lock_or_recover().device_size, Some(configured));
+        let warning = format!("DEVICE_SIZE is {} bytes, but the drive was created with {} bytes!", configured, stored);
+        assert!(LOGS.lock_or_recover().iter().any(|line| line.starts_with(&warning)));
+        drop(plugin);
+
+        // With ADOPT_DEVICE_SIZE the stored size is used.
+        let config = Config { device_size: stored, adopt_device_size: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.read(stored).unwrap(), vec![0; 4096]);
+        assert!(matches!(plugin.read(configured), Err(Error::OutOfBounds { size, .. }) if size == configured));
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+
+        let found = plugin.rt.block_on(Superblock::find(plugin.backend(), CHANNEL, "", &superblock::Sealing::default())).unwrap().unwrap();
+        assert_eq!(found.device_size, Some(configured));
+    }
+
+    #[test]
+    fn shortens_unaligned_last_block() {
+        let backend = Arc::new(MemoryBackend::new());
+        let size = 1024*1024*12 + 1000;
+        let config = Config { device_size: size, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        let last = 1024*1024*12;
+        plugin.write(last, &[1; 1000]).unwrap();
+        assert_eq!(plugin.read(last).unwrap(), vec![1; 1000]);
+
+        assert!(matches!(plugin.write(last, &[1; 4096]), Err(Error::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn read_at_reads_several_blocks() {
+        let backend = Arc::new(MemoryBackend::new());
+        let size = PAGE * 2 + 1000;
+        let config = Config { device_size: size, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        let data: Vec<u8> = (0..3 * 4096).map(|i| (i % 251) as u8).collect();
+        plugin.write(PAGE - 4096, &data[..4096]).unwrap();
+        plugin.write(PAGE, &data[4096..8192]).unwrap();
+        plugin.write(PAGE + 4096, &data[8192..]).unwrap();
+        plugin.write(PAGE * 2, &[7; 1000]).unwrap();
+
+        let mut buf = vec![0; 8192];
+        Server::read_at(&plugin, &mut buf, 0).unwrap();
+        assert_eq!(buf, vec![0; 8192]);
+
+        // Unaligned, over the end of a page.
+        let mut buf = vec![0; 3 * 4096 - 200];
+        Server::read_at(&plugin, &mut buf, PAGE - 4096 + 100).unwrap();
+        assert_eq!(buf, data[100..3 * 4096 - 100]);
+
+        // Up to the end of the drive, but not past it.
+        let mut buf = vec![0; 1500];
+        Server::read_at(&plugin, &mut buf, PAGE * 2 - 500).unwrap();
+        assert_eq!(buf, [vec![0; 500], vec![7; 1000]].concat());
+        let error = Server::read_at(&plugin, &mut vec![0; 4096], PAGE * 2).unwrap_err();
+        assert!(error.to_string().contains("past the end"), "{}", error);
+    }
+
+    #[test]
+    fn backend_errors_are_returned() {
+        let backend = Arc::new(MemoryBackend::new());
+        two_block_drive(&backend);
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        backend.disconnect();
+
+        let mut buf = [0; 4096];
+        let error = Server::read_at(&plugin, &mut buf, PAGE).unwrap_err();
+        assert!(error.to_string().starts_with("Discord operation failed"));
+
+        let error = Server::write_at(&plugin, &[1; 4096], PAGE * 4, nbdkit::Flags::empty()).unwrap_err();
+        assert!(error.to_string().starts_with("Discord operation failed"));
+
+        // The unloaded block is loaded once discord is reachable again.
+        plugin.rt.block_on(backend.reconnect()).unwrap();
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.meta.lock_or_recover().len(), 1);
+    }
+
+    #[test]
+    fn stores_25mb_pages() {
+        const MB: usize = 1024 * 1024;
+
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_upload_limit(50 * MB);
+
+        let config = Config { page_size: 25 * MB, upload_limit: 50 * MB, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(24 * MB as u64, &[2; 4096]).unwrap();
+        plugin.write(25 * MB as u64, &[3; 4096]).unwrap();
+        plugin.flush().unwrap();
+        drop(plugin);
+
+        // One message for each 25MB page.
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let files: Vec<Vec<u8>> = backend.messages(CHANNEL).iter()
+            .flat_map(|message| message.attachments.iter())
+            .map(|url| rt.block_on(backend.download(url)).unwrap())
+            .collect();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|file| file.len() == metadata::upload_len(0, 25 * MB, false)));
+
+        let config = Config { page_size: 25 * MB, upload_limit: 50 * MB, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(4096).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(24 * MB as u64).unwrap(), vec![2; 4096]);
+        assert_eq!(plugin.read(25 * MB as u64).unwrap(), vec![3; 4096]);
+    }
+
+    #[test]
+    fn coarse_zero_blocks() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { zero_block_size: Some(64 * 1024), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+
+        // Zeroing 4KB of a 64KB block that holds data doesn't zero the block,
+        // zeroing all of it does.
+        plugin.write(PAGE + 68 * 1024, &[1; 4096]).unwrap();
+        plugin.write(PAGE + 64 * 1024, &[0; 4096]).unwrap();
+        plugin.write(PAGE + 128 * 1024, &[1; 4096]).unwrap();
+        plugin.write(PAGE + 128 * 1024, &[0; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.usage().used, 8 * 1024 * 1024 - 64 * 1024);
+        drop(plugin);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert_eq!(plugin.read(PAGE + 68 * 1024).unwrap(), vec![1; 4096]);
+        assert_eq!(plugin.read(PAGE + 64 * 1024).unwrap(), vec![0; 4096]);
+        assert_eq!(plugin.read(PAGE + 128 * 1024).unwrap(), vec![0; 4096]);
+        let page = plugin.meta.lock_or_recover()[0].pages[0].clone();
+        assert_eq!(page.zero_mask.count_ones(), 1);
+        assert!(page.zero_mask.get(2));
+
+        let config = Config { zero_block_size: Some(2048), ..Config::default() };
+        assert!(matches!(DiscordDrivePlugin::new(backend, CHANNEL, config), Err(Error::InvalidZeroBlockSize { .. })));
+    }
+
+    #[test]
+    fn rejects_pages_over_upload_limit() {
+        const MB: usize = 1024 * 1024;
+        let backend = Arc::new(MemoryBackend::new());
+
+        // The page header doesn't fit anymore.
+        let config = Config { page_size: 25 * MB, upload_limit: 25 * MB, ..Config::default() };
+        let error = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).err().unwrap();
+        assert!(matches!(error, Error::PageTooLarge { limit, .. } if limit == 25 * MB));
+
+        let config = Config { page_size: 24 * MB, upload_limit: 25 * MB, ..Config::default() };
+        assert!(DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).is_ok());
+
+        let config = Config { page_size: 8 * MB + 1, upload_limit: 25 * MB, ..Config::default() };
+        assert!(matches!(DiscordDrivePlugin::new(backend, CHANNEL, config), Err(Error::InvalidPageSize { .. })));
+    }
+
+    #[test]
+    fn flush_is_not_starved_by_reads() {
+        let backend = Arc::new(MemoryBackend::new());
+        let size = 16 * 4096;
+        let config = Config { page_size: size as usize, zero_block_size: Some(4096), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        for page in 0..2 {
+            plugin.write(size * page, &[page as u8 + 1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+        drop(plugin);
+
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        backend.set_download_latency(Duration::from_millis(300));
+        let downloads = backend.calls("download");
+        std::thread::scope(|scope| {
+            let first = scope.spawn(|| plugin.read(0).unwrap());
+            std::thread::sleep(Duration::from_millis(50));
+            let flush = scope.spawn(|| plugin.flush().unwrap());
+            std::thread::sleep(Duration::from_millis(50));
+
+            // A read starting while the flush waits for the first download doesn't start another one,
+            // otherwise steady reads could keep the flush waiting forever.
+            let second = scope.spawn(|| plugin.read(size).unwrap());
+            std::thread::sleep(Duration::from_millis(100));
+            assert_eq!(backend.calls("download"), downloads + 1);
+
+            flush.join().unwrap();
+            assert_eq!(first.join().unwrap(), vec![1; 4096]);
+            assert_eq!(second.join().unwrap(), vec![2; 4096]);
+        });
+    }
+
+    #[test]
+    fn reads_see_latest_write() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        let value = |i: u64| i.to_le_bytes().repeat(512);
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                        let data = plugin.read(0).unwrap();
+                        let seen = u64::from_le_bytes(data[..8].try_into().unwrap());
+                        assert_eq!(data, value(seen));
+                        assert!(seen >= last, "read {} after {}", seen, last);
+                        last = seen;
+                    }
+                });
+            }
+
+            for i in 1..=20 {
+                plugin.write(0, &value(i)).unwrap();
+                // Touch other pages, so page 0 keeps moving between the cache, the queue and discord.
+                plugin.write(PAGE * (1 + i % 5), &value(i)).unwrap();
+                if i % 5 == 0 {
+                    plugin.flush().unwrap();
+                }
+            }
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+
+        assert_eq!(plugin.read(0).unwrap(), value(20));
+    }
+
+    #[test]
+    fn bounded_memory_holds_at_most_memory_pages() {
+        let backend = Arc::new(MemoryBackend::new());
+        let mut keyring = Keyring::none();
+        keyring.add(1, [1; 32]);
+        let size = 16 * 4096;
+        let config = Config {
+            page_size: size as usize,
+            zero_block_size: Some(4096),
+            keyring,
+            checksums: true,
+            verify_uploads: true,
+            memory_pages: Some(8),
+            ..Config::default()
+        };
+        let split = config.memory_split().unwrap();
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        backend.set_upload_latency(Duration::from_millis(5));
+        backend.set_download_latency(Duration::from_millis(5));
+
+        let value = |page: u64, round: u64| [page as u8 + 1, round as u8].repeat(2048);
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let flushed = std::sync::atomic::AtomicBool::new(false);
+        let peak = std::sync::atomic::AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !flushed.load(std::sync::atomic::Ordering::SeqCst) {
+                    peak.fetch_max(plugin.resident_pages(), std::sync::atomic::Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let writers: Vec<_> = (0..3u64).map(|writer| {
+                let plugin = &plugin;
+                scope.spawn(move || {
+                    for round in 0..3 {
+                        for page in (writer..24).step_by(3) {
+                            plugin.write(page * size, &value(page, round)).unwrap();
+                        }
+                    }
+                })
+            }).collect();
+            let readers: Vec<_> = (0..2).map(|_| scope.spawn(|| {
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    for page in 0..24 {
+                        plugin.read(page * size).unwrap();
+                    }
+                }
+            })).collect();
+
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::SeqCst);
+            for reader in readers {
+                reader.join().unwrap();
+            }
+            // Readers are done first, flushing waits for no page to be loading.
+            plugin.flush().unwrap();
+            flushed.store(true, std::sync::atomic::Ordering::SeqCst);
+        });
+
+        assert!(peak.load(std::sync::atomic::Ordering::SeqCst) <= 8);
+        assert!(plugin.pages.memory.peak() <= split.downloads + split.uploads);
+        for page in 0..24 {
+            assert_eq!(plugin.read(page * size).unwrap(), value(page, 2));
+        }
+    }
+
+    #[test]
+    fn reads_find_pages_moving_from_the_queue() {
+        let backend = Arc::new(MemoryBackend::new());
+        let page = 16 * 4096;
+        let config = Config { page_size: page, zero_block_size: Some(4096), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let stored = plugin.meta.lock_or_recover().find(0).cloned().unwrap();
+
+        // The discord copy of the page is stale while the rewrite is queued.
+        backend.set_upload_latency(Duration::from_millis(50));
+        for round in 2..=6u8 {
+            plugin.queue.push(stored.clone(), vec![round; page]);
+            std::thread::scope(|scope| {
+                for _ in 0..4 {
+                    scope.spawn(|| assert_eq!(plugin.read(4096 * round as u64).unwrap(), vec![round; 4096]));
+                }
+            });
+            plugin.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn drop_stops_sync_thread() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        plugin.queue.push(Page::new(0), vec![1; PAGE as usize]);
+        drop(plugin);
+
+        // The queued page was uploaded and the thread's handle of the backend is gone.
+        assert_eq!(data_pages(&backend), 1);
+        assert_eq!(Arc::strong_count(&backend), 1);
+    }
+
+    #[test]
+    fn full_device_reports_no_space() {
+        let backend = Arc::new(MemoryBackend::new());
+        assert_eq!(Config::default().metadata_block_limit(), 4);
+
+        let config = Config { max_metadata_blocks: Some(1), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        for page in 0..4 {
+            plugin.write(PAGE * page, &[1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+        drop(plugin);
+
+        // The block is not loaded yet, but still has room for the last page.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        plugin.write(PAGE * 4, &[1; 4096]).unwrap();
+
+        let error = Server::write_at(&plugin, &[1; 4096], PAGE * 5, nbdkit::Flags::empty()).unwrap_err();
+        assert!(error.to_string().starts_with("No space left"));
+        assert!(matches!(plugin.write(PAGE * 5, &[1; 4096]), Err(Error::DeviceFull { blocks: 1 })));
+
+        // Pages that already exist can still be written.
+        plugin.write(PAGE * 2 + 4096, &[2; 4096]).unwrap();
+        assert_eq!(plugin.meta.lock_or_recover().len(), 1);
+    }
+
+    #[test]
+    fn refuses_pages_past_storage_cap() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { max_data_messages: Some(2), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config.clone()).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &[1; 4096]).unwrap();
+
+        let error = Server::write_at(&plugin, &[1; 4096], PAGE * 2, nbdkit::Flags::empty()).unwrap_err();
+        assert!(error.to_string().starts_with("No space left"));
+        assert!(matches!(plugin.preallocate(PAGE * 2, PAGE), Err(Error::StorageFull { messages: 2 })));
+        // Pages that already exist can still be written.
+        plugin.write(PAGE + 4096, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+        let status = plugin.status();
+        assert_eq!((status.data_messages, status.max_data_messages), (2, Some(2)));
+        drop(plugin);
+
+        // Pages of blocks that are not loaded yet count too.
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        assert!(matches!(plugin.write(PAGE * 3, &[1; 4096]), Err(Error::StorageFull { messages: 2 })));
+        assert_eq!(data_pages(&backend), 2);
+    }
+
+    #[test]
+    fn reads_of_different_pages_overlap() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        for page in 0..4 {
+            plugin.write(PAGE * page, &[page as u8 + 1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+
+        backend.set_download_latency(Duration::from_millis(300));
+        std::thread::scope(|scope| {
+            for page in 0..4 {
+                let plugin = &plugin;
+                scope.spawn(move || assert_eq!(plugin.read(PAGE * page).unwrap(), vec![page as u8 + 1; 4096]));
+            }
+        });
+
+        assert_eq!(backend.calls("download"), 4);
+        assert!(backend.max_concurrent_downloads() > 1);
+    }
+
+    #[test]
+    fn limits_concurrent_downloads() {
+        let backend = Arc::new(MemoryBackend::new());
+        let config = Config { max_downloads: Some(2), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        for page in 0..8 {
+            plugin.write(PAGE * page, &[page as u8 + 1; 4096]).unwrap();
+        }
+        plugin.flush().unwrap();
+
+        // Every read misses the cache, only two of them download at a time.
+        backend.set_download_latency(Duration::from_millis(100));
+        std::thread::scope(|scope| {
+            for page in 0..8 {
+                let plugin = &plugin;
+                scope.spawn(move || assert_eq!(plugin.read(PAGE * page).unwrap(), vec![page as u8 + 1; 4096]));
+            }
+        });
+
+        assert_eq!(backend.calls("download"), 8);
+        assert_eq!(backend.max_concurrent_downloads(), 2);
+    }
+
+    #[test]
+    fn concurrent_misses_download_once() {
+        let backend = Arc::new(MemoryBackend::new());
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+        plugin.write(PAGE, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        // Every read misses the cache at once, the first one downloads the page for all of them.
+        backend.set_download_latency(Duration::from_millis(200));
+        std::thread::scope(|scope| {
+            for block in 0..16 {
+                let plugin = &plugin;
+                scope.spawn(move || assert_eq!(plugin.read(PAGE + 4096 * block).unwrap()[0], (block == 0) as u8));
+            }
+        });
+
+        assert_eq!(backend.calls("download"), 1);
+        assert!(plugin.loading.lock_or_recover().is_empty());
+    }
+
+    fn key_ids(plugin: &DiscordDrivePlugin) -> Vec<(u64, u8)> {
+        let mut pages: Vec<(u64, u8)> = plugin.meta.lock_or_recover().iter()
+            .flat_map(|block| block.pages.iter().map(|page| (page.offset, page.key_id)))
+            .collect();
+        pages.sort();
+        pages
+    }
+
+    #[test]
+    fn rotates_keys() {
+        let backend = Arc::new(MemoryBackend::new());
+        let mut keyring = Keyring::none();
+        keyring.add(1, [1; 32]);
+        let page = 16 * 4096;
+
+        let config = Config { page_size: page as usize, zero_block_size: Some(4096), keyring: keyring.clone(), ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(page * 2, &[3; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(key_ids(&plugin), vec![(0, 1), (2, 1)]);
+        drop(plugin);
+
+        // Rotate to the second key, without migrating anything on flush.
+        keyring.add(2, [2; 32]);
+        let config = Config { page_size: page as usize, zero_block_size: Some(4096), keyring, rekey_batch: 0, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        assert_eq!(plugin.read(0).unwrap(), vec![1; 4096]);
+        plugin.write(page, &[2; 4096]).unwrap();
+        plugin.flush().unwrap();
+
+        // Written pages use the new key, the ones that were only read or untouched still use the old key.
+        assert_eq!(key_ids(&plugin), vec![(0, 1), (1, 2), (2, 1)]);
+
+        assert_eq!(plugin.rekey(4).unwrap(), 2);
+        plugin.flush().unwrap();
+        assert_eq!(key_ids(&plugin), vec![(0, 2), (1, 2), (2, 2)]);
+        assert_eq!(plugin.read(page * 2).unwrap(), vec![3; 4096]);
+    }
+
+    #[test]
+    fn uploads_have_the_same_size() {
+        let backend = Arc::new(MemoryBackend::new());
+        let mut keyring = Keyring::none();
+        keyring.add(1, [1; 32]);
+
+        // Pages are uploaded whole and not compressed, so their content doesn't show in the upload size.
+        let config = Config { keyring, checksums: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+        let noise: Vec<u8> = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+        plugin.write(0, &[1; 4096]).unwrap();
+        plugin.write(PAGE, &noise).unwrap();
+        plugin.flush().unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let sizes: Vec<usize> = backend.messages(CHANNEL).iter()
+            .filter_map(|message| message.attachments.first())
+            .map(|url| rt.block_on(backend.download(url)).unwrap().len())
+            .collect();
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0], sizes[1]);
+    }
+
+    #[test]
+    fn missing_page_is_an_io_error() {
+        let backend = Arc::new(MemoryBackend::new());
+
+        // Metadata points at a data message that was deleted, next to a page that was never uploaded.
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut block = MetadataBlock::empty(None);
+            let mut page = Page::new(0);
+            page.message_id = Some(404);
+            block.pages.push(page);
+            block.pages.push(Page::new(1));
+            block.update_message(backend.as_ref(), &CHANNEL).await.unwrap();
+        });
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, Config::default()).unwrap();
+
+        assert!(matches!(plugin.read(0), Err(Error::MissingPage { offset: 0, message_id: 404 })));
+        let mut buf = [0; 4096];
+        assert!(Server::read_at(&plugin, &mut buf, 0).is_err());
+
+        // The drive keeps working for every other page.
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![0; 4096]);
+        plugin.write(PAGE, &[1; 4096]).unwrap();
+        plugin.flush().unwrap();
+        assert_eq!(plugin.read(PAGE).unwrap(), vec![1; 4096]);
+    }
+
+    #[test]
+    fn eagerly_loads_all_blocks() {
+        let backend = Arc::new(MemoryBackend::new());
+        two_block_drive(&backend);
+
+        let config = Config { eager_metadata: true, ..Config::default() };
+        let plugin = DiscordDrivePlugin::new(backend.clone(), CHANNEL, config).unwrap();
+
+        assert_eq!(plugin.meta.lock_or_recover().len(), 2);
+    }
+}
\ No newline at end of file
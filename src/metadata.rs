@@ -1,201 +1,985 @@
-use serenity::{http::Http, model::prelude::ChannelId};
+use std::{collections::{BTreeMap, HashMap}, io::{Read, Write}, ops::{Deref, DerefMut}, str::FromStr, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::ChannelId;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use zeroize::Zeroizing;
+
+use crate::backend::{Backend, BackendError, Download, StoredMessage};
+use crate::crypto::{self, Keyring};
+use crate::deletions::Deletions;
+use crate::error::{Error, Result};
+use crate::pool::{BufferPool, PageMemory};
+use crate::urls::UrlCache;
+use crate::utils::{BitMask, LockOrRecover, ToBase32, byte_to_base_255, header_volume, try_base_255_to_byte, try_from_base32, volume_magic};
+
+/// Maximum number of pages described by a single metadata block.
+pub const PAGES_PER_BLOCK: usize = 5;
+
+/// Size of pages if it is not configured (the upload limit of discord used to be 8MB).
+pub const DEFAULT_PAGE_SIZE: usize = 1024 * 1024 * 8;
+
+/// Most blocks the zero mask of a page can track.
+pub const MASK_BITS: usize = 2048;
+
+/// More attempts to edit a metadata block with `EditFallback::Retry`, the first after `EDIT_RETRY_DELAY`.
+const EDIT_RETRIES: u32 = 3;
+const EDIT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Marks the data message of a page version that replaced the message `superseded` (kept with `KEEP_HISTORY`).
+const SUPERSEDES: &str = "supersedes ";
+
+/// Text of a data message that replaced the message `superseded`.
+pub fn supersedes_content(content: &str, superseded: u64) -> String {
+    if content.is_empty() {
+        format!("{}{}", SUPERSEDES, superseded)
+    } else {
+        format!("{}\n{}{}", content, SUPERSEDES, superseded)
+    }
+}
+
+/// Message id stored in a metadata block, pages without a message are stored with id 0.
+fn stored_message(message_id: u64) -> Option<u64> {
+    (message_id != 0).then_some(message_id)
+}
+
+/// Fills a data message template for the page at given offset (as a multiple of the page size).
+/// `{page}` is replaced with the page number and `{offset}` with the byte offset of the page on the drive.
+pub fn render_template(template: &str, page: u64, page_size: usize) -> String {
+    template
+        .replace("{page}", &page.to_string())
+        .replace("{offset}", &(page * page_size as u64).to_string())
+}
+
+/// Returns the message a data message replaced, if it records one.
+/// Following these ids from the current page message walks back through its older versions.
+pub fn supersedes(content: &str) -> Option<u64> {
+    content.lines().last()?.strip_prefix(SUPERSEDES)?.parse().ok()
+}
+
+/// Size of the blocks tracked by the zero mask of a page of given size, if it is not configured.
+/// Pages up to 8MB track every 4KB block, bigger pages track groups of them.
+pub fn default_zero_block_size(page_size: usize) -> usize {
+    4096 * page_size.div_ceil(4096 * MASK_BITS)
+}
+
+/// Number of zero mask blocks of given size in a page of given size.
+pub fn zero_blocks(page_size: usize, block_size: usize) -> usize {
+    page_size.div_ceil(block_size)
+}
+
+/// Zero mask of page data, marking every block of `block_size` that holds only zeros.
+pub fn zero_mask_of(page: &[u8], block_size: usize) -> BitMask<256> {
+    let mut mask = BitMask::new();
+    for (i, block) in page.chunks(block_size).enumerate() {
+        mask.set(i, block.iter().all(|byte| *byte == 0));
+    }
+    mask
+}
+
+/// Writes data at the offset of a page buffer, keeping its zero mask (of blocks of `block_size`) up to date.
+/// A block is only marked as zeroed once all of it is zero.
+pub fn write_block(page: &mut [u8], mask: &mut BitMask<256>, block_size: usize, offset: usize, data: &[u8]) {
+    // Flip the mask once for every block the data touches.
+    let mut start = 0;
+    while start < data.len() {
+        let index = (offset + start) / block_size;
+        let block = index * block_size..((index + 1) * block_size).min(page.len());
+        let end = (block.end - offset).min(data.len());
+
+        // The buffer may still hold old data of a zeroed block.
+        if mask.get(index) {
+            page[block.clone()].fill(0);
+        }
+        page[offset + start..offset + end].copy_from_slice(&data[start..end]);
+
+        mask.set(index, page[block].iter().all(|byte| *byte == 0));
+        start = end;
+    }
+}
+
+/// Magic starting metadata block messages, followed by the format version.
+/// Blocks written before versioning start with just `METABLOCK`.
+const MAGIC: &str = "METABLOCK";
+const VERSION: u32 = 1;
+/// Version of blocks whose pages are stored as JSON in an attachment of another message,
+/// the block message only points at it.
+const VERSION_JSON: u32 = 2;
+/// Version of text blocks that grew past `METADATA_COMPRESS_ABOVE`, their text is stored gzip-compressed
+/// in an attachment of another message, the block message only points at it.
+const VERSION_GZIP: u32 = 3;
+
+/// Magic and format version prepended to uploaded page data.
+/// Pages written before versioning have no header, they are recognized by their length.
+/// Version 2 pages are followed by a CRC32 of the stored data.
+const PAGE_MAGIC: &[u8; 4] = b"DAAF";
+const PAGE_VERSION: u8 = 1;
+const PAGE_VERSION_CHECKSUM: u8 = 2;
+const PAGE_HEADER_LEN: usize = PAGE_MAGIC.len() + 1;
+const CHECKSUM_LEN: usize = 4;
+/// How many times a page is uploaded when the verification read doesn't match (`VERIFY_UPLOADS`).
+const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// Size of the file a page of given size is uploaded as.
+pub fn upload_len(key_id: u8, page_size: usize, checksum: bool) -> usize {
+    let checksum = if checksum { CHECKSUM_LEN } else { 0 };
+    PAGE_HEADER_LEN + checksum + Keyring::stored_len(key_id, page_size)
+}
+
+/// Settings of how pages are stored, shared by everything that reads or uploads them.
+#[derive(Clone, Debug)]
+pub struct PageOptions {
+    /// Bytes of data in a page
+    pub size: usize,
+    /// Bytes tracked by one bit of the zero mask
+    pub zero_block_size: usize,
+    /// Keys pages are encrypted with
+    pub keyring: Keyring,
+    /// Text of the messages holding page data, a template (see `render_template`)
+    pub content: String,
+    /// Name of the uploaded page files, a template (see `render_template`). Names are random if it is empty.
+    pub file_name: String,
+    /// Extension of the random file names, discord picks the content type of the file by it
+    pub file_extension: String,
+    /// Prefix file names with `SPOILER_`, so discord hides the files and doesn't preview them
+    pub spoiler: bool,
+    /// Store a checksum with every page and verify it on download
+    pub checksums: bool,
+    /// How many times a page is downloaded before giving up on a broken download
+    pub download_attempts: u32,
+    /// Cached download urls of page messages
+    pub urls: UrlCache,
+    /// Keep the old data messages of rewritten pages instead of deleting them
+    pub keep_history: bool,
+    /// Download every uploaded page and compare it with the upload before using it
+    pub verify_uploads: bool,
+    /// Buffers page data is read into
+    pub buffers: BufferPool,
+    /// Old data messages waiting for their grace period to be deleted
+    pub deletions: Deletions,
+    /// Slots for page downloads (`MAX_DOWNLOADS`), downloads are not limited if None
+    pub downloads: Option<Arc<Semaphore>>,
+    /// Page buffers held by transfers, limited in bounded-memory mode (`MEMORY_PAGES`)
+    pub memory: Arc<PageMemory>,
+    /// Channel pages that were never uploaded go to once the drive rolled over (`CHANNEL_MESSAGE_LIMIT`),
+    /// 0 for the channel of the drive
+    pub new_pages: Arc<AtomicU64>,
+}
+
+impl PageOptions {
+    /// Channel a page that was never uploaded goes to, None for the channel of the drive.
+    pub fn new_page_channel(&self) -> Option<u64> {
+        Some(self.new_pages.load(Ordering::Relaxed)).filter(|channel| *channel != 0)
+    }
+
+    /// Waits until a page may be downloaded, the slot is freed when the permit is dropped.
+    async fn download_slot(&self) -> Option<SemaphorePermit<'_>> {
+        // The semaphore is never closed.
+        self.downloads.as_ref()?.acquire().await.ok()
+    }
+}
+
+impl Default for PageOptions {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_PAGE_SIZE,
+            zero_block_size: default_zero_block_size(DEFAULT_PAGE_SIZE),
+            keyring: Keyring::none(),
+            content: String::new(),
+            file_name: String::new(),
+            file_extension: "bin".to_string(),
+            spoiler: false,
+            checksums: false,
+            download_attempts: 2,
+            urls: UrlCache::new(),
+            keep_history: false,
+            verify_uploads: false,
+            buffers: BufferPool::default(),
+            deletions: Deletions::default(),
+            downloads: None,
+            memory: Arc::default(),
+            new_pages: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+/// How metadata blocks store their pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MetadataFormat {
+    /// Lines of base32 and base255 text in the block message
+    #[default]
+    Text,
+    /// JSON attachment of a separate message, the block message holds its id.
+    /// The attachment can't be edited, so it is sent again on every update.
+    Json,
+}
+
+impl FromStr for MetadataFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What is done when editing a metadata block message fails (`METADATA_EDIT_FALLBACK`),
+/// for example because discord refuses to edit it or rate limits the edit.
+/// Deleted block messages are always sent again, and a lost connection is already retried by the backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EditFallback {
+    /// The update fails, the page is synced again later
+    #[default]
+    Fail,
+    /// The edit is tried a few more times, waiting longer every time
+    Retry,
+    /// The block is sent as a new message and the old one is deleted
+    Recreate,
+    /// The block stays unsaved and is stored by the next flush (recreated if the edit fails again)
+    Defer,
+}
+
+impl FromStr for EditFallback {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "retry" => Ok(Self::Retry),
+            "recreate" => Ok(Self::Recreate),
+            "defer" => Ok(Self::Defer),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where the scan for metadata blocks starts (`METADATA_SCAN_ORDER`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScanOrder {
+    /// From the newest message back, blocks moved to the bottom of the channel are found first
+    #[default]
+    NewestFirst,
+    /// From the first message of the channel on, for drives that keep their blocks in place
+    OldestFirst,
+}
+
+impl FromStr for ScanOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(Self::NewestFirst),
+            "oldest" => Ok(Self::OldestFirst),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the channel is scanned for metadata blocks when the drive has no superblock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scan {
+    pub order: ScanOrder,
+    /// Most messages scanned, or with `only_metadata` the most metadata blocks loaded
+    pub limit: usize,
+    /// Count only metadata blocks against `limit`, data and other messages are skipped for free
+    pub only_metadata: bool,
+    /// Most messages scanned in any case
+    pub cap: usize,
+}
+
+impl Scan {
+    /// Scans the `limit` newest messages.
+    pub fn newest(limit: usize) -> Self {
+        Self { order: ScanOrder::NewestFirst, limit, only_metadata: false, cap: limit }
+    }
+
+    /// Most messages the scan may still fetch after scanning `scanned` and finding `found` blocks.
+    fn remaining(&self, scanned: usize, found: usize) -> usize {
+        match self.only_metadata {
+            true if found >= self.limit => 0,
+            true => self.cap.saturating_sub(scanned),
+            false => self.limit.min(self.cap).saturating_sub(scanned),
+        }
+    }
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Self::newest(500)
+    }
+}
 
-use crate::utils::{BitMask, ToBase32, byte_to_base_255, base_255_to_byte};
+/// Block as stored in a JSON attachment.
+#[derive(Serialize, Deserialize)]
+struct JsonBlock {
+    pages: Vec<JsonPage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonPage {
+    offset: u64,
+    message_id: u64,
+    #[serde(default)]
+    key_id: u8,
+    /// Hex encoded, without trailing zero bytes
+    zero_mask: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<u64>,
+}
 
 /// Block containing metadata about discord pages
 pub struct MetadataBlock {
-    /// Id of the message this block is currently associated with
-    pub message_id: u64,
+    /// Id of the message this block is currently associated with, None until it is sent
+    pub message_id: Option<u64>,
     /// Blocks that are linked to this block
-    pub pages: Vec<Page>
+    pub pages: Vec<Page>,
+    /// Whether the message was changed since the block was last moved to the bottom
+    pub changed: bool,
+    /// Whether the last update of the message failed, so the pages may not be stored yet
+    pub unsaved: bool,
+    /// Format the block is written in on the next update
+    pub format: MetadataFormat,
+    /// What is done if editing the block message fails
+    pub edit_fallback: EditFallback,
+    /// Id of the message with the JSON or compressed attachment of the block (0 if there is none)
+    pub attachment_id: u64,
+    /// Text blocks longer than this are compressed into an attachment (`METADATA_COMPRESS_ABOVE`)
+    pub compress_above: Option<usize>,
+    /// Volume the block belongs to (empty for the default volume)
+    pub volume: String,
+    /// Text of the pages as of the last time the block was written
+    pub lines: PageLines,
+}
+
+/// Lines of the text format generated for the pages of a block, by page offset.
+/// Every line is kept with the page it was generated from, so only pages that changed are written again.
+#[derive(Default)]
+pub struct PageLines(Mutex<HashMap<u64, (Page, String)>>);
+
+/// Data returned by `Page::read_range`.
+pub enum PageRead {
+    /// Just the requested bytes
+    Range(Vec<u8>),
+    /// The whole page
+    Full(Zeroizing<Vec<u8>>),
 }
 
-/// Each page is 8MB of data that is stored in a discord message
+/// Each page is `PAGE_SIZE` bytes of data that is stored in a discord message
 #[derive(Clone)]
 pub struct Page {
-    /// Offset of this page (stored as a multiple of 8MB)
+    /// Offset of this page (stored as a multiple of the page size)
     pub offset: u64,
-    /// Id of the message this page is currently associated with
-    pub message_id: u64,
+    /// Id of the message this page is currently associated with, None until it is uploaded
+    /// (or if it was preallocated). Stored as 0 in metadata blocks.
+    pub message_id: Option<u64>,
     /// Bitmask representing which blocks are zeroed out (1 = zeroed, 0 = not zeroed).
     /// This is used for faster reads/writes.
-    pub zero_mask: BitMask<256>, // 256 bytes = 2048 bits (one for each 4KB block)
+    pub zero_mask: BitMask<256>, // 256 bytes = 2048 bits (one for each 4KB block of 8MB pages)
+    /// Id of the key the page data is encrypted with (0 = not encrypted)
+    pub key_id: u8,
+    /// Channel the message is in if the page was relocated (`relocate_page`), None for the channel of the drive
+    pub channel: Option<u64>,
 }
 
 impl MetadataBlock {
-    pub fn empty(message_id: u64) -> Self {
+    pub fn empty(message_id: Option<u64>) -> Self {
         Self {
             message_id,
-            pages: Vec::new()
+            pages: Vec::new(),
+            changed: false,
+            unsaved: false,
+            format: MetadataFormat::Text,
+            edit_fallback: EditFallback::Fail,
+            attachment_id: 0,
+            compress_above: None,
+            volume: String::new(),
+            lines: PageLines::default(),
         }
     }
 
+    /// Returns true if the message text is a metadata block (of any version and volume).
+    /// Other messages that just happen to start with `METABLOCK` are not.
+    pub fn is_metablock(text: &str) -> bool {
+        text.lines().next().and_then(|line| header_volume(line, MAGIC)).is_some()
+    }
+
     /// Loads the metadata from text in a discord message
-    pub fn from_text(message_id: u64, text: &str) -> Self {
+    pub fn from_text(message_id: u64, text: &str) -> Result<Self> {
         // Format:
-        // METABLOCK
-        // <offset>:<message_id>
+        // METABLOCK[:<volume>] v1
+        // <offset>:<message_id>[.<key_id>][@<channel>]:<page_data>
         // ...
 
         let mut pages = Vec::new();
 
         let mut lines = text.lines();
-        lines.next(); // Skip METABLOCK
+        let header = lines.next().unwrap_or_default();
+        let volume = match header_volume(header, MAGIC) {
+            Some((volume, 0 | VERSION)) => volume,
+            // Pages are in the attachment, see `from_message`.
+            Some((_, VERSION_JSON | VERSION_GZIP)) => return Err(Error::InvalidMetadata { message_id, line: header.to_string() }),
+            Some((_, version)) => return Err(Error::UnsupportedVersion { format: "metadata block", version }),
+            None => return Err(Error::InvalidMetadata { message_id, line: header.to_string() }),
+        };
 
         for line in lines {
-            let mut split = line.split(':');
-            let offset = u64::from_base32(split.next().unwrap());
-            let message_id = u64::from_base32(split.next().unwrap());
+            let invalid = || Error::InvalidMetadata { message_id, line: line.to_string() };
+
+            // Page data may contain ':' itself.
+            let mut split = line.splitn(3, ':');
+            let offset = split.next().and_then(try_from_base32).ok_or_else(invalid)?;
+            let (field, channel) = split.next()
+                .and_then(|field| match field.split_once('@') {
+                    Some((field, channel)) => Some((field, Some(try_from_base32(channel)?))),
+                    None => Some((field, None)),
+                })
+                .ok_or_else(invalid)?;
+            let (page_message_id, key_id) = match field.split_once('.') {
+                Some((id, key)) => try_from_base32(id).zip(try_from_base32(key).and_then(|key| u8::try_from(key).ok())),
+                None => try_from_base32(field).map(|id| (id, 0)),
+            }.ok_or_else(invalid)?;
+            let mut page = split.next()
+                .and_then(|text| Page::from_text(stored_message(page_message_id), offset, text))
+                .ok_or_else(invalid)?;
+            page.key_id = key_id;
+            page.channel = channel;
+
+            pages.push(page);
+        }
 
-            pages.push(Page::from_text(message_id, offset, split.next().unwrap()));
+        Ok(Self {
+            pages,
+            volume: volume.to_string(),
+            ..Self::empty(Some(message_id))
+        })
+    }
+
+    /// Loads the block from its message, downloading the attachment of JSON and compressed blocks.
+    pub async fn from_message(backend: &dyn Backend, channel_id: ChannelId, message: &StoredMessage) -> Result<Self> {
+        // Format:
+        // METABLOCK[:<volume>] v2 (or v3)
+        // <attachment_message_id>
+
+        let mut lines = message.content.lines();
+        let (volume, version) = match lines.next().and_then(|line| header_volume(line, MAGIC)) {
+            Some((volume, version @ (VERSION_JSON | VERSION_GZIP))) => (volume, version),
+            _ => return Self::from_text(message.id, &message.content),
+        };
+
+        let line = lines.next().unwrap_or_default();
+        let attachment_id = try_from_base32(line)
+            .ok_or_else(|| Error::InvalidMetadata { message_id: message.id, line: line.to_string() })?;
+        let invalid = |reason: String| Error::InvalidMetadataAttachment { message_id: message.id, reason };
+
+        let attachment = backend.get_message(channel_id, attachment_id).await?;
+        let url = attachment.attachments.first().ok_or_else(|| invalid("the message has no attachment".to_string()))?;
+        let data = backend.download(url).await?;
+
+        if version == VERSION_GZIP {
+            let mut text = String::new();
+            GzDecoder::new(&data[..]).read_to_string(&mut text).map_err(|e| invalid(e.to_string()))?;
+
+            let block = Self::from_text(message.id, &text)?;
+            if block.volume != volume {
+                return Err(invalid(format!("the attachment belongs to volume {:?}", block.volume)));
+            }
+            return Ok(Self { attachment_id, ..block });
         }
+        let json: JsonBlock = serde_json::from_slice(&data).map_err(|e| invalid(e.to_string()))?;
 
-        Self {
-            message_id,
-            pages
+        let mut pages = Vec::new();
+        for page in json.pages {
+            let zero_mask = (0..page.zero_mask.len()).step_by(2)
+                .map(|i| page.zero_mask.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .filter(|bytes| bytes.len() <= 256)
+                .ok_or_else(|| invalid(format!("invalid zero mask of page {}", page.offset)))?;
+
+            let mut bytes = [0; 256];
+            bytes[..zero_mask.len()].copy_from_slice(&zero_mask);
+            pages.push(Page {
+                message_id: stored_message(page.message_id),
+                key_id: page.key_id,
+                zero_mask: BitMask::from_bytes(&bytes),
+                channel: page.channel,
+                ..Page::new(page.offset)
+            });
+        }
+
+        Ok(Self {
+            pages,
+            format: MetadataFormat::Json,
+            attachment_id,
+            volume: volume.to_string(),
+            ..Self::empty(Some(message.id))
+        })
+    }
+
+    /// Generates the JSON attachment of the block.
+    pub fn as_json(&self) -> Vec<u8> {
+        let pages = self.pages.iter().map(|page| {
+            let zero_mask = page.zero_mask.as_bytes();
+            let len = zero_mask.iter().rposition(|byte| *byte != 0).map_or(0, |i| i + 1);
+
+            JsonPage {
+                offset: page.offset,
+                message_id: page.message_id.unwrap_or(0),
+                key_id: page.key_id,
+                zero_mask: zero_mask[..len].iter().map(|byte| format!("{:02x}", byte)).collect(),
+                channel: page.channel,
+            }
+        }).collect();
+
+        // Plain structs of numbers and strings always serialize.
+        serde_json::to_vec(&JsonBlock { pages }).unwrap()
+    }
+
+    /// Generates the compressed attachment of a text block.
+    pub fn as_gzip(&self) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        // Writing to a vector can't fail.
+        encoder.write_all(self.as_text().as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Text of the block message in the format of the block.
+    fn message_text(&self) -> String {
+        match self.format {
+            // The text is in the compressed attachment.
+            MetadataFormat::Text if self.attachment_id != 0 => format!("{} v{}\n{}\n", volume_magic(MAGIC, &self.volume), VERSION_GZIP, self.attachment_id.to_base32()),
+            MetadataFormat::Text => self.as_text(),
+            MetadataFormat::Json => format!("{} v{}\n{}\n", volume_magic(MAGIC, &self.volume), VERSION_JSON, self.attachment_id.to_base32()),
         }
     }
 
     /// Generates the text that should be stored in a discord message
     pub fn as_text(&self) -> String {
         // Format:
-        // METABLOCK
-        // <offset>:<message_id>:<page_data>
+        // METABLOCK[:<volume>] v1
+        // <offset>:<message_id>[.<key_id>][@<channel>]:<page_data>
         // ...
 
         let mut text = String::new();
 
-        text.push_str("METABLOCK\n");
+        text.push_str(&format!("{} v{}\n", volume_magic(MAGIC, &self.volume), VERSION));
 
+        let mut lines = self.lines.0.lock_or_recover();
+        let mut current = HashMap::with_capacity(self.pages.len());
         for page in &self.pages {
-            let line = format!("{}:{}:{}\n", page.offset.to_base32(), page.message_id.to_base32(), page.as_text());
+            let line = match lines.remove(&page.offset) {
+                Some((old, line)) if old.message_id == page.message_id && old.key_id == page.key_id && old.channel == page.channel
+                    && old.zero_mask.as_bytes() == page.zero_mask.as_bytes() => line,
+                _ => {
+                    // Unencrypted pages in the channel of the drive are written the same way as before encryption existed.
+                    let mut message_id = page.message_id.unwrap_or(0).to_base32();
+                    if page.key_id != 0 {
+                        message_id = format!("{}.{}", message_id, (page.key_id as u64).to_base32());
+                    }
+                    if let Some(channel) = page.channel {
+                        message_id = format!("{}@{}", message_id, channel.to_base32());
+                    }
+                    format!("{}:{}:{}\n", page.offset.to_base32(), message_id, page.as_text())
+                }
+            };
             text.push_str(&line);
+            current.insert(page.offset, (page.clone(), line));
         }
 
+        // Lines of pages that left the block are forgotten.
+        *lines = current;
+
         text
     }
 
-    pub async fn load_from_discord(http: &Http, channel_id: ChannelId, message_id: u64) -> Self {
-        let message = channel_id.message(http, message_id).await.unwrap();
+    /// Text of the pages of the block, without the header line.
+    pub fn pages_text(&self) -> String {
+        let text = self.as_text();
+        text.split_once('\n').map(|(_, pages)| pages.to_string()).unwrap_or_default()
+    }
 
-        Self::from_text(message_id, &message.content)
+    /// Loads the block from the text of its pages (see `pages_text`).
+    pub fn from_pages_text(message_id: u64, text: &str) -> Result<Self> {
+        Self::from_text(message_id, &format!("{} v{}\n{}", MAGIC, VERSION, text))
     }
 
-    pub async fn move_to_bottom(&mut self, http: &Http, channel_id: ChannelId) {
-        if self.message_id != 0 {
+    pub async fn load_from_discord(backend: &dyn Backend, channel_id: ChannelId, message_id: u64) -> Result<Self> {
+        let message = backend.get_message(channel_id, message_id).await?;
+
+        Self::from_message(backend, channel_id, &message).await
+    }
+
+    pub async fn move_to_bottom(&mut self, backend: &dyn Backend, channel_id: ChannelId) -> Result<()> {
+        if let Some(message_id) = self.message_id {
             // Delete old message
-            channel_id.delete_message(http, self.message_id).await.ok();
+            backend.delete_message(channel_id, message_id).await.ok();
         }
 
         // Create message
-        let message = channel_id.send_message(http, |m| {
-            m.content(self.as_text())
-        }).await.unwrap();
+        let message_id = backend.send_message(channel_id, &self.message_text()).await?;
 
         // Set message id
-        self.message_id = message.id.0;
+        self.message_id = Some(message_id);
+        self.changed = false;
+
+        Ok(())
     }
 
-    pub async fn load_all(http: &Http, channel_id: ChannelId, mut limit: usize) -> Vec<Self> {
+    /// Scans the channel for metadata blocks of the volume, in the order and up to the limit of `scan`.
+    /// Everything else in the channel (including blocks of other volumes) is skipped.
+    pub async fn load_all(backend: &dyn Backend, channel_id: ChannelId, scan: &Scan, volume: &str) -> Result<Vec<Self>> {
         let mut blocks = Vec::new();
+        let mut scanned = 0;
+        let mut skipped_data = 0;
+        let mut skipped_other = 0;
 
-        let mut current_id = 0;
+        let mut remaining = scan.remaining(0, 0);
+        let mut batch = Vec::new();
 
-        while limit > 0 {
-            let messages = 
-                channel_id.messages(http, |retriever| {
-                    retriever.limit(100);
-                    if current_id != 0 {
-                        retriever.before(current_id);
+        if remaining > 0 {
+            batch = Self::scan_batch(backend, channel_id, scan.order, None, remaining.min(100)).await?;
+        }
+
+        // Continue past the oldest (or newest) message of the batch. It doesn't matter if it was deleted meanwhile,
+        // since message ids only grow, but the batch may not be sorted.
+        let cursor_of = |batch: &[StoredMessage]| match scan.order {
+            ScanOrder::NewestFirst => batch.iter().map(|message| message.id).min(),
+            ScanOrder::OldestFirst => batch.iter().map(|message| message.id).max(),
+        };
+        while let Some(cursor) = cursor_of(&batch) {
+            // A short batch means we reached the end of the channel.
+            let requested = remaining.min(100);
+            // Blocks are only counted once they are parsed, the next batch is fetched meanwhile anyway.
+            remaining = scan.remaining(scanned + batch.len(), blocks.len());
+            let more = remaining > 0 && batch.len() >= requested;
+
+            // Fetch the next batch while parsing the current one.
+            let (next, parsed) = tokio::join!(
+                async {
+                    if more {
+                        Self::scan_batch(backend, channel_id, scan.order, Some(cursor), remaining.min(100)).await
+                    } else {
+                        Ok(Vec::new())
                     }
-                    retriever
-                })
-                .await
-                .unwrap();
+                },
+                async {
+                    let mut parsed = Vec::new();
+                    for message in batch.iter() {
+                        if !message.attachments.is_empty() {
+                            skipped_data += 1;
+                            continue;
+                        }
+                        if !Self::is_metablock(&message.content) {
+                            skipped_other += 1;
+                            continue;
+                        }
+
+                        match Self::from_message(backend, channel_id, message).await {
+                            Ok(block) if block.volume == volume => parsed.push(block),
+                            Ok(_) => skipped_other += 1,
+                            Err(e) => {
+                                log::warn!("Skipping metadata block: {}", e);
+                                skipped_other += 1;
+                            }
+                        }
+                    }
+                    parsed
+                }
+            );
 
-            if messages.len() == 0 {
+            scanned += batch.len();
+            blocks.extend(parsed);
+            if scan.remaining(scanned, blocks.len()) == 0 {
                 break;
             }
 
-            for message in messages.iter() {
-                if message.content.starts_with("METABLOCK") {
-                    blocks.push(Self::from_text(message.id.0, &message.content));
-                }
-            }
+            // Messages on the scanned side of the cursor were already scanned.
+            batch = next?;
+            batch.retain(|message| match scan.order {
+                ScanOrder::NewestFirst => message.id < cursor,
+                ScanOrder::OldestFirst => message.id > cursor,
+            });
+        }
+
+        log::info!(
+            "Scanned {} messages, found {} metadata blocks (skipped {} data pages and {} other messages).",
+            scanned, blocks.len(), skipped_data, skipped_other
+        );
+
+        Ok(blocks)
+    }
+
+    /// Fetches the batch of history past the cursor in the order of the scan, or the first batch without one.
+    async fn scan_batch(backend: &dyn Backend, channel_id: ChannelId, order: ScanOrder, cursor: Option<u64>, limit: usize) -> Result<Vec<StoredMessage>> {
+        let batch = match order {
+            ScanOrder::NewestFirst => backend.get_messages(channel_id, cursor, limit as u64).await?,
+            ScanOrder::OldestFirst => backend.get_messages_after(channel_id, cursor.unwrap_or(0), limit as u64).await?,
+        };
+
+        Ok(batch)
+    }
+
+    /// Removes pages that are entirely zeroed and deletes their data messages (in `data_channel`).
+    /// Pages that are not listed read as zeros anyway. Returns the number of removed pages.
+    /// Pages without a data message were preallocated (synced pages always have one), they stay reserved.
+    pub async fn drop_zeroed(&mut self, backend: &dyn Backend, channel_id: ChannelId, data_channel: ChannelId, options: &PageOptions) -> Result<usize> {
+        let (zeroed, kept): (Vec<Page>, Vec<Page>) = self.pages.drain(..)
+            .partition(|page| page.message_id.is_some() && page.is_zeroed(options.size, options.zero_block_size));
+        self.pages = kept;
+
+        if zeroed.is_empty() {
+            return Ok(0);
+        }
+
+        // Update the block first, so it never points at a deleted message.
+        if let Err(e) = self.update_message(backend, &channel_id).await {
+            self.pages.extend(zeroed);
+            return Err(e);
+        }
 
-            limit -= messages.len();
-            current_id = messages.last().unwrap().id.0;
+        // Old versions of the pages are kept with the history.
+        for page in zeroed.iter().filter(|_| !options.keep_history) {
+            let Some(message_id) = page.message_id else {
+                continue;
+            };
+            options.deletions.delete(backend, page.channel_or(data_channel), message_id).await;
+            options.urls.remove(message_id);
         }
 
-        blocks
+        Ok(zeroed.len())
     }
 
-    pub async fn try_read(&self, channel: &ChannelId, http: &Http, offset: u64) -> Option<(Vec<u8>, Page)> {
-        // Check if page exists
-        let page = self.pages.iter().find(|page| page.offset == offset / (1024*1024*8));
+    /// Merges pages of underfull blocks into as few blocks as possible,
+    /// deleting messages of the blocks that end up empty.
+    /// Pages keep their data messages. Returns the number of removed blocks.
+    pub async fn compact(blocks: &mut Vec<Self>, backend: &dyn Backend, channel_id: ChannelId) -> Result<usize> {
+        let (mut underfull, full): (Vec<Self>, Vec<Self>) = blocks
+            .drain(..)
+            .partition(|block| block.pages.len() < PAGES_PER_BLOCK);
+        blocks.extend(full);
+
+        let pages: Vec<Page> = underfull.iter_mut().flat_map(|block| block.pages.drain(..)).collect();
+        let needed = pages.len().div_ceil(PAGES_PER_BLOCK);
+
+        // Nothing to merge, put the blocks back untouched.
+        if needed >= underfull.len() {
+            for (block, pages) in underfull.iter_mut().zip(pages.chunks(PAGES_PER_BLOCK)) {
+                block.pages = pages.to_vec();
+            }
+            blocks.extend(underfull);
+            return Ok(0);
+        }
 
-        if let Some(page) = page {
-            // Read page
-            Some((page.read(channel, http, offset).await, page.clone()))
-        } else {
-            None
+        let removed = underfull.split_off(needed);
+
+        // Update the kept blocks first, so no page is ever missing on discord.
+        for (mut block, pages) in underfull.into_iter().zip(pages.chunks(PAGES_PER_BLOCK)) {
+            block.pages = pages.to_vec();
+            block.update_message(backend, &channel_id).await?;
+            blocks.push(block);
+        }
+
+        for block in removed.iter() {
+            if let Some(message_id) = block.message_id {
+                backend.delete_message(channel_id, message_id).await?;
+            }
+            if block.attachment_id != 0 {
+                backend.delete_message(channel_id, block.attachment_id).await.ok();
+            }
         }
+
+        Ok(removed.len())
     }
 
-    pub async fn try_write(&mut self, channel: &ChannelId, http: &Http, offset: u64, data: &[u8]) -> Option<(Vec<u8>, Page)> {
+    /// Writes data to the page at offset (reading its current data from `channel`), adding the page if the block has room for it.
+    /// A new page is recorded in the block message in `meta_channel` right away.
+    pub async fn try_write(&mut self, channel: &ChannelId, meta_channel: &ChannelId, backend: &dyn Backend, options: &PageOptions, offset: u64, data: &[u8]) -> Result<Option<(Zeroizing<Vec<u8>>, Page)>> {
         // Check if page with offset exists
-        let page = self.pages.iter_mut().find(|page| page.offset == offset / (1024*1024*8));
+        let page = self.pages.iter_mut().find(|page| page.offset == offset / options.size as u64);
 
         if let Some(page) = page {
             // Write page
-            let d = page.write(channel, http, offset, data).await;
+            let d = page.write(channel, backend, options, offset, data).await;
             return d;
         }
 
         // Check if there is enough space to create a new page
-        if self.pages.len() >= 5 {
-            return None;
+        if self.pages.len() >= PAGES_PER_BLOCK {
+            return Ok(None);
         }
 
         // Create new page
-        let mut page = Page::new(offset / (1024*1024*8));
+        let mut page = Page::new(offset / options.size as u64);
 
         // Write page
-        let d = page.write(channel, http, offset, data).await;
+        let d = page.write(channel, backend, options, offset, data).await;
         self.pages.push(page);
-        self.update_message(http, channel).await;
+        self.update_message(backend, meta_channel).await?;
         d
     }
 
-    pub async fn update_page(&mut self, http: &Http, channel: &ChannelId, page_new: Page) -> bool {
-        // Check if page with offset exists
-        let page = self.pages.iter_mut().find(|page| page.offset == page_new.offset);
+    pub async fn update_page(&mut self, backend: &dyn Backend, channel: &ChannelId, page_new: Page) -> Result<bool> {
+        if !self.set_page(&page_new) {
+            return Ok(false);
+        }
 
-        if let Some(page) = page {
-            page.message_id = page_new.message_id;
-            page.zero_mask = page_new.zero_mask;
-        } else {
+        self.update_message(backend, channel).await?;
+        Ok(true)
+    }
+
+    /// Points the page with the same offset at the message, zero mask, key and channel of `page_new`,
+    /// without storing the block. Returns false if the page is not in the block.
+    pub fn set_page(&mut self, page_new: &Page) -> bool {
+        let Some(page) = self.pages.iter_mut().find(|page| page.offset == page_new.offset) else {
             return false;
-        }
+        };
 
-        self.update_message(http, channel).await;
+        page.message_id = page_new.message_id;
+        page.zero_mask = page_new.zero_mask.clone();
+        page.key_id = page_new.key_id;
+        page.channel = page_new.channel;
         true
     }
 
-    pub async fn update_message(&mut self, http: &Http, channel: &ChannelId) {
-        if self.message_id == 0 {
-            let message = channel.send_message(http, |m| {
-                m.content(self.as_text())
-            }).await.unwrap();
-            self.message_id = message.id.0;
-            return;
+    /// Returns a copy of the block that can be stored without holding the metadata lock.
+    /// The block is marked unsaved until the copy is taken back with `adopt_stored`.
+    pub fn detach(&mut self) -> Self {
+        self.changed = true;
+        self.unsaved = true;
+        Self {
+            message_id: self.message_id,
+            pages: self.pages.clone(),
+            changed: self.changed,
+            unsaved: self.unsaved,
+            format: self.format,
+            edit_fallback: self.edit_fallback,
+            attachment_id: self.attachment_id,
+            compress_above: self.compress_above,
+            volume: self.volume.clone(),
+            lines: PageLines(Mutex::new(self.lines.0.lock_or_recover().clone())),
+        }
+    }
+
+    /// Takes over the message a detached copy of the block was stored in, `detached_from` being the message
+    /// of the block when it was detached. If the block was stored elsewhere meanwhile its message is kept,
+    /// and if its pages changed meanwhile it stays unsaved, so the next flush stores them.
+    /// Returns the messages of the copy that are not used by the block, to be deleted.
+    pub fn adopt_stored(&mut self, detached_from: Option<u64>, stored: Self) -> Vec<u64> {
+        if self.message_id != detached_from {
+            self.unsaved = true;
+            let unused = [stored.message_id.filter(|id| Some(*id) != self.message_id && Some(*id) != detached_from), Some(stored.attachment_id)];
+            return unused.into_iter().flatten().filter(|id| *id != 0 && *id != self.attachment_id).collect();
+        }
+
+        self.message_id = stored.message_id;
+        self.attachment_id = stored.attachment_id;
+        self.unsaved = self.as_text() != stored.as_text();
+        Vec::new()
+    }
+
+    /// Exchanges the messages (and zero masks) two pages of the block point at, without moving any data.
+    /// The block is written once. Returns false if either page is not in the block.
+    pub async fn swap_pages(&mut self, backend: &dyn Backend, channel: &ChannelId, a: u64, b: u64) -> Result<bool> {
+        let position = |offset| self.pages.iter().position(|page| page.offset == offset);
+        let (Some(a), Some(b)) = (position(a), position(b)) else {
+            return Ok(false);
+        };
+        if a == b {
+            return Ok(true);
+        }
+
+        let (head, tail) = self.pages.split_at_mut(a.max(b));
+        let (first, second) = (&mut head[a.min(b)], &mut tail[0]);
+        std::mem::swap(&mut first.message_id, &mut second.message_id);
+        std::mem::swap(&mut first.zero_mask, &mut second.zero_mask);
+        std::mem::swap(&mut first.key_id, &mut second.key_id);
+        std::mem::swap(&mut first.channel, &mut second.channel);
+
+        self.update_message(backend, channel).await?;
+        Ok(true)
+    }
+
+    /// Stores the block in its message. If there is no message yet (or it was deleted), a new one is sent.
+    /// JSON blocks (and text blocks longer than `compress_above`) send a new attachment first,
+    /// and delete the old one once the block points at the new one.
+    pub async fn update_message(&mut self, backend: &dyn Backend, channel: &ChannelId) -> Result<()> {
+        self.store(backend, channel, self.edit_fallback).await
+    }
+
+    /// Like `update_message`, with the fallback used if the edit of the block message fails.
+    pub async fn store(&mut self, backend: &dyn Backend, channel: &ChannelId, fallback: EditFallback) -> Result<()> {
+        self.changed = true;
+        // Stays set if the update fails (or panics) before the block is stored.
+        self.unsaved = true;
+        let old_attachment = self.attachment_id;
+        self.attachment_id = match self.format {
+            MetadataFormat::Text => match self.compress_above {
+                Some(limit) if self.as_text().len() > limit => backend.send_file(*channel, "", "metablock.txt.gz", &self.as_gzip()).await?,
+                _ => 0,
+            },
+            MetadataFormat::Json => backend.send_file(*channel, "", "metablock.json", &self.as_json()).await?,
+        };
+
+        let stored = self.edit_or_send(backend, channel, fallback).await;
+        if !matches!(stored, Ok(true)) {
+            // The block still points at the old attachment.
+            if self.attachment_id != 0 {
+                backend.delete_message(*channel, self.attachment_id).await.ok();
+            }
+            self.attachment_id = old_attachment;
+            return stored.map(|_| ());
+        }
+
+        if old_attachment != 0 {
+            backend.delete_message(*channel, old_attachment).await.ok();
         }
+        self.unsaved = false;
+        Ok(())
+    }
 
-        let mut message = channel.message(http, self.message_id).await.unwrap();
+    /// Returns false if the update was deferred (`EditFallback::Defer`).
+    async fn edit_or_send(&mut self, backend: &dyn Backend, channel: &ChannelId, fallback: EditFallback) -> Result<bool> {
+        if let Some(message_id) = self.message_id {
+            let text = self.message_text();
+            let mut result = backend.edit_message(*channel, message_id, &text).await;
+            let mut delay = EDIT_RETRY_DELAY;
+            for _ in 0..EDIT_RETRIES {
+                match &result {
+                    Err(e) if fallback == EditFallback::Retry && !e.is_connection_error() && *e != BackendError::NotFound => {
+                        log::warn!("Failed to edit metadata block {} ({}), trying again in {:?}.", message_id, e, delay);
+                    }
+                    _ => break,
+                }
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                result = backend.edit_message(*channel, message_id, &text).await;
+            }
 
-        message.edit(http, |m| {
-            m.content(self.as_text())
-        }).await.unwrap();
+            match result {
+                Ok(()) => return Ok(true),
+                Err(BackendError::NotFound) => {
+                    log::warn!("Metadata block {} was deleted, sending it again.", message_id);
+                }
+                Err(e) if fallback == EditFallback::Defer => {
+                    log::warn!("Failed to edit metadata block {} ({}), storing it with the next flush.", message_id, e);
+                    return Ok(false);
+                }
+                // Sending the block wouldn't work without a connection either.
+                Err(e) if fallback == EditFallback::Recreate && !e.is_connection_error() => {
+                    log::warn!("Failed to edit metadata block {} ({}), sending it as a new message.", message_id, e);
+                    self.message_id = Some(backend.send_message(*channel, &text).await?);
+                    backend.delete_message(*channel, message_id).await.ok();
+                    return Ok(true);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.message_id = Some(backend.send_message(*channel, &self.message_text()).await?);
+        Ok(true)
     }
 }
 
@@ -203,29 +987,55 @@ impl Page {
     pub fn new(offset: u64) -> Self {
         Self {
             offset,
-            message_id: 0,
-            zero_mask: BitMask::new()
+            message_id: None,
+            zero_mask: BitMask::new(),
+            key_id: 0,
+            channel: None,
         }
     }
 
-    /// Loads the metadata from text in a discord message
-    pub fn from_text(message_id: u64, offset: u64, text: &str) -> Self {
+    /// Channel the message of the page is in, `channel` being the channel of the drive.
+    pub fn channel_or(&self, channel: ChannelId) -> ChannelId {
+        self.channel.map_or(channel, ChannelId)
+    }
+
+    /// Returns true if every block of the page is zeroed.
+    pub fn is_zeroed(&self, page_size: usize, block_size: usize) -> bool {
+        (0..zero_blocks(page_size, block_size)).all(|i| self.zero_mask.get(i))
+    }
+
+    /// Bytes of the page that are not zeroed.
+    pub fn used_bytes(&self, page_size: usize, block_size: usize) -> usize {
+        let size = block_size;
+        let zeroed: usize = (0..zero_blocks(page_size, block_size))
+            .filter(|i| self.zero_mask.get(*i))
+            .map(|i| size.min(page_size - i * size))
+            .sum();
+
+        page_size - zeroed
+    }
+
+    /// Loads the metadata from text in a discord message.
+    /// Returns None if the text is not a valid zero mask.
+    pub fn from_text(message_id: Option<u64>, offset: u64, text: &str) -> Option<Self> {
         // Format:
         // <zero_mask>
 
         let mut zero_mask_bytes = [0; 256];
 
         for (i, byte) in text.chars().enumerate() {
-            zero_mask_bytes[i] = base_255_to_byte(byte);
+            *zero_mask_bytes.get_mut(i)? = try_base_255_to_byte(byte)?;
         }
 
         let zero_mask = BitMask::from_bytes(&zero_mask_bytes);
 
-        Self {
+        Some(Self {
             offset,
             message_id,
-            zero_mask
-        }
+            zero_mask,
+            key_id: 0,
+            channel: None,
+        })
     }
 
     /// Generates the text that should be stored in a discord message
@@ -235,65 +1045,215 @@ impl Page {
 
         let mut text = String::new();
 
+        // Trailing blocks that are not zeroed are left out, so pages with fewer
+        // (bigger) zero mask blocks take less space.
         let zero_mask = self.zero_mask.as_bytes();
+        let len = zero_mask.iter().rposition(|byte| *byte != 0).map_or(0, |i| i + 1);
 
-        for byte in zero_mask {
+        for byte in &zero_mask[..len] {
             text.push(byte_to_base_255(*byte));
         }
 
         text
     }
 
-    /// Read at relative offset
-    pub async fn read(&self, channel: &ChannelId, http: &Http, offset: u64) -> Vec<u8> {
-        // If page message id is 0, return empty data
-        if self.message_id == 0 {
-            return vec![0; 1024*1024*8];
+    /// Reads the whole page containing the offset
+    pub async fn read(&self, channel: &ChannelId, backend: &dyn Backend, options: &PageOptions) -> Result<Zeroizing<Vec<u8>>> {
+        let channel = &self.channel_or(*channel);
+        let page_size = options.size;
+
+        // A page without a message was never uploaded, return empty data
+        let Some(message_id) = self.message_id else {
+            return Ok(options.buffers.take(page_size));
+        };
+
+        // Whole page is zeroed, no need to download it.
+        // (The returned buffer is cached as the whole page, so a single
+        // zeroed block is not enough to skip the download.)
+        if self.is_zeroed(page_size, options.zero_block_size) {
+            return Ok(options.buffers.take(page_size));
         }
 
-        // Calculate offset
-        let offset = offset - self.offset * 1024 * 1024 * 8;
+        // Read message from discord
+        let fetch_url = || self.fetch_url(channel, backend, options, message_id);
+        let (mut url, mut cached) = match options.urls.get(message_id) {
+            Some(url) => (url, true),
+            None => (fetch_url().await?, false),
+        };
+
+        // Read data from message, downloading it again if it came broken.
+        let _slot = options.download_slot().await;
+        let mut error = None;
+        for attempt in 1..=options.download_attempts.max(1) {
+            let mut result = backend.download(&url).await;
+            if cached && result.is_err() {
+                // The cached url may have stopped working, try a fresh one.
+                options.urls.remove(message_id);
+                url = fetch_url().await?;
+                cached = false;
+                result = backend.download(&url).await;
+            }
 
-        // Check mask
-        if self.zero_mask.get((offset / 4096) as usize) {
-            return vec![0; 1024*1024*8];
+            let mut data = match result {
+                Ok(data) => Zeroizing::new(data),
+                // The url was just fetched from the message, so it didn't only expire.
+                Err(BackendError::NotFound) => return Err(Error::MissingAttachment { offset: self.offset, message_id }),
+                Err(e) => {
+                    log::warn!("Failed to download page at offset {} (attempt {}): {}", self.offset, attempt, e);
+                    error = Some(e.into());
+                    continue;
+                }
+            };
+
+            match self.strip_header(&data, page_size) {
+                // With bounded memory, the page is decrypted where it was downloaded instead of into another buffer.
+                Ok(stored) if options.memory.is_bounded() => {
+                    let start = data.len() - stored.len();
+                    options.keyring.decrypt_in_place(self.key_id, &mut data, start)?;
+                    return Ok(data);
+                }
+                Ok(stored) if self.key_id == 0 => return Ok(options.buffers.copy(stored)),
+                Ok(stored) => return Ok(options.keyring.decrypt(self.key_id, stored)?.into()),
+                Err(e @ (Error::InvalidPageLength { .. } | Error::ChecksumMismatch { .. })) => {
+                    log::warn!("Downloaded page is broken (attempt {}): {}", attempt, e);
+                    error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
         }
 
-        // Read message from discord
-        let message = channel.message(http, self.message_id).await.unwrap();
+        Err(error.unwrap())
+    }
 
-        // Read data from message (using reqwest)
-        let url = message.attachments[0].url.clone();
+    /// Reads `len` bytes at `offset` within the page, downloading just them if the server supports it.
+    /// Encrypted pages and pages with a checksum can only be checked whole, so they are downloaded whole,
+    /// like pages the server sent whole anyway (those are returned to be cached).
+    pub async fn read_range(&self, channel: &ChannelId, backend: &dyn Backend, options: &PageOptions, offset: usize, len: usize) -> Result<PageRead> {
+        let channel = &self.channel_or(*channel);
+        let blocks = offset / options.zero_block_size..(offset + len).div_ceil(options.zero_block_size);
+        let message_id = match self.message_id {
+            Some(message_id) if !blocks.into_iter().all(|i| self.zero_mask.get(i)) => message_id,
+            _ => return Ok(PageRead::Range(vec![0; len])),
+        };
+
+        if self.key_id != 0 || options.checksums {
+            return Ok(PageRead::Full(self.read(channel, backend, options).await?));
+        }
 
-        let data = reqwest::get(&url).await.unwrap().bytes().await.unwrap();
+        let url = match options.urls.get(message_id) {
+            Some(url) => url,
+            None => self.fetch_url(channel, backend, options, message_id).await?,
+        };
+
+        // Pages are uploaded with the version 1 header when checksums are off,
+        // the size of the attachment tells if this one was.
+        let start = PAGE_HEADER_LEN + offset;
+        let slot = options.download_slot().await;
+        let download = backend.download_range(&url, start..start + len).await;
+        drop(slot);
+        match download {
+            Ok(Download::Range { data, total }) if total == upload_len(0, options.size, false) && data.len() == len => {
+                return Ok(PageRead::Range(data));
+            }
+            Ok(Download::Full(data)) => {
+                if let Ok(stored) = self.strip_header(&data, options.size) {
+                    return Ok(PageRead::Full(options.buffers.copy(stored)));
+                }
+            }
+            Ok(Download::Range { .. }) => {}
+            Err(e) => log::warn!("Failed to download part of page at offset {}: {}", self.offset, e),
+        }
 
-        // Return data
-        data.to_vec()
+        // Anything unexpected is handled by the whole page download.
+        Ok(PageRead::Full(self.read(channel, backend, options).await?))
     }
 
-    /// Write at relative offset. Returns new data if the page was modified.
-    pub async fn write(&mut self, channel: &ChannelId, http: &Http, ooffset: u64, data: &[u8]) -> Option<(Vec<u8>, Page)> {
-        let mut current_data = vec![0; 1024 * 1024 * 8];
-        let offset = ooffset - self.offset * 1024 * 1024 * 8;
+    /// Returns the url of the page attachment, from the url cache or its message,
+    /// and whether it was cached. None if the page was never uploaded.
+    pub async fn url(&self, channel: &ChannelId, backend: &dyn Backend, options: &PageOptions) -> Result<Option<(String, bool)>> {
+        let Some(message_id) = self.message_id else {
+            return Ok(None);
+        };
 
-        // Check if page is already written
-        if self.message_id != 0 {
-            // Read current data
-            current_data = self.read(channel, http, ooffset).await;
+        match options.urls.get(message_id) {
+            Some(url) => Ok(Some((url, true))),
+            None => Ok(Some((self.fetch_url(&self.channel_or(*channel), backend, options, message_id).await?, false))),
         }
+    }
 
-        // Check if data is all zeroes
-        if data.iter().all(|byte| *byte == 0) {
-            // Set mask
-            self.zero_mask.set((offset / 4096) as usize, true);
+    /// Fetches the url of the page attachment (in message `message_id`) and caches it.
+    async fn fetch_url(&self, channel: &ChannelId, backend: &dyn Backend, options: &PageOptions, message_id: u64) -> Result<String> {
+        let message = backend.get_message(*channel, message_id).await.map_err(|e| self.missing(message_id, e))?;
+        let url = message.attachments.first().ok_or(Error::MissingAttachment { offset: self.offset, message_id })?;
+        options.urls.insert(message_id, url);
+        Ok(url.clone())
+    }
+
+    /// Downloads the upload in the message and checks that it is the uploaded file.
+    /// The url is cached, so the first read of the page doesn't fetch the message again.
+    async fn verify_upload(&self, channel: &ChannelId, backend: &dyn Backend, options: &PageOptions, message_id: u64, file: &[u8]) -> Result<()> {
+        let message = backend.get_message(*channel, message_id).await?;
+        let url = message.attachments.first().ok_or(BackendError::NotFound)?;
+        if backend.download(url).await? != file {
+            return Err(Error::UploadMismatch { offset: self.offset });
+        }
+
+        options.urls.insert(message_id, url);
+        Ok(())
+    }
+
+    /// Reports the page message as missing if it doesn't exist.
+    fn missing(&self, message_id: u64, e: BackendError) -> Error {
+        match e {
+            BackendError::NotFound => Error::MissingPage { offset: self.offset, message_id },
+            e => e.into(),
+        }
+    }
 
-            return Some((current_data, self.clone()));
+    /// Returns the stored page data without the format header, checking its length and checksum.
+    fn strip_header<'a>(&self, data: &'a [u8], page_size: usize) -> Result<&'a [u8]> {
+        let stored = Keyring::stored_len(self.key_id, page_size);
+        let invalid_length = |checksum| Error::InvalidPageLength {
+            offset: self.offset,
+            expected: upload_len(self.key_id, page_size, checksum),
+            actual: data.len(),
+        };
+
+        // Unversioned page.
+        if data.len() == stored {
+            return Ok(data);
         }
 
-        // Modify data
-        for (i, byte) in data.iter().enumerate() {
-            current_data[offset as usize + i as usize] = *byte;
+        match data.strip_prefix(PAGE_MAGIC).and_then(|rest| rest.split_first()) {
+            Some((&PAGE_VERSION, rest)) => Some(rest).filter(|rest| rest.len() == stored).ok_or_else(|| invalid_length(false)),
+            Some((&PAGE_VERSION_CHECKSUM, rest)) => {
+                if rest.len() != CHECKSUM_LEN + stored {
+                    return Err(invalid_length(true));
+                }
+
+                let (checksum, rest) = rest.split_at(CHECKSUM_LEN);
+                if crc32fast::hash(rest).to_le_bytes() != checksum {
+                    return Err(Error::ChecksumMismatch { offset: self.offset });
+                }
+                Ok(rest)
+            }
+            Some((&version, _)) => Err(Error::UnsupportedVersion { format: "page", version: version as u32 }),
+            None => Err(invalid_length(false)),
         }
+    }
+
+    /// Write at relative offset. Returns new data if the page was modified.
+    pub async fn write(&mut self, channel: &ChannelId, backend: &dyn Backend, options: &PageOptions, ooffset: u64, data: &[u8]) -> Result<Option<(Zeroizing<Vec<u8>>, Page)>> {
+        let offset = ooffset - self.offset * options.size as u64;
+
+        // Read current data if page is already written
+        let mut current_data = match self.message_id {
+            None => options.buffers.take(options.size),
+            Some(_) => self.read(channel, backend, options).await?,
+        };
+
+        // Modify data and flip mask if needed
+        write_block(&mut current_data, &mut self.zero_mask, options.zero_block_size, offset as usize, data);
 
         // // Create message
         // let page_name = format!("page_{}.bin", self.offset);
@@ -309,51 +1269,1130 @@ impl Page {
         // self.message_id = message.id.0;
 
         // Return
-        Some((current_data, self.clone()))
+        Ok(Some((current_data, self.clone())))
     }
 
-    pub async fn update_message(&mut self, http: &Http, channel: &ChannelId, data: &[u8]) {
-        let page_name = format!("page_{}.bin", self.offset);
-        if self.message_id != 0 {
-            // Delete old message
-            channel.delete_message(http, self.message_id).await.ok();
+    /// Uploads the page data encrypted with the current key of the keyring.
+    /// Without a file name template the file gets a random name, so only metadata tells which page it holds.
+    /// The page stays in its channel if it was relocated, `channel` is the channel of the drive.
+    pub async fn update_message(&mut self, backend: &dyn Backend, channel: &ChannelId, options: &PageOptions, data: &[u8]) -> Result<()> {
+        if let Some(old) = self.message_id.filter(|_| !options.keep_history) {
+            // Delete old message (once no read can be using it anymore)
+            options.deletions.delete(backend, self.channel_or(*channel), old).await;
         }
+        self.upload(backend, channel, options, data).await
+    }
 
-        // Create message
-        let files = vec![(
-            data,
-            page_name.as_str(),
-        )];
-        let message = channel.send_files(http, files, |m| {
-            m.content("DATA PAGE")
-        }).await.unwrap();
+    /// Like `update_message`, but the old message of the page is left for the caller to delete
+    /// once the stored metadata doesn't point at it anymore.
+    pub async fn upload(&mut self, backend: &dyn Backend, channel: &ChannelId, options: &PageOptions, data: &[u8]) -> Result<()> {
+        let channel = &self.channel_or(*channel);
+        let mut page_name = match options.file_name.as_str() {
+            "" => crypto::random_name(&options.file_extension),
+            template => render_template(template, self.offset, options.size),
+        };
+        if options.spoiler {
+            page_name.insert_str(0, "SPOILER_");
+        }
+        let mut content = render_template(&options.content, self.offset, options.size);
+        if let Some(old) = self.message_id {
+            if options.keep_history {
+                // Keep the old message, the new one records which message it replaced.
+                content = supersedes_content(&content, old);
+            }
+            options.urls.remove(old);
+        }
+
+        // The file is the only copy of the page an upload makes (and the download verifying it the second one).
+        let _memory = options.memory.hold_upload(if options.verify_uploads { 2 } else { 1 }).await;
+
+        // Create message, the page is encrypted right in the file.
+        let mut file = Vec::with_capacity(upload_len(options.keyring.current(), data.len(), options.checksums));
+        file.extend_from_slice(PAGE_MAGIC);
+        file.push(if options.checksums { PAGE_VERSION_CHECKSUM } else { PAGE_VERSION });
+        // The checksum of the stored data is filled in once it is encrypted.
+        let start = PAGE_HEADER_LEN + if options.checksums { CHECKSUM_LEN } else { 0 };
+        file.resize(start, 0);
+        let key_id = options.keyring.encrypt_into(data, &mut file)?;
+        if options.checksums {
+            let checksum = crc32fast::hash(&file[start..]).to_le_bytes();
+            file[PAGE_HEADER_LEN..start].copy_from_slice(&checksum);
+        }
+
+        let mut attempt = 1;
+        let message_id = loop {
+            let message_id = backend.send_file(*channel, &content, &page_name, &file).await?;
+            if !options.verify_uploads {
+                break message_id;
+            }
+
+            match self.verify_upload(channel, backend, options, message_id, &file).await {
+                Ok(()) => break message_id,
+                Err(e) => {
+                    backend.delete_message(*channel, message_id).await.ok();
+                    if attempt >= UPLOAD_ATTEMPTS {
+                        return Err(e);
+                    }
+                    log::warn!("Upload of page {} (attempt {}) didn't verify, uploading it again: {}", self.offset, attempt, e);
+                    attempt += 1;
+                }
+            }
+        };
 
         // Set message id
-        self.message_id = message.id.0;
+        self.message_id = Some(message_id);
+        self.key_id = key_id;
+
+        Ok(())
+    }
+}
+
+/// Loaded metadata blocks, with an index of the block and position of every page,
+/// so looking up a page doesn't scan all the blocks.
+/// Blocks are changed through `DerefMut`, which may move pages around,
+/// so the index is rebuilt on the next lookup after every change.
+#[derive(Default)]
+pub struct Metadata {
+    blocks: Vec<MetadataBlock>,
+    /// Page offset -> (block, page) positions, None if it needs to be rebuilt
+    index: Option<BTreeMap<u64, (usize, usize)>>,
+    rebuilds: usize,
+}
+
+impl Metadata {
+    pub fn new(blocks: Vec<MetadataBlock>) -> Self {
+        Self {
+            blocks,
+            index: None,
+            rebuilds: 0,
+        }
+    }
+
+    fn index(&mut self) -> &BTreeMap<u64, (usize, usize)> {
+        if self.index.is_none() {
+            self.rebuilds += 1;
+        }
+
+        let blocks = &self.blocks;
+        self.index.get_or_insert_with(|| {
+            blocks.iter().enumerate()
+                .flat_map(|(b, block)| block.pages.iter().enumerate().map(move |(p, page)| (page.offset, (b, p))))
+                .collect()
+        })
+    }
+
+    /// Returns the page at given offset (stored as a multiple of the page size).
+    pub fn find(&mut self, offset: u64) -> Option<&Page> {
+        let (b, p) = *self.index().get(&offset)?;
+        Some(&self.blocks[b].pages[p])
+    }
+
+    /// Returns the block holding the page at given offset.
+    /// Pages of the block may be updated in place, but not added or removed.
+    pub fn block_of(&mut self, offset: u64) -> Option<&mut MetadataBlock> {
+        let (b, _) = *self.index().get(&offset)?;
+        Some(&mut self.blocks[b])
+    }
+
+    /// Points the pages at their new messages and detaches every block holding one of them
+    /// (see `MetadataBlock::detach`), with the offsets of its pages and the message it was detached from.
+    /// Pages that are in no block are left out.
+    pub fn detach_pages(&mut self, pages: &[Page]) -> Vec<(Vec<u64>, Option<u64>, MetadataBlock)> {
+        let mut offsets: BTreeMap<usize, Vec<u64>> = BTreeMap::new();
+        for page in pages {
+            if let Some(&(b, _)) = self.index().get(&page.offset) {
+                self.blocks[b].set_page(page);
+                offsets.entry(b).or_default().push(page.offset);
+            }
+        }
+
+        offsets.into_iter().map(|(b, offsets)| {
+            let block = &mut self.blocks[b];
+            (offsets, block.message_id, block.detach())
+        }).collect()
+    }
+
+    /// Returns how many times the index was built.
+    pub fn rebuilds(&self) -> usize {
+        self.rebuilds
+    }
+}
+
+impl From<Vec<MetadataBlock>> for Metadata {
+    fn from(blocks: Vec<MetadataBlock>) -> Self {
+        Self::new(blocks)
+    }
+}
+
+impl Deref for Metadata {
+    type Target = Vec<MetadataBlock>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.blocks
+    }
+}
+
+impl DerefMut for Metadata {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.index = None;
+        &mut self.blocks
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::backend::MemoryBackend;
+    use proptest::prelude::*;
+    use std::time::Duration;
+
+    const CHANNEL: ChannelId = ChannelId(1);
+
+    /// Offset, message id, zero mask, key id and channel of every page.
+    type PageFields = (u64, Option<u64>, Vec<u8>, u8, Option<u64>);
+
+    fn page_fields(block: &MetadataBlock) -> Vec<PageFields> {
+        block.pages.iter().map(|page| (page.offset, page.message_id, page.zero_mask.as_bytes().to_vec(), page.key_id, page.channel)).collect()
+    }
+
+    #[test]
+    fn writes_only_changed_page_lines() {
+        let mut block = MetadataBlock { pages: (0..3).map(|offset| Page { message_id: Some(100 + offset), ..Page::new(offset) }).collect(), ..MetadataBlock::empty(None) };
+        let text = block.as_text();
+
+        // A line that is generated again would replace the marker.
+        block.lines.0.lock_or_recover().get_mut(&1).unwrap().1 = "kept\n".to_string();
+        block.pages[0].zero_mask.set(3, true);
+        block.pages.pop();
+
+        let updated = block.as_text();
+        assert_eq!(updated.lines().nth(2), Some("kept"));
+        assert_ne!(updated.lines().nth(1), text.lines().nth(1));
+        assert_eq!(block.lines.0.lock_or_recover().len(), 2);
+    }
+
+    #[test]
+    fn detached_blocks_are_adopted_once_stored() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let mut meta = Metadata::new(vec![MetadataBlock { pages: vec![Page::new(0), Page::new(1)], ..MetadataBlock::empty(None) }]);
+
+        let detached = meta.detach_pages(&[Page { message_id: Some(7), ..Page::new(1) }, Page { message_id: Some(8), ..Page::new(5) }]);
+        assert_eq!(detached.len(), 1);
+        let (offsets, detached_from, mut copy) = detached.into_iter().next().unwrap();
+        assert_eq!((offsets, detached_from), (vec![1], None));
+        assert!(meta[0].unsaved);
+        assert_eq!(meta.find(1).unwrap().message_id, Some(7));
+
+        rt.block_on(copy.update_message(&backend, &CHANNEL)).unwrap();
+        assert!(meta.block_of(1).unwrap().adopt_stored(detached_from, copy).is_empty());
+        assert!(meta[0].message_id.is_some() && !meta[0].unsaved);
+
+        // Pages changed while the copy was stored are stored by the next flush.
+        let (_, detached_from, mut copy) = meta.detach_pages(&[Page { message_id: Some(9), ..Page::new(0) }]).pop().unwrap();
+        rt.block_on(copy.update_message(&backend, &CHANNEL)).unwrap();
+        meta.block_of(1).unwrap().set_page(&Page { message_id: Some(10), ..Page::new(1) });
+        meta.block_of(0).unwrap().adopt_stored(detached_from, copy);
+        assert!(meta[0].unsaved);
+    }
+
+    proptest! {
+        #[test]
+        fn from_text_never_panics(text in "\\PC*") {
+            let _ = MetadataBlock::from_text(1, &text);
+        }
+
+        #[test]
+        fn from_text_never_panics_on_metablock_lines(text in "METABLOCK\n([0-9a-vA-Z:€]{0,300}\n?){0,5}") {
+            let _ = MetadataBlock::from_text(1, &text);
+        }
+
+        #[test]
+        fn as_text_round_trips(pages in prop::collection::vec((any::<u64>(), prop::option::of(1..=u64::MAX), prop::collection::vec(any::<u8>(), 256), any::<u8>(), prop::option::of(any::<u64>())), 0..=PAGES_PER_BLOCK)) {
+            let mut block = MetadataBlock::empty(Some(1));
+            for (offset, message_id, mask, key_id, channel) in pages {
+                block.pages.push(Page { offset, message_id, zero_mask: BitMask::from_bytes(&mask), key_id, channel });
+            }
+
+            let parsed = MetadataBlock::from_text(1, &block.as_text()).unwrap();
+            prop_assert_eq!(page_fields(&parsed), page_fields(&block));
+        }
+    }
+
+    #[test]
+    fn indexes_pages() {
+        let blocks: Vec<MetadataBlock> = (0..200u64).map(|b| {
+            let mut block = MetadataBlock::empty(Some(b + 1));
+            block.pages = (0..PAGES_PER_BLOCK as u64).map(|p| Page { message_id: Some(1000 + b), ..Page::new(b * 10 + p) }).collect();
+            block
+        }).collect();
+        let mut meta = Metadata::new(blocks);
+
+        // Every lookup uses the index, it is only built once.
+        for b in 0..200u64 {
+            for p in 0..PAGES_PER_BLOCK as u64 {
+                assert_eq!(meta.find(b * 10 + p).unwrap().message_id, Some(1000 + b));
+            }
+            assert!(meta.find(b * 10 + 9).is_none());
+        }
+        assert_eq!(meta.block_of(1994).unwrap().message_id, Some(200));
+        assert_eq!(meta.rebuilds(), 1);
+
+        // Changing the blocks invalidates the index.
+        meta.swap(0, 199);
+        meta.push(MetadataBlock { pages: vec![Page::new(5000)], ..MetadataBlock::empty(Some(300)) });
+        assert_eq!(meta.block_of(0).unwrap().message_id, Some(1));
+        assert_eq!(meta.block_of(5000).unwrap().message_id, Some(300));
+        assert_eq!(meta.rebuilds(), 2);
+    }
+
+    #[test]
+    fn keeps_history() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { size: 4096, zero_block_size: 4096, keep_history: true, ..PageOptions::default() };
+        let mut page = Page::new(0);
+
+        rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &options, &[1; 4096]).await.unwrap();
+            let old = page.message_id;
+            page.update_message(&backend, &CHANNEL, &options, &[2; 4096]).await.unwrap();
+
+            // The old version is still there and the new one points at it.
+            assert_eq!(backend.calls("delete_message"), 0);
+            let message = backend.get_message(CHANNEL, page.message_id.unwrap()).await.unwrap();
+            assert_eq!(supersedes(&message.content), old);
+            assert_eq!(*Page { message_id: old, ..page.clone() }.read(&CHANNEL, &backend, &options).await.unwrap(), vec![1; 4096]);
+            assert_eq!(*page.read(&CHANNEL, &backend, &options).await.unwrap(), vec![2; 4096]);
+        });
+
+        assert_eq!(supersedes(&supersedes_content("DATA", 42)), Some(42));
+        assert_eq!(supersedes("DATA"), None);
+    }
+
+    #[test]
+    fn defers_deleting_pages_being_read() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { size: 4096, zero_block_size: 4096, deletions: Deletions::new(Duration::from_millis(300)), ..PageOptions::default() };
+        let mut page = Page::new(0);
+
+        rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &options, &[1; 4096]).await.unwrap();
+            let old = page.clone();
+            backend.set_download_latency(Duration::from_millis(100));
+
+            // The page is rewritten while its old message is still being downloaded.
+            let (read, written) = tokio::join!(
+                old.read(&CHANNEL, &backend, &options),
+                async {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    page.update_message(&backend, &CHANNEL, &options, &[2; 4096]).await
+                }
+            );
+            written.unwrap();
+            assert_eq!(*read.unwrap(), vec![1; 4096]);
+            assert_eq!(backend.calls("delete_message"), 0);
+            assert_eq!(options.deletions.run_due(&backend).await, 0);
+
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(options.deletions.run_due(&backend).await, 1);
+            assert!(backend.get_message(CHANNEL, old.message_id.unwrap()).await.is_err());
+            assert_eq!(*page.read(&CHANNEL, &backend, &options).await.unwrap(), vec![2; 4096]);
+        });
+    }
+
+    #[test]
+    fn writes_several_blocks_at_once() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { size: 4096 * 4, zero_block_size: 4096, ..PageOptions::default() };
+        let mut page = Page::new(1);
+        page.zero_mask = zero_mask_of(&[0; 4096 * 4], 4096);
+
+        rt.block_on(async {
+            let data = [[3; 4096], [0; 4096], [4; 4096]].concat();
+            let (written, _) = page.write(&CHANNEL, &backend, &options, 4096 * 4, &data).await.unwrap().unwrap();
+            page.update_message(&backend, &CHANNEL, &options, &written).await.unwrap();
+
+            assert!(!page.zero_mask.get(0));
+            assert!(page.zero_mask.get(1));
+            assert!(!page.zero_mask.get(2));
+            assert!(page.zero_mask.get(3));
+            assert_eq!(&page.read(&CHANNEL, &backend, &options).await.unwrap()[..data.len()], &data[..]);
+        });
+    }
+
+    #[test]
+    fn corpus() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/corpus/metadata");
+
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_string();
+            let text = std::fs::read_to_string(&path).unwrap();
+
+            let parsed = MetadataBlock::from_text(1, &text);
+            if name.starts_with("good-") {
+                let block = parsed.unwrap_or_else(|e| panic!("{} should parse: {}", name, e));
+                let reparsed = MetadataBlock::from_text(1, &block.as_text()).unwrap();
+                assert_eq!(page_fields(&reparsed), page_fields(&block), "{} should round trip", name);
+            } else {
+                assert!(parsed.is_err(), "{} should be rejected", name);
+            }
+        }
+    }
 
     #[test]
     fn metadata_block() {
-        let mut block = MetadataBlock::empty(1234567890);
+        let mut block = MetadataBlock::empty(Some(1234567890));
         block.pages.push(Page {
             offset: 0,
-            message_id: 1234567891,
-            zero_mask: BitMask::new()
+            message_id: Some(1234567891),
+            zero_mask: BitMask::new(),
+            key_id: 0,
+            channel: None,
         });
 
         let text = block.as_text();
 
-        let block = MetadataBlock::from_text(1234567890, &text);
+        let block = MetadataBlock::from_text(1234567890, &text).unwrap();
 
-        assert_eq!(block.message_id, 1234567890);
+        assert_eq!(block.message_id, Some(1234567890));
         assert_eq!(block.pages.len(), 1);
         assert_eq!(block.pages[0].offset, 0);
-        assert_eq!(block.pages[0].message_id, 1234567891);
+        assert_eq!(block.pages[0].message_id, Some(1234567891));
         assert_eq!(block.pages[0].zero_mask.as_bytes(), [0; 256]);
+        assert_eq!(block.pages[0].key_id, 0);
+    }
+
+    #[test]
+    fn metadata_block_key_id() {
+        let mut block = MetadataBlock::empty(Some(1));
+        let mut page = Page::new(3);
+        page.message_id = Some(1234567891);
+        page.key_id = 33;
+        block.pages.push(page);
+
+        let text = block.as_text();
+        assert!(text.starts_with("METABLOCK v1\n3:14pc0mj.11:"));
+
+        let block = MetadataBlock::from_text(1, &text).unwrap();
+        assert_eq!(block.pages[0].key_id, 33);
+    }
+
+    #[test]
+    fn scan_stops_at_limit() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let blocks = rt.block_on(async {
+            for _ in 0..400 {
+                MetadataBlock::empty(None).update_message(&backend, &CHANNEL).await.unwrap();
+            }
+
+            MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(250), "").await.unwrap()
+        });
+
+        assert_eq!(blocks.len(), 250);
+        assert_eq!(backend.calls("get_messages"), 3);
+    }
+
+    #[test]
+    fn scan_handles_unsorted_batches() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let blocks = rt.block_on(async {
+            for _ in 0..250 {
+                MetadataBlock::empty(None).update_message(&backend, &CHANNEL).await.unwrap();
+            }
+            backend.set_oldest_first(true);
+
+            // The last message of a batch is the newest, it can't be used as the cursor.
+            MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(500), "").await.unwrap()
+        });
+
+        let mut ids: Vec<u64> = blocks.iter().filter_map(|block| block.message_id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(blocks.len(), 250);
+        assert_eq!(ids.len(), 250);
+        assert_eq!(backend.calls("get_messages"), 3);
+    }
+
+    #[test]
+    fn scan_finds_blocks_between_other_messages() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            // Blocks that were never moved, each buried under chat and data messages.
+            let mut ids = Vec::new();
+            for _ in 0..4 {
+                let mut block = MetadataBlock::empty(None);
+                block.update_message(&backend, &CHANNEL).await.unwrap();
+                ids.extend(block.message_id);
+                for i in 0..200 {
+                    match i % 2 {
+                        0 => backend.send_message(CHANNEL, "chat").await.unwrap(),
+                        _ => backend.send_file(CHANNEL, "", "page.bin", &[0; 16]).await.unwrap(),
+                    };
+                }
+            }
+            let found = |blocks: Vec<MetadataBlock>| {
+                let mut found: Vec<u64> = blocks.iter().filter_map(|block| block.message_id).collect();
+                found.sort_unstable();
+                found
+            };
+
+            // Only the newest block is within the 300 newest messages.
+            assert_eq!(found(MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(300), "").await.unwrap()), ids[3..]);
+
+            let scan = Scan { only_metadata: true, cap: 10_000, ..Scan::newest(300) };
+            assert_eq!(found(MetadataBlock::load_all(&backend, CHANNEL, &scan, "").await.unwrap()), ids);
+
+            // The scan stops once enough blocks were found, or at the cap.
+            let scan = Scan { only_metadata: true, cap: 10_000, ..Scan::newest(2) };
+            assert_eq!(found(MetadataBlock::load_all(&backend, CHANNEL, &scan, "").await.unwrap()), ids[2..]);
+            let scan = Scan { only_metadata: true, cap: 300, ..Scan::newest(300) };
+            assert_eq!(found(MetadataBlock::load_all(&backend, CHANNEL, &scan, "").await.unwrap()), ids[3..]);
+
+            // Scanning from the first message finds the oldest blocks first.
+            let calls = backend.calls("get_messages");
+            let scan = Scan { order: ScanOrder::OldestFirst, only_metadata: true, cap: 10_000, ..Scan::newest(2) };
+            assert_eq!(found(MetadataBlock::load_all(&backend, CHANNEL, &scan, "").await.unwrap()), ids[..2]);
+            let scan = Scan { order: ScanOrder::OldestFirst, ..Scan::newest(1000) };
+            assert_eq!(found(MetadataBlock::load_all(&backend, CHANNEL, &scan, "").await.unwrap()), ids);
+            assert_eq!(backend.calls("get_messages"), calls);
+        });
+    }
+
+    #[test]
+    fn scan_stops_at_channel_start() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let blocks = rt.block_on(async {
+            for _ in 0..150 {
+                MetadataBlock::empty(None).update_message(&backend, &CHANNEL).await.unwrap();
+            }
+
+            MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(500), "").await.unwrap()
+        });
+
+        assert_eq!(blocks.len(), 150);
+        assert_eq!(backend.calls("get_messages"), 2);
+    }
+
+    #[test]
+    fn compacts_underfull_blocks() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut blocks = rt.block_on(async {
+            let mut blocks = Vec::new();
+            for b in 0..2u64 {
+                let mut block = MetadataBlock::empty(None);
+                for p in 0..2 {
+                    let mut page = Page::new(b * 2 + p);
+                    page.message_id = Some(100 + b * 2 + p);
+                    block.pages.push(page);
+                }
+                block.update_message(&backend, &CHANNEL).await.unwrap();
+                blocks.push(block);
+            }
+
+            let removed = MetadataBlock::compact(&mut blocks, &backend, CHANNEL).await.unwrap();
+            assert_eq!(removed, 1);
+            blocks
+        });
+
+        assert_eq!(blocks.len(), 1);
+        let block = blocks.remove(0);
+        let pages: Vec<(u64, u64)> = block.pages.iter().map(|page| (page.offset, page.message_id.unwrap())).collect();
+        assert_eq!(pages, vec![(0, 100), (1, 101), (2, 102), (3, 103)]);
+
+        // Only the merged block is left on discord, and it lists every page.
+        let messages = backend.messages(CHANNEL);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(MetadataBlock::from_text(messages[0].id, &messages[0].content).unwrap().pages.len(), 4);
+    }
+
+    #[test]
+    fn leaves_full_blocks_alone() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut full = MetadataBlock::empty(Some(1));
+        for p in 0..PAGES_PER_BLOCK as u64 {
+            full.pages.push(Page::new(p));
+        }
+        let mut partial = MetadataBlock::empty(Some(2));
+        partial.pages.push(Page::new(10));
+        let mut blocks = vec![full, partial];
+
+        let removed = rt.block_on(MetadataBlock::compact(&mut blocks, &backend, CHANNEL)).unwrap();
+
+        assert_eq!(removed, 0);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(backend.calls("edit_message") + backend.calls("delete_message"), 0);
+    }
+
+    #[test]
+    fn upload_name_hides_offset() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut page = Page::new(1234);
+        rt.block_on(page.update_message(&backend, &CHANNEL, &PageOptions::default(), &[1; 16])).unwrap();
+
+        let message = &backend.messages(CHANNEL)[0];
+        assert_eq!(message.content, "");
+        assert!(!message.attachments[0].contains("1234"));
+        assert!(!message.attachments[0].contains("page"));
+    }
+
+    #[test]
+    fn upload_uses_templates() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions {
+            size: 16 * 4096,
+            content: "page {page} at {offset}".to_string(),
+            file_name: "page_{page}.bin".to_string(),
+            ..PageOptions::default()
+        };
+
+        let mut page = Page::new(3);
+        rt.block_on(page.update_message(&backend, &CHANNEL, &options, &[1; 16])).unwrap();
+
+        let message = &backend.messages(CHANNEL)[0];
+        assert_eq!(message.content, "page 3 at 196608");
+        assert!(message.attachments[0].ends_with("/page_3.bin"));
+    }
+
+    #[test]
+    fn upload_uses_extension_and_spoiler() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { size: 4096, file_extension: "dat".to_string(), spoiler: true, ..PageOptions::default() };
+
+        let mut page = Page::new(3);
+        rt.block_on(page.update_message(&backend, &CHANNEL, &options, &[1; 16])).unwrap();
+        let name = backend.messages(CHANNEL)[0].attachments[0].rsplit('/').next().unwrap().to_string();
+        assert!(name.starts_with("SPOILER_") && name.ends_with(".dat"), "{}", name);
+
+        // Templates keep their own extension.
+        let options = PageOptions { file_name: "page_{page}.bin".to_string(), ..options };
+        rt.block_on(page.update_message(&backend, &CHANNEL, &options, &[1; 16])).unwrap();
+        assert!(backend.messages(CHANNEL)[0].attachments[0].ends_with("/SPOILER_page_3.bin"));
+    }
+
+    #[test]
+    fn retries_unverified_uploads() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { size: 4096, verify_uploads: true, ..PageOptions::default() };
+
+        // The first upload reads back with a flipped byte, so it is sent again.
+        backend.break_downloads(0, 1);
+        let mut page = Page::new(0);
+        rt.block_on(page.update_message(&backend, &CHANNEL, &options, &[1; 4096])).unwrap();
+        assert_eq!(backend.calls("send_file"), 2);
+        assert_eq!(backend.calls("download"), 2);
+        assert_eq!(backend.messages(CHANNEL).len(), 1);
+        assert_eq!(Some(backend.messages(CHANNEL)[0].id), page.message_id);
+
+        let data = rt.block_on(page.read(&CHANNEL, &backend, &options)).unwrap();
+        assert_eq!(data.as_slice(), &[1; 4096]);
+        assert_eq!(backend.calls("get_message"), 2, "The verified url is reused");
+
+        // Uploads that never verify fail.
+        backend.break_downloads(0, UPLOAD_ATTEMPTS as usize);
+        let result = rt.block_on(Page::new(1).update_message(&backend, &CHANNEL, &options, &[2; 4096]));
+        assert!(matches!(result, Err(Error::UploadMismatch { offset: 1 })));
+        assert_eq!(backend.messages(CHANNEL).len(), 1);
+    }
+
+    #[test]
+    fn scan_skips_other_messages() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let blocks = rt.block_on(async {
+            let mut page = Page::new(0);
+            page.update_message(&backend, &CHANNEL, &PageOptions::default(), &[1; 16]).await.unwrap();
+            backend.send_message(CHANNEL, "hello").await.unwrap();
+            backend.send_message(CHANNEL, "METABLOCKS are neat").await.unwrap();
+            backend.send_file(CHANNEL, "METABLOCK", "cat.png", &[1]).await.unwrap();
+            backend.send_message(CHANNEL, "METABLOCK\n0:1:0\n").await.unwrap();
+
+            let mut block = MetadataBlock::empty(None);
+            block.pages.push(Page::new(1));
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+
+            MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(500), "").await.unwrap()
+        });
+
+        let mut offsets: Vec<u64> = blocks.iter().flat_map(|block| block.pages.iter().map(|page| page.offset)).collect();
+        offsets.sort();
+        assert_eq!(offsets, vec![0, 1]);
+    }
+
+    #[test]
+    fn scan_loads_blocks_of_the_volume() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let offsets = |volume: &str| {
+            let blocks = rt.block_on(MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(500), volume)).unwrap();
+            assert!(blocks.iter().all(|block| block.volume == volume));
+            let mut offsets: Vec<u64> = blocks.iter().flat_map(|block| block.pages.iter().map(|page| page.offset)).collect();
+            offsets.sort();
+            offsets
+        };
+
+        rt.block_on(async {
+            for offset in 0..6 {
+                let volume = ["", "a", "b"][offset as usize % 3];
+                let mut block = MetadataBlock { volume: volume.to_string(), ..MetadataBlock::empty(None) };
+                block.pages.push(Page::new(offset));
+                block.update_message(&backend, &CHANNEL).await.unwrap();
+            }
+        });
+
+        assert!(backend.messages(CHANNEL).iter().any(|message| message.content.starts_with("METABLOCK:a v1\n")));
+        assert_eq!(offsets(""), vec![0, 3]);
+        assert_eq!(offsets("a"), vec![1, 4]);
+        assert_eq!(offsets("b"), vec![2, 5]);
+        assert!(offsets("c").is_empty());
+    }
+
+    #[test]
+    fn big_pages_track_groups_of_blocks() {
+        let page_size = 1024 * 1024 * 25;
+        let block_size = default_zero_block_size(page_size);
+        assert_eq!(default_zero_block_size(DEFAULT_PAGE_SIZE), 4096);
+        assert_eq!(block_size, 4096 * 4);
+        assert_eq!(zero_blocks(page_size, block_size), 1600);
+
+        let mut data = vec![0; page_size];
+        let mut page = Page::new(0);
+        write_block(&mut data, &mut page.zero_mask, block_size, 0, &[0; 4096]);
+        assert!(page.zero_mask.get(0));
+
+        // The group is only zeroed once all of it is.
+        write_block(&mut data, &mut page.zero_mask, block_size, 4096, &[1; 4096]);
+        write_block(&mut data, &mut page.zero_mask, block_size, 0, &[0; 4096]);
+        assert!(!page.zero_mask.get(0));
+        write_block(&mut data, &mut page.zero_mask, block_size, 4096, &[0; 4096]);
+        assert!(page.zero_mask.get(0));
+
+        assert_eq!(page.used_bytes(page_size, block_size), page_size - 4096 * 4);
+        assert!(!page.is_zeroed(page_size, block_size));
+        for i in 0..1600 {
+            page.zero_mask.set(i, true);
+        }
+        assert!(page.is_zeroed(page_size, block_size));
+    }
+
+    #[test]
+    fn writes_spanning_blocks() {
+        let block_size = 4096;
+        let mut data = vec![0; DEFAULT_PAGE_SIZE];
+        let mut page = Page::new(0);
+        for i in 0..4 {
+            page.zero_mask.set(i, true);
+        }
+
+        // Four blocks, starting in the middle of the first one.
+        let mut written = vec![1; 4096 * 2];
+        written.extend([0; 4096]);
+        write_block(&mut data, &mut page.zero_mask, block_size, 2048, &written);
+        assert_eq!(&data[2048..2048 + written.len()], &written[..]);
+        assert!(!page.zero_mask.get(0));
+        assert!(!page.zero_mask.get(1));
+        assert!(!page.zero_mask.get(2));
+        assert!(page.zero_mask.get(3));
+
+        // Zeroing them again marks every block.
+        write_block(&mut data, &mut page.zero_mask, block_size, 0, &[0; 4096 * 3]);
+        assert!((0..4).all(|i| page.zero_mask.get(i)));
+        assert!(data.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn coarse_zero_blocks() {
+        let block_size = 64 * 1024;
+        let mut data = vec![1; DEFAULT_PAGE_SIZE];
+        let mut page = Page::new(0);
+
+        // Zero the third block, 4KB at a time.
+        for offset in (2 * block_size..3 * block_size).step_by(4096) {
+            write_block(&mut data, &mut page.zero_mask, block_size, offset, &[0; 4096]);
+            assert_eq!(page.zero_mask.get(2), offset == 3 * block_size - 4096);
+        }
+        assert_eq!(page.zero_mask.count_ones(), 1);
+        assert_eq!(page.used_bytes(DEFAULT_PAGE_SIZE, block_size), DEFAULT_PAGE_SIZE - block_size);
+
+        // Only the tracked blocks are stored.
+        for i in 0..zero_blocks(DEFAULT_PAGE_SIZE, block_size) {
+            page.zero_mask.set(i, true);
+        }
+        assert!(page.is_zeroed(DEFAULT_PAGE_SIZE, block_size));
+        assert_eq!(page.as_text().chars().count(), 16);
+        assert_eq!(Page::from_text(None, 0, &page.as_text()).unwrap().zero_mask.as_bytes(), page.zero_mask.as_bytes());
+    }
+
+    #[test]
+    fn deleted_block_is_sent_again() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut block = MetadataBlock::empty(None);
+        block.pages.push(Page::new(3));
+        rt.block_on(async {
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+            let deleted = block.message_id;
+            backend.delete_message(CHANNEL, deleted.unwrap()).await.unwrap();
+
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+            assert_ne!(block.message_id, deleted);
+
+            let message = backend.get_message(CHANNEL, block.message_id.unwrap()).await.unwrap();
+            assert_eq!(MetadataBlock::from_text(message.id, &message.content).unwrap().pages[0].offset, 3);
+        });
+    }
+
+    #[test]
+    fn failed_edit_falls_back() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            let mut blocks = Vec::new();
+            for fallback in [EditFallback::Fail, EditFallback::Recreate, EditFallback::Defer] {
+                let mut block = MetadataBlock { edit_fallback: fallback, ..MetadataBlock::empty(None) };
+                block.pages.push(Page::new(3));
+                block.update_message(&backend, &CHANNEL).await.unwrap();
+                blocks.push(block);
+            }
+
+            // Discord refuses every edit from now on.
+            backend.forbid("edit_message");
+            for block in blocks.iter_mut() {
+                block.pages[0].message_id = Some(7);
+            }
+            let [fail, recreate, defer] = &mut blocks[..] else { unreachable!() };
+
+            let sent = fail.message_id;
+            assert!(matches!(fail.update_message(&backend, &CHANNEL).await, Err(Error::Backend(BackendError::Forbidden(_)))));
+            assert!(fail.unsaved);
+            assert_eq!(fail.message_id, sent);
+
+            let old = recreate.message_id.unwrap();
+            recreate.update_message(&backend, &CHANNEL).await.unwrap();
+            assert!(!recreate.unsaved);
+            assert_ne!(recreate.message_id, Some(old));
+            assert!(backend.get_message(CHANNEL, old).await.is_err());
+            let message = backend.get_message(CHANNEL, recreate.message_id.unwrap()).await.unwrap();
+            assert_eq!(MetadataBlock::from_text(message.id, &message.content).unwrap().pages[0].message_id, Some(7));
+
+            let sent = defer.message_id;
+            defer.update_message(&backend, &CHANNEL).await.unwrap();
+            assert!(defer.unsaved);
+            assert_eq!(defer.message_id, sent);
+            // Storing it later can recreate it instead.
+            defer.store(&backend, &CHANNEL, EditFallback::Recreate).await.unwrap();
+            assert!(!defer.unsaved);
+            assert_ne!(defer.message_id, sent);
+        });
+    }
+
+    #[test]
+    fn zeroed_page_is_not_downloaded() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut page = Page::new(0);
+        page.message_id = Some(999);
+        for i in 0..2048 {
+            page.zero_mask.set(i, true);
+        }
+
+        let data = rt.block_on(page.read(&CHANNEL, &backend, &PageOptions::default())).unwrap();
+
+        assert_eq!(*data, vec![0; 1024*1024*8]);
+        assert_eq!(backend.calls("get_message"), 0);
+        assert_eq!(backend.calls("download"), 0);
+    }
+
+    #[test]
+    fn page_without_message_is_not_fetched() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { size: 4096, zero_block_size: 4096, ..PageOptions::default() };
+
+        // Nothing is masked, only the missing message tells that the page holds no data.
+        let page = Page::new(0);
+        assert_eq!(*rt.block_on(page.read(&CHANNEL, &backend, &options)).unwrap(), vec![0; 4096]);
+        assert!(matches!(rt.block_on(page.read_range(&CHANNEL, &backend, &options, 0, 4096)).unwrap(), PageRead::Range(data) if data == vec![0; 4096]));
+        assert_eq!(backend.calls("get_message"), 0);
+        assert_eq!(backend.calls("download"), 0);
+
+        // Lines of such pages keep storing id 0, which reads back as no message.
+        let block = MetadataBlock { pages: vec![page], ..MetadataBlock::empty(None) };
+        assert_eq!(MetadataBlock::from_text(1, &block.as_text()).unwrap().pages[0].message_id, None);
+    }
+
+    #[test]
+    fn partially_zeroed_page_is_downloaded() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut page = Page::new(0);
+        page.zero_mask.set(0, true);
+
+        let data = rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &PageOptions::default(), &vec![1; 1024*1024*8]).await.unwrap();
+            page.read(&CHANNEL, &backend, &PageOptions::default()).await.unwrap()
+        });
+
+        assert_eq!(*data, vec![1; 1024*1024*8]);
+        assert_eq!(backend.calls("download"), 1);
+    }
+
+    #[test]
+    fn reads_ranges() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { size: 16 * 4096, zero_block_size: 4096, ..PageOptions::default() };
+
+        let mut page = Page::new(0);
+        let data: Vec<u8> = (0..16 * 4096).map(|i| (i / 4096) as u8).collect();
+
+        rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &options, &data).await.unwrap();
+
+            let read = page.read_range(&CHANNEL, &backend, &options, 3 * 4096, 4096).await.unwrap();
+            assert!(matches!(read, PageRead::Range(slice) if slice == data[3 * 4096..4 * 4096]));
+            assert_eq!(backend.calls("download"), 0);
+
+            // Zeroed blocks are not downloaded at all.
+            page.zero_mask.set(5, true);
+            let read = page.read_range(&CHANNEL, &backend, &options, 5 * 4096, 4096).await.unwrap();
+            assert!(matches!(read, PageRead::Range(slice) if slice == vec![0; 4096]));
+            assert_eq!(backend.calls("download_range"), 1);
+
+            // Servers without range support return the whole page.
+            backend.set_ignore_ranges(true);
+            let read = page.read_range(&CHANNEL, &backend, &options, 3 * 4096, 4096).await.unwrap();
+            assert!(matches!(read, PageRead::Full(whole) if *whole == data));
+            assert_eq!(backend.calls("download"), 0);
+        });
+    }
+
+    #[test]
+    fn encrypted_pages_are_read_whole() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let mut keyring = Keyring::none();
+        keyring.add(1, [7; 32]);
+        let options = PageOptions { size: 16 * 4096, zero_block_size: 4096, keyring, ..PageOptions::default() };
+
+        let mut page = Page::new(0);
+        rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &options, &[2; 16 * 4096]).await.unwrap();
+
+            let read = page.read_range(&CHANNEL, &backend, &options, 4096, 4096).await.unwrap();
+            assert!(matches!(read, PageRead::Full(whole) if *whole == [2; 16 * 4096]));
+            assert_eq!(backend.calls("download_range"), 0);
+        });
+    }
+
+    #[test]
+    fn truncated_page_is_rejected() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut page = Page::new(0);
+
+        let result = rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &PageOptions::default(), &vec![1; 1024*1024*8]).await.unwrap();
+
+            let message = backend.get_message(CHANNEL, page.message_id.unwrap()).await.unwrap();
+            backend.replace_attachment(&message.attachments[0], vec![1; 1024*1024*4]);
+
+            page.read(&CHANNEL, &backend, &PageOptions::default()).await
+        });
+
+        assert!(matches!(result, Err(Error::InvalidPageLength { expected, actual, .. }) if expected == PAGE_HEADER_LEN + 1024*1024*8 && actual == 1024*1024*4));
+        assert_eq!(backend.calls("download"), 2);
+    }
+
+    #[test]
+    fn retries_broken_downloads() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let options = PageOptions { checksums: true, download_attempts: 3, ..PageOptions::default() };
+
+        let mut page = Page::new(0);
+        let mut data = vec![1; 1024*1024*8];
+        data[123] = 7;
+
+        let read = rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &options, &data).await.unwrap();
+
+            // A failed and a corrupted download before the good one.
+            backend.break_downloads(1, 1);
+            let read = page.read(&CHANNEL, &backend, &options).await.unwrap();
+            assert_eq!(backend.calls("download"), 3);
+
+            // Out of attempts.
+            backend.break_downloads(0, 3);
+            let result = page.read(&CHANNEL, &backend, &options).await;
+            assert!(matches!(result, Err(Error::ChecksumMismatch { offset: 0 })));
+
+            read
+        });
+
+        assert_eq!(*read, data);
+    }
+
+    #[test]
+    fn reads_unversioned_page() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut page = Page::new(0);
+
+        let data = rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &PageOptions::default(), &vec![1; 1024*1024*8]).await.unwrap();
+
+            let message = backend.get_message(CHANNEL, page.message_id.unwrap()).await.unwrap();
+            assert_eq!(&backend.download(&message.attachments[0]).await.unwrap()[..PAGE_HEADER_LEN], b"DAAF\x01");
+            backend.replace_attachment(&message.attachments[0], vec![2; 1024*1024*8]);
+
+            page.read(&CHANNEL, &backend, &PageOptions::default()).await.unwrap()
+        });
+
+        assert_eq!(*data, vec![2; 1024*1024*8]);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut page = Page::new(0);
+
+        let result = rt.block_on(async {
+            page.update_message(&backend, &CHANNEL, &PageOptions::default(), &vec![1; 1024*1024*8]).await.unwrap();
+
+            let message = backend.get_message(CHANNEL, page.message_id.unwrap()).await.unwrap();
+            let mut data = b"DAAF\xe7".to_vec();
+            data.resize(PAGE_HEADER_LEN + 1024*1024*8, 1);
+            backend.replace_attachment(&message.attachments[0], data);
+
+            page.read(&CHANNEL, &backend, &PageOptions::default()).await
+        });
+
+        assert!(matches!(result, Err(Error::UnsupportedVersion { format: "page", version: 231 })));
+        assert!(matches!(
+            MetadataBlock::from_text(1, "METABLOCK v999\n0:1:0\n"),
+            Err(Error::UnsupportedVersion { format: "metadata block", version: 999 })
+        ));
+        assert!(MetadataBlock::from_text(1, "METABLOCK v1\n0:1:0\n").is_ok());
+    }
+
+    #[test]
+    fn json_blocks_round_trip() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut block = MetadataBlock { format: MetadataFormat::Json, ..MetadataBlock::empty(None) };
+        for offset in 0..PAGES_PER_BLOCK as u64 {
+            let mut page = Page { message_id: Some(100 + offset), key_id: offset as u8, channel: (offset % 2 == 1).then_some(7), ..Page::new(offset) };
+            page.zero_mask.set(offset as usize * 300, true);
+            block.pages.push(page);
+        }
+
+        rt.block_on(async {
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+            let (message_id, attachment_id) = (block.message_id.unwrap(), block.attachment_id);
+
+            let loaded = MetadataBlock::load_from_discord(&backend, CHANNEL, message_id).await.unwrap();
+            assert_eq!(loaded.format, MetadataFormat::Json);
+            assert_eq!(loaded.attachment_id, attachment_id);
+            assert_eq!(loaded.pages.len(), PAGES_PER_BLOCK);
+            for (page, original) in loaded.pages.iter().zip(block.pages.iter()) {
+                assert_eq!((page.offset, page.message_id, page.key_id, page.channel), (original.offset, original.message_id, original.key_id, original.channel));
+                assert_eq!(page.zero_mask.as_bytes(), original.zero_mask.as_bytes());
+            }
+
+            // The block keeps its message, only the attachment is replaced.
+            block.pages.pop();
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+            assert_eq!(block.message_id, Some(message_id));
+            assert!(backend.get_message(CHANNEL, attachment_id).await.is_err());
+
+            let found = MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(100), "").await.unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].pages.len(), PAGES_PER_BLOCK - 1);
+
+            // Converting back to text drops the attachment.
+            block.format = MetadataFormat::Text;
+            let attachment_id = block.attachment_id;
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+            assert!(backend.get_message(CHANNEL, attachment_id).await.is_err());
+            assert_eq!(backend.messages(CHANNEL).len(), 1);
+        });
+    }
+
+    #[test]
+    fn compressed_blocks_round_trip() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        let mut block = MetadataBlock { compress_above: Some(256), volume: "big".to_string(), ..MetadataBlock::empty(None) };
+        for offset in 0..PAGES_PER_BLOCK as u64 {
+            let mut page = Page { message_id: Some(1000 + offset), key_id: offset as u8, ..Page::new(offset) };
+            for i in (0..MASK_BITS).step_by(offset as usize + 2) {
+                page.zero_mask.set(i, true);
+            }
+            block.pages.push(page);
+        }
+        let text = block.as_text();
+        assert!(text.len() > 1000);
+
+        rt.block_on(async {
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+            let (message_id, attachment_id) = (block.message_id.unwrap(), block.attachment_id);
+            assert_ne!(attachment_id, 0);
+
+            // The message only points at the attachment.
+            let message = backend.get_message(CHANNEL, message_id).await.unwrap();
+            assert!(message.content.starts_with("METABLOCK:big v3\n"));
+            assert!(message.content.len() < 32);
+            assert!(MetadataBlock::is_metablock(&message.content));
+
+            let loaded = MetadataBlock::load_from_discord(&backend, CHANNEL, message_id).await.unwrap();
+            assert_eq!((loaded.format, loaded.attachment_id, loaded.volume.as_str()), (MetadataFormat::Text, attachment_id, "big"));
+            assert_eq!(loaded.as_text(), text);
+
+            let found = MetadataBlock::load_all(&backend, CHANNEL, &Scan::newest(100), "big").await.unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].pages.len(), PAGES_PER_BLOCK);
+
+            // Once the block is short enough it is written inline again.
+            block.pages.truncate(1);
+            block.pages[0].zero_mask = BitMask::new();
+            block.update_message(&backend, &CHANNEL).await.unwrap();
+            assert_eq!(block.attachment_id, 0);
+            assert!(backend.get_message(CHANNEL, attachment_id).await.is_err());
+            let message = backend.get_message(CHANNEL, message_id).await.unwrap();
+            assert_eq!(message.content, block.as_text());
+        });
     }
 }
\ No newline at end of file
@@ -0,0 +1,213 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::utils::LockOrRecover;
+
+/// Page buffers kept for reuse (`BUFFER_POOL`), so pages read, written and uploaded one after another
+/// don't allocate and free a whole page every time.
+/// Buffers are zeroed when they are returned, so no page data stays around in the pool.
+#[derive(Clone, Debug, Default)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// Most buffers kept, 0 disables the pool
+    limit: usize,
+    /// Buffers that had to be allocated because the pool had none big enough
+    allocations: Arc<AtomicUsize>,
+}
+
+impl BufferPool {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, ..Self::default() }
+    }
+
+    /// Returns a zeroed buffer of `len` bytes, reusing a pooled one if there is one.
+    pub fn take(&self, len: usize) -> Zeroizing<Vec<u8>> {
+        let pooled = self.buffers.lock_or_recover().pop();
+        let mut buffer = match pooled {
+            Some(buffer) if buffer.capacity() >= len => buffer,
+            _ => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(len)
+            }
+        };
+
+        buffer.resize(len, 0);
+        Zeroizing::new(buffer)
+    }
+
+    /// Returns a zeroed buffer holding a copy of `data`.
+    pub fn copy(&self, data: &[u8]) -> Zeroizing<Vec<u8>> {
+        let mut buffer = self.take(data.len());
+        buffer.copy_from_slice(data);
+        buffer
+    }
+
+    /// Gives the buffer back to the pool, it is dropped if the pool is full.
+    pub fn put(&self, mut buffer: Zeroizing<Vec<u8>>) {
+        let mut buffers = self.buffers.lock_or_recover();
+        if buffers.len() >= self.limit {
+            return;
+        }
+
+        let mut buffer = std::mem::take(&mut *buffer);
+        buffer.zeroize();
+        buffers.push(buffer);
+    }
+
+    /// Number of buffers allocated by `take` so far.
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(Ordering::Relaxed)
+    }
+}
+
+/// Page buffers held by downloads and uploads, on top of the pages in the cache and the sync queue.
+/// In bounded-memory mode (`MEMORY_PAGES`) there are only so many slots for them, and a transfer
+/// waits for a free one before it allocates its buffers. Otherwise transfers only count their buffers.
+#[derive(Debug, Default)]
+pub struct PageMemory {
+    /// Buffers downloads may hold at once, unlimited if None
+    downloads: Option<Arc<Semaphore>>,
+    /// Buffers uploads may hold at once. They have their own slots, so downloads waiting to be cached
+    /// can't keep the uploads that make room for them from running.
+    uploads: Option<Arc<Semaphore>>,
+    held: Arc<AtomicUsize>,
+    /// Most buffers that were held at once
+    peak: AtomicUsize,
+}
+
+/// Buffers a transfer holds, given back when it is dropped.
+#[derive(Debug)]
+pub struct MemorySlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    buffers: usize,
+    held: Arc<AtomicUsize>,
+}
+
+impl PageMemory {
+    /// Lets downloads and uploads hold at most `downloads` and `uploads` page buffers at once.
+    pub fn bounded(downloads: usize, uploads: usize) -> Self {
+        Self {
+            downloads: Some(Arc::new(Semaphore::new(downloads))),
+            uploads: Some(Arc::new(Semaphore::new(uploads))),
+            ..Self::default()
+        }
+    }
+
+    /// Whether transfers wait for a free slot, and so keep as few copies of a page as they can.
+    pub fn is_bounded(&self) -> bool {
+        self.downloads.is_some()
+    }
+
+    /// Waits until a download may hold a page buffer.
+    pub async fn hold_download(&self) -> MemorySlot {
+        self.hold(&self.downloads, 1).await
+    }
+
+    /// Waits until an upload may hold `buffers` page buffers.
+    pub async fn hold_upload(&self, buffers: usize) -> MemorySlot {
+        self.hold(&self.uploads, buffers).await
+    }
+
+    /// Holds a page buffer for a download if a slot is free right now.
+    pub fn try_hold_download(&self) -> Option<MemorySlot> {
+        let permit = match &self.downloads {
+            Some(slots) => Some(slots.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(self.count(permit, 1))
+    }
+
+    async fn hold(&self, slots: &Option<Arc<Semaphore>>, buffers: usize) -> MemorySlot {
+        let permit = match slots {
+            // The semaphore is never closed.
+            Some(slots) => Some(slots.clone().acquire_many_owned(buffers as u32).await.expect("Memory slots closed")),
+            None => None,
+        };
+        self.count(permit, buffers)
+    }
+
+    fn count(&self, permit: Option<OwnedSemaphorePermit>, buffers: usize) -> MemorySlot {
+        let held = self.held.fetch_add(buffers, Ordering::SeqCst) + buffers;
+        self.peak.fetch_max(held, Ordering::SeqCst);
+        MemorySlot { _permit: permit, buffers, held: self.held.clone() }
+    }
+
+    /// Page buffers transfers hold right now.
+    pub fn held(&self) -> usize {
+        self.held.load(Ordering::SeqCst)
+    }
+
+    /// Most page buffers transfers held at once so far.
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MemorySlot {
+    fn drop(&mut self) {
+        self.held.fetch_sub(self.buffers, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reuses_zeroed_buffers() {
+        let pool = BufferPool::new(1);
+
+        let buffer = pool.copy(&[1; 4096]);
+        let address = buffer.as_ptr();
+        pool.put(buffer);
+        pool.put(pool.take(4096));
+
+        let buffer = pool.take(4096);
+        assert_eq!(buffer.as_ptr(), address);
+        assert!(buffer.iter().all(|byte| *byte == 0));
+        assert_eq!(pool.allocations(), 1);
+
+        // Only `limit` buffers are kept.
+        let other = pool.copy(&[2; 4096]);
+        pool.put(buffer);
+        pool.put(other);
+        let _reused = pool.take(4096);
+        let _allocated = pool.take(4096);
+        assert_eq!(pool.allocations(), 3);
+    }
+
+    #[test]
+    fn disabled_pool_always_allocates() {
+        let pool = BufferPool::new(0);
+        pool.put(pool.take(4096));
+        pool.take(4096);
+
+        assert_eq!(pool.allocations(), 2);
+    }
+
+    #[test]
+    fn bounded_memory_waits_for_slots() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let memory = Arc::new(PageMemory::bounded(1, 2));
+
+        let first = rt.block_on(memory.hold_upload(2));
+        let waiting = rt.spawn({
+            let memory = memory.clone();
+            async move { memory.hold_upload(1).await.buffers }
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+
+        // Downloads don't wait for uploads.
+        let download = rt.block_on(memory.hold_download());
+        assert_eq!(memory.held(), 3);
+        assert!(memory.try_hold_download().is_none());
+
+        drop(first);
+        assert_eq!(rt.block_on(waiting).unwrap(), 1);
+        drop(download);
+        assert_eq!((memory.held(), memory.peak()), (0, 3));
+    }
+}
@@ -1,31 +1,190 @@
-use std::sync::{Mutex, Arc, atomic::AtomicBool};
+use std::{any::Any, collections::HashMap, panic::{self, AssertUnwindSafe}, str::FromStr, sync::{Mutex, Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::{Duration, Instant}};
 
-use serenity::{http::Http, model::prelude::ChannelId};
+use serenity::model::prelude::ChannelId;
+use zeroize::Zeroizing;
 
-use crate::metadata::{Page, MetadataBlock};
+use crate::{backend::{Backend, BackendError}, metadata::{Page, Metadata, PageOptions}, utils::LockOrRecover, error::{Error, Result}, health::Health, journal::MaskJournal, tier::LocalTier};
+
+/// Blocks uploaded at once if it is not configured.
+pub const DEFAULT_MAX_UPLOADS: usize = 3;
+/// Blocks the sync queue of the drive holds.
+pub const QUEUE_PAGES: usize = 4;
+
+/// What the sync thread does when syncing a block panics (`SYNC_PANIC`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// The block is put back into the queue like after a failed sync, and syncing goes on.
+    #[default]
+    Recover,
+    /// The block is put back into the queue and the sync thread exits,
+    /// so flushes fail right away instead of retrying a bug.
+    Abort,
+}
+
+impl FromStr for PanicPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "recover" => Ok(Self::Recover),
+            "abort" => Ok(Self::Abort),
+            _ => Err(()),
+        }
+    }
+}
 
 /// This queue is used to sync data between drive and discord.
 pub struct Queue<const S: usize> {
     pub data: Arc<Mutex<Vec<QueueBlock>>>,
+    /// Offsets of the pages the sync thread is uploading (locked after `data`).
+    /// Their metadata is only up to date once the upload is done.
+    pub in_flight: Arc<Mutex<Vec<u64>>>,
     pub thread: Option<std::thread::JoinHandle<()>>,
     pub is_syncing: Arc<AtomicBool>,
+    /// Tells the sync thread and the health monitor to exit.
+    pub stop: Arc<AtomicBool>,
+    /// Keeps the sync thread from uploading (see `pause`), blocks wait in the queue meanwhile.
+    pub paused: Arc<AtomicBool>,
+    pub stats: Arc<QueueStats>,
+    /// Depth at which a warning is logged that syncing falls behind.
+    high_water: usize,
+    /// Most blocks held, counting the ones being uploaded (`MEMORY_PAGES`). `S` blocks wait for upload if None.
+    limit: Option<usize>,
+    /// Failed syncs of a block after which it is given up on (retried forever if None).
+    sync_attempts: Option<u32>,
+    /// Most blocks uploaded at once
+    max_uploads: usize,
+    /// Most metadata blocks stored at once
+    metadata_updates: usize,
+    /// What happens when syncing a block panics
+    panic_policy: PanicPolicy,
+    /// Channel of the metadata blocks (the channel of the pages if None)
+    metadata_channel: Option<ChannelId>,
+    /// Offsets of the blocks given up on, with why they couldn't be synced, reported by the next flush or write.
+    pub failures: Arc<Mutex<Vec<(u64, BackendError)>>>,
+    /// Local directory spilled blocks wait in until the sync thread takes them (`LOCAL_TIER_DIR`).
+    /// Moved to `data` with it locked, so a block is always in one of them.
+    tier: Option<Arc<LocalTier>>,
+    /// Local journal the metadata of uploaded blocks is recorded in until it is stored (`MASK_JOURNAL_DIR`).
+    journal: Option<Arc<MaskJournal>>,
+}
+
+/// Counters describing how well the sync thread keeps up.
+pub struct QueueStats {
+    /// Last time a block was synced (or the queue went from empty to not empty)
+    last_progress: Mutex<Instant>,
+    /// Failed syncs since the last successful one
+    recent_errors: AtomicUsize,
+    /// Blocks synced since the queue was created
+    synced_blocks: AtomicUsize,
+    /// Failed syncs since the queue was created
+    sync_errors: AtomicUsize,
+    /// Last time a block was synced
+    last_synced: Mutex<Option<Instant>>,
+    /// Pushes that had to wait for room in the queue
+    blocked_pushes: AtomicUsize,
+    /// Time pushes spent waiting for room in the queue
+    blocked_for: Mutex<Duration>,
+    /// Whether the queue is at its high-water mark (or pushes are blocked), so it is only reported once.
+    above_high_water: AtomicBool,
+    blocking: AtomicBool,
+}
+
+impl QueueStats {
+    fn new() -> Self {
+        Self {
+            last_progress: Mutex::new(Instant::now()),
+            recent_errors: AtomicUsize::new(0),
+            synced_blocks: AtomicUsize::new(0),
+            sync_errors: AtomicUsize::new(0),
+            last_synced: Mutex::new(None),
+            blocked_pushes: AtomicUsize::new(0),
+            blocked_for: Mutex::new(Duration::ZERO),
+            above_high_water: AtomicBool::new(false),
+            blocking: AtomicBool::new(false),
+        }
+    }
+
+    fn progressed(&self) {
+        *self.last_progress.lock_or_recover() = Instant::now();
+    }
+
+    fn synced(&self) {
+        self.progressed();
+        self.recent_errors.store(0, Ordering::SeqCst);
+        self.synced_blocks.fetch_add(1, Ordering::SeqCst);
+        *self.last_synced.lock_or_recover() = Some(Instant::now());
+    }
+
+    fn failed(&self) {
+        self.recent_errors.fetch_add(1, Ordering::SeqCst);
+        self.sync_errors.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn since_last_progress(&self) -> Duration {
+        self.last_progress.lock_or_recover().elapsed()
+    }
+
+    pub fn recent_errors(&self) -> usize {
+        self.recent_errors.load(Ordering::SeqCst)
+    }
+
+    pub fn synced_blocks(&self) -> usize {
+        self.synced_blocks.load(Ordering::SeqCst)
+    }
+
+    pub fn sync_errors(&self) -> usize {
+        self.sync_errors.load(Ordering::SeqCst)
+    }
+
+    /// Time since a block was last synced, None if none was synced yet.
+    pub fn since_last_sync(&self) -> Option<Duration> {
+        self.last_synced.lock_or_recover().map(|at| at.elapsed())
+    }
+
+    fn blocked(&self, duration: Duration) {
+        self.blocked_pushes.fetch_add(1, Ordering::SeqCst);
+        *self.blocked_for.lock_or_recover() += duration;
+    }
+
+    pub fn blocked_pushes(&self) -> usize {
+        self.blocked_pushes.load(Ordering::SeqCst)
+    }
+
+    pub fn blocked_for(&self) -> Duration {
+        *self.blocked_for.lock_or_recover()
+    }
 }
 
 pub struct QueueBlock {
     pub page: Page,
-    pub data: Vec<u8>,
+    /// Plaintext page data, zeroed when dropped.
+    pub data: Zeroizing<Vec<u8>>,
+    /// Failed syncs of this block
+    pub attempts: u32,
+    /// Message the stored metadata points at while the block is uploaded with a journal (`MASK_JOURNAL_DIR`),
+    /// deleted once the metadata points at the upload.
+    pub replaced: Option<u64>,
 }
 
 impl QueueBlock {
-    pub fn new(page: Page, data: Vec<u8>) -> Self {
+    pub fn new(page: Page, data: impl Into<Zeroizing<Vec<u8>>>) -> Self {
         Self {
             page,
-            data,
+            data: data.into(),
+            attempts: 0,
+            replaced: None,
         }
     }
 
-    pub async fn sync(&mut self, http: &Http, channel_id: ChannelId) {
-        self.page.update_message(http, &channel_id, &self.data).await;
+    pub async fn sync(&mut self, backend: &dyn Backend, options: &PageOptions, channel_id: ChannelId) -> Result<()> {
+        self.page.update_message(backend, &channel_id, options, &self.data).await
+    }
+}
+
+impl<const S: usize> Default for Queue<S> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -33,116 +192,791 @@ impl<const S: usize> Queue<S> {
     pub fn new() -> Self {
         Self {
             data: Arc::new(Mutex::new(Vec::with_capacity(S))),
+            in_flight: Arc::new(Mutex::new(Vec::new())),
             thread: None,
             is_syncing: Arc::new(AtomicBool::new(false)),
+            stop: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(QueueStats::new()),
+            high_water: S,
+            limit: None,
+            sync_attempts: None,
+            max_uploads: DEFAULT_MAX_UPLOADS,
+            metadata_updates: 1,
+            panic_policy: PanicPolicy::default(),
+            metadata_channel: None,
+            failures: Arc::new(Mutex::new(Vec::new())),
+            tier: None,
+            journal: None,
         }
     }
 
-    pub fn push(&self, page: Page, data: Vec<u8>) {
-        let mut sdl;
-        {
-            let sdata = self.data.lock().unwrap();
-            sdl = sdata.len();
+    /// Logs a warning once the queue holds `depth` blocks (by default when it is full).
+    pub fn with_high_water(mut self, depth: usize) -> Self {
+        self.high_water = depth.clamp(1, S);
+        self
+    }
+
+    /// Holds at most `blocks` blocks, counting the ones being uploaded, so their data takes a bounded amount of memory.
+    pub fn with_limit(mut self, blocks: usize) -> Self {
+        self.limit = Some(blocks.clamp(1, S));
+        self
+    }
+
+    /// Blocks that count against the limit of the queue.
+    fn held(&self) -> usize {
+        let queued = self.data.lock_or_recover().len();
+        match self.limit {
+            Some(_) => queued + self.in_flight.lock_or_recover().len(),
+            None => queued,
         }
+    }
 
-        while sdl >= S {
-            // Wait for the queue to be empty.
-            std::thread::sleep(std::time::Duration::from_millis(100));
-            {
-                let sdata = self.data.lock().unwrap();
-                sdl = sdata.len();
+    /// Gives up on a block once it failed to sync `attempts` times, dropping its data.
+    /// The failure is reported by the next flush (or write), so a long outage
+    /// fails requests instead of blocking them forever.
+    pub fn with_sync_attempts(mut self, attempts: u32) -> Self {
+        self.sync_attempts = Some(attempts.max(1));
+        self
+    }
+
+    /// Uploads up to `uploads` blocks at once.
+    pub fn with_max_uploads(mut self, uploads: usize) -> Self {
+        self.max_uploads = uploads.max(1);
+        self
+    }
+
+    /// Stores up to `updates` metadata blocks of uploaded pages at once (see `update_blocks`).
+    pub fn with_metadata_updates(mut self, updates: usize) -> Self {
+        self.metadata_updates = updates.max(1);
+        self
+    }
+
+    pub fn with_panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    /// Records synced pages in metadata blocks of `channel` instead of the channel the pages are uploaded to.
+    pub fn with_metadata_channel(mut self, channel: ChannelId) -> Self {
+        self.metadata_channel = Some(channel);
+        self
+    }
+
+    /// Spills blocks to the local tier (see `spill`) before they are uploaded.
+    pub fn with_tier(mut self, tier: LocalTier) -> Self {
+        self.tier = Some(Arc::new(tier));
+        self
+    }
+
+    /// Records the metadata of uploaded blocks in the journal until their metadata block is updated.
+    pub fn with_journal(mut self, journal: Arc<MaskJournal>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Returns (and forgets) which blocks were given up on since the last call, and why the last one couldn't be synced.
+    pub fn take_failure(&self) -> Option<Error> {
+        let mut failures = std::mem::take(&mut *self.failures.lock_or_recover());
+        let (_, last) = failures.last()?;
+        let reason = last.to_string();
+
+        let mut offsets: Vec<u64> = failures.drain(..).map(|(offset, _)| offset).collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+        Some(Error::SyncFailed { offsets, reason })
+    }
+
+    pub fn push(&self, page: Page, data: impl Into<Zeroizing<Vec<u8>>>) {
+        let limit = self.limit.unwrap_or(S);
+        let mut sdl = self.held();
+
+        if sdl >= limit {
+            if !self.stats.blocking.swap(true, Ordering::SeqCst) {
+                log::warn!("Sync queue is full, writes wait for uploads. Discord (or the uplink) can't keep up.");
+            }
+
+            let blocked = Instant::now();
+            while sdl >= limit {
+                // Wait for the queue to be empty.
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                sdl = self.held();
             }
+            self.stats.blocked(blocked.elapsed());
+        } else {
+            self.stats.blocking.store(false, Ordering::SeqCst);
         }
 
-        let mut sdata = self.data.lock().unwrap();
+        let mut sdata = self.data.lock_or_recover();
+        if sdata.is_empty() {
+            // Don't count the time the queue was idle as a stall.
+            self.stats.progressed();
+        }
         sdata.push(QueueBlock::new(page, data));
+
+        if sdata.len() < self.high_water {
+            self.stats.above_high_water.store(false, Ordering::SeqCst);
+        } else if !self.stats.above_high_water.swap(true, Ordering::SeqCst) {
+            log::warn!("Sync queue holds {} of {} blocks, syncing falls behind.", sdata.len(), S);
+        }
+    }
+
+    /// Stores the block in the local tier while it has room, and pushes it to the queue otherwise.
+    /// The sync thread takes spilled blocks into the queue as it uploads them.
+    pub fn spill(&self, page: Page, data: impl Into<Zeroizing<Vec<u8>>>) {
+        let data = data.into();
+        if let Some(tier) = &self.tier {
+            match tier.put(&page, &data) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => log::error!("Failed to spill page {} to the local tier, queueing it instead: {}", page.offset, e),
+            }
+        }
+
+        self.push(page, data);
     }
 
     /// Tries to release the offset from the queue and returns the data if it exists.
-    pub fn release_offset(&self, offset: u64) -> Option<(Page, Vec<u8>)> {
-        let mut sdata = self.data.lock().unwrap();
-        for i in 0..sdata.len() {
-            if sdata[i].page.offset == offset {
-                let block = sdata.remove(i);
-                return Some((block.page, block.data));
+    /// If the page is being uploaded, waits until it is synced (or put back after a failure),
+    /// so when None is returned the metadata holds the latest version of the page.
+    pub fn release_offset(&self, offset: u64) -> Option<(Page, Zeroizing<Vec<u8>>)> {
+        loop {
+            let mut sdata = self.data.lock_or_recover();
+            if self.in_flight.lock_or_recover().contains(&offset) {
+                drop(sdata);
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
             }
+
+            let Some(i) = sdata.iter().position(|block| block.page.offset == offset) else {
+                return self.tier.as_ref()?.take(offset).unwrap_or_else(|e| {
+                    log::error!("Page {} can't be taken from the local tier, its changes are lost: {}", offset, e);
+                    None
+                });
+            };
+            let block = sdata.remove(i);
+            return Some((block.page, block.data));
         }
+    }
 
-        None
+    /// Returns where the page at given offset waits to be synced, without taking it out:
+    /// "being uploaded", "queued" or "in the local tier". None if it doesn't.
+    pub fn state_of(&self, offset: u64) -> Option<&'static str> {
+        if self.in_flight.lock_or_recover().contains(&offset) {
+            Some("being uploaded")
+        } else if self.data.lock_or_recover().iter().any(|block| block.page.offset == offset) {
+            Some("queued")
+        } else if self.tier.as_ref().is_some_and(|tier| tier.offsets().contains(&offset)) {
+            Some("in the local tier")
+        } else {
+            None
+        }
     }
 
     pub fn pop(&self) -> Option<QueueBlock> {
-        let mut sdata = self.data.lock().unwrap();
+        let mut sdata = self.data.lock_or_recover();
         sdata.pop()
     }
 
-    /// Flushes the queue. This will block until the queue is empty.
-    pub fn flush(&self) {
-        let mut slen;
-        {
-            let sdata = self.data.lock().unwrap();
-            slen = sdata.len();
+    /// Returns the number of blocks waiting to be synced.
+    pub fn len(&self) -> usize {
+        self.data.lock_or_recover().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes and returns every block of the local tier.
+    pub fn drain_tier(&self) -> Result<Vec<(Page, Zeroizing<Vec<u8>>)>> {
+        let mut blocks = Vec::new();
+        while let Some(block) = self.tier.as_ref().map(|tier| tier.take_oldest()).transpose()?.flatten() {
+            blocks.push(block);
         }
+        Ok(blocks)
+    }
 
-        while slen > 0 {
-            // Wait for the queue to be empty.
-            std::thread::sleep(std::time::Duration::from_millis(100));
-            {
-                let sdata = self.data.lock().unwrap();
-                slen = sdata.len();
+    /// Returns the number of blocks waiting in the local tier.
+    pub fn tier_len(&self) -> usize {
+        self.tier.as_ref().map_or(0, |tier| tier.len())
+    }
+
+    /// Stops the sync thread from talking to discord once the blocks it is uploading are synced.
+    /// Pushed blocks wait in the queue (pushes block once it is full), until `resume` is called.
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::SeqCst) {
+            log::info!("Syncing paused with {} blocks queued.", self.len());
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::SeqCst) {
+            // The time the queue was paused is not a stall.
+            self.stats.progressed();
+            log::info!("Syncing resumed with {} blocks queued.", self.len());
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Returns false if the sync thread was started but has since exited.
+    pub fn is_sync_thread_alive(&self) -> bool {
+        match &self.thread {
+            Some(thread) => !thread.is_finished(),
+            None => true,
+        }
+    }
+
+    /// Samples the current state of the queue.
+    pub fn health(&self, stall_after: Duration) -> Health {
+        Health {
+            blocked_pushes: self.stats.blocked_pushes(),
+            blocked_for: self.stats.blocked_for(),
+            ..Health::evaluate(
+                self.len(),
+                S,
+                self.stats.since_last_progress(),
+                self.stats.recent_errors(),
+                self.is_sync_thread_alive(),
+                stall_after,
+            )
+        }
+    }
+
+    /// Periodically logs a warning if the queue is not healthy, until the queue is shut down.
+    pub fn start_health_monitor(&self, interval: Duration, stall_after: Duration) -> std::thread::JoinHandle<()> {
+        let data = self.data.clone();
+        let stats = self.stats.clone();
+        let stop = self.stop.clone();
+        let paused = self.paused.clone();
+
+        std::thread::spawn(move || loop {
+            let start = Instant::now();
+            while start.elapsed() < interval {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                std::thread::sleep(interval.saturating_sub(start.elapsed()).min(Duration::from_millis(100)));
+            }
+
+            if paused.load(Ordering::SeqCst) {
+                // Nothing is expected to sync meanwhile.
+                stats.progressed();
+                continue;
+            }
+
+            let depth = data.lock_or_recover().len();
+            // The monitor can't see the sync thread handle, dead threads are reported by `health`.
+            Health {
+                blocked_pushes: stats.blocked_pushes(),
+                blocked_for: stats.blocked_for(),
+                ..Health::evaluate(depth, S, stats.since_last_progress(), stats.recent_errors(), true, stall_after)
+            }.log();
+        })
+    }
+
+    /// Flushes the queue. This will block until the queue is empty and the metadata of every
+    /// uploaded block is stored, or return an error if it doesn't drain within `timeout`.
+    pub fn flush(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+
+        let mut announced = false;
+        loop {
+            let pending = self.len() + self.tier_len();
+            // Blocks stay in flight until their metadata block is updated, not just until they are uploaded.
+            let syncing = self.is_syncing.load(std::sync::atomic::Ordering::SeqCst) || !self.in_flight.lock_or_recover().is_empty();
+
+            if let Some(error) = self.take_failure() {
+                log::warn!("Flush failed: {}", error);
+                return Err(error);
+            }
+
+            if pending == 0 && !syncing {
+                break;
+            }
+
+            if self.is_paused() && !syncing {
+                log::warn!("Syncing is paused, {} blocks can't be flushed.", pending);
+                return Err(Error::SyncPaused { pending });
+            }
+
+            if pending == 0 && !announced {
+                log::debug!("Waiting for last block to sync.");
+                announced = true;
             }
+
+            if !self.is_sync_thread_alive() {
+                log::warn!("Sync thread is dead, {} blocks will never be synced.", pending);
+                return Err(Error::SyncThreadDead { pending });
+            }
+
+            if start.elapsed() >= timeout {
+                log::warn!("Flush timed out after {:?} with {} blocks still pending.", timeout, pending);
+                return Err(Error::FlushTimeout { pending });
+            }
+
+            // Wait for the queue to be empty.
+            std::thread::sleep(Duration::from_millis(100));
         }
 
-        println!("Waiting for last block to sync.");
+        log::debug!("Queue flushed.");
+        Ok(())
+    }
+
+    /// Waits until the queue is empty (up to `timeout`) and stops the sync thread.
+    /// Blocks that didn't sync in time are dropped.
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<()> {
+        // Queued blocks are lost otherwise.
+        self.resume();
+        let result = self.flush(timeout);
 
-        // Wait for the thread to finish syncing.
-        while self.is_syncing.load(std::sync::atomic::Ordering::SeqCst) {
-            std::thread::sleep(std::time::Duration::from_millis(100));
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
         }
 
-        println!("Queue flushed.");
+        result
     }
 
-    pub fn start_sync_thread(mut self, http: Arc<Http>, channel_id: ChannelId, metadata: Arc<Mutex<Vec<MetadataBlock>>>) -> Self {
+    /// Starts uploading queued pages.
+    pub fn start_sync_thread(mut self, backend: Arc<dyn Backend>, options: Arc<PageOptions>, channel_id: ChannelId, metadata: Arc<Mutex<Metadata>>) -> Self {
         let data = self.data.clone();
+        let in_flight = self.in_flight.clone();
         let is_syncing = Arc::clone(&self.is_syncing);
+        let stop = self.stop.clone();
+        let paused = self.paused.clone();
+        let stats = self.stats.clone();
+        let failures = self.failures.clone();
+        let sync_attempts = self.sync_attempts;
+        let max_uploads = self.max_uploads;
+        let metadata_updates = self.metadata_updates;
+        let panic_policy = self.panic_policy;
+        let metadata_channel = self.metadata_channel.unwrap_or(channel_id);
+        let tier = self.tier.clone();
+        let journal = self.journal.clone();
         let t = std::thread::spawn(move || {
-            // TODO: Await multiple blocks at once.
             let rt = tokio::runtime::Runtime::new().unwrap();
-            loop {
-                let mut sdata = data.lock().unwrap();
-                if sdata.len() == 0 {
+            while !stop.load(Ordering::SeqCst) {
+                if paused.load(Ordering::SeqCst) {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    continue;
+                }
+                rt.block_on(options.deletions.run_due(backend.as_ref()));
+
+                let mut sdata = data.lock_or_recover();
+                // Spilled blocks come in as fast as they are uploaded.
+                while let Some(tier) = tier.as_ref().filter(|_| sdata.len() < max_uploads) {
+                    match tier.take_oldest() {
+                        Ok(Some((page, data))) => sdata.push(QueueBlock::new(page, data)),
+                        Ok(None) => break,
+                        Err(e) => log::error!("Failed to take a page from the local tier, its changes are lost: {}", e),
+                    }
+                }
+                if sdata.is_empty() {
                     // Ensure that the thread doesn't spinlock.
                     drop(sdata);
                     std::thread::sleep(std::time::Duration::from_millis(100));
                     continue;
                 }
 
-                // Sync the data.
+                // Take up to `max_uploads` blocks to upload at once.
                 is_syncing.store(true, std::sync::atomic::Ordering::SeqCst);
-                let mut block = sdata.pop().unwrap();
+                let count = sdata.len().min(max_uploads);
+                let mut batch: Vec<QueueBlock> = (0..count).filter_map(|_| sdata.pop()).collect();
+                in_flight.lock_or_recover().extend(batch.iter().map(|block| block.page.offset));
                 // We don't need the lock anymore. Drop it.
                 drop(sdata);
 
-                // Sync the data.
-                let channel_id = channel_id.clone();
-                rt.block_on(async {
-                    block.sync(&http, channel_id).await;
+                // Relocated pages are uploaded to the channel they were moved to, where their old message is.
+                // Pages that were never uploaded go to the channel the drive rolled over to.
+                {
+                    let mut meta = metadata.lock_or_recover();
+                    for block in batch.iter_mut() {
+                        block.page.channel = match meta.find(block.page.offset) {
+                            Some(page) if page.message_id.is_some() => page.channel,
+                            _ => options.new_page_channel(),
+                        };
+                    }
+                }
+
+                let uploaded = rt.block_on(async {
+                    // Uploads only borrow the data, so a block whose upload panicked can still be put back.
+                    let uploads: Vec<_> = batch.into_iter().map(|block| {
+                        let data = Arc::new(block.data);
+                        // With a journal the old message is kept until the metadata points at the new one,
+                        // an upload whose metadata wasn't stored yet is replaced right away.
+                        let keep = journal.is_some() && block.replaced.is_none();
+                        let replaced = if keep { block.page.message_id } else { block.replaced };
+                        let upload = tokio::spawn({
+                            let (backend, options, data, mut page) = (backend.clone(), options.clone(), data.clone(), block.page.clone());
+                            async move {
+                                let result = match keep {
+                                    true => page.upload(backend.as_ref(), &channel_id, &options, &data).await,
+                                    false => page.update_message(backend.as_ref(), &channel_id, &options, &data).await,
+                                };
+                                (page, result)
+                            }
+                        });
+                        (block.page, data, block.attempts, replaced, upload)
+                    }).collect();
+
+                    let mut uploaded = Vec::new();
+                    for (page, data, attempts, replaced, upload) in uploads {
+                        let (page, result) = match upload.await {
+                            Ok(uploaded) => uploaded,
+                            Err(e) => {
+                                let reason = e.try_into_panic().map_or_else(|e| e.to_string(), |payload| panic_reason(&*payload));
+                                let offset = page.offset;
+                                (page, Err(Error::SyncPanicked { offset, reason }))
+                            }
+                        };
+                        let data = Arc::try_unwrap(data).unwrap_or_else(|data| (*data).clone());
+                        // Nothing is replaced yet if the page still points at the old message (the upload failed).
+                        let replaced = replaced.filter(|old| page.message_id != Some(*old));
+                        uploaded.push((QueueBlock { page, data, attempts, replaced }, result));
+                    }
+                    uploaded
+                });
+
+                // The blocks of all uploaded pages are stored up front, from copies taken under the metadata lock.
+                let pages: Vec<Page> = uploaded.iter().filter(|(_, result)| result.is_ok()).map(|(block, _)| block.page.clone()).collect();
+                for (block, _) in uploaded.iter().filter(|(_, result)| result.is_ok()) {
+                    // The data is uploaded, a crash from here on would leave the metadata pointing at the old message.
+                    if let Some(Err(e)) = journal.as_ref().map(|journal| journal.record(&block.page, block.replaced)) {
+                        log::error!("Failed to journal page {} before updating its metadata: {}", block.page.offset, e);
+                    }
+                }
+                let mut updated = panic::catch_unwind(AssertUnwindSafe(|| {
+                    rt.block_on(update_blocks(backend.clone(), metadata_channel, &metadata, &pages, metadata_updates))
+                })).unwrap_or_else(|payload| {
+                    let reason = panic_reason(&*payload);
+                    pages.iter().map(|page| (page.offset, Err(Error::SyncPanicked { offset: page.offset, reason: reason.clone() }))).collect()
+                });
 
-                    let mut meta = metadata.lock().unwrap();
-                    for m in meta.iter_mut() {
-                        if m.update_page(&http, &channel_id, block.page.clone()).await {
-                            break;
+                let mut failed = false;
+                let mut panicked = false;
+                for (mut block, result) in uploaded {
+                    let result = result.and_then(|()| updated.remove(&block.page.offset).unwrap_or(Ok(())));
+
+                    let offset = block.page.offset;
+                    if let Err(e @ Error::SyncPanicked { .. }) = &result {
+                        log::error!("{}", e);
+                        panicked = true;
+                    }
+                    match result {
+                        Ok(()) => {
+                            // The metadata points at the upload, the old message isn't needed anymore.
+                            if let Some(old) = block.replaced.filter(|_| !options.keep_history) {
+                                rt.block_on(options.deletions.delete(backend.as_ref(), block.page.channel_or(channel_id), old));
+                            }
+                            if let Some(Err(e)) = journal.as_ref().map(|journal| journal.remove(offset)) {
+                                log::warn!("Failed to remove page {} from the mask journal: {}", offset, e);
+                            }
+                            stats.synced();
+                            log::info!("Synced block at offset {}.", offset);
+                            options.buffers.put(block.data);
+                        }
+                        Err(e) => {
+                            stats.failed();
+                            block.attempts += 1;
+
+                            if sync_attempts.is_some_and(|attempts| block.attempts >= attempts) {
+                                log::error!("Giving up on block at offset {} after {} attempts, its changes are lost: {}", offset, block.attempts, e);
+                                failures.lock_or_recover().push((offset, BackendError::RetriesExhausted { attempts: block.attempts, last: e.to_string() }));
+                            } else {
+                                log::warn!("Failed to sync block at offset {}, retrying later: {}", offset, e);
+
+                                // Put the block back.
+                                data.lock_or_recover().push(block);
+                                failed = true;
+                            }
                         }
                     }
-                }); 
-                is_syncing.store(false, std::sync::atomic::Ordering::SeqCst);
+                    in_flight.lock_or_recover().retain(|o| *o != offset);
+                }
 
-                println!("Synced block at offset {}.", block.page.offset);
+                if panicked && panic_policy == PanicPolicy::Abort {
+                    log::error!("Sync thread stops after a panic (SYNC_PANIC=abort), {} blocks will not be synced.", data.lock_or_recover().len());
+                    is_syncing.store(false, std::sync::atomic::Ordering::SeqCst);
+                    break;
+                }
+                if failed {
+                    // Give discord a moment.
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+                is_syncing.store(false, std::sync::atomic::Ordering::SeqCst);
             }
         });
 
         self.thread = Some(t);
         self
     }
+}
+
+/// Stores the metadata blocks of the uploaded pages, up to `concurrency` of them at once (`MAX_METADATA_UPDATES`),
+/// and returns how the update of every page went. The pages are set in their blocks under the metadata lock,
+/// and detached copies of the blocks are stored without it, so reads, writes and the other blocks don't wait
+/// for Discord. Every block is stored by one update at a time, whichever pages it holds.
+async fn update_blocks(backend: Arc<dyn Backend>, channel: ChannelId, metadata: &Mutex<Metadata>, pages: &[Page], concurrency: usize) -> HashMap<u64, Result<()>> {
+    let detached = metadata.lock_or_recover().detach_pages(pages);
+    let mut results: HashMap<u64, Result<()>> = pages.iter().map(|page| (page.offset, Ok(()))).collect();
+
+    let mut detached = detached.into_iter();
+    loop {
+        let stores: Vec<_> = detached.by_ref().take(concurrency).map(|(offsets, detached_from, mut block)| {
+            let backend = backend.clone();
+            let store = tokio::spawn(async move {
+                let result = block.update_message(backend.as_ref(), &channel).await;
+                (block, result)
+            });
+            (offsets, detached_from, store)
+        }).collect();
+        if stores.is_empty() {
+            break;
+        }
+
+        for (offsets, detached_from, store) in stores {
+            let (block, result) = match store.await {
+                Ok(stored) => stored,
+                Err(e) => {
+                    let reason = e.try_into_panic().map_or_else(|e| e.to_string(), |payload| panic_reason(&*payload));
+                    for offset in &offsets {
+                        results.insert(*offset, Err(Error::SyncPanicked { offset: *offset, reason: reason.clone() }));
+                    }
+                    continue;
+                }
+            };
+
+            // A failed store leaves the block unsaved, its pages are uploaded again.
+            if let Err(e) = result {
+                let reason = e.to_string();
+                results.insert(offsets[0], Err(e));
+                for offset in &offsets[1..] {
+                    results.insert(*offset, Err(Error::Backend(BackendError::Other(reason.clone()))));
+                }
+                continue;
+            }
+
+            let unused = metadata.lock_or_recover().block_of(offsets[0]).map(|live| live.adopt_stored(detached_from, block)).unwrap_or_default();
+            for message_id in unused {
+                backend.delete_message(channel, message_id).await.ok();
+            }
+        }
+    }
+
+    results
+}
+
+/// Message of a caught panic.
+fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{backend::MemoryBackend, health::HealthStatus};
+
+    #[test]
+    fn flush_times_out_without_sync_thread() {
+        let queue = Queue::<4>::new();
+        queue.push(Page::new(0), vec![0; 4096]);
+
+        let start = Instant::now();
+        let result = queue.flush(Duration::from_millis(300));
+
+        assert!(matches!(result, Err(Error::FlushTimeout { pending: 1 })));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn flush_detects_dead_sync_thread() {
+        let mut queue = Queue::<4>::new();
+        queue.thread = Some(std::thread::spawn(|| {}));
+        queue.push(Page::new(0), vec![0; 4096]);
+
+        while queue.is_sync_thread_alive() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let result = queue.flush(Duration::from_secs(60));
+        assert!(matches!(result, Err(Error::SyncThreadDead { pending: 1 })));
+    }
+
+    #[test]
+    fn stuck_queue_is_degraded() {
+        let queue = Queue::<4>::new();
+        let stall_after = Duration::from_millis(50);
+
+        assert_eq!(queue.health(stall_after).status, HealthStatus::Healthy);
+
+        // Nothing syncs the block, so it is stuck.
+        queue.push(Page::new(0), vec![0; 4096]);
+        std::thread::sleep(Duration::from_millis(100));
+
+        let health = queue.health(stall_after);
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(health.queue_depth, 1);
+    }
+
+    #[test]
+    fn dead_sync_thread_is_failed() {
+        let mut queue = Queue::<4>::new();
+        queue.thread = Some(std::thread::spawn(|| {}));
+
+        while queue.is_sync_thread_alive() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(queue.health(Duration::from_secs(60)).status, HealthStatus::Failed);
+    }
+
+    #[test]
+    fn queued_data_zeroized_on_drop() {
+        let mut data = crate::utils::WipeCheck::buffer();
+        data.resize(4096, 1);
+        let queue = Queue::<4>::new();
+        queue.push(Page::new(0), data);
+
+        let (wiped, not_wiped) = crate::utils::WipeCheck::freed();
+        drop(queue.release_offset(0).unwrap());
+        assert_eq!(crate::utils::WipeCheck::freed(), (wiped + 1, not_wiped));
+    }
+
+    #[test]
+    fn release_waits_for_upload() {
+        let queue = Arc::new(Queue::<4>::new());
+        queue.in_flight.lock_or_recover().push(3);
+
+        let q = queue.clone();
+        let release = std::thread::spawn(move || q.release_offset(3).map(|(page, _)| page.offset));
+
+        // The upload failed, so the page is back in the queue.
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!release.is_finished());
+        queue.data.lock_or_recover().push(QueueBlock::new(Page::new(3), vec![0; 4096]));
+        queue.in_flight.lock_or_recover().clear();
+
+        assert_eq!(release.join().unwrap(), Some(3));
+        assert!(queue.release_offset(4).is_none());
+    }
+
+    #[test]
+    fn shutdown_stops_health_monitor() {
+        let mut queue = Queue::<4>::new();
+        let monitor = queue.start_health_monitor(Duration::from_secs(60), Duration::from_secs(60));
+
+        assert!(queue.shutdown(Duration::from_millis(100)).is_ok());
+        monitor.join().unwrap();
+    }
+
+    #[test]
+    fn counts_blocked_pushes() {
+        let queue = Arc::new(Queue::<4>::new());
+        for offset in 0..4 {
+            queue.push(Page::new(offset), vec![0; 4096]);
+        }
+        assert_eq!(queue.stats.blocked_pushes(), 0);
+
+        // Nothing syncs the queue, so the push waits until a block is taken out.
+        let q = queue.clone();
+        let push = std::thread::spawn(move || q.push(Page::new(4), vec![0; 4096]));
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(!push.is_finished());
+        queue.pop();
+        push.join().unwrap();
+
+        let health = queue.health(Duration::from_secs(60));
+        assert_eq!(health.queue_depth, 4);
+        assert_eq!(health.blocked_pushes, 1);
+        assert!(health.blocked_for >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn limits_concurrent_uploads() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_upload_latency(Duration::from_millis(50));
+
+        let queue = Queue::<8>::new()
+            .with_max_uploads(2)
+            .start_sync_thread(backend.clone(), Arc::new(PageOptions::default()), ChannelId(1), Arc::new(Mutex::new(Metadata::default())));
+        for offset in 0..8 {
+            queue.push(Page::new(offset), vec![1; 4096]);
+        }
+        queue.flush(Duration::from_secs(10)).unwrap();
+
+        assert_eq!(backend.calls("send_file"), 8);
+        assert_eq!(backend.max_concurrent_uploads(), 2);
+    }
+
+    #[test]
+    fn flush_reports_given_up_blocks() {
+        let backend = Arc::new(MemoryBackend::new());
+        backend.set_upload_limit(8192);
+
+        let queue = Queue::<4>::new()
+            .with_sync_attempts(2)
+            .start_sync_thread(backend.clone(), Arc::new(PageOptions::default()), ChannelId(1), Arc::new(Mutex::new(Metadata::default())));
+        queue.push(Page::new(0), vec![1; 4096]);
+        queue.push(Page::new(1), vec![1; 16384]);
+        queue.push(Page::new(2), vec![1; 4096]);
+
+        match queue.flush(Duration::from_secs(10)) {
+            Err(Error::SyncFailed { offsets, reason }) => {
+                assert_eq!(offsets, vec![1]);
+                assert!(reason.contains("2 attempts"), "{}", reason);
+            }
+            result => panic!("Expected a sync failure, got {:?}", result),
+        }
+
+        // The other blocks were synced and the failure is only reported once.
+        queue.flush(Duration::from_secs(10)).unwrap();
+        assert!(queue.take_failure().is_none());
+        assert_eq!(backend.messages(ChannelId(1)).len(), 2);
+    }
+
+    #[test]
+    fn recovers_from_panicking_sync() {
+        let backend = Arc::new(MemoryBackend::new());
+        let queue = Queue::<4>::new()
+            .start_sync_thread(backend.clone(), Arc::new(PageOptions::default()), ChannelId(1), Arc::new(Mutex::new(Metadata::default())));
+
+        backend.panic_once("send_file");
+        queue.push(Page::new(0), vec![1; 4096]);
+        queue.flush(Duration::from_secs(10)).unwrap();
+
+        // The block was uploaded again and the thread keeps syncing.
+        assert_eq!(backend.calls("send_file"), 2);
+        assert_eq!(queue.stats.sync_errors(), 1);
+        queue.push(Page::new(1), vec![1; 4096]);
+        queue.flush(Duration::from_secs(10)).unwrap();
+        assert!(queue.is_sync_thread_alive());
+    }
+
+    #[test]
+    fn aborts_on_panicking_sync() {
+        let backend = Arc::new(MemoryBackend::new());
+        let queue = Queue::<4>::new()
+            .with_panic_policy(PanicPolicy::Abort)
+            .start_sync_thread(backend.clone(), Arc::new(PageOptions::default()), ChannelId(1), Arc::new(Mutex::new(Metadata::default())));
+
+        backend.panic_once("send_file");
+        queue.push(Page::new(0), vec![1; 4096]);
+
+        // The block is kept and flushes fail instead of waiting for it.
+        let start = Instant::now();
+        assert!(matches!(queue.flush(Duration::from_secs(60)), Err(Error::SyncThreadDead { pending: 1 })));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn flush_empty_queue() {
+        let queue = Queue::<4>::new();
+        assert!(queue.flush(Duration::from_millis(100)).is_ok());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,201 @@
+use serenity::model::prelude::ChannelId;
+
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+use crate::metadata::{MetadataBlock, Page, PAGES_PER_BLOCK};
+use crate::utils::{header_volume, volume_magic};
+
+/// Magic starting every message of a snapshot, followed by the format version.
+const MAGIC: &str = "SNAPSHOT";
+const VERSION: u32 = 1;
+
+/// Name of the snapshots holding metadata backups (`METADATA_BACKUP_INTERVAL`).
+pub const BACKUP_NAME: &str = "daafs metadata backup";
+
+/// Longest name of a snapshot, so its messages stay below the message length limit.
+const MAX_NAME_LEN: usize = 100;
+
+/// Point in time mapping of every page to the message holding its data.
+/// Data is not copied, so the messages must be kept (`KEEP_HISTORY`) for the snapshot to be restored.
+/// Pages are stored like in metadata blocks, a few in every message.
+pub struct Snapshot {
+    pub name: String,
+    pub pages: Vec<Page>,
+    /// Volume the snapshot belongs to (empty for the default volume)
+    pub volume: String,
+    /// Ids of the messages holding the snapshot (empty if it wasn't sent yet)
+    pub message_ids: Vec<u64>,
+}
+
+impl Snapshot {
+    pub fn new(name: &str, pages: Vec<Page>) -> Result<Self> {
+        let name = name.trim();
+        if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('\n') {
+            return Err(Error::InvalidSnapshotName { name: name.to_string() });
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            pages,
+            volume: String::new(),
+            message_ids: Vec::new(),
+        })
+    }
+
+    /// Generates the texts of the messages holding the snapshot.
+    pub fn as_texts(&self) -> Vec<String> {
+        // Format:
+        // SNAPSHOT[:<volume>] v1 <part>/<parts> <name>
+        // <offset>:<message_id>[.<key_id>]:<page_data>
+        // ...
+
+        // A snapshot of an empty drive still needs a message.
+        let chunks: Vec<&[Page]> = match self.pages.is_empty() {
+            true => vec![&[]],
+            false => self.pages.chunks(PAGES_PER_BLOCK).collect(),
+        };
+
+        chunks.iter().enumerate().map(|(i, pages)| {
+            let block = MetadataBlock { pages: pages.to_vec(), ..MetadataBlock::empty(None) };
+            format!("{} v{} {}/{} {}\n{}", volume_magic(MAGIC, &self.volume), VERSION, i + 1, chunks.len(), self.name, block.pages_text())
+        }).collect()
+    }
+
+    /// Parses the header of a snapshot message into its volume, part, number of parts and the snapshot name.
+    /// Returns None if the message is not a snapshot.
+    fn header(text: &str) -> Result<Option<(&str, usize, usize, &str)>> {
+        let line = text.lines().next().unwrap_or_default();
+
+        // The magic and version are followed by the part and the name.
+        let head = line.match_indices(' ').nth(1).map_or(line, |(i, _)| &line[..i]);
+        let volume = match header_volume(head, MAGIC) {
+            Some((volume, VERSION)) => volume,
+            Some((_, 0)) | None => return Ok(None),
+            Some((_, version)) => return Err(Error::UnsupportedVersion { format: "snapshot", version }),
+        };
+
+        let mut split = line[head.len()..].trim_start().splitn(2, ' ');
+        let header = split.next().zip(split.next()).and_then(|(part, name)| {
+            let (part, parts) = part.split_once('/')?;
+            Some((volume, part.parse().ok()?, parts.parse().ok()?, name))
+        });
+        Ok(header)
+    }
+
+    /// Sends the snapshot to the channel.
+    pub async fn save(&mut self, backend: &dyn Backend, channel: ChannelId) -> Result<()> {
+        self.message_ids.clear();
+        for text in self.as_texts() {
+            self.message_ids.push(backend.send_message(channel, &text).await?);
+        }
+
+        Ok(())
+    }
+
+    /// Looks for the newest snapshot of the volume with given name in the whole channel history.
+    pub async fn find(backend: &dyn Backend, channel: ChannelId, volume: &str, name: &str) -> Result<Option<Self>> {
+        let name = name.trim();
+        let mut parts: Vec<Option<(u64, String)>> = Vec::new();
+        let mut before = None;
+
+        loop {
+            let batch = backend.get_messages(channel, before, 100).await?;
+            let Some(oldest) = batch.iter().map(|message| message.id).min() else {
+                return Ok(None);
+            };
+
+            for message in batch.iter() {
+                let Some((found_volume, part, count, found)) = Self::header(&message.content)? else {
+                    continue;
+                };
+                if found_volume != volume || found != name || part == 0 || part > count {
+                    continue;
+                }
+
+                // The newest message of the name tells how many parts there are,
+                // parts of older snapshots with the same name are ignored.
+                if parts.is_empty() {
+                    parts = vec![None; count];
+                }
+                if parts.len() == count && parts[part - 1].is_none() {
+                    parts[part - 1] = Some((message.id, message.content.clone()));
+                }
+
+                if parts.iter().all(Option::is_some) {
+                    return Self::from_parts(volume, name, parts.into_iter().flatten().collect()).map(Some);
+                }
+            }
+
+            before = Some(oldest);
+        }
+    }
+
+    fn from_parts(volume: &str, name: &str, parts: Vec<(u64, String)>) -> Result<Self> {
+        let mut snapshot = Self::new(name, Vec::new())?;
+        snapshot.volume = volume.to_string();
+
+        for (message_id, text) in parts {
+            let body = text.split_once('\n').map(|(_, body)| body).unwrap_or_default();
+            snapshot.pages.extend(MetadataBlock::from_pages_text(message_id, body)?.pages);
+            snapshot.message_ids.push(message_id);
+        }
+
+        Ok(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    const CHANNEL: ChannelId = ChannelId(1);
+
+    fn pages(count: u64) -> Vec<Page> {
+        (0..count).map(|offset| Page { message_id: Some(100 + offset), key_id: (offset % 2) as u8, ..Page::new(offset) }).collect()
+    }
+
+    fn fields(pages: &[Page]) -> Vec<(u64, Option<u64>, u8)> {
+        pages.iter().map(|page| (page.offset, page.message_id, page.key_id)).collect()
+    }
+
+    #[test]
+    fn finds_newest_snapshot() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            Snapshot::new("daily", pages(3)).unwrap().save(&backend, CHANNEL).await.unwrap();
+            backend.send_message(CHANNEL, "METABLOCK v1").await.unwrap();
+
+            let mut newest = Snapshot::new("daily", pages(12)).unwrap();
+            newest.save(&backend, CHANNEL).await.unwrap();
+            assert_eq!(newest.message_ids.len(), 3);
+            Snapshot::new("other", pages(1)).unwrap().save(&backend, CHANNEL).await.unwrap();
+
+            let found = Snapshot::find(&backend, CHANNEL, "", "daily").await.unwrap().unwrap();
+            assert_eq!(fields(&found.pages), fields(&newest.pages));
+            assert!(Snapshot::find(&backend, CHANNEL, "", "missing").await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn empty_snapshot() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            Snapshot::new("empty", Vec::new()).unwrap().save(&backend, CHANNEL).await.unwrap();
+
+            let found = Snapshot::find(&backend, CHANNEL, "", "empty").await.unwrap().unwrap();
+            assert!(found.pages.is_empty());
+        });
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        assert!(matches!(Snapshot::new("", Vec::new()), Err(Error::InvalidSnapshotName { .. })));
+        assert!(matches!(Snapshot::new("a\nb", Vec::new()), Err(Error::InvalidSnapshotName { .. })));
+        assert!(Snapshot::new("before upgrade", Vec::new()).is_ok());
+    }
+}
@@ -0,0 +1,799 @@
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serenity::model::prelude::ChannelId;
+
+use crate::backend::{Backend, BackendError, MESSAGE_LIMIT, StoredMessage};
+use crate::crypto::Keyring;
+use crate::error::{Error, Result};
+use crate::layout::IntegrityProblem;
+use crate::metadata::{MetadataBlock, Scan};
+use crate::utils::{ToBase32, header_volume, join_all, to_hex, try_from_base32, try_from_hex, volume_magic};
+
+/// Magic starting the superblock message, followed by the format version.
+const MAGIC: &str = "SUPERBLOCK";
+/// Version 3 records the channel new pages go to. Superblocks without one are still written as version 2,
+/// so older versions can open them.
+const VERSION: u32 = 3;
+const VERSION_WITHOUT_CHANNEL: u32 = 2;
+/// Version of a superblock too long for a message (`MESSAGE_LIMIT`), followed by the id of the message
+/// with the compressed attachment holding its text.
+const VERSION_GZIP: u32 = 4;
+/// Most metadata blocks fetched at once by `load_blocks` and `check_blocks`.
+const LOAD_CONCURRENCY: usize = 16;
+/// Line after the header holding the authentication tag of a signed superblock.
+const SIGNED: &str = "signed ";
+/// Line after the header holding the rest of an encrypted superblock.
+const SEALED: &str = "sealed ";
+
+/// How the superblock is protected (`SUPERBLOCK_SECURITY`).
+/// Tampering with the superblock could point reads at other messages, so a signed or encrypted
+/// superblock whose tag doesn't match is never used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SuperblockSecurity {
+    /// Plain text, anyone who can edit the message can change it
+    #[default]
+    None,
+    /// Plain text with an authentication tag, superblocks without one are ignored
+    Signed,
+    /// Encrypted and authenticated, superblocks that are not are ignored
+    Encrypted,
+}
+
+impl FromStr for SuperblockSecurity {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "sign" => Ok(Self::Signed),
+            "encrypt" => Ok(Self::Encrypted),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Protection of the superblock, with the keys it is signed or encrypted with (the current one of the keyring).
+#[derive(Clone, Debug, Default)]
+pub struct Sealing {
+    pub security: SuperblockSecurity,
+    pub keyring: Keyring,
+}
+
+/// Pinned message indexing all metadata blocks of the drive,
+/// so that startup doesn't have to scan the whole channel.
+pub struct Superblock {
+    /// Id of the pinned message (0 if it wasn't sent yet)
+    pub message_id: u64,
+    /// Metadata blocks of the drive
+    pub blocks: Vec<SuperblockEntry>,
+    /// Volume of the drive (empty for the default volume)
+    pub volume: String,
+    /// Size of the drive in bytes (None in superblocks of older versions)
+    pub device_size: Option<u64>,
+    /// Channel pages that were never uploaded go to, once the drive rolled over from the channel of the drive
+    /// (`CHANNEL_MESSAGE_LIMIT`). None in superblocks of older versions.
+    pub data_channel: Option<u64>,
+    /// How the superblock is protected when it is saved
+    pub sealing: Sealing,
+    /// Whether the superblock was signed or encrypted when it was loaded
+    pub sealed: bool,
+    /// Id of the message with the compressed attachment of a long superblock (0 if there is none)
+    pub attachment_id: u64,
+}
+
+/// Metadata block as listed in the superblock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SuperblockEntry {
+    /// Id of the message holding the metadata block
+    pub message_id: u64,
+    /// Offsets of pages stored in the block (None if unknown)
+    pub offsets: Option<Vec<u64>>,
+}
+
+impl SuperblockEntry {
+    /// Entry of the block, None if the block wasn't sent yet.
+    pub fn of(block: &MetadataBlock) -> Option<Self> {
+        Some(Self {
+            message_id: block.message_id?,
+            offsets: Some(block.pages.iter().map(|page| page.offset).collect()),
+        })
+    }
+
+    /// Returns true if the block may contain the page at given offset (stored as a multiple of the page size).
+    pub fn may_contain(&self, offset: u64) -> bool {
+        match &self.offsets {
+            Some(offsets) => offsets.contains(&offset),
+            None => true,
+        }
+    }
+}
+
+impl Superblock {
+    pub fn empty() -> Self {
+        Self {
+            message_id: 0,
+            blocks: Vec::new(),
+            volume: String::new(),
+            device_size: None,
+            data_channel: None,
+            sealing: Sealing::default(),
+            sealed: false,
+            attachment_id: 0,
+        }
+    }
+
+    /// Loads the superblock from text in a discord message
+    pub fn from_text(message_id: u64, text: &str) -> Result<Self> {
+        // Format:
+        // SUPERBLOCK[:<volume>] v2
+        // size <device_size>
+        // channel <data_channel>
+        // <metadata_block_message_id>:<page_offset>,<page_offset>,...
+        // ...
+
+        let mut lines = text.lines().peekable();
+        let volume = match lines.next().and_then(|line| header_volume(line, MAGIC)) {
+            // Versions 0 (no version in the header) and 1 only lack the size.
+            Some((volume, 0 | 1 | VERSION_WITHOUT_CHANNEL | VERSION)) => volume,
+            None => "",
+            Some((_, version)) => return Err(Error::UnsupportedVersion { format: "superblock", version }),
+        };
+
+        let device_size = match lines.next_if(|line| line.starts_with("size ")) {
+            Some(line) => Some(try_from_base32(&line["size ".len()..])
+                .ok_or_else(|| Error::InvalidMetadata { message_id, line: line.to_string() })?),
+            None => None,
+        };
+
+        let data_channel = match lines.next_if(|line| line.starts_with("channel ")) {
+            Some(line) => Some(try_from_base32(&line["channel ".len()..])
+                .ok_or_else(|| Error::InvalidMetadata { message_id, line: line.to_string() })?),
+            None => None,
+        };
+
+        let blocks = lines
+            .map(|line| {
+                let invalid = || Error::InvalidMetadata { message_id, line: line.to_string() };
+
+                let mut split = line.split(':');
+                let block_id = split.next().and_then(try_from_base32).ok_or_else(invalid)?;
+                // Offsets are missing in superblocks of older versions.
+                let offsets = split.next().map(|offsets| {
+                    offsets.split(',')
+                        .filter(|offset| !offset.is_empty())
+                        .map(|offset| try_from_base32(offset).ok_or_else(invalid))
+                        .collect::<Result<Vec<u64>>>()
+                }).transpose()?;
+                if split.next().is_some() {
+                    return Err(invalid());
+                }
+
+                Ok(SuperblockEntry { message_id: block_id, offsets })
+            })
+            .collect::<Result<Vec<SuperblockEntry>>>()?;
+
+        Ok(Self {
+            message_id,
+            blocks,
+            volume: volume.to_string(),
+            device_size,
+            data_channel,
+            ..Self::empty()
+        })
+    }
+
+    /// Loads the superblock from a discord message that may be signed or encrypted:
+    /// the header is followed by `signed <key_id> <nonce and tag>` (authenticating the rest of the text)
+    /// or by `sealed <key_id> <encrypted rest of the text>`, both in hex.
+    /// Fails with `TamperedSuperblock` if the tag doesn't match, so a changed or truncated superblock is not used.
+    pub fn open(message_id: u64, text: &str, keyring: &Keyring) -> Result<Self> {
+        let (header, rest) = text.split_once('\n').unwrap_or((text, ""));
+        let (line, body) = rest.split_once('\n').unwrap_or((rest, ""));
+        let tampered = || Error::TamperedSuperblock { message_id };
+
+        let seal = |line: &str| -> Result<(u8, Vec<u8>)> {
+            let (key_id, stored) = line.split_once(' ').ok_or_else(tampered)?;
+            let key_id = key_id.parse().map_err(|_| tampered())?;
+            Ok((key_id, try_from_hex(stored).ok_or_else(tampered)?))
+        };
+        let open = |key_id, stored: &[u8], associated: &str| match keyring.open(key_id, stored, associated.as_bytes()) {
+            Err(Error::Encryption) => Err(tampered()),
+            result => result,
+        };
+
+        let plain = if let Some(line) = line.strip_prefix(SIGNED) {
+            let (key_id, tag) = seal(line)?;
+            let plain = format!("{}\n{}", header, body);
+            open(key_id, &tag, &plain)?;
+            plain
+        } else if let Some(line) = line.strip_prefix(SEALED) {
+            let (key_id, stored) = seal(line)?;
+            if !body.is_empty() {
+                return Err(tampered());
+            }
+            let body = String::from_utf8(open(key_id, &stored, header)?).map_err(|_| tampered())?;
+            format!("{}\n{}", header, body)
+        } else {
+            return Self::from_text(message_id, text);
+        };
+
+        Ok(Self { sealed: true, ..Self::from_text(message_id, &plain)? })
+    }
+
+    /// Text of the discord message, signed or encrypted as configured by `sealing`.
+    pub fn message_text(&self) -> Result<String> {
+        let text = self.as_text();
+        let (header, body) = text.split_once('\n').unwrap_or((&text, ""));
+        let keyring = &self.sealing.keyring;
+
+        Ok(match self.sealing.security {
+            SuperblockSecurity::None => text,
+            SuperblockSecurity::Signed => {
+                let (key_id, tag) = keyring.seal(&[], text.as_bytes())?;
+                format!("{}\n{}{} {}\n{}", header, SIGNED, key_id, to_hex(&tag), body)
+            }
+            SuperblockSecurity::Encrypted => {
+                let (key_id, stored) = keyring.seal(body.as_bytes(), header.as_bytes())?;
+                format!("{}\n{}{} {}\n", header, SEALED, key_id, to_hex(&stored))
+            }
+        })
+    }
+
+    /// Generates the text that should be stored in a discord message
+    pub fn as_text(&self) -> String {
+        let version = if self.data_channel.is_some() { VERSION } else { VERSION_WITHOUT_CHANNEL };
+        let mut text = format!("{} v{}\n", volume_magic(MAGIC, &self.volume), version);
+        if let Some(size) = self.device_size {
+            text.push_str(&format!("size {}\n", size.to_base32()));
+        }
+        if let Some(channel) = self.data_channel {
+            text.push_str(&format!("channel {}\n", channel.to_base32()));
+        }
+
+        for block in &self.blocks {
+            text.push_str(&block.message_id.to_base32());
+            if let Some(offsets) = &block.offsets {
+                let offsets: Vec<String> = offsets.iter().map(|offset| offset.to_base32()).collect();
+                text.push(':');
+                text.push_str(&offsets.join(","));
+            }
+            text.push('\n');
+        }
+
+        text
+    }
+
+    /// Looks for the superblock of the volume among pinned messages of the channel.
+    /// With `sealing` set, superblocks that are not signed or encrypted are skipped,
+    /// and one whose tag doesn't match fails the lookup.
+    pub async fn find(backend: &dyn Backend, channel: ChannelId, volume: &str, sealing: &Sealing) -> Result<Option<Self>> {
+        let pins = backend.get_pins(channel).await?;
+
+        let candidates = pins.iter()
+            .filter(|message| message.content.lines().next().and_then(|line| header_volume(line, MAGIC)).is_some_and(|(found, _)| found == volume));
+        for message in candidates {
+            let (text, attachment_id) = Self::stored_text(backend, channel, message).await?;
+            let superblock = Self::open(message.id, &text, &sealing.keyring)?;
+            if superblock.sealed || sealing.security == SuperblockSecurity::None {
+                return Ok(Some(Self { sealing: sealing.clone(), attachment_id, ..superblock }));
+            }
+
+            log::warn!("Superblock {} is not signed or encrypted (SUPERBLOCK_SECURITY), it is not used.", message.id);
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the text of the superblock message, downloading the attachment of a long superblock,
+    /// and the id of the attachment message (0 if there is none).
+    async fn stored_text(backend: &dyn Backend, channel: ChannelId, message: &StoredMessage) -> Result<(String, u64)> {
+        // Format:
+        // SUPERBLOCK[:<volume>] v4
+        // <attachment_message_id>
+
+        let mut lines = message.content.lines();
+        let Some((volume, VERSION_GZIP)) = lines.next().and_then(|line| header_volume(line, MAGIC)) else {
+            return Ok((message.content.clone(), 0));
+        };
+
+        let line = lines.next().unwrap_or_default();
+        let attachment_id = try_from_base32(line)
+            .ok_or_else(|| Error::InvalidMetadata { message_id: message.id, line: line.to_string() })?;
+        let invalid = |reason: String| Error::InvalidMetadataAttachment { message_id: message.id, reason };
+
+        let attachment = backend.get_message(channel, attachment_id).await?;
+        let url = attachment.attachments.first().ok_or_else(|| invalid("the message has no attachment".to_string()))?;
+        let data = backend.download(url).await?;
+        let mut text = String::new();
+        GzDecoder::new(&data[..]).read_to_string(&mut text).map_err(|e| invalid(e.to_string()))?;
+
+        if text.lines().next().and_then(|line| header_volume(line, MAGIC)).is_none_or(|(found, _)| found != volume) {
+            return Err(invalid(format!("the attachment is not a superblock of volume {:?}", volume)));
+        }
+        Ok((text, attachment_id))
+    }
+
+    /// Updates the list of metadata blocks. Returns true if it changed.
+    pub fn set_blocks(&mut self, blocks: Vec<SuperblockEntry>) -> bool {
+        if blocks == self.blocks {
+            return false;
+        }
+
+        self.blocks = blocks;
+        true
+    }
+
+    /// Updates the stored size of the drive. Returns true if it changed.
+    pub fn set_device_size(&mut self, size: u64) -> bool {
+        if self.device_size == Some(size) {
+            return false;
+        }
+
+        self.device_size = Some(size);
+        true
+    }
+
+    /// Updates the channel new pages go to. Returns true if it changed.
+    pub fn set_data_channel(&mut self, channel: u64) -> bool {
+        if self.data_channel == Some(channel) {
+            return false;
+        }
+
+        self.data_channel = Some(channel);
+        true
+    }
+
+    /// Saves the superblock, sending and pinning it if it doesn't exist yet.
+    /// A superblock longer than a message (`MESSAGE_LIMIT`) is sent as a compressed attachment first,
+    /// and the attachment of the previous save is deleted once the pinned message points at the new one.
+    pub async fn save(&mut self, backend: &dyn Backend, channel: ChannelId) -> Result<()> {
+        let mut text = self.message_text()?;
+        let attachment_id = match text.chars().count() > MESSAGE_LIMIT {
+            true => backend.send_file(channel, "", "superblock.txt.gz", &compress(&text)).await?,
+            false => 0,
+        };
+        if attachment_id != 0 {
+            text = format!("{} v{}\n{}\n", volume_magic(MAGIC, &self.volume), VERSION_GZIP, attachment_id.to_base32());
+        }
+
+        if let Err(e) = self.store(backend, channel, &text).await {
+            // The superblock still points at the old attachment.
+            if attachment_id != 0 {
+                backend.delete_message(channel, attachment_id).await.ok();
+            }
+            return Err(e);
+        }
+
+        if self.attachment_id != 0 {
+            backend.delete_message(channel, self.attachment_id).await.ok();
+        }
+        self.attachment_id = attachment_id;
+        Ok(())
+    }
+
+    /// Edits the pinned message, or sends and pins it if there is none.
+    async fn store(&mut self, backend: &dyn Backend, channel: ChannelId, text: &str) -> Result<()> {
+        if self.message_id != 0 {
+            match backend.edit_message(channel, self.message_id, text).await {
+                Err(BackendError::NotFound) => log::warn!("Superblock {} was deleted, pinning a new one.", self.message_id),
+                result => return Ok(result?),
+            }
+        }
+
+        self.message_id = backend.send_message(channel, text).await?;
+        backend.pin_message(channel, self.message_id).await?;
+        Ok(())
+    }
+}
+
+/// Compresses the text of a long superblock into its attachment.
+fn compress(text: &str) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    // Writing to a vector can't fail.
+    encoder.write_all(text.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Fetches the listed metadata blocks, up to `LOAD_CONCURRENCY` of them at once.
+/// Blocks that were deleted or can't be parsed are skipped.
+pub async fn load_blocks(backend: &dyn Backend, channel: ChannelId, entries: &[SuperblockEntry]) -> Result<Vec<MetadataBlock>> {
+    let mut blocks = Vec::new();
+
+    for chunk in entries.chunks(LOAD_CONCURRENCY) {
+        let loaded = join_all(chunk.iter().map(|entry| async move {
+            let message = backend.get_message(channel, entry.message_id).await?;
+            Ok(MetadataBlock::from_message(backend, channel, &message).await)
+        })).await;
+
+        for (entry, result) in chunk.iter().zip(loaded) {
+            match result {
+                Ok(Ok(block)) => blocks.push(block),
+                Ok(Err(e)) => log::warn!("Failed to parse metadata block listed in the superblock: {}", e),
+                Err(BackendError::NotFound) => log::warn!("Metadata block {} listed in the superblock doesn't exist.", entry.message_id),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Fetches the listed metadata blocks like `load_blocks`, but returns the ones that were deleted
+/// or can't be parsed as problems instead of skipping them.
+pub async fn check_blocks(backend: &dyn Backend, channel: ChannelId, entries: &[SuperblockEntry]) -> Result<Vec<std::result::Result<MetadataBlock, IntegrityProblem>>> {
+    let mut blocks = Vec::new();
+
+    for chunk in entries.chunks(LOAD_CONCURRENCY) {
+        let loaded = join_all(chunk.iter().map(|entry| async move {
+            let message = backend.get_message(channel, entry.message_id).await?;
+            Ok(MetadataBlock::from_message(backend, channel, &message).await)
+        })).await;
+
+        for (entry, result) in chunk.iter().zip(loaded) {
+            let message_id = entry.message_id;
+            blocks.push(match result {
+                Ok(Ok(block)) => Ok(block),
+                Ok(Err(e)) => Err(IntegrityProblem::UnreadableBlock { message_id, reason: e.to_string() }),
+                Err(BackendError::NotFound) => Err(IntegrityProblem::MissingBlock { message_id }),
+                Err(e) => return Err(e.into()),
+            });
+        }
+    }
+
+    Ok(blocks)
+}
+
+/// Metadata of the drive right after startup.
+pub struct LoadedMetadata {
+    /// Metadata blocks that were already fetched
+    pub blocks: Vec<MetadataBlock>,
+    /// Metadata blocks listed in the superblock that are loaded on demand
+    pub unloaded: Vec<SuperblockEntry>,
+    pub superblock: Superblock,
+}
+
+/// Loads metadata of the drive on the volume.
+/// Uses the superblock if it is pinned (fetching blocks only if `eager` is set),
+/// otherwise scans the channel as configured by `scan` and creates it, protected by `sealing`.
+pub async fn load_metadata(backend: &dyn Backend, channel: ChannelId, scan: &Scan, eager: bool, volume: &str, sealing: &Sealing) -> Result<LoadedMetadata> {
+    if let Some(superblock) = Superblock::find(backend, channel, volume, sealing).await? {
+        if !eager {
+            return Ok(LoadedMetadata {
+                blocks: Vec::new(),
+                unloaded: superblock.blocks.clone(),
+                superblock,
+            });
+        }
+
+        return Ok(LoadedMetadata {
+            blocks: load_blocks(backend, channel, &superblock.blocks).await?,
+            unloaded: Vec::new(),
+            superblock,
+        });
+    }
+
+    log::info!("Superblock not found, scanning the channel.");
+
+    let blocks = MetadataBlock::load_all(backend, channel, scan, volume).await?;
+
+    let mut superblock = Superblock { volume: volume.to_string(), sealing: sealing.clone(), ..Superblock::empty() };
+    superblock.set_blocks(blocks.iter().filter_map(SuperblockEntry::of).collect());
+    superblock.save(backend, channel).await?;
+
+    Ok(LoadedMetadata {
+        blocks,
+        unloaded: Vec::new(),
+        superblock,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::metadata::Page;
+
+    const CHANNEL: ChannelId = ChannelId(1);
+
+    #[test]
+    fn superblock_text() {
+        let superblock = Superblock {
+            message_id: 1,
+            blocks: vec![
+                SuperblockEntry { message_id: 1234567890, offsets: Some(vec![0, 33]) },
+                SuperblockEntry { message_id: 42, offsets: Some(vec![]) },
+            ],
+            volume: String::new(),
+            device_size: Some(1024 * 1024 * 128),
+            ..Superblock::empty()
+        };
+
+        let text = superblock.as_text();
+        let parsed = Superblock::from_text(1, &text).unwrap();
+
+        assert!(text.starts_with("SUPERBLOCK v2\nsize 400000\n"));
+        assert_eq!(parsed.blocks, superblock.blocks);
+        assert_eq!(parsed.device_size, superblock.device_size);
+
+        // Superblocks of version 1 have no size.
+        let parsed = Superblock::from_text(1, "SUPERBLOCK v1\n14pc0mi:0\n").unwrap();
+        assert_eq!(parsed.device_size, None);
+        assert_eq!(parsed.blocks.len(), 1);
+
+        // Only superblocks that rolled over to another channel are written as version 3.
+        let superblock = Superblock { data_channel: Some(7), ..superblock };
+        let text = superblock.as_text();
+        assert!(text.starts_with("SUPERBLOCK v3\nsize 400000\nchannel 7\n"));
+        let parsed = Superblock::from_text(1, &text).unwrap();
+        assert_eq!((parsed.data_channel, parsed.blocks), (Some(7), superblock.blocks));
+    }
+
+    fn sealing(security: SuperblockSecurity) -> Sealing {
+        let mut keyring = Keyring::none();
+        keyring.add(1, [7; 32]);
+        Sealing { security, keyring }
+    }
+
+    fn sealed_superblock(security: SuperblockSecurity) -> Superblock {
+        Superblock {
+            blocks: vec![SuperblockEntry { message_id: 1234567890, offsets: Some(vec![0, 33]) }],
+            device_size: Some(4096),
+            sealing: sealing(security),
+            ..Superblock::empty()
+        }
+    }
+
+    #[test]
+    fn sealed_superblock_text() {
+        for security in [SuperblockSecurity::Signed, SuperblockSecurity::Encrypted] {
+            let superblock = sealed_superblock(security);
+            let text = superblock.message_text().unwrap();
+            let opened = Superblock::open(1, &text, &superblock.sealing.keyring).unwrap();
+
+            assert!(text.starts_with("SUPERBLOCK v2\n"));
+            assert!(opened.sealed);
+            assert_eq!(opened.blocks, superblock.blocks);
+            assert_eq!(opened.device_size, Some(4096));
+            // Only the encrypted superblock hides the blocks.
+            assert_eq!(text.contains("14pc0mi"), security == SuperblockSecurity::Signed, "{}", text);
+        }
+
+        // Plain superblocks open like before.
+        let plain = Superblock::open(1, "SUPERBLOCK v2\n14pc0mi\n", &Keyring::none()).unwrap();
+        assert!(!plain.sealed);
+        assert_eq!(plain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn rejects_tampered_superblock() {
+        let keyring = sealing(SuperblockSecurity::None).keyring;
+        let signed = sealed_superblock(SuperblockSecurity::Signed).message_text().unwrap();
+        let encrypted = sealed_superblock(SuperblockSecurity::Encrypted).message_text().unwrap();
+
+        let (sealed_line, _) = encrypted.trim_end().rsplit_once(' ').unwrap();
+        let tampered = [
+            // Redirected block, dropped block, changed size.
+            signed.replace("14pc0mi", "14pc0mj"),
+            signed.lines().take(3).collect::<Vec<_>>().join("\n"),
+            signed.replace("size 400", "size 800"),
+            // Another volume, truncated or changed ciphertext, text appended to it.
+            encrypted.replace("SUPERBLOCK v2", "SUPERBLOCK:other v2"),
+            format!("{} {}\n", sealed_line, &encrypted.trim_end().rsplit_once(' ').unwrap().1[..40]),
+            encrypted.replacen("sealed 1 ", "sealed 1 00", 1),
+            format!("{}14pc0mj\n", encrypted),
+        ];
+        for text in tampered {
+            assert!(matches!(Superblock::open(1, &text, &keyring), Err(Error::TamperedSuperblock { message_id: 1 })), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn tampered_superblock_is_not_used() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            let signed = sealing(SuperblockSecurity::Signed);
+
+            // A plain superblock is ignored, the channel is scanned and a signed one pinned.
+            let mut plain = Superblock::empty();
+            plain.save(&backend, CHANNEL).await.unwrap();
+            assert!(Superblock::find(&backend, CHANNEL, "", &signed).await.unwrap().is_none());
+            let loaded = load_metadata(&backend, CHANNEL, &Scan::default(), false, "", &signed).await.unwrap();
+            backend.delete_message(CHANNEL, plain.message_id).await.unwrap();
+            let found = Superblock::find(&backend, CHANNEL, "", &signed).await.unwrap().unwrap();
+            assert_eq!(found.message_id, loaded.superblock.message_id);
+            assert!(found.sealed);
+
+            // Pointing the superblock at another block fails to open the drive.
+            let message = backend.get_message(CHANNEL, found.message_id).await.unwrap();
+            let forged = format!("{}1\n", message.content);
+            backend.edit_message(CHANNEL, found.message_id, &forged).await.unwrap();
+            let result = load_metadata(&backend, CHANNEL, &Scan::default(), false, "", &signed).await;
+            assert!(matches!(result, Err(Error::TamperedSuperblock { message_id }) if message_id == found.message_id));
+        });
+    }
+
+    #[test]
+    fn superblock_without_offsets() {
+        let superblock = Superblock::from_text(1, "SUPERBLOCK\n14pc0mi\n").unwrap();
+
+        assert_eq!(superblock.blocks, vec![SuperblockEntry { message_id: 1234567890, offsets: None }]);
+        assert!(superblock.blocks[0].may_contain(7));
+    }
+
+    #[test]
+    fn rejects_malformed_superblock() {
+        for text in ["SUPERBLOCK v1\n\n", "SUPERBLOCK v1\n14pc0mi:0,X\n", "SUPERBLOCK v1\nvvvvvvvvvvvvvvvv\n", "SUPERBLOCK v1\n1:2:3\n", "SUPERBLOCK v2\nsize X\n"] {
+            assert!(matches!(Superblock::from_text(1, text), Err(Error::InvalidMetadata { message_id: 1, .. })), "{:?}", text);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn from_text_never_panics(text in "\\PC*") {
+            let _ = Superblock::from_text(1, &text);
+        }
+
+        #[test]
+        fn from_text_never_panics_on_superblock_lines(text in "SUPERBLOCK v1\n([0-9a-vA-Z:,€]{0,100}\n?){0,5}") {
+            let _ = Superblock::from_text(1, &text);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_superblock_version() {
+        assert!(matches!(
+            Superblock::from_text(1, "SUPERBLOCK v999\n14pc0mi\n"),
+            Err(Error::UnsupportedVersion { format: "superblock", version: 999 })
+        ));
+    }
+
+    #[test]
+    fn deleted_superblock_is_pinned_again() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            let mut superblock = Superblock::empty();
+            superblock.save(&backend, CHANNEL).await.unwrap();
+            let deleted = superblock.message_id;
+            backend.delete_message(CHANNEL, deleted).await.unwrap();
+
+            superblock.set_blocks(vec![SuperblockEntry { message_id: 7, offsets: Some(vec![1]) }]);
+            superblock.save(&backend, CHANNEL).await.unwrap();
+            assert_ne!(superblock.message_id, deleted);
+
+            let pins = backend.get_pins(CHANNEL).await.unwrap();
+            assert_eq!(pins.len(), 1);
+            assert_eq!(pins[0].id, superblock.message_id);
+        });
+    }
+
+    #[test]
+    fn saves_superblock_longer_than_a_message() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            // 1000 pages in blocks with snowflake ids.
+            let blocks: Vec<SuperblockEntry> = (0..200)
+                .map(|block| SuperblockEntry { message_id: 1_100_000_000_000_000_000 + block, offsets: Some((block * 5..block * 5 + 5).collect()) })
+                .collect();
+            let signed = sealing(SuperblockSecurity::Signed);
+            let mut superblock = Superblock { sealing: signed.clone(), ..Superblock::empty() };
+            superblock.set_blocks(blocks.clone());
+            assert!(superblock.message_text().unwrap().len() > MESSAGE_LIMIT);
+            superblock.save(&backend, CHANNEL).await.unwrap();
+
+            let message = backend.get_message(CHANNEL, superblock.message_id).await.unwrap();
+            assert!(message.content.starts_with("SUPERBLOCK v4\n"));
+            let attachment_id = superblock.attachment_id;
+            assert_ne!(attachment_id, 0);
+
+            let found = Superblock::find(&backend, CHANNEL, "", &signed).await.unwrap().unwrap();
+            assert_eq!((found.message_id, found.attachment_id), (superblock.message_id, attachment_id));
+            assert_eq!(found.blocks, blocks);
+            assert!(found.sealed);
+
+            // Saving it again replaces the attachment, a short superblock is back in the message.
+            let mut found = found;
+            found.set_blocks(blocks[..100].to_vec());
+            found.save(&backend, CHANNEL).await.unwrap();
+            assert_ne!(found.attachment_id, 0);
+            assert!(backend.get_message(CHANNEL, attachment_id).await.is_err());
+            let loaded = load_metadata(&backend, CHANNEL, &Scan::default(), false, "", &signed).await.unwrap();
+            assert_eq!(loaded.unloaded, blocks[..100]);
+
+            found.set_blocks(blocks[..1].to_vec());
+            found.save(&backend, CHANNEL).await.unwrap();
+            assert_eq!(found.attachment_id, 0);
+            let loaded = load_metadata(&backend, CHANNEL, &Scan::default(), false, "", &signed).await.unwrap();
+            assert_eq!(loaded.unloaded, blocks[..1]);
+            assert_eq!(backend.messages(CHANNEL).len(), 1);
+        });
+    }
+
+    #[test]
+    fn loads_only_indexed_blocks() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            let text = MetadataBlock::empty(None).as_text();
+            let a = backend.send_message(CHANNEL, &text).await.unwrap();
+            let _stray = backend.send_message(CHANNEL, &text).await.unwrap();
+            let b = backend.send_message(CHANNEL, &text).await.unwrap();
+
+            let mut superblock = Superblock::empty();
+            superblock.set_blocks(vec![
+                SuperblockEntry { message_id: a, offsets: Some(vec![]) },
+                SuperblockEntry { message_id: b, offsets: Some(vec![]) },
+            ]);
+            superblock.save(&backend, CHANNEL).await.unwrap();
+
+            let loaded = load_metadata(&backend, CHANNEL, &Scan::default(), true, "", &Sealing::default()).await.unwrap();
+
+            let ids: Vec<Option<u64>> = loaded.blocks.iter().map(|block| block.message_id).collect();
+            assert_eq!(ids, vec![Some(a), Some(b)]);
+            assert_eq!(backend.calls("get_messages"), 0);
+            assert_eq!(backend.calls("get_message"), 2);
+        });
+    }
+
+    #[test]
+    fn fetches_indexed_blocks_concurrently() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            let mut entries = Vec::new();
+            for offset in 0..20 {
+                let block = MetadataBlock { pages: vec![Page::new(offset)], ..MetadataBlock::empty(None) };
+                let message_id = backend.send_message(CHANNEL, &block.as_text()).await.unwrap();
+                entries.push(SuperblockEntry { message_id, offsets: Some(vec![offset]) });
+            }
+            let missing = SuperblockEntry { message_id: 12345, offsets: None };
+            entries.insert(3, missing);
+
+            backend.set_fetch_latency(Duration::from_millis(100));
+            let start = Instant::now();
+            let blocks = load_blocks(&backend, CHANNEL, &entries).await.unwrap();
+
+            // Blocks come back in the order of the superblock, the deleted one is skipped.
+            let offsets: Vec<u64> = blocks.iter().map(|block| block.pages[0].offset).collect();
+            assert_eq!(offsets, (0..20).collect::<Vec<_>>());
+            assert_eq!(backend.max_concurrent_fetches(), LOAD_CONCURRENCY);
+            // Two rounds of fetches instead of 21.
+            assert!(start.elapsed() < Duration::from_millis(1000));
+        });
+    }
+
+    #[test]
+    fn falls_back_to_scan() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+
+        rt.block_on(async {
+            let text = MetadataBlock::empty(None).as_text();
+            backend.send_message(CHANNEL, &text).await.unwrap();
+            backend.send_message(CHANNEL, &text).await.unwrap();
+
+            let loaded = load_metadata(&backend, CHANNEL, &Scan::default(), false, "", &Sealing::default()).await.unwrap();
+
+            assert_eq!(loaded.blocks.len(), 2);
+            assert_eq!(loaded.superblock.blocks.len(), 2);
+            assert!(backend.calls("get_messages") > 0);
+
+            // Superblock should now be pinned.
+            let found = Superblock::find(&backend, CHANNEL, "", &Sealing::default()).await.unwrap().unwrap();
+            assert_eq!(found.message_id, loaded.superblock.message_id);
+        });
+    }
+}
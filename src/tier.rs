@@ -0,0 +1,195 @@
+use std::{collections::VecDeque, fs, io::Write, path::{Path, PathBuf}, sync::Mutex};
+
+use zeroize::Zeroizing;
+
+use crate::crypto::Keyring;
+use crate::error::{Error, Result};
+use crate::metadata::Page;
+use crate::utils::{BitMask, LockOrRecover};
+
+/// Magic starting every page file, followed by the format version.
+const MAGIC: &[u8; 8] = b"DAAFTIER";
+const VERSION: u8 = 1;
+/// Magic, version, page offset, message id, zero mask, key id and the CRC32 of the rest.
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8 + 8 + 256 + 1 + 4;
+const EXTENSION: &str = "page";
+
+/// Local directory that pages evicted from the cache spill to before they are uploaded (`LOCAL_TIER_DIR`).
+/// The sync thread moves the pages from here to discord as fast as it uploads, so bursts of writes
+/// wait on the local disk instead of in memory. Pages are encrypted with the current key of the keyring, like on discord,
+/// and pages left behind by a previous run are uploaded once the drive is opened again.
+pub struct LocalTier {
+    dir: PathBuf,
+    keyring: Keyring,
+    /// Most pages kept, further pages go to the sync queue
+    capacity: usize,
+    /// Offsets of the stored pages, oldest first
+    offsets: Mutex<VecDeque<u64>>,
+}
+
+impl LocalTier {
+    /// Opens (or creates) the tier in the directory.
+    pub fn open(dir: impl AsRef<Path>, keyring: Keyring, capacity: usize) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|error| Error::LocalTier { path: dir.clone(), error })?;
+
+        let files = fs::read_dir(&dir).map_err(|error| Error::LocalTier { path: dir.clone(), error })?;
+        let mut offsets: Vec<u64> = files.filter_map(|file| {
+            let path = file.ok()?.path();
+            if path.extension()? != EXTENSION {
+                return None;
+            }
+            u64::from_str_radix(path.file_stem()?.to_str()?, 16).ok()
+        }).collect();
+        offsets.sort_unstable();
+
+        Ok(Self { dir, keyring, capacity, offsets: Mutex::new(offsets.into()) })
+    }
+
+    fn path(&self, offset: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", offset, EXTENSION))
+    }
+
+    /// Stores the page, unless the tier is full. Returns true if it was stored.
+    /// The page is on disk when this returns.
+    pub fn put(&self, page: &Page, data: &[u8]) -> Result<bool> {
+        let mut offsets = self.offsets.lock_or_recover();
+        if offsets.len() >= self.capacity && !offsets.contains(&page.offset) {
+            return Ok(false);
+        }
+
+        let (key_id, data) = self.keyring.encrypt(data)?;
+        let mut file = Vec::with_capacity(HEADER_LEN + data.len());
+        file.extend_from_slice(MAGIC);
+        file.push(VERSION);
+        file.extend_from_slice(&page.offset.to_le_bytes());
+        file.extend_from_slice(&page.message_id.unwrap_or(0).to_le_bytes());
+        file.extend_from_slice(page.zero_mask.as_bytes());
+        file.push(key_id);
+        file.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        file.extend(data);
+
+        // Written next to the page and renamed, so a crash never leaves half a page.
+        let path = self.path(page.offset);
+        let temporary = path.with_extension("tmp");
+        fs::File::create(&temporary)
+            .and_then(|mut temporary| {
+                temporary.write_all(&file)?;
+                temporary.sync_all()
+            })
+            .and_then(|()| fs::rename(&temporary, &path))
+            .map_err(|error| Error::LocalTier { path, error })?;
+
+        offsets.retain(|offset| *offset != page.offset);
+        offsets.push_back(page.offset);
+        Ok(true)
+    }
+
+    /// Removes and returns the page at given offset (as a multiple of the page size) if it is stored.
+    pub fn take(&self, offset: u64) -> Result<Option<(Page, Zeroizing<Vec<u8>>)>> {
+        let mut offsets = self.offsets.lock_or_recover();
+        let Some(i) = offsets.iter().position(|stored| *stored == offset) else {
+            return Ok(None);
+        };
+        offsets.remove(i);
+
+        self.read(offset).map(Some)
+    }
+
+    /// Removes and returns the page that was stored first.
+    pub fn take_oldest(&self) -> Result<Option<(Page, Zeroizing<Vec<u8>>)>> {
+        let Some(offset) = self.offsets.lock_or_recover().pop_front() else {
+            return Ok(None);
+        };
+
+        self.read(offset).map(Some)
+    }
+
+    /// Reads and deletes the file of the page. A damaged file is deleted as well.
+    fn read(&self, offset: u64) -> Result<(Page, Zeroizing<Vec<u8>>)> {
+        let path = self.path(offset);
+        let file = Zeroizing::new(fs::read(&path).map_err(|error| Error::LocalTier { path: path.clone(), error })?);
+        fs::remove_file(&path).map_err(|error| Error::LocalTier { path: path.clone(), error })?;
+
+        self.parse(&file).map_err(|reason| {
+            Error::LocalTier { path, error: std::io::Error::new(std::io::ErrorKind::InvalidData, reason) }
+        })
+    }
+
+    /// Returns the page and data of a file, or why it can't be used.
+    fn parse(&self, file: &[u8]) -> std::result::Result<(Page, Zeroizing<Vec<u8>>), String> {
+        let (header, data) = file.split_at_checked(HEADER_LEN).ok_or("it is truncated")?;
+        let (magic, header) = header.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err("it is not a page".to_string());
+        }
+        if header[0] != VERSION {
+            return Err(Error::UnsupportedVersion { format: "local tier", version: header[0] as u32 }.to_string());
+        }
+
+        let offset = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let message_id = u64::from_le_bytes(header[9..17].try_into().unwrap());
+        let zero_mask = BitMask::from_bytes(&header[17..273]);
+        let key_id = header[273];
+        if u32::from_le_bytes(header[274..278].try_into().unwrap()) != crc32fast::hash(data) {
+            return Err("its checksum doesn't match".to_string());
+        }
+
+        let data = self.keyring.decrypt(key_id, data).map_err(|e| e.to_string())?;
+        let page = Page { message_id: (message_id != 0).then_some(message_id), zero_mask, ..Page::new(offset) };
+        Ok((page, Zeroizing::new(data)))
+    }
+
+    /// Number of stored pages.
+    pub fn len(&self) -> usize {
+        self.offsets.lock_or_recover().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Offsets of the stored pages (as multiples of the page size), oldest first.
+    pub fn offsets(&self) -> Vec<u64> {
+        self.offsets.lock_or_recover().iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn keeps_pages_until_taken() {
+        let dir = TempDir::new("tier");
+        let mut keyring = Keyring::none();
+        keyring.add(1, [7; 32]);
+
+        let tier = LocalTier::open(&dir, keyring.clone(), 2).unwrap();
+        let mut page = Page { message_id: Some(5), ..Page::new(3) };
+        page.zero_mask.set(1, true);
+        assert!(tier.put(&page, &[3; 4096]).unwrap());
+        assert!(tier.put(&Page::new(1), &[1; 4096]).unwrap());
+        assert!(!tier.put(&Page::new(2), &[2; 4096]).unwrap());
+        // A stored page can be replaced even if the tier is full.
+        assert!(tier.put(&Page::new(1), &[4; 4096]).unwrap());
+
+        // Pages stay on disk and are encrypted.
+        let tier = LocalTier::open(&dir, keyring, 2).unwrap();
+        assert_eq!(tier.offsets(), vec![1, 3]);
+        for file in fs::read_dir(&dir).unwrap() {
+            assert!(!fs::read(file.unwrap().path()).unwrap().windows(16).any(|bytes| bytes == [3; 16]));
+        }
+
+        let (taken, data) = tier.take(3).unwrap().unwrap();
+        assert_eq!((taken.message_id, taken.zero_mask.as_bytes()), (Some(5), page.zero_mask.as_bytes()));
+        assert_eq!(data[0], 3);
+        assert!(tier.take(3).unwrap().is_none());
+
+        let (taken, data) = tier.take_oldest().unwrap().unwrap();
+        assert_eq!((taken.offset, taken.message_id, data[0]), (1, None, 4));
+        assert!(tier.is_empty());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
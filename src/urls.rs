@@ -0,0 +1,144 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use serenity::model::prelude::ChannelId;
+
+use crate::{backend::{Backend, BackendResult}, utils::LockOrRecover};
+
+/// How long a url without an expiry time is used before it is fetched again.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+/// Urls that expire sooner than this are fetched again instead of being used.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Attachment urls of page messages, so reads don't have to fetch the message first.
+/// Discord signs attachment urls and they stop working after a while,
+/// so every url is kept only until it is about to expire.
+#[derive(Clone, Debug, Default)]
+pub struct UrlCache {
+    urls: Arc<Mutex<HashMap<u64, (String, SystemTime)>>>,
+}
+
+impl UrlCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the url of the first attachment of the message, if it is cached and still valid.
+    pub fn get(&self, message_id: u64) -> Option<String> {
+        let mut urls = self.urls.lock_or_recover();
+        let (url, expires) = urls.get(&message_id)?;
+
+        if *expires < SystemTime::now() + EXPIRY_MARGIN {
+            urls.remove(&message_id);
+            return None;
+        }
+        Some(url.clone())
+    }
+
+    pub fn insert(&self, message_id: u64, url: &str) {
+        let expires = expires_at(url).unwrap_or_else(|| SystemTime::now() + DEFAULT_TTL);
+        self.urls.lock_or_recover().insert(message_id, (url.to_string(), expires));
+    }
+
+    /// Forgets the url of the message (it didn't work or the message was replaced).
+    pub fn remove(&self, message_id: u64) {
+        self.urls.lock_or_recover().remove(&message_id);
+    }
+
+    /// Fetches urls of all given messages that are not cached yet.
+    /// Messages are fetched from the channel history 100 at a time, starting at the newest
+    /// missing message, so messages sent one after another (as pages usually are) are fetched together.
+    /// Messages that don't exist are skipped.
+    pub async fn prefetch(&self, backend: &dyn Backend, channel: ChannelId, message_ids: &[u64]) -> BackendResult<()> {
+        let mut missing: Vec<u64> = message_ids.iter()
+            .copied()
+            .filter(|id| self.get(*id).is_none())
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+
+        while let Some(&newest) = missing.last() {
+            let batch = backend.get_messages(channel, Some(newest + 1), 100).await?;
+
+            for message in &batch {
+                if let Some(url) = message.attachments.first() {
+                    if missing.binary_search(&message.id).is_ok() {
+                        self.insert(message.id, url);
+                    }
+                }
+            }
+
+            // Everything newer than the oldest fetched message was either found or doesn't exist.
+            let oldest = match batch.iter().map(|message| message.id).min() {
+                Some(oldest) if batch.len() == 100 => oldest,
+                _ => break,
+            };
+            missing.retain(|id| *id < oldest);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns when a signed discord url expires (the `ex` parameter holds the unix time in hex).
+fn expires_at(url: &str) -> Option<SystemTime> {
+    let (_, query) = url.split_once('?')?;
+    let hex = query.split('&').find_map(|param| param.strip_prefix("ex="))?;
+    let seconds = u64::from_str_radix(hex, 16).ok()?;
+
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::MemoryBackend;
+
+    use super::*;
+
+    const CHANNEL: ChannelId = ChannelId(1);
+
+    #[test]
+    fn parses_expiry() {
+        let url = "https://cdn.discordapp.com/attachments/1/2/a.bin?ex=65ec9a3c&is=65da253c&hm=abc";
+
+        assert_eq!(expires_at(url), Some(UNIX_EPOCH + Duration::from_secs(0x65ec9a3c)));
+        assert_eq!(expires_at("https://cdn.discordapp.com/attachments/1/2/a.bin"), None);
+    }
+
+    #[test]
+    fn drops_expiring_urls() {
+        let urls = UrlCache::new();
+        let soon = (SystemTime::now() + Duration::from_secs(60)).duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let later = (SystemTime::now() + Duration::from_secs(60 * 60)).duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        urls.insert(1, &format!("https://cdn/a.bin?ex={:x}", soon));
+        urls.insert(2, &format!("https://cdn/b.bin?ex={:x}", later));
+        urls.insert(3, "memory://c.bin");
+
+        assert_eq!(urls.get(1), None);
+        assert!(urls.get(2).is_some());
+        assert!(urls.get(3).is_some());
+    }
+
+    #[test]
+    fn prefetches_in_batches() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let backend = MemoryBackend::new();
+        let urls = UrlCache::new();
+
+        rt.block_on(async {
+            let mut ids = Vec::new();
+            for i in 0..150 {
+                ids.push(backend.send_file(CHANNEL, "", &format!("{}.bin", i), &[1]).await.unwrap());
+                backend.send_message(CHANNEL, "not a page").await.unwrap();
+            }
+
+            urls.prefetch(&backend, CHANNEL, &ids).await.unwrap();
+            assert_eq!(backend.calls("get_messages"), 3);
+            assert!(ids.iter().all(|id| urls.get(*id).is_some()));
+
+            // Cached urls are not fetched again.
+            urls.prefetch(&backend, CHANNEL, &ids).await.unwrap();
+            assert_eq!(backend.calls("get_messages"), 3);
+        });
+    }
+}
@@ -1,22 +1,44 @@
+use std::{future::Future, sync::{Mutex, MutexGuard}, task::Poll};
+
 // ========< CONVERSION UTILITIES >========
 /// Converts unsigned integer to base32 string.
 pub fn to_base32(value: u64) -> String {
     let mut value = value;
-    let mut result = String::new();
-    let alphabet = "0123456789abcdefghijklmnopqrstuv";
+    let alphabet = b"0123456789abcdefghijklmnopqrstuv";
     let base = alphabet.len() as u64;
 
+    // u64 has at most 13 base32 digits, fill them from the end.
+    let mut digits = [b'0'; 13];
+    let mut start = digits.len();
+
     while value > 0 {
-        let index = (value % base) as usize;
-        result.push(alphabet.chars().nth(index).unwrap());
+        start -= 1;
+        digits[start] = alphabet[(value % base) as usize];
         value /= base;
     }
 
-    if result == "" {
-        result.push('0');
+    if start == digits.len() {
+        start -= 1;
     }
 
-    result.chars().rev().collect()
+    // Digits are always ascii.
+    String::from_utf8(digits[start..].to_vec()).unwrap()
+}
+
+/// Converts base32 string to unsigned integer.
+/// Returns None if the string is empty, has invalid characters or doesn't fit.
+pub fn try_from_base32(value: &str) -> Option<u64> {
+    let alphabet = "0123456789abcdefghijklmnopqrstuv";
+    let base = alphabet.len() as u64;
+
+    if value.is_empty() {
+        return None;
+    }
+
+    value.chars().try_fold(0u64, |result, c| {
+        let index = alphabet.find(c)? as u64;
+        result.checked_mul(base)?.checked_add(index)
+    })
 }
 
 /// Converts base32 string to unsigned integer.
@@ -68,29 +90,85 @@ impl ToBase32 for u128 {
 
 /// Alphabet used for base255 conversion.
 /// It was chosen to be as readable as possible.
+/// Every character is a single code point that NFC normalization leaves as is,
+/// so discord can't alter stored masks. Changing it breaks masks of existing drives.
 const BASE_255_ALPHABET: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!\"#$%&'()+,-./:;<=>?@[]^{}~‰£¤¥¦§«¬²³µÀÁÂÃÄÅÆÇÈÉÊËÌÍÎÏÐÑÒØßàáâãäåæçèéêëìþÿǷǾǿɅɆɄɃȽȾȺȸȹɎʘʗʖʕʔʓʒʑʊʇʆʁʂϠϡϢϭϱϺϻϿϾϼ◔◍◎◐◑◒◓◚◛◳◲◱◰◯◿◜◝◞◟◠◡◉◊▣▤▥▦▧▨▩▚▙▜▛▝▞▟▂▃▄▅▆▇█▉▊▋▌▍░▒▓①②③④⑤⑥⑦⑧⑨⑩⑪⑫⑬⑭⑮ⒶⒷⒸⒹⒺⒻⒼⒽⒾⒿ⑴⑵⑶⑷⑸⑹‹";
 
 pub fn byte_to_base_255(byte: u8) -> char {
     BASE_255_ALPHABET.chars().nth(byte as usize).unwrap()
 }
 
+/// Returns None if the character is not in the alphabet.
+pub fn try_base_255_to_byte(c: char) -> Option<u8> {
+    BASE_255_ALPHABET.chars().position(|b| b == c).map(|i| i as u8)
+}
+
 pub fn base_255_to_byte(c: char) -> u8 {
     for (i, b) in BASE_255_ALPHABET.chars().enumerate() {
         if b == c {
             return i as u8;
         }
     }
-    return 0;
+    0
 }
 
 #[cfg(test)]
 mod test_utils {
+    use proptest::prelude::*;
+
+    use super::ToBase32;
+
+    proptest! {
+        #[test]
+        fn base32_round_trips_u64(value in any::<u64>()) {
+            prop_assert_eq!(u64::from_base32(&value.to_base32()), value);
+            prop_assert_eq!(super::try_from_base32(&value.to_base32()), Some(value));
+        }
+
+        #[test]
+        fn base32_round_trips_u128(value in any::<u128>()) {
+            prop_assert_eq!(u128::from_base32(&value.to_base32()), value);
+        }
+    }
+
+    #[test]
+    fn base32_round_trips_edges() {
+        for value in [0, 1, 31, 32, u64::MAX - 1, u64::MAX] {
+            assert_eq!(u64::from_base32(&value.to_base32()), value);
+        }
+
+        // Values with a zero high or low word, and words at their limits.
+        for value in [0, 1, u64::MAX as u128, 1 << 64, (u64::MAX as u128) << 64, u128::MAX - 1, u128::MAX] {
+            assert_eq!(u128::from_base32(&value.to_base32()), value);
+        }
+        assert_eq!(u128::MAX.to_base32(), "fvvvvvvvvvvvv.fvvvvvvvvvvvv");
+        assert_eq!((1u128 << 64).to_base32(), "1.0");
+    }
+
     #[test]
     fn to_base32() {
         let value = super::to_base32(1234567890);
         assert_eq!(value, "14pc0mi");
     }
 
+    #[test]
+    fn try_from_base32() {
+        assert_eq!(super::try_from_base32("14pc0mi"), Some(1234567890));
+        assert_eq!(super::try_from_base32("fvvvvvvvvvvvv"), Some(u64::MAX));
+        assert_eq!(super::try_from_base32("g0000000000000"), None);
+        assert_eq!(super::try_from_base32("14PC0MI"), None);
+        assert_eq!(super::try_from_base32(""), None);
+    }
+
+    #[test]
+    fn to_base32_edges() {
+        assert_eq!(super::to_base32(0), "0");
+        assert_eq!(super::to_base32(31), "v");
+        assert_eq!(super::to_base32(32), "10");
+        assert_eq!(super::to_base32(u64::MAX), "fvvvvvvvvvvvv");
+        assert_eq!(super::from_base32("fvvvvvvvvvvvv"), u64::MAX);
+    }
+
     #[test]
     fn from_base32() {
         let value = super::from_base32("14pc0mi");
@@ -122,6 +200,113 @@ mod test_utils {
             assert_eq!(i, b);
         }
     }
+
+    #[test]
+    fn base255_alphabet_is_unique() {
+        let mut chars: Vec<char> = super::BASE_255_ALPHABET.chars().collect();
+        assert_eq!(chars.len(), 256);
+
+        chars.sort_unstable();
+        chars.dedup();
+        assert_eq!(chars.len(), 256);
+    }
+
+    #[test]
+    fn base255_survives_nfc() {
+        use unicode_normalization::UnicodeNormalization;
+
+        for byte in 0..=255 {
+            let c = super::byte_to_base_255(byte);
+            let normalized: String = c.to_string().nfc().collect();
+            assert_eq!(normalized, c.to_string(), "{} ({:?}) is changed by NFC", byte, c);
+        }
+
+        // Neighbouring characters must not compose either.
+        let text: String = (0..=255).map(super::byte_to_base_255).collect();
+        let normalized: String = text.nfc().collect();
+        let bytes: Vec<Option<u8>> = normalized.chars().map(super::try_base_255_to_byte).collect();
+        assert_eq!(bytes, (0..=255).map(Some).collect::<Vec<_>>());
+    }
+}
+
+
+// ========< FORMAT UTILITIES >========
+/// Converts bytes to lowercase hex.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Converts hex to bytes. Returns None if the length is odd or a digit is invalid.
+pub fn try_from_hex(value: &str) -> Option<Vec<u8>> {
+    (0..value.len()).step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Returns the format version of a `<magic> v<version>` header line of the default volume.
+/// A bare magic is version 0 (written before formats were versioned).
+/// Returns None if the line is not a header of the format.
+pub fn header_version(line: &str, magic: &str) -> Option<u32> {
+    header_volume(line, magic).filter(|(volume, _)| volume.is_empty()).map(|(_, version)| version)
+}
+
+/// Returns the volume and format version of a `<magic>[:<volume>] v<version>` header line.
+/// The volume is empty for the default volume, whose headers are written like before volumes existed.
+pub fn header_volume<'a>(line: &'a str, magic: &str) -> Option<(&'a str, u32)> {
+    let rest = line.strip_prefix(magic)?;
+    let (volume, rest) = match rest.strip_prefix(':') {
+        Some(rest) => {
+            let (volume, rest) = rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+            if !is_volume_name(volume) {
+                return None;
+            }
+            (volume, rest)
+        }
+        None => ("", rest),
+    };
+
+    if rest.is_empty() {
+        return Some((volume, 0));
+    }
+    Some((volume, rest.strip_prefix(" v")?.parse().ok()?))
+}
+
+/// Magic of headers of the volume (see `header_volume`).
+pub fn volume_magic(magic: &str, volume: &str) -> String {
+    match volume {
+        "" => magic.to_string(),
+        volume => format!("{}:{}", magic, volume),
+    }
+}
+
+/// Volume names are up to 32 letters, digits, `-` and `_`, so they can't be confused with the rest of a header.
+pub fn is_volume_name(volume: &str) -> bool {
+    !volume.is_empty() && volume.len() <= 32 && volume.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod test_format {
+    #[test]
+    fn header_version() {
+        assert_eq!(super::header_version("METABLOCK", "METABLOCK"), Some(0));
+        assert_eq!(super::header_version("METABLOCK v1", "METABLOCK"), Some(1));
+        assert_eq!(super::header_version("METABLOCK v999", "METABLOCK"), Some(999));
+        assert_eq!(super::header_version("METABLOCKS are neat", "METABLOCK"), None);
+        assert_eq!(super::header_version("METABLOCK vx", "METABLOCK"), None);
+        assert_eq!(super::header_version("METABLOCK:photos v1", "METABLOCK"), None);
+    }
+
+    #[test]
+    fn header_volume() {
+        assert_eq!(super::header_volume("METABLOCK v1", "METABLOCK"), Some(("", 1)));
+        assert_eq!(super::header_volume("METABLOCK:photos v1", "METABLOCK"), Some(("photos", 1)));
+        assert_eq!(super::header_volume("METABLOCK:photos", "METABLOCK"), Some(("photos", 0)));
+        assert_eq!(super::header_volume("METABLOCK: v1", "METABLOCK"), None);
+        assert_eq!(super::header_volume("METABLOCK:a/b v1", "METABLOCK"), None);
+        assert_eq!(super::header_volume("METABLOCKS are neat", "METABLOCK"), None);
+        assert_eq!(super::volume_magic("METABLOCK", "photos"), "METABLOCK:photos");
+        assert_eq!(super::volume_magic("METABLOCK", ""), "METABLOCK");
+    }
 }
 
 
@@ -131,6 +316,12 @@ pub struct BitMask<const S: usize> {
     mask: [u8; S]
 }
 
+impl<const S: usize> Default for BitMask<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const S: usize> BitMask<S> {
     pub const fn new() -> Self {
         Self {
@@ -166,6 +357,16 @@ impl<const S: usize> BitMask<S> {
         self.mask[byte] & (1 << bit) != 0
     }
 
+    /// Returns true if every bit is set.
+    pub fn is_all_set(&self) -> bool {
+        self.mask.iter().all(|byte| *byte == 0xFF)
+    }
+
+    /// Returns the number of set bits.
+    pub fn count_ones(&self) -> usize {
+        self.mask.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         &self.mask
     }
@@ -184,6 +385,17 @@ impl<const S: usize> BitMask<S> {
 
 #[cfg(test)]
 mod test_bitmask {
+    #[test]
+    fn test_bitmask_count_ones() {
+        let mut mask = super::BitMask::<2>::new();
+        assert_eq!(mask.count_ones(), 0);
+
+        mask.set(0, true);
+        mask.set(9, true);
+        mask.set(15, true);
+        assert_eq!(mask.count_ones(), 3);
+    }
+
     #[test]
     fn test_bitmask() {
         let mut mask = super::BitMask::<1>::new();
@@ -231,4 +443,188 @@ mod test_bitmask {
 
         assert_eq!(mask.mask[0], 0b00000000);
     }
+
+    #[test]
+    fn test_bitmask_all_set() {
+        let mut mask = super::BitMask::<2>::new();
+        assert!(!mask.is_all_set());
+
+        for i in 0..16 {
+            mask.set(i, true);
+        }
+        assert!(mask.is_all_set());
+
+        mask.set(9, false);
+        assert!(!mask.is_all_set());
+    }
+}
+
+
+// ========< SYNC UTILITIES >========
+/// Allows locking a mutex without panicking if it was poisoned.
+pub trait LockOrRecover<T> {
+    /// Locks the mutex. If a thread panicked while holding it, the lock
+    /// is recovered (with a warning) instead of propagating the panic.
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockOrRecover<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|poisoned| {
+            log::warn!("Recovering from a poisoned lock, a thread panicked while holding it.");
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
+/// Runs the futures concurrently on the current task and returns their outputs in order.
+/// Unlike spawned tasks, the futures may borrow (like the backend of a drive).
+pub async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures.into_iter().map(|future| Some(Box::pin(future))).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+
+    std::future::poll_fn(|cx| {
+        let mut pending = false;
+        for (slot, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            let Some(future) = slot else {
+                continue;
+            };
+            match future.as_mut().poll(cx) {
+                Poll::Ready(value) => {
+                    *output = Some(value);
+                    *slot = None;
+                }
+                Poll::Pending => pending = true,
+            }
+        }
+
+        if pending { Poll::Pending } else { Poll::Ready(()) }
+    }).await;
+
+    outputs.into_iter().map(|output| output.expect("Every future finished")).collect()
+}
+
+/// Directory for tests that doesn't exist yet, removed with everything in it once dropped.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Directory named `daafs-<name>-<random>` in the temporary directory of the system.
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("daafs-{}-{}", name, crate::crypto::random_name(""))))
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Allocator of the tests that checks whether buffers of `WipeCheck::CAPACITY` bytes are wiped before they are freed,
+/// so tests can drop real page buffers and see their memory.
+#[cfg(test)]
+pub struct WipeCheck;
+
+#[cfg(test)]
+static WIPED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+#[cfg(test)]
+static NOT_WIPED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: WipeCheck = WipeCheck;
+
+#[cfg(test)]
+impl WipeCheck {
+    /// Capacity of the checked buffers, odd enough that nothing else allocates it.
+    pub const CAPACITY: usize = 3 * 4096 + 5;
+
+    /// Empty buffer that is checked when it is freed.
+    pub fn buffer() -> Vec<u8> {
+        Vec::with_capacity(Self::CAPACITY)
+    }
+
+    /// Checked buffers freed so far, wiped and not.
+    pub fn freed() -> (usize, usize) {
+        (WIPED.load(std::sync::atomic::Ordering::SeqCst), NOT_WIPED.load(std::sync::atomic::Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+unsafe impl std::alloc::GlobalAlloc for WipeCheck {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        unsafe { std::alloc::System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        if layout.size() == Self::CAPACITY {
+            // The buffer is still allocated, so it can be read.
+            let wiped = unsafe { std::slice::from_raw_parts(ptr, layout.size()) }.iter().all(|byte| *byte == 0);
+            let counter = if wiped { &WIPED } else { &NOT_WIPED };
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        unsafe { std::alloc::System.dealloc(ptr, layout) }
+    }
+}
+
+#[cfg(test)]
+mod test_sync {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::{join_all, LockOrRecover};
+
+    #[test]
+    fn joins_futures_concurrently() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let start = Instant::now();
+
+        let outputs = rt.block_on(join_all((0..10u64).rev().map(|i| async move {
+            tokio::time::sleep(Duration::from_millis(10 * i)).await;
+            i
+        })));
+
+        assert_eq!(outputs, (0..10).rev().collect::<Vec<_>>());
+        assert!(start.elapsed() < Duration::from_millis(450));
+        assert!(rt.block_on(join_all(Vec::<std::future::Ready<()>>::new())).is_empty());
+    }
+
+    #[test]
+    fn recovers_poisoned_lock() {
+        let mutex = Arc::new(Mutex::new(1));
+
+        let m = mutex.clone();
+        let result = std::thread::spawn(move || {
+            let mut guard = m.lock().unwrap();
+            *guard = 2;
+            panic!("Poisoning the lock");
+        }).join();
+
+        assert!(result.is_err());
+        assert!(mutex.is_poisoned());
+
+        assert_eq!(*mutex.lock_or_recover(), 2);
+        *mutex.lock_or_recover() = 3;
+        assert_eq!(*mutex.lock_or_recover(), 3);
+    }
 }
\ No newline at end of file
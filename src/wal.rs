@@ -0,0 +1,328 @@
+use std::{collections::HashMap, fs, io::{Read, Write}, path::{Path, PathBuf}, str::FromStr, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use zeroize::Zeroizing;
+
+use crate::crypto::Keyring;
+use crate::error::{Error, Result};
+
+/// Magic starting every entry, followed by the format version.
+const MAGIC: &[u8; 7] = b"DAAFWAL";
+/// Version 2 entries store the id of their `QueuePersistence` after the version, version 1 entries are raw.
+const VERSION: u8 = 2;
+const VERSION_RAW: u8 = 1;
+/// Magic, version, persistence id, page offset, key id and the CRC32 of the rest.
+const HEADER_LEN: usize = MAGIC.len() + 1 + 1 + 8 + 1 + 4;
+const EXTENSION: &str = "page";
+
+/// How the data of a queued page is stored in its log entry (before it is encrypted).
+/// Formats trade disk space for the time it takes to write and replay the log.
+pub trait QueuePersistence: Send + Sync {
+    /// Stored in every entry, so entries are decoded by the format that wrote them.
+    /// 0 to 2 are the built-in `WalFormat`s.
+    fn id(&self) -> u8;
+
+    fn encode(&self, page: &[u8]) -> Zeroizing<Vec<u8>>;
+
+    /// Returns the page data of an encoded entry, or why it can't be decoded.
+    fn decode(&self, encoded: &[u8]) -> std::result::Result<Zeroizing<Vec<u8>>, String>;
+}
+
+/// Built-in formats of the log (`WAL_FORMAT`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalFormat {
+    /// The whole page as it is, the fastest to write and replay
+    #[default]
+    Raw,
+    /// The length of the page followed by the page without its trailing zeros,
+    /// smaller for pages that are only partly written
+    Trimmed,
+    /// The page compressed with gzip, the smallest but slowest
+    Gzip,
+}
+
+impl WalFormat {
+    /// Returns the built-in format with the id.
+    fn of_id(id: u8) -> Option<Self> {
+        [Self::Raw, Self::Trimmed, Self::Gzip].into_iter().find(|format| format.id() == id)
+    }
+}
+
+impl FromStr for WalFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "trimmed" => Ok(Self::Trimmed),
+            "gzip" => Ok(Self::Gzip),
+            _ => Err(()),
+        }
+    }
+}
+
+impl QueuePersistence for WalFormat {
+    fn id(&self) -> u8 {
+        *self as u8
+    }
+
+    fn encode(&self, page: &[u8]) -> Zeroizing<Vec<u8>> {
+        match self {
+            Self::Raw => Zeroizing::new(page.to_vec()),
+            Self::Trimmed => {
+                let len = page.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+                let mut encoded = Zeroizing::new(Vec::with_capacity(4 + len));
+                encoded.extend_from_slice(&(page.len() as u32).to_le_bytes());
+                encoded.extend_from_slice(&page[..len]);
+                encoded
+            }
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(page).unwrap();
+                Zeroizing::new(encoder.finish().unwrap())
+            }
+        }
+    }
+
+    fn decode(&self, encoded: &[u8]) -> std::result::Result<Zeroizing<Vec<u8>>, String> {
+        match self {
+            Self::Raw => Ok(Zeroizing::new(encoded.to_vec())),
+            Self::Trimmed => {
+                let (len, data) = encoded.split_at_checked(4).ok_or("it is truncated")?;
+                let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                if data.len() > len {
+                    return Err("it is longer than its page".to_string());
+                }
+                let mut page = Zeroizing::new(vec![0; len]);
+                page[..data.len()].copy_from_slice(data);
+                Ok(page)
+            }
+            Self::Gzip => {
+                let mut page = Zeroizing::new(Vec::new());
+                GzDecoder::new(encoded).read_to_end(&mut page).map_err(|e| format!("it doesn't decompress ({})", e))?;
+                Ok(page)
+            }
+        }
+    }
+}
+
+/// Local write-ahead log of pages queued for upload (`WAL_DIR`).
+/// Every queued page is written to its own file before it is uploaded, and the log is cleared
+/// once a flush synced everything, so pages that were queued when the process died can be uploaded on the next start.
+/// Pages are encrypted with the current key of the keyring, like on discord.
+pub struct Wal {
+    dir: PathBuf,
+    keyring: Keyring,
+    /// Format new entries are written in
+    persistence: Arc<dyn QueuePersistence>,
+    /// Number of the next entry, entries of the same page with higher numbers are newer
+    next: AtomicU64,
+}
+
+impl Wal {
+    /// Opens (or creates) the log in the directory.
+    pub fn open(dir: impl AsRef<Path>, keyring: Keyring) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|error| Error::Wal { path: dir.clone(), error })?;
+
+        let next = Self::entries(&dir)?.iter().map(|(number, _)| number + 1).max().unwrap_or(0);
+        Ok(Self { dir, keyring, persistence: Arc::new(WalFormat::Raw), next: AtomicU64::new(next) })
+    }
+
+    /// Writes new entries in the format. Entries written in other formats are still read,
+    /// if they are built-in or the format has the same id.
+    pub fn with_persistence(self, persistence: Arc<dyn QueuePersistence>) -> Self {
+        Self { persistence, ..self }
+    }
+
+    /// Numbers and paths of the entries in the directory.
+    fn entries(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+        let files = fs::read_dir(dir).map_err(|error| Error::Wal { path: dir.to_path_buf(), error })?;
+
+        Ok(files.filter_map(|file| {
+            let path = file.ok()?.path();
+            let (_, number) = path.file_stem()?.to_str()?.split_once('-')?;
+            if path.extension()? != EXTENSION {
+                return None;
+            }
+            Some((u64::from_str_radix(number, 16).ok()?, path))
+        }).collect())
+    }
+
+    /// Records the data of the page at given offset (as a multiple of the page size).
+    /// The entry is on disk when this returns.
+    pub fn append(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let (key_id, data) = self.keyring.encrypt(&self.persistence.encode(data))?;
+        let mut entry = Vec::with_capacity(HEADER_LEN + data.len());
+        entry.extend_from_slice(MAGIC);
+        entry.push(VERSION);
+        entry.push(self.persistence.id());
+        entry.extend_from_slice(&offset.to_le_bytes());
+        entry.push(key_id);
+        entry.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        entry.extend(data);
+
+        // Written next to the log and renamed, so a crash never leaves half an entry.
+        let number = self.next.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("{}-{:016x}.{}", offset, number, EXTENSION));
+        let temporary = path.with_extension("tmp");
+        let written = fs::File::create(&temporary)
+            .and_then(|mut file| {
+                file.write_all(&entry)?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temporary, &path));
+
+        written.map_err(|error| Error::Wal { path, error })
+    }
+
+    /// Returns the newest data of every logged page, by its offset.
+    /// Entries that are damaged are skipped.
+    pub fn pending(&self) -> Result<Vec<(u64, Zeroizing<Vec<u8>>)>> {
+        let mut entries = Self::entries(&self.dir)?;
+        entries.sort_unstable();
+
+        let mut pages = HashMap::new();
+        for (_, path) in entries {
+            let entry = fs::read(&path).map_err(|error| Error::Wal { path: path.clone(), error })?;
+            match self.parse(&entry) {
+                Ok((offset, data)) => {
+                    pages.insert(offset, data);
+                }
+                Err(reason) => log::warn!("Skipping write-ahead log entry {}: {}", path.display(), reason),
+            }
+        }
+
+        let mut pages: Vec<(u64, Zeroizing<Vec<u8>>)> = pages.into_iter().collect();
+        pages.sort_unstable_by_key(|(offset, _)| *offset);
+        Ok(pages)
+    }
+
+    /// Returns the page offset and data of an entry, or why it can't be used.
+    fn parse(&self, entry: &[u8]) -> std::result::Result<(u64, Zeroizing<Vec<u8>>), String> {
+        let (magic, entry) = entry.split_at_checked(MAGIC.len()).ok_or("it is truncated")?;
+        if magic != MAGIC {
+            return Err("it is not an entry".to_string());
+        }
+        let (persistence, entry): (Arc<dyn QueuePersistence>, &[u8]) = match entry.first() {
+            Some(&VERSION_RAW) => (Arc::new(WalFormat::Raw), entry),
+            Some(&VERSION) => {
+                let id = *entry.get(1).ok_or("it is truncated")?;
+                let persistence: Arc<dyn QueuePersistence> = match WalFormat::of_id(id) {
+                    _ if id == self.persistence.id() => self.persistence.clone(),
+                    Some(format) => Arc::new(format),
+                    None => return Err(format!("it was written in unknown format {}", id)),
+                };
+                // The rest of the header is that of version 1, after the id.
+                (persistence, &entry[1..])
+            }
+            Some(version) => return Err(Error::UnsupportedVersion { format: "write-ahead log", version: *version as u32 }.to_string()),
+            None => return Err("it is truncated".to_string()),
+        };
+        let (header, data) = entry.split_at_checked(HEADER_LEN - MAGIC.len() - 1).ok_or("it is truncated")?;
+
+        let offset = u64::from_le_bytes(header[1..9].try_into().unwrap());
+        let key_id = header[9];
+        if u32::from_le_bytes(header[10..14].try_into().unwrap()) != crc32fast::hash(data) {
+            return Err("its checksum doesn't match".to_string());
+        }
+
+        let data = Zeroizing::new(self.keyring.decrypt(key_id, data).map_err(|e| e.to_string())?);
+        Ok((offset, persistence.decode(&data)?))
+    }
+
+    /// Removes every entry, the pages are synced.
+    pub fn clear(&self) -> Result<()> {
+        for (_, path) in Self::entries(&self.dir)? {
+            fs::remove_file(&path).map_err(|error| Error::Wal { path, error })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::TempDir;
+
+    #[test]
+    fn keeps_newest_entries() {
+        let dir = TempDir::new("wal");
+        let mut keyring = Keyring::none();
+        keyring.add(1, [7; 32]);
+
+        let wal = Wal::open(&dir, keyring.clone()).unwrap();
+        wal.append(3, &[1; 4096]).unwrap();
+        wal.append(1, &[2; 4096]).unwrap();
+        wal.append(3, &[3; 4096]).unwrap();
+
+        // Entries stay on disk and are encrypted.
+        let wal = Wal::open(&dir, keyring).unwrap();
+        let pending = wal.pending().unwrap();
+        assert_eq!(pending.iter().map(|(offset, data)| (*offset, data[0])).collect::<Vec<_>>(), vec![(1, 2), (3, 3)]);
+        for (_, path) in Wal::entries(&dir).unwrap() {
+            assert!(!fs::read(path).unwrap().windows(16).any(|bytes| bytes == [3; 16]));
+        }
+
+        wal.clear().unwrap();
+        assert!(wal.pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trips_pages_in_every_format() {
+        let page = [[5; 4096], [0; 4096]].concat().repeat(4);
+        for format in [WalFormat::default(), WalFormat::Trimmed, WalFormat::Gzip] {
+            let encoded = format.encode(&page);
+            assert_eq!(*format.decode(&encoded).unwrap(), page, "{:?} should round trip", format);
+            assert_eq!(*format.decode(&format.encode(&[])).unwrap(), Vec::<u8>::new());
+        }
+        assert_eq!(WalFormat::Trimmed.encode(&page).len(), 4 + 7 * 4096);
+        assert!(WalFormat::Trimmed.decode(&[1, 0, 0, 0, 1, 1]).is_err());
+
+        // Entries are recovered by the format that wrote them, whatever the log writes now.
+        let dir = TempDir::new("wal");
+        let wal = Wal::open(&dir, Keyring::none()).unwrap();
+        wal.append(0, &page).unwrap();
+        let wal = wal.with_persistence(Arc::new(WalFormat::Gzip));
+        wal.append(1, &page).unwrap();
+        wal.append(2, &[]).unwrap();
+
+        let wal = Wal::open(&dir, Keyring::none()).unwrap().with_persistence(Arc::new(WalFormat::Trimmed));
+        let pending = wal.pending().unwrap();
+        assert_eq!(pending.iter().map(|(offset, data)| (*offset, data.len())).collect::<Vec<_>>(), vec![(0, page.len()), (1, page.len()), (2, 0)]);
+        assert!(pending.iter().take(2).all(|(_, data)| **data == page));
+    }
+
+    #[test]
+    fn reads_version_1_entries() {
+        let dir = TempDir::new("wal");
+        fs::create_dir_all(&dir).unwrap();
+        let data = [3; 4096];
+        let mut entry = MAGIC.to_vec();
+        entry.push(VERSION_RAW);
+        entry.extend_from_slice(&4u64.to_le_bytes());
+        entry.push(0);
+        entry.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        entry.extend_from_slice(&data);
+        fs::write(dir.join(format!("4-{:016x}.{}", 0, EXTENSION)), entry).unwrap();
+
+        let wal = Wal::open(&dir, Keyring::none()).unwrap();
+        assert_eq!(wal.pending().unwrap().iter().map(|(offset, data)| (*offset, data.to_vec())).collect::<Vec<_>>(), vec![(4, data.to_vec())]);
+    }
+
+    #[test]
+    fn skips_damaged_entries() {
+        let dir = TempDir::new("wal");
+        let wal = Wal::open(&dir, Keyring::none()).unwrap();
+        wal.append(0, &[1; 4096]).unwrap();
+        wal.append(1, &[2; 4096]).unwrap();
+
+        let (_, path) = Wal::entries(&dir).unwrap().into_iter().find(|(number, _)| *number == 1).unwrap();
+        let mut entry = fs::read(&path).unwrap();
+        entry[HEADER_LEN] ^= 1;
+        fs::write(&path, entry).unwrap();
+
+        assert_eq!(wal.pending().unwrap().iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), vec![0]);
+    }
+}